@@ -12,11 +12,11 @@ use bitcoin::util::hash::Sha256dHash;
 
 use crypto::digest::Digest;
 
-use lightning::chain::chaininterface::{BroadcasterInterface,ConfirmationTarget,ChainListener,FeeEstimator,ChainWatchInterfaceUtil};
+use lightning::chain::chaininterface::{BroadcasterInterface,ConfirmationTarget,ChainListener,FeeEstimator,ChainWatchInterfaceUtil,WalletInterface};
 use lightning::chain::transaction::OutPoint;
 use lightning::ln::channelmonitor;
 use lightning::ln::channelmanager::ChannelManager;
-use lightning::ln::peer_handler::{MessageHandler,PeerManager,SocketDescriptor};
+use lightning::ln::peer_handler::{MessageHandler,PeerManager,PeerLimits,SocketDescriptor};
 use lightning::ln::router::Router;
 use lightning::util::events::{EventsProvider,Event};
 use lightning::util::reset_rng_state;
@@ -36,6 +36,7 @@ use std::cmp;
 use std::hash::Hash;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::Duration;
 
 #[inline]
 pub fn slice_to_be16(v: &[u8]) -> u16 {
@@ -104,6 +105,15 @@ impl BroadcasterInterface for TestBroadcaster {
 	fn broadcast_transaction(&self, _tx: &Transaction) {}
 }
 
+struct TestWallet {}
+impl WalletInterface for TestWallet {
+	fn get_shutdown_scriptpubkey(&self) -> Script {
+		let mut script = vec![0, 20];
+		script.extend_from_slice(&[0; 20]);
+		Script::from(script)
+	}
+}
+
 #[derive(Clone)]
 struct Peer<'a> {
 	id: u8,
@@ -234,16 +244,21 @@ pub fn do_test(data: &[u8], logger: &Arc<Logger>) {
 
 	let watch = Arc::new(ChainWatchInterfaceUtil::new(Network::Bitcoin, Arc::clone(&logger)));
 	let broadcast = Arc::new(TestBroadcaster{});
-	let monitor = channelmonitor::SimpleManyChannelMonitor::new(watch.clone(), broadcast.clone());
+	let monitor = channelmonitor::SimpleManyChannelMonitor::new(watch.clone(), broadcast.clone(), fee_est.clone());
 
-	let channelmanager = ChannelManager::new(our_network_key, slice_to_be32(get_slice!(4)), get_slice!(1)[0] != 0, Network::Bitcoin, fee_est.clone(), monitor.clone(), watch.clone(), broadcast.clone(), Arc::clone(&logger)).unwrap();
+	let channelmanager = ChannelManager::new(our_network_key, slice_to_be32(get_slice!(4)), get_slice!(1)[0] != 0, Network::Bitcoin, fee_est.clone(), monitor.clone(), watch.clone(), broadcast.clone(), Arc::new(TestWallet{}), Arc::clone(&logger)).unwrap();
 	let router = Arc::new(Router::new(PublicKey::from_secret_key(&secp_ctx, &our_network_key), watch.clone(), Arc::clone(&logger)));
 
 	let peers = RefCell::new([false; 256]);
 	let mut loss_detector = MoneyLossDetector::new(&peers, channelmanager.clone(), monitor.clone(), PeerManager::new(MessageHandler {
 		chan_handler: channelmanager.clone(),
 		route_handler: router.clone(),
-	}, our_network_key, Arc::clone(&logger)));
+	}, our_network_key, PeerLimits {
+		max_peers: 256,
+		max_channelless_peers: 256,
+		max_pending_handshakes: 256,
+		handshake_timeout: Duration::from_secs(60),
+	}, Arc::clone(&logger)));
 
 	let mut should_forward = false;
 	let mut payments_received: Vec<[u8; 32]> = Vec::new();
@@ -275,7 +290,7 @@ pub fn do_test(data: &[u8], logger: &Arc<Logger>) {
 					}
 				}
 				if new_id == 0 { return; }
-				loss_detector.handler.new_inbound_connection(Peer{id: (new_id - 1) as u8, peers_connected: &peers}).unwrap();
+				loss_detector.handler.new_inbound_connection(Peer{id: (new_id - 1) as u8, peers_connected: &peers}, None).unwrap();
 				peers.borrow_mut()[new_id - 1] = true;
 			},
 			2 => {
@@ -426,6 +441,10 @@ pub fn do_test(data: &[u8], logger: &Arc<Logger>) {
 				},
 				Event::PaymentSent {..} => {},
 				Event::PaymentFailed {..} => {},
+				Event::PaymentForwarded {..} => {},
+				Event::ChannelPending {..} => {},
+				Event::ChannelReady {..} => {},
+				Event::ChannelClosed {..} => {},
 
 				Event::PendingHTLCsForwardable {..} => {
 					should_forward = true;
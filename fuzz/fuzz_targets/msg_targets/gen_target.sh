@@ -30,7 +30,8 @@ GEN_TEST NodeAnnouncement test_msg_exact ""
 
 GEN_TEST UpdateAddHTLC test_msg_hole ", 85, 33"
 GEN_TEST ErrorMessage test_msg_hole ", 32, 2"
-GEN_TEST OnionHopData test_msg_hole ", 1+8+8+4, 12"
 
 GEN_TEST Ping test_msg_simple ""
 GEN_TEST Pong test_msg_simple ""
+# TLV hop data drops unknown odd records, so doesn't round-trip
+GEN_TEST OnionHopData test_msg_simple ""
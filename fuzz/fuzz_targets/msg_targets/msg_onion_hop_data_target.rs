@@ -12,7 +12,7 @@ use utils::VecWriter;
 #[inline]
 pub fn do_test(data: &[u8]) {
 	reset_rng_state();
-	test_msg_hole!(msgs::OnionHopData, data, 1+8+8+4, 12);
+	test_msg_simple!(msgs::OnionHopData, data);
 }
 
 #[cfg(feature = "afl")]
@@ -206,6 +206,9 @@ pub fn do_test(data: &[u8]) {
 								remote_network_id: get_pubkey!(),
 								channel_value_satoshis: slice_to_be64(get_slice!(8)),
 								user_id: 0,
+								shutdown_scriptpubkey: Script::new(),
+								their_shutdown_scriptpubkey: None,
+								is_enabled: true,
 							});
 						}
 						Some(&first_hops_vec[..])
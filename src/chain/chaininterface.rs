@@ -63,6 +63,16 @@ pub trait BroadcasterInterface: Sync + Send {
 	fn broadcast_transaction(&self, tx: &Transaction);
 }
 
+/// An interface to the user's on-chain wallet, which receives our funds when channels are closed.
+pub trait WalletInterface: Sync + Send {
+	/// Gets a scriptPubKey to which our balance should be paid when a channel is closed
+	/// cooperatively. Called once per channel, as the channel is opened, and committed to via
+	/// option_upfront_shutdown_script, so should generally return a fresh script each time.
+	///
+	/// Must be a P2PKH, P2SH, P2WPKH or P2WSH script, or the channel will fail to open.
+	fn get_shutdown_scriptpubkey(&self) -> Script;
+}
+
 /// A trait indicating a desire to listen for events from the chain
 pub trait ChainListener: Sync + Send {
 	/// Notifies a listener that a block was connected.
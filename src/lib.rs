@@ -11,6 +11,7 @@
 
 #![cfg_attr(not(feature = "fuzztarget"), deny(missing_docs))]
 
+extern crate bech32;
 extern crate bitcoin;
 extern crate crypto;
 extern crate rand;
@@ -21,3 +22,4 @@ extern crate secp256k1;
 pub mod util;
 pub mod chain;
 pub mod ln;
+pub mod net;
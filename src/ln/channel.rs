@@ -30,7 +30,8 @@ use util::errors::APIError;
 use std;
 use std::default::Default;
 use std::{cmp,mem};
-use std::time::Instant;
+use std::collections::HashMap;
+use std::time::{Instant,Duration};
 use std::sync::{Arc};
 
 pub struct ChannelKeys {
@@ -39,6 +40,8 @@ pub struct ChannelKeys {
 	pub payment_base_key: SecretKey,
 	pub delayed_payment_base_key: SecretKey,
 	pub htlc_base_key: SecretKey,
+	/// Unused: cooperative closes now pay out to WalletInterface::get_shutdown_scriptpubkey.
+	#[deprecated(note = "Unused, closing transactions pay to WalletInterface::get_shutdown_scriptpubkey")]
 	pub channel_close_key: SecretKey,
 	pub channel_monitor_claim_key: SecretKey,
 	pub commitment_seed: [u8; 32],
@@ -56,6 +59,7 @@ pub struct ChannelValueStat {
 }
 
 impl ChannelKeys {
+	#[allow(deprecated)]
 	pub fn new_from_seed(seed: &[u8; 32]) -> Result<ChannelKeys, secp256k1::Error> {
 		let mut prk = [0; 32];
 		hkdf_extract(Sha256::new(), b"rust-lightning key gen salt", seed, &mut prk);
@@ -184,6 +188,23 @@ struct OutboundHTLCOutput {
 	fail_reason: Option<HTLCFailReason>,
 }
 
+/// The state of an update_fee which has not yet been irrevocably committed, tracked in the same
+/// way as the HTLC states above so that it can be dropped or re-sent across a reconnection.
+#[derive(Clone, Copy, PartialEq)]
+enum FeeUpdateState {
+	/// Only used on inbound channels. Received an update_fee from the remote, to be included in
+	/// the next local commitment tx. Dropped on disconnection, as the remote will re-send it.
+	RemoteAnnounced,
+	/// Only used on inbound channels. Included in a received commitment_signed message (implying
+	/// we've revoke_and_ack'ed it and updated feerate_per_kw), but we were AwaitingRemoteRevoke so
+	/// have not yet sent a commitment_signed at the new feerate. We'll do so once they revoke.
+	AwaitingRemoteRevokeToAnnounce,
+	/// Only used on outbound channels. Sent in an update_fee along with a commitment_signed. When
+	/// they next revoke_and_ack feerate_per_kw is updated. Until then the update_fee must be
+	/// re-sent with any commitment_signed we re-transmit on reconnection.
+	Outbound,
+}
+
 macro_rules! get_htlc_in_commitment {
 	($htlc: expr, $offered: expr) => {
 		HTLCOutputInCommitment {
@@ -267,11 +288,50 @@ enum ChannelState {
 	/// We've successfully negotiated a closing_signed dance. At this point ChannelManager is about
 	/// to drop us, but we store this anyway.
 	ShutdownComplete = 2048,
+	/// Flag which is set on ChannelFunded to indicate that a ChannelMonitor update failed with a
+	/// TemporaryFailure. Until ChannelManager::test_restore_channel_monitor succeeds in storing it
+	/// we may not send any revoke_and_ack or commitment_signed, so they (and any HTLCs they
+	/// would have let us forward or fail backwards) are held in the monitor_pending_* fields.
+	MonitorUpdateFailed = (1 << 12),
 }
 const BOTH_SIDES_SHUTDOWN_MASK: u32 = (ChannelState::LocalShutdownSent as u32 | ChannelState::RemoteShutdownSent as u32);
-const MULTI_STATE_FLAGS: u32 = (BOTH_SIDES_SHUTDOWN_MASK | ChannelState::PeerDisconnected as u32);
+const MULTI_STATE_FLAGS: u32 = (BOTH_SIDES_SHUTDOWN_MASK | ChannelState::PeerDisconnected as u32 | ChannelState::MonitorUpdateFailed as u32);
+
+/// The order in which a revoke_and_ack and commitment_signed which were both generated while a
+/// ChannelMonitor update was failing must be sent once it succeeds.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RAACommitmentOrder {
+	/// Send the commitment_signed (and the updates before it) first
+	CommitmentFirst,
+	/// Send the revoke_and_ack first
+	RevokeAndACKFirst,
+}
 
-const INITIAL_COMMITMENT_NUMBER: u64 = (1 << 48) - 1;
+pub(super) const INITIAL_COMMITMENT_NUMBER: u64 = (1 << 48) - 1;
+
+/// Whether our channel_update for a channel announces it as enabled. This follows is_live(), but
+/// only once the channel has been (dis)connected for ChannelManager's channel disable timeout, so
+/// that peers briefly disconnecting don't cause us to flood the network with channel_updates.
+#[derive(Clone, Copy, PartialEq)]
+enum ChannelUpdateStatus {
+	Enabled,
+	/// The channel stopped being live at the given time, but is still announced as enabled.
+	DisabledStaged(Instant),
+	Disabled,
+	/// The channel became live again at the given time, but is still announced as disabled.
+	EnabledStaged(Instant),
+}
+
+/// The parameters we forward HTLCs over a channel with, as announced in our channel_update for it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(super) struct ForwardingConfig {
+	pub(super) fee_base_msat: u32,
+	pub(super) fee_proportional_millionths: u32,
+	pub(super) cltv_expiry_delta: u16,
+	pub(super) htlc_minimum_msat: u64,
+	/// Not (yet) included in our channel_update, but still enforced on HTLCs we forward.
+	pub(super) htlc_maximum_msat: u64,
+}
 
 // TODO: We should refactor this to be an Inbound/OutboundChannel until initial setup handshaking
 // has been completed, and then turn into a Channel to get compiler-time enforcement of things like
@@ -297,10 +357,15 @@ pub(super) struct Channel {
 	cur_remote_commitment_transaction_number: u64,
 	value_to_self_msat: u64, // Excluding all pending_htlcs, excluding fees
 	pending_inbound_htlcs: Vec<InboundHTLCOutput>,
+	/// Inbound HTLCs which we forwarded and have since claimed, by htlc_id, with the channel_id and
+	/// amount (in msat) of the outbound HTLC they were forwarded over. Handed back by
+	/// revoke_and_ack once the claim is irrevocably committed.
+	claimed_forwarded_htlcs: HashMap<u64, ([u8; 32], u64)>,
 	pending_outbound_htlcs: Vec<OutboundHTLCOutput>,
 	holding_cell_htlc_updates: Vec<HTLCUpdateAwaitingACK>,
 
-	// pending_update_fee is filled when sending and receiving update_fee
+	// pending_update_fee is filled when sending and receiving update_fee, see FeeUpdateState for
+	// the lifecycle of each side.
 	// For outbound channel, feerate_per_kw is updated with the value from
 	// pending_update_fee when revoke_and_ack is received
 	//
@@ -311,16 +376,36 @@ pub(super) struct Channel {
 	// revoke_and_ack is received and new commitment_signed is generated to be
 	// sent to the funder. Otherwise, the pending value is removed when receiving
 	// commitment_signed.
-	pending_update_fee: Option<u64>,
+	pending_update_fee: Option<(u64, FeeUpdateState)>,
 	// update_fee() during ChannelState::AwaitingRemoteRevoke is hold in
 	// holdina_cell_update_fee then moved to pending_udpate_fee when revoke_and_ack
 	// is received. holding_cell_update_fee is updated when there are additional
 	// update_fee() during ChannelState::AwaitingRemoteRevoke.
 	holding_cell_update_fee: Option<u64>,
+	// Set while ChannelState::MonitorUpdateFailed is, tracking the messages we owe our peer once
+	// the ChannelMonitor update succeeds. The messages themselves are re-generated then, the same
+	// way we would re-send them in response to a channel_reestablish.
+	monitor_pending_revoke_and_ack: bool,
+	monitor_pending_commitment_signed: bool,
+	monitor_pending_order: RAACommitmentOrder,
+	monitor_pending_forwards: Vec<(PendingForwardHTLCInfo, u64)>,
+	monitor_pending_failures: Vec<(HTLCSource, [u8; 32], HTLCFailReason)>,
 	next_local_htlc_id: u64,
 	next_remote_htlc_id: u64,
 	channel_update_count: u32,
 	feerate_per_kw: u64,
+	/// Set by ChannelManager::update_channel_config, otherwise ChannelManager's defaults apply.
+	forwarding_config: Option<ForwardingConfig>,
+	/// The config which applied before the last update_channel_config, which HTLCs may still be
+	/// forwarded under until the given time, so that payments routed with it don't fail.
+	prev_forwarding_config: Option<(ForwardingConfig, Instant)>,
+	update_status: ChannelUpdateStatus,
+	/// The (node, bitcoin) announcement_signatures our peer sent us, once we've received them. Until
+	/// then we (re-)send ours on each reconnection.
+	their_announcement_sigs: Option<(Signature, Signature)>,
+	/// Whether we've sent our announcement_signatures since the last channel_reestablish, so that
+	/// we send them at most once per connection.
+	announcement_sigs_sent: bool,
 
 	#[cfg(debug_assertions)]
 	/// Max to_local and to_remote outputs in a locally-generated commitment transaction
@@ -335,7 +420,13 @@ pub(super) struct Channel {
 	#[cfg(not(test))]
 	last_local_commitment_txn: Vec<Transaction>,
 
-	last_sent_closing_fee: Option<(u64, u64)>, // (feerate, fee)
+	last_sent_closing_fee: Option<u64>,
+	/// The feerate (in sat/1000-weight) the user asked us to use for our initial closing_signed
+	/// proposal, if any. Only used if we're the funder.
+	target_closing_feerate: Option<u64>,
+	/// The maximum total fee the user is willing to pay for the closing transaction, if any. Only
+	/// used if we're the funder.
+	max_closing_fee_satoshis: Option<u64>,
 
 	/// The hash of the block in which the funding transaction reached our CONF_TARGET. We use this
 	/// to detect unconfirmation after a serialize-unserialize roudtrip where we may not see a full
@@ -371,6 +462,11 @@ pub(super) struct Channel {
 	their_prev_commitment_point: Option<PublicKey>,
 	their_node_id: PublicKey,
 
+	/// The script our funds will be paid to on cooperative close, committed to in our
+	/// open_channel/accept_channel via option_upfront_shutdown_script.
+	shutdown_scriptpubkey: Script,
+	/// Set either when our peer commits to a shutdown script in their open_channel/accept_channel
+	/// or when they send us shutdown, after which it may no longer change.
 	their_shutdown_scriptpubkey: Option<Script>,
 
 	channel_monitor: ChannelMonitor,
@@ -390,6 +486,10 @@ const BREAKDOWN_TIMEOUT: u16 = 6 * 24 * 7; //TODO?
 const MAX_LOCAL_BREAKDOWN_TIMEOUT: u16 = 6 * 24 * 14;
 const COMMITMENT_TX_BASE_WEIGHT: u64 = 724;
 const COMMITMENT_TX_WEIGHT_PER_HTLC: u64 = 172;
+
+/// We only send an update_fee once our fee estimate has moved at least this many percent away
+/// from a channel's current feerate, to avoid spamming updates as the estimate jitters.
+const UPDATE_FEE_HYSTERESIS_PERCENT: u64 = 20;
 const SPENDING_INPUT_FOR_A_OUTPUT_WEIGHT: u64 = 79; // prevout: 36, nSequence: 4, script len: 1, witness lengths: (3+1)/4, sig: 73/4, if-selector: 1, redeemScript: (6 ops + 2*33 pubkeys + 1*2 delay)/4
 const B_OUTPUT_PLUS_SPENDING_INPUT_WEIGHT: u64 = 104; // prevout: 40, nSequence: 4, script len: 1, witness lengths: 3/4, sig: 73/4, pubkey: 33/4, output: 31 (TODO: Wrong? Useless?)
 /// Maximmum `funding_satoshis` value, according to the BOLT #2 specification
@@ -402,6 +502,13 @@ pub const MAX_FUNDING_SATOSHIS: u64 = (1 << 24);
 pub(super) enum ChannelError {
 	Ignore(&'static str),
 	Close(&'static str),
+	/// Our counterparty proved our channel state is stale, so the channel must be closed without
+	/// broadcasting our local commitment transaction. The ChannelMonitor update (which lets us
+	/// claim our funds from their commitment transaction) must be persisted first.
+	CloseDelayBroadcast {
+		msg: &'static str,
+		update: ChannelMonitor,
+	},
 }
 
 macro_rules! secp_call {
@@ -453,10 +560,13 @@ impl Channel {
 	}
 
 	// Constructors:
-	pub fn new_outbound(fee_estimator: &FeeEstimator, chan_keys: ChannelKeys, their_node_id: PublicKey, channel_value_satoshis: u64, push_msat: u64, announce_publicly: bool, user_id: u64, logger: Arc<Logger>) -> Result<Channel, APIError> {
+	pub fn new_outbound(fee_estimator: &FeeEstimator, chan_keys: ChannelKeys, their_node_id: PublicKey, channel_value_satoshis: u64, push_msat: u64, announce_publicly: bool, user_id: u64, shutdown_scriptpubkey: Script, logger: Arc<Logger>) -> Result<Channel, APIError> {
 		if channel_value_satoshis >= MAX_FUNDING_SATOSHIS {
 			return Err(APIError::APIMisuseError{err: "funding value > 2^24"});
 		}
+		if !Channel::is_valid_shutdown_scriptpubkey(&shutdown_scriptpubkey) {
+			return Err(APIError::APIMisuseError{err: "Shutdown scriptpubkey must be P2PKH, P2SH, P2WPKH or P2WSH"});
+		}
 
 		if push_msat > channel_value_satoshis * 1000 {
 			return Err(APIError::APIMisuseError{err: "push value > channel value"});
@@ -493,13 +603,24 @@ impl Channel {
 			cur_remote_commitment_transaction_number: INITIAL_COMMITMENT_NUMBER,
 			value_to_self_msat: channel_value_satoshis * 1000 - push_msat,
 			pending_inbound_htlcs: Vec::new(),
+			claimed_forwarded_htlcs: HashMap::new(),
 			pending_outbound_htlcs: Vec::new(),
 			holding_cell_htlc_updates: Vec::new(),
 			pending_update_fee: None,
 			holding_cell_update_fee: None,
+			monitor_pending_revoke_and_ack: false,
+			monitor_pending_commitment_signed: false,
+			monitor_pending_order: RAACommitmentOrder::RevokeAndACKFirst,
+			monitor_pending_forwards: Vec::new(),
+			monitor_pending_failures: Vec::new(),
 			next_local_htlc_id: 0,
 			next_remote_htlc_id: 0,
 			channel_update_count: 1,
+			forwarding_config: None,
+			prev_forwarding_config: None,
+			update_status: ChannelUpdateStatus::Enabled,
+			their_announcement_sigs: None,
+			announcement_sigs_sent: false,
 
 			#[cfg(debug_assertions)]
 			max_commitment_tx_output_local: ::std::sync::Mutex::new((channel_value_satoshis * 1000 - push_msat, push_msat)),
@@ -509,6 +630,8 @@ impl Channel {
 			last_local_commitment_txn: Vec::new(),
 
 			last_sent_closing_fee: None,
+			target_closing_feerate: None,
+			max_closing_fee_satoshis: None,
 
 			funding_tx_confirmed_in: None,
 			short_channel_id: None,
@@ -535,6 +658,7 @@ impl Channel {
 			their_prev_commitment_point: None,
 			their_node_id: their_node_id,
 
+			shutdown_scriptpubkey,
 			their_shutdown_scriptpubkey: None,
 
 			channel_monitor: channel_monitor,
@@ -555,7 +679,7 @@ impl Channel {
 
 	/// Creates a new channel from a remote sides' request for one.
 	/// Assumes chain_hash has already been checked and corresponds with what we expect!
-	pub fn new_from_req(fee_estimator: &FeeEstimator, chan_keys: ChannelKeys, their_node_id: PublicKey, msg: &msgs::OpenChannel, user_id: u64, require_announce: bool, allow_announce: bool, logger: Arc<Logger>) -> Result<Channel, ChannelError> {
+	pub fn new_from_req(fee_estimator: &FeeEstimator, chan_keys: ChannelKeys, their_node_id: PublicKey, msg: &msgs::OpenChannel, user_id: u64, require_announce: bool, allow_announce: bool, shutdown_scriptpubkey: Script, logger: Arc<Logger>) -> Result<Channel, ChannelError> {
 		// Check sanity of message fields:
 		if msg.funding_satoshis >= MAX_FUNDING_SATOSHIS {
 			return Err(ChannelError::Close("funding value > 2^24"));
@@ -586,6 +710,10 @@ impl Channel {
 		if msg.max_accepted_htlcs > 483 {
 			return Err(ChannelError::Close("max_accpted_htlcs > 483"));
 		}
+		let their_shutdown_scriptpubkey = Channel::get_upfront_shutdown_scriptpubkey(&msg.shutdown_scriptpubkey)?;
+		if !Channel::is_valid_shutdown_scriptpubkey(&shutdown_scriptpubkey) {
+			return Err(ChannelError::Close("Our shutdown scriptpubkey is not of a standard form"));
+		}
 
 		// Convert things into internal flags and prep our state:
 
@@ -648,13 +776,24 @@ impl Channel {
 			cur_remote_commitment_transaction_number: INITIAL_COMMITMENT_NUMBER,
 			value_to_self_msat: msg.push_msat,
 			pending_inbound_htlcs: Vec::new(),
+			claimed_forwarded_htlcs: HashMap::new(),
 			pending_outbound_htlcs: Vec::new(),
 			holding_cell_htlc_updates: Vec::new(),
 			pending_update_fee: None,
 			holding_cell_update_fee: None,
+			monitor_pending_revoke_and_ack: false,
+			monitor_pending_commitment_signed: false,
+			monitor_pending_order: RAACommitmentOrder::RevokeAndACKFirst,
+			monitor_pending_forwards: Vec::new(),
+			monitor_pending_failures: Vec::new(),
 			next_local_htlc_id: 0,
 			next_remote_htlc_id: 0,
 			channel_update_count: 1,
+			forwarding_config: None,
+			prev_forwarding_config: None,
+			update_status: ChannelUpdateStatus::Enabled,
+			their_announcement_sigs: None,
+			announcement_sigs_sent: false,
 
 			#[cfg(debug_assertions)]
 			max_commitment_tx_output_local: ::std::sync::Mutex::new((msg.push_msat, msg.funding_satoshis * 1000 - msg.push_msat)),
@@ -664,6 +803,8 @@ impl Channel {
 			last_local_commitment_txn: Vec::new(),
 
 			last_sent_closing_fee: None,
+			target_closing_feerate: None,
+			max_closing_fee_satoshis: None,
 
 			funding_tx_confirmed_in: None,
 			short_channel_id: None,
@@ -691,7 +832,8 @@ impl Channel {
 			their_prev_commitment_point: None,
 			their_node_id: their_node_id,
 
-			their_shutdown_scriptpubkey: None,
+			shutdown_scriptpubkey,
+			their_shutdown_scriptpubkey,
 
 			channel_monitor: channel_monitor,
 
@@ -918,8 +1060,26 @@ impl Channel {
 
 	#[inline]
 	fn get_closing_scriptpubkey(&self) -> Script {
-		let our_channel_close_key_hash = Hash160::from_data(&PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.channel_close_key).serialize());
-		Builder::new().push_opcode(opcodes::All::OP_PUSHBYTES_0).push_slice(&our_channel_close_key_hash[..]).into_script()
+		self.shutdown_scriptpubkey.clone()
+	}
+
+	/// BOLT 2 only allows shutdown scripts of certain standard forms, which are at most 34 bytes.
+	fn is_valid_shutdown_scriptpubkey(script: &Script) -> bool {
+		script.is_p2pkh() || script.is_p2sh() || script.is_v0_p2wpkh() || script.is_v0_p2wsh()
+	}
+
+	/// Checks the (optional) shutdown_scriptpubkey from an open_channel/accept_channel message,
+	/// returning the script our peer committed to, if any. An empty script means they opted out.
+	fn get_upfront_shutdown_scriptpubkey(script: &Option<Script>) -> Result<Option<Script>, ChannelError> {
+		match script {
+			&Some(ref script) if script.len() != 0 => {
+				if !Channel::is_valid_shutdown_scriptpubkey(script) {
+					return Err(ChannelError::Close("Peer committed to a shutdown_scriptpubkey which is not of a standard form"));
+				}
+				Ok(Some(script.clone()))
+			},
+			_ => Ok(None),
+		}
 	}
 
 	#[inline]
@@ -927,6 +1087,39 @@ impl Channel {
 		(4 + 1 + 36 + 4 + 1 + 1 + 2*(8+1) + 4 + a_scriptpubkey.len() as u64 + b_scriptpubkey.len() as u64)*4 + 2 + 1 + 1 + 2*(1 + 72)
 	}
 
+	/// Gets the range of total fees (min, max) we're willing to sign for a closing transaction of
+	/// the given weight. The funder pays the closing fee, so only it has an upper bound, either as
+	/// provided by the user or based on our Normal feerate, while the fundee insists on at least our
+	/// Background feerate so that the closing transaction will confirm.
+	fn get_closing_fee_range(&self, fee_estimator: &FeeEstimator, tx_weight: u64) -> (u64, u64) {
+		if self.channel_outbound {
+			let max_fee = match self.max_closing_fee_satoshis {
+				Some(max_fee) => max_fee,
+				None => {
+					let mut max_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal);
+					if let Some(target_feerate) = self.target_closing_feerate {
+						if target_feerate > max_feerate {
+							max_feerate = target_feerate;
+						}
+					}
+					max_feerate * tx_weight / 1000
+				}
+			};
+			(0, max_fee)
+		} else {
+			(fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background) * tx_weight / 1000, 21000000 * 100000000)
+		}
+	}
+
+	/// Gets the total fee we propose in our first closing_signed as the funder.
+	fn get_initial_closing_fee(&self, fee_estimator: &FeeEstimator, tx_weight: u64) -> u64 {
+		let proposed_feerate = match self.target_closing_feerate {
+			Some(target_feerate) => target_feerate,
+			None => cmp::max(fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background), self.feerate_per_kw),
+		};
+		cmp::min(proposed_feerate * tx_weight / 1000, self.get_closing_fee_range(fee_estimator, tx_weight).1)
+	}
+
 	#[inline]
 	fn build_closing_transaction(&self, proposed_total_fee_satoshis: u64, skip_remote_output: bool) -> (Transaction, u64) {
 		let txins = {
@@ -1156,7 +1349,7 @@ impl Channel {
 		// can claim it even if the channel hits the chain before we see their next commitment.
 		self.channel_monitor.provide_payment_preimage(&payment_hash_calc, &payment_preimage_arg);
 
-		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32 | ChannelState::PeerDisconnected as u32)) != 0 {
+		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32 | ChannelState::PeerDisconnected as u32 | ChannelState::MonitorUpdateFailed as u32)) != 0 {
 			for pending_update in self.holding_cell_htlc_updates.iter() {
 				match pending_update {
 					&HTLCUpdateAwaitingACK::ClaimHTLC { htlc_id, .. } => {
@@ -1233,7 +1426,7 @@ impl Channel {
 		}
 
 		// Now update local state:
-		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32 | ChannelState::PeerDisconnected as u32)) != 0 {
+		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32 | ChannelState::PeerDisconnected as u32 | ChannelState::MonitorUpdateFailed as u32)) != 0 {
 			for pending_update in self.holding_cell_htlc_updates.iter() {
 				match pending_update {
 					&HTLCUpdateAwaitingACK::ClaimHTLC { htlc_id, .. } => {
@@ -1320,6 +1513,7 @@ impl Channel {
 		if msg.max_accepted_htlcs > 483 {
 			return Err(ChannelError::Close("max_accpted_htlcs > 483"));
 		}
+		let their_shutdown_scriptpubkey = Channel::get_upfront_shutdown_scriptpubkey(&msg.shutdown_scriptpubkey)?;
 
 		// TODO: Optional additional constraints mentioned in the spec
 		// MAY fail the channel if
@@ -1344,6 +1538,7 @@ impl Channel {
 		self.their_delayed_payment_basepoint = Some(msg.delayed_payment_basepoint);
 		self.their_htlc_basepoint = Some(msg.htlc_basepoint);
 		self.their_cur_commitment_point = Some(msg.first_per_commitment_point);
+		self.their_shutdown_scriptpubkey = their_shutdown_scriptpubkey;
 
 		let obscure_factor = self.get_commitment_transaction_number_obscure_factor();
 		self.channel_monitor.set_commitment_obscure_factor(obscure_factor);
@@ -1530,7 +1725,8 @@ impl Channel {
 			return Err(HandleError{err: "Remote provided CLTV expiry in seconds instead of block height", action: None});
 		}
 
-		//TODO: Check msg.cltv_expiry further? Do this in channel manager?
+		// Note that cltv_expiry is checked against the current block height in ChannelManager, where
+		// we can fail the HTLC back with the right onion error.
 
 		// Now update local state:
 		self.next_remote_htlc_id += 1;
@@ -1545,9 +1741,31 @@ impl Channel {
 		Ok(())
 	}
 
+	/// Removes any HTLCs we were waiting to add to the channel (but haven't yet sent to our
+	/// counterparty) which expire at or before expiry_height, returning them so that they can be
+	/// failed backwards.
+	pub fn time_out_holding_cell_htlcs(&mut self, expiry_height: u32) -> Vec<(HTLCSource, [u8; 32])> {
+		let mut timed_out_htlcs = Vec::new();
+		self.holding_cell_htlc_updates.retain(|htlc_update| {
+			match htlc_update {
+				&HTLCUpdateAwaitingACK::AddHTLC { ref payment_hash, ref source, cltv_expiry, .. } => {
+					if cltv_expiry <= expiry_height {
+						timed_out_htlcs.push((source.clone(), payment_hash.clone()));
+						false
+					} else { true }
+				},
+				_ => true,
+			}
+		});
+		if !timed_out_htlcs.is_empty() {
+			log_debug!(self, "Timed out {} holding cell HTLCs on channel {} before adding them", timed_out_htlcs.len(), log_bytes!(self.channel_id()));
+		}
+		timed_out_htlcs
+	}
+
 	/// Removes an outbound HTLC which has been commitment_signed by the remote end
 	#[inline]
-	fn mark_outbound_htlc_removed(&mut self, htlc_id: u64, check_preimage: Option<[u8; 32]>, fail_reason: Option<HTLCFailReason>) -> Result<&HTLCSource, ChannelError> {
+	fn mark_outbound_htlc_removed(&mut self, htlc_id: u64, check_preimage: Option<[u8; 32]>, fail_reason: Option<HTLCFailReason>) -> Result<&OutboundHTLCOutput, ChannelError> {
 		for htlc in self.pending_outbound_htlcs.iter_mut() {
 			if htlc.htlc_id == htlc_id {
 				match check_preimage {
@@ -1567,13 +1785,14 @@ impl Channel {
 					OutboundHTLCState::AwaitingRemoteRevokeToRemove | OutboundHTLCState::AwaitingRemovedRemoteRevoke | OutboundHTLCState::RemoteRemoved =>
 						return Err(ChannelError::Close("Remote tried to fulfill HTLC that they'd already fulfilled")),
 				}
-				return Ok(&htlc.source);
+				return Ok(htlc);
 			}
 		}
 		Err(ChannelError::Close("Remote tried to fulfill/fail an HTLC we couldn't find"))
 	}
 
-	pub fn update_fulfill_htlc(&mut self, msg: &msgs::UpdateFulfillHTLC) -> Result<&HTLCSource, ChannelError> {
+	/// Returns the source of the fulfilled HTLC as well as its amount, in msat.
+	pub fn update_fulfill_htlc(&mut self, msg: &msgs::UpdateFulfillHTLC) -> Result<(HTLCSource, u64), ChannelError> {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32)) != (ChannelState::ChannelFunded as u32) {
 			return Err(ChannelError::Close("Got fulfill HTLC message when channel was not in an operational state"));
 		}
@@ -1586,7 +1805,7 @@ impl Channel {
 		let mut payment_hash = [0; 32];
 		sha.result(&mut payment_hash);
 
-		self.mark_outbound_htlc_removed(msg.htlc_id, Some(payment_hash), None)
+		self.mark_outbound_htlc_removed(msg.htlc_id, Some(payment_hash), None).map(|htlc| (htlc.source.clone(), htlc.amount_msat))
 	}
 
 	pub fn update_fail_htlc(&mut self, msg: &msgs::UpdateFailHTLC, fail_reason: HTLCFailReason) -> Result<&HTLCSource, ChannelError> {
@@ -1597,7 +1816,7 @@ impl Channel {
 			return Err(ChannelError::Close("Peer sent update_fail_htlc when we needed a channel_reestablish"));
 		}
 
-		self.mark_outbound_htlc_removed(msg.htlc_id, None, Some(fail_reason))
+		self.mark_outbound_htlc_removed(msg.htlc_id, None, Some(fail_reason)).map(|htlc| &htlc.source)
 	}
 
 	pub fn update_fail_malformed_htlc<'a>(&mut self, msg: &msgs::UpdateFailMalformedHTLC, fail_reason: HTLCFailReason) -> Result<&HTLCSource, ChannelError> {
@@ -1608,7 +1827,7 @@ impl Channel {
 			return Err(ChannelError::Close("Peer sent update_fail_malformed_htlc when we needed a channel_reestablish"));
 		}
 
-		self.mark_outbound_htlc_removed(msg.htlc_id, None, Some(fail_reason))
+		self.mark_outbound_htlc_removed(msg.htlc_id, None, Some(fail_reason)).map(|htlc| &htlc.source)
 	}
 
 	pub fn commitment_signed(&mut self, msg: &msgs::CommitmentSigned) -> Result<(msgs::RevokeAndACK, Option<msgs::CommitmentSigned>, ChannelMonitor), HandleError> {
@@ -1623,10 +1842,9 @@ impl Channel {
 
 		let local_keys = self.build_local_transaction_keys(self.cur_local_commitment_transaction_number)?;
 
-		let feerate_per_kw = if !self.channel_outbound && self.pending_update_fee.is_some() {
-			self.pending_update_fee.unwrap()
-		} else {
-			self.feerate_per_kw
+		let feerate_per_kw = match self.pending_update_fee {
+			Some((feerate, FeeUpdateState::RemoteAnnounced)) => feerate,
+			_ => self.feerate_per_kw,
 		};
 
 		let mut local_commitment_tx = self.build_commitment_transaction(self.cur_local_commitment_transaction_number, &local_keys, true, false, feerate_per_kw);
@@ -1663,16 +1881,16 @@ impl Channel {
 
 		// Update state now that we've passed all the can-fail calls...
 		let mut need_our_commitment = false;
-		if !self.channel_outbound {
-			if let Some(fee_update) = self.pending_update_fee {
-				self.feerate_per_kw = fee_update;
-				// We later use the presence of pending_update_fee to indicate we should generate a
-				// commitment_signed upon receipt of revoke_and_ack, so we can only set it to None
-				// if we're not awaiting a revoke (ie will send a commitment_signed now).
-				if (self.channel_state & ChannelState::AwaitingRemoteRevoke as u32) == 0 {
-					need_our_commitment = true;
-					self.pending_update_fee = None;
-				}
+		if let Some((feerate, FeeUpdateState::RemoteAnnounced)) = self.pending_update_fee {
+			debug_assert!(!self.channel_outbound);
+			self.feerate_per_kw = feerate;
+			// If we're awaiting a revoke we can't send a commitment_signed now, so keep the
+			// update around to generate one upon receipt of revoke_and_ack.
+			if (self.channel_state & ChannelState::AwaitingRemoteRevoke as u32) == 0 {
+				need_our_commitment = true;
+				self.pending_update_fee = None;
+			} else {
+				self.pending_update_fee = Some((feerate, FeeUpdateState::AwaitingRemoteRevokeToAnnounce));
 			}
 		}
 
@@ -1779,7 +1997,8 @@ impl Channel {
 						return Ok(None);
 					}
 					let update_fee = if let Some(feerate) = self.holding_cell_update_fee {
-							self.pending_update_fee = self.holding_cell_update_fee.take();
+							self.pending_update_fee = Some((feerate, FeeUpdateState::Outbound));
+							self.holding_cell_update_fee = None;
 							Some(msgs::UpdateFee {
 								channel_id: self.channel_id,
 								feerate_per_kw: feerate as u32,
@@ -1809,7 +2028,9 @@ impl Channel {
 	/// waiting on this revoke_and_ack. The generation of this new commitment_signed may also fail,
 	/// generating an appropriate error *after* the channel state has been updated based on the
 	/// revoke_and_ack message.
-	pub fn revoke_and_ack(&mut self, msg: &msgs::RevokeAndACK) -> Result<(Option<msgs::CommitmentUpdate>, Vec<(PendingForwardHTLCInfo, u64)>, Vec<(HTLCSource, [u8; 32], HTLCFailReason)>, ChannelMonitor), HandleError> {
+	/// Also returns the forwarded HTLCs (registered with track_forwarded_htlc_claim) whose claim is
+	/// now irrevocably committed, as (next_channel_id, incoming_amt_msat, outgoing_amt_msat).
+	pub fn revoke_and_ack(&mut self, msg: &msgs::RevokeAndACK) -> Result<(Option<msgs::CommitmentUpdate>, Vec<(PendingForwardHTLCInfo, u64)>, Vec<(HTLCSource, [u8; 32], HTLCFailReason)>, ChannelMonitor, Vec<([u8; 32], u64, u64)>), HandleError> {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32)) != (ChannelState::ChannelFunded as u32) {
 			return Err(HandleError{err: "Got revoke/ACK message when channel was not in an operational state", action: None});
 		}
@@ -1838,11 +2059,16 @@ impl Channel {
 		let mut update_fail_malformed_htlcs = Vec::new();
 		let mut require_commitment = false;
 		let mut value_to_self_msat_diff: i64 = 0;
+		let mut forwarded_claims = Vec::new();
+		let claimed_forwarded_htlcs = &mut self.claimed_forwarded_htlcs;
 		// We really shouldnt have two passes here, but retain gives a non-mutable ref (Rust bug)
 		self.pending_inbound_htlcs.retain(|htlc| {
 			if let &InboundHTLCState::LocalRemoved(ref reason) = &htlc.state {
 				if let &InboundHTLCRemovalReason::Fulfill(_) = reason {
 					value_to_self_msat_diff += htlc.amount_msat as i64;
+					if let Some((next_channel_id, outgoing_amt_msat)) = claimed_forwarded_htlcs.remove(&htlc.htlc_id) {
+						forwarded_claims.push((next_channel_id, htlc.amount_msat, outgoing_amt_msat));
+					}
 				}
 				false
 			} else { true }
@@ -1904,25 +2130,23 @@ impl Channel {
 		}
 		self.value_to_self_msat = (self.value_to_self_msat as i64 + value_to_self_msat_diff) as u64;
 
-		if self.channel_outbound {
-			if let Some(feerate) = self.pending_update_fee.take() {
+		match self.pending_update_fee {
+			Some((feerate, FeeUpdateState::Outbound)) => {
 				self.feerate_per_kw = feerate;
-			}
-		} else {
-			if let Some(feerate) = self.pending_update_fee {
-				// Because a node cannot send two commitment_signed's in a row without getting a
-				// revoke_and_ack from us (as it would otherwise not know the per_commitment_point
-				// it should use to create keys with) and because a node can't send a
-				// commitment_signed without changes, checking if the feerate is equal to the
-				// pending feerate update is sufficient to detect require_commitment.
-				if feerate == self.feerate_per_kw {
-					require_commitment = true;
-					self.pending_update_fee = None;
-				}
-			}
+				self.pending_update_fee = None;
+			},
+			Some((_, FeeUpdateState::AwaitingRemoteRevokeToAnnounce)) => {
+				require_commitment = true;
+				self.pending_update_fee = None;
+			},
+			// An update_fee we haven't yet received a commitment_signed for
+			Some((_, FeeUpdateState::RemoteAnnounced)) | None => {},
 		}
 
-		match self.free_holding_cell_htlcs()? {
+		// While a ChannelMonitor update is failing anything we'd add from the holding cell would
+		// just go back into it, so leave it for monitor_updating_restored.
+		let holding_cell_update = if self.is_awaiting_monitor_update() { None } else { self.free_holding_cell_htlcs()? };
+		match holding_cell_update {
 			Some(mut commitment_update) => {
 				commitment_update.0.update_fail_htlcs.reserve(update_fail_htlcs.len());
 				for fail_msg in update_fail_htlcs.drain(..) {
@@ -1932,7 +2156,7 @@ impl Channel {
 				for fail_msg in update_fail_malformed_htlcs.drain(..) {
 					commitment_update.0.update_fail_malformed_htlcs.push(fail_msg);
 				}
-				Ok((Some(commitment_update.0), to_forward_infos, revoked_htlcs, commitment_update.1))
+				Ok((Some(commitment_update.0), to_forward_infos, revoked_htlcs, commitment_update.1, forwarded_claims))
 			},
 			None => {
 				if require_commitment {
@@ -1944,9 +2168,9 @@ impl Channel {
 						update_fail_malformed_htlcs,
 						update_fee: None,
 						commitment_signed
-					}), to_forward_infos, revoked_htlcs, monitor_update))
+					}), to_forward_infos, revoked_htlcs, monitor_update, forwarded_claims))
 				} else {
-					Ok((None, to_forward_infos, revoked_htlcs, self.channel_monitor.clone()))
+					Ok((None, to_forward_infos, revoked_htlcs, self.channel_monitor.clone(), forwarded_claims))
 				}
 			}
 		}
@@ -1973,7 +2197,7 @@ impl Channel {
 		}
 
 		debug_assert!(self.pending_update_fee.is_none());
-		self.pending_update_fee = Some(feerate_per_kw);
+		self.pending_update_fee = Some((feerate_per_kw, FeeUpdateState::Outbound));
 
 		Some(msgs::UpdateFee {
 			channel_id: self.channel_id,
@@ -1991,6 +2215,54 @@ impl Channel {
 		}
 	}
 
+	/// Checks whether our fee estimate has moved far enough from this (outbound) channel's feerate
+	/// that we should send an update_fee, returning the new feerate if so.
+	///
+	/// Increases are capped at the highest feerate we can pay while keeping the counterparty's
+	/// reserve on our side of the commitment transaction. Returns None if the channel isn't in a
+	/// state to send update_fee or a previous update_fee is still in flight.
+	pub fn get_update_fee_feerate(&self, fee_estimator: &FeeEstimator) -> Option<u64> {
+		if !self.channel_outbound || !self.is_usable() || !self.is_live() {
+			return None;
+		}
+		if self.pending_update_fee.is_some() || self.holding_cell_update_fee.is_some() {
+			return None;
+		}
+
+		let target_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal);
+		if target_feerate * 100 < self.feerate_per_kw * (100 - UPDATE_FEE_HYSTERESIS_PERCENT) {
+			// Lowering the fee only ever makes the commitment transaction more affordable for us
+			return Some(target_feerate);
+		}
+		if target_feerate * 100 <= self.feerate_per_kw * (100 + UPDATE_FEE_HYSTERESIS_PERCENT) {
+			return None;
+		}
+
+		// We pay the commitment transaction fee, so it must come out of our balance above the reserve
+		// they require of us, net of any HTLCs we've offered (which are assumed to be non-dust).
+		let (inbound_htlc_count, _) = self.get_inbound_pending_htlc_stats();
+		let (outbound_htlc_count, htlc_outbound_value_msat) = self.get_outbound_pending_htlc_stats();
+		let mut holding_cell_htlc_count = 0;
+		let mut holding_cell_outbound_amount_msat = 0;
+		for holding_htlc in self.holding_cell_htlc_updates.iter() {
+			if let &HTLCUpdateAwaitingACK::AddHTLC { ref amount_msat, .. } = holding_htlc {
+				holding_cell_htlc_count += 1;
+				holding_cell_outbound_amount_msat += *amount_msat;
+			}
+		}
+		let spendable_msat = (self.value_to_self_msat as i64) - (htlc_outbound_value_msat as i64) - (holding_cell_outbound_amount_msat as i64) - (self.their_channel_reserve_satoshis as i64) * 1000;
+		if spendable_msat <= 0 {
+			return None;
+		}
+		let weight = COMMITMENT_TX_BASE_WEIGHT + (inbound_htlc_count as u64 + outbound_htlc_count as u64 + holding_cell_htlc_count) * COMMITMENT_TX_WEIGHT_PER_HTLC;
+		let max_affordable_feerate = (spendable_msat as u64) / weight;
+		let new_feerate = cmp::min(target_feerate, max_affordable_feerate);
+		if new_feerate <= self.feerate_per_kw {
+			return None;
+		}
+		Some(new_feerate)
+	}
+
 	/// Removes any uncommitted HTLCs, to be used on peer disconnection, including any pending
 	/// HTLCs that we intended to add but haven't as we were waiting on a remote revoke.
 	/// Returns the set of PendingHTLCStatuses from remote uncommitted HTLCs (which we're
@@ -2042,6 +2314,13 @@ impl Channel {
 			}
 		}
 
+		if let Some((_, FeeUpdateState::RemoteAnnounced)) = self.pending_update_fee {
+			// They sent us an update_fee but haven't yet sent the corresponding commitment_signed,
+			// they'll re-send it upon reconnection.
+			debug_assert!(!self.channel_outbound);
+			self.pending_update_fee = None;
+		}
+
 		self.holding_cell_htlc_updates.retain(|htlc_update| {
 			match htlc_update {
 				&HTLCUpdateAwaitingACK::AddHTLC { ref payment_hash, ref source, .. } => {
@@ -2051,6 +2330,11 @@ impl Channel {
 				&HTLCUpdateAwaitingACK::ClaimHTLC {..} | &HTLCUpdateAwaitingACK::FailHTLC {..} => true,
 			}
 		});
+		// Anything we were holding for a failed ChannelMonitor update will be re-sent (if our peer
+		// still needs it) in response to their channel_reestablish instead.
+		self.monitor_pending_revoke_and_ack = false;
+		self.monitor_pending_commitment_signed = false;
+
 		self.channel_state |= ChannelState::PeerDisconnected as u32;
 		log_debug!(self, "Peer disconnection resulted in {} remote-announced HTLC drops and {} waiting-to-locally-announced HTLC drops on channel {}", outbound_drops.len(), inbound_drop_count, log_bytes!(self.channel_id()));
 		outbound_drops
@@ -2065,11 +2349,143 @@ impl Channel {
 		}
 		Channel::check_remote_fee(fee_estimator, msg.feerate_per_kw)?;
 
-		self.pending_update_fee = Some(msg.feerate_per_kw as u64);
+		self.pending_update_fee = Some((msg.feerate_per_kw as u64, FeeUpdateState::RemoteAnnounced));
 		self.channel_update_count += 1;
 		Ok(())
 	}
 
+	/// Indicates that a ChannelMonitor update failed with a TemporaryFailure, pausing the channel
+	/// until monitor_updating_restored is called. resend_raa and resend_commitment indicate whether
+	/// a revoke_and_ack and/or commitment_signed generated along with the failed update must be
+	/// sent once it succeeds, instead of now. pending_forwards and pending_failures are the HTLCs
+	/// it would have let us forward or fail backwards.
+	pub fn monitor_update_failed(&mut self, resend_raa: bool, resend_commitment: bool, mut pending_forwards: Vec<(PendingForwardHTLCInfo, u64)>, mut pending_failures: Vec<(HTLCSource, [u8; 32], HTLCFailReason)>) {
+		if !self.monitor_pending_revoke_and_ack && !self.monitor_pending_commitment_signed {
+			self.monitor_pending_order = if resend_commitment && !resend_raa {
+				RAACommitmentOrder::CommitmentFirst
+			} else {
+				RAACommitmentOrder::RevokeAndACKFirst
+			};
+		}
+		self.monitor_pending_revoke_and_ack |= resend_raa;
+		self.monitor_pending_commitment_signed |= resend_commitment;
+		self.monitor_pending_forwards.append(&mut pending_forwards);
+		self.monitor_pending_failures.append(&mut pending_failures);
+		self.channel_state |= ChannelState::MonitorUpdateFailed as u32;
+	}
+
+	/// Indicates that the latest ChannelMonitor has been stored after a monitor_update_failed,
+	/// unpausing the channel. Returns the messages we held in the meantime (in the order they must
+	/// be sent) and the HTLCs which may now be forwarded or failed backwards. If we also freed our
+	/// holding cell, the returned ChannelMonitor must be stored before the CommitmentUpdate is
+	/// sent.
+	pub fn monitor_updating_restored(&mut self) -> Result<(Option<msgs::RevokeAndACK>, Option<msgs::CommitmentUpdate>, RAACommitmentOrder, Option<ChannelMonitor>, Vec<(PendingForwardHTLCInfo, u64)>, Vec<(HTLCSource, [u8; 32], HTLCFailReason)>), HandleError> {
+		assert_eq!(self.channel_state & ChannelState::MonitorUpdateFailed as u32, ChannelState::MonitorUpdateFailed as u32);
+		self.channel_state &= !(ChannelState::MonitorUpdateFailed as u32);
+
+		// Note that if our peer disconnected in the meantime nothing is pending any more, we'll
+		// re-send whatever they still need in response to their channel_reestablish.
+		let raa = if self.monitor_pending_revoke_and_ack { Some(self.get_last_revoke_and_ack()) } else { None };
+		let mut commitment_update = if self.monitor_pending_commitment_signed { Some(self.get_last_commitment_update()) } else { None };
+		let mut order = self.monitor_pending_order;
+		self.monitor_pending_revoke_and_ack = false;
+		self.monitor_pending_commitment_signed = false;
+
+		// Anything we tried to send while paused went into the holding cell, so free it now if
+		// we're not still waiting on a revoke_and_ack for our last commitment_signed.
+		let mut monitor_update = None;
+		if commitment_update.is_none() && (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32 | ChannelState::PeerDisconnected as u32)) == 0 {
+			if let Some((update, monitor)) = self.free_holding_cell_htlcs()? {
+				commitment_update = Some(update);
+				monitor_update = Some(monitor);
+				order = RAACommitmentOrder::RevokeAndACKFirst;
+			}
+		}
+
+		let mut pending_forwards = Vec::new();
+		mem::swap(&mut pending_forwards, &mut self.monitor_pending_forwards);
+		let mut pending_failures = Vec::new();
+		mem::swap(&mut pending_failures, &mut self.monitor_pending_failures);
+		Ok((raa, commitment_update, order, monitor_update, pending_forwards, pending_failures))
+	}
+
+	/// Re-generates the update messages and commitment_signed for the latest remote commitment
+	/// transaction, which our peer may not have received.
+	fn get_last_commitment_update(&self) -> msgs::CommitmentUpdate {
+		let mut update_add_htlcs = Vec::new();
+		let mut update_fulfill_htlcs = Vec::new();
+		let mut update_fail_htlcs = Vec::new();
+		let mut update_fail_malformed_htlcs = Vec::new();
+
+		for htlc in self.pending_outbound_htlcs.iter() {
+			if let &OutboundHTLCState::LocalAnnounced(ref onion_packet) = &htlc.state {
+				update_add_htlcs.push(msgs::UpdateAddHTLC {
+					channel_id: self.channel_id(),
+					htlc_id: htlc.htlc_id,
+					amount_msat: htlc.amount_msat,
+					payment_hash: htlc.payment_hash,
+					cltv_expiry: htlc.cltv_expiry,
+					onion_routing_packet: (**onion_packet).clone(),
+				});
+			}
+		}
+
+		for htlc in self.pending_inbound_htlcs.iter() {
+			if let &InboundHTLCState::LocalRemoved(ref reason) = &htlc.state {
+				match reason {
+					&InboundHTLCRemovalReason::FailRelay(ref err_packet) => {
+						update_fail_htlcs.push(msgs::UpdateFailHTLC {
+							channel_id: self.channel_id(),
+							htlc_id: htlc.htlc_id,
+							reason: err_packet.clone()
+						});
+					},
+					&InboundHTLCRemovalReason::FailMalformed((ref sha256_of_onion, ref failure_code)) => {
+						update_fail_malformed_htlcs.push(msgs::UpdateFailMalformedHTLC {
+							channel_id: self.channel_id(),
+							htlc_id: htlc.htlc_id,
+							sha256_of_onion: sha256_of_onion.clone(),
+							failure_code: failure_code.clone(),
+						});
+					},
+					&InboundHTLCRemovalReason::Fulfill(ref payment_preimage) => {
+						update_fulfill_htlcs.push(msgs::UpdateFulfillHTLC {
+							channel_id: self.channel_id(),
+							htlc_id: htlc.htlc_id,
+							payment_preimage: payment_preimage.clone(),
+						});
+					},
+				}
+			}
+		}
+
+		// If the commitment_signed they missed included an update_fee from us, re-send it too
+		let update_fee = match self.pending_update_fee {
+			Some((feerate, FeeUpdateState::Outbound)) => Some(msgs::UpdateFee {
+				channel_id: self.channel_id(),
+				feerate_per_kw: feerate as u32,
+			}),
+			_ => None,
+		};
+
+		msgs::CommitmentUpdate {
+			update_add_htlcs, update_fulfill_htlcs, update_fail_htlcs, update_fail_malformed_htlcs,
+			update_fee,
+			commitment_signed: self.send_commitment_no_state_update().expect("It looks like we failed to re-generate a commitment_signed we had previously sent?").0,
+		}
+	}
+
+	/// Re-generates the revoke_and_ack revoking our previous local commitment transaction.
+	fn get_last_revoke_and_ack(&self) -> msgs::RevokeAndACK {
+		let next_per_commitment_point = PublicKey::from_secret_key(&self.secp_ctx, &self.build_local_commitment_secret(self.cur_local_commitment_transaction_number));
+		let per_commitment_secret = chan_utils::build_commitment_secret(self.local_keys.commitment_seed, self.cur_local_commitment_transaction_number + 2);
+		msgs::RevokeAndACK {
+			channel_id: self.channel_id,
+			per_commitment_secret,
+			next_per_commitment_point,
+		}
+	}
+
 	/// May panic if some calls other than message-handling calls (which will all Err immediately)
 	/// have been called between remove_uncommitted_htlcs_and_mark_paused and this call.
 	pub fn channel_reestablish(&mut self, msg: &msgs::ChannelReestablish) -> Result<(Option<msgs::FundingLocked>, Option<msgs::RevokeAndACK>, Option<msgs::CommitmentUpdate>, Option<ChannelMonitor>), ChannelError> {
@@ -2085,6 +2501,41 @@ impl Channel {
 			return Err(ChannelError::Close("Peer sent a garbage channel_reestablish"));
 		}
 
+		// Our peer may not have received any announcement_signatures we sent before the reconnect
+		self.announcement_sigs_sent = false;
+
+		if let Some(ref data_loss) = msg.data_loss_protect {
+			// next_remote_commitment_number is one more than the number of revoke_and_acks our
+			// peer has received from us, so if it is at least 2 they must be able to prove it by
+			// handing us back the last per_commitment_secret we sent them.
+			if msg.next_remote_commitment_number > 1 {
+				let last_secret = chan_utils::build_commitment_secret(self.local_keys.commitment_seed, INITIAL_COMMITMENT_NUMBER - msg.next_remote_commitment_number + 2);
+				if last_secret != data_loss.your_last_per_commitment_secret {
+					return Err(ChannelError::Close("Peer sent a garbage channel_reestablish with secret key not matching the commitment height provided"));
+				}
+				if msg.next_remote_commitment_number > INITIAL_COMMITMENT_NUMBER - self.cur_local_commitment_transaction_number {
+					// Our peer has seen revocation secrets we have no record of sending, so we must
+					// have lost state. Broadcasting our latest local commitment transaction would
+					// let them claim all funds, so instead we have to wait for them to broadcast
+					// theirs and claim our to_remote output using their current point.
+					let to_remote_privkey = match chan_utils::derive_private_key(&self.secp_ctx, &data_loss.my_current_per_commitment_point, &self.local_keys.payment_base_key) {
+						Ok(key) => key,
+						Err(_) => return Err(ChannelError::Close("Peer sent an invalid my_current_per_commitment_point in channel_reestablish")),
+					};
+					let to_remote_key = PublicKey::from_secret_key(&self.secp_ctx, &to_remote_privkey);
+					let to_remote_script = Builder::new().push_opcode(opcodes::All::OP_PUSHBYTES_0)
+					                                     .push_slice(&Hash160::from_data(&to_remote_key.serialize())[..])
+					                                     .into_script();
+					log_error!(self, "Peer proved we have fallen behind on channel {}, we will not broadcast our stale commitment transaction", log_bytes!(self.channel_id()));
+					self.channel_monitor.provide_rescue_remote_commitment_tx_info(to_remote_script, to_remote_privkey);
+					return Err(ChannelError::CloseDelayBroadcast {
+						msg: "We have fallen behind - our peer proved they have a later state than us",
+						update: self.channel_monitor.clone(),
+					});
+				}
+			}
+		}
+
 		// Go ahead and unmark PeerDisconnected as various calls we may make check for it (and all
 		// remaining cases either succeed or ErrorMessage-fail).
 		self.channel_state &= !(ChannelState::PeerDisconnected as u32);
@@ -2094,13 +2545,7 @@ impl Channel {
 			// Note that if we need to repeat our FundingLocked we'll do that in the next if block.
 			None
 		} else if msg.next_remote_commitment_number == (INITIAL_COMMITMENT_NUMBER - 1) - self.cur_local_commitment_transaction_number {
-			let next_per_commitment_point = PublicKey::from_secret_key(&self.secp_ctx, &self.build_local_commitment_secret(self.cur_local_commitment_transaction_number));
-			let per_commitment_secret = chan_utils::build_commitment_secret(self.local_keys.commitment_seed, self.cur_local_commitment_transaction_number + 2);
-			Some(msgs::RevokeAndACK {
-				channel_id: self.channel_id,
-				per_commitment_secret,
-				next_per_commitment_point,
-			})
+			Some(self.get_last_revoke_and_ack())
 		} else {
 			return Err(ChannelError::Close("Peer attempted to reestablish channel with a very old local commitment transaction"));
 		};
@@ -2127,6 +2572,14 @@ impl Channel {
 				log_debug!(self, "Reconnected channel {} with no loss", log_bytes!(self.channel_id()));
 			}
 
+			if self.is_awaiting_monitor_update() {
+				// Hold the revoke_and_ack (and any holding cell updates) until the ChannelMonitor
+				// update succeeds, see monitor_updating_restored.
+				self.monitor_pending_revoke_and_ack = required_revoke.is_some();
+				self.monitor_pending_commitment_signed = false;
+				return Ok((resend_funding_locked, None, None, None));
+			}
+
 			if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == 0 {
 				// We're up-to-date and not waiting on a remote revoke (if we are our
 				// channel_reestablish should result in them sending a revoke_and_ack), but we may
@@ -2154,59 +2607,16 @@ impl Channel {
 			} else {
 				log_debug!(self, "Reconnected channel {} with only lost remote commitment tx", log_bytes!(self.channel_id()));
 			}
-			let mut update_add_htlcs = Vec::new();
-			let mut update_fulfill_htlcs = Vec::new();
-			let mut update_fail_htlcs = Vec::new();
-			let mut update_fail_malformed_htlcs = Vec::new();
-
-			for htlc in self.pending_outbound_htlcs.iter() {
-				if let &OutboundHTLCState::LocalAnnounced(ref onion_packet) = &htlc.state {
-					update_add_htlcs.push(msgs::UpdateAddHTLC {
-						channel_id: self.channel_id(),
-						htlc_id: htlc.htlc_id,
-						amount_msat: htlc.amount_msat,
-						payment_hash: htlc.payment_hash,
-						cltv_expiry: htlc.cltv_expiry,
-						onion_routing_packet: (**onion_packet).clone(),
-					});
-				}
-			}
-
-			for htlc in self.pending_inbound_htlcs.iter() {
-				if let &InboundHTLCState::LocalRemoved(ref reason) = &htlc.state {
-					match reason {
-						&InboundHTLCRemovalReason::FailRelay(ref err_packet) => {
-							update_fail_htlcs.push(msgs::UpdateFailHTLC {
-								channel_id: self.channel_id(),
-								htlc_id: htlc.htlc_id,
-								reason: err_packet.clone()
-							});
-						},
-						&InboundHTLCRemovalReason::FailMalformed((ref sha256_of_onion, ref failure_code)) => {
-							update_fail_malformed_htlcs.push(msgs::UpdateFailMalformedHTLC {
-								channel_id: self.channel_id(),
-								htlc_id: htlc.htlc_id,
-								sha256_of_onion: sha256_of_onion.clone(),
-								failure_code: failure_code.clone(),
-							});
-						},
-						&InboundHTLCRemovalReason::Fulfill(ref payment_preimage) => {
-							update_fulfill_htlcs.push(msgs::UpdateFulfillHTLC {
-								channel_id: self.channel_id(),
-								htlc_id: htlc.htlc_id,
-								payment_preimage: payment_preimage.clone(),
-							});
-						},
-					}
-				}
+			if self.is_awaiting_monitor_update() {
+				// The ChannelMonitor hasn't seen our latest commitment (or the local commitment
+				// we'd be revoking) yet, so hold both until test_restore_channel_monitor.
+				self.monitor_pending_revoke_and_ack = required_revoke.is_some();
+				self.monitor_pending_commitment_signed = true;
+				self.monitor_pending_order = RAACommitmentOrder::RevokeAndACKFirst;
+				return Ok((resend_funding_locked, None, None, None));
 			}
 
-			return Ok((resend_funding_locked, required_revoke,
-					Some(msgs::CommitmentUpdate {
-						update_add_htlcs, update_fulfill_htlcs, update_fail_htlcs, update_fail_malformed_htlcs,
-						update_fee: None, //TODO: We need to support re-generating any update_fees in the last commitment_signed!
-						commitment_signed: self.send_commitment_no_state_update().expect("It looks like we failed to re-generate a commitment_signed we had previously sent?").0,
-					}), None));
+			return Ok((resend_funding_locked, required_revoke, Some(self.get_last_commitment_update()), None));
 		} else {
 			return Err(ChannelError::Close("Peer attempted to reestablish channel with a very old remote commitment transaction"));
 		}
@@ -2238,14 +2648,15 @@ impl Channel {
 		}
 
 		//Check shutdown_scriptpubkey form as BOLT says we must
-		if !(msg.scriptpubkey.is_p2pkh()) && !(msg.scriptpubkey.is_p2sh())
-			&& !(msg.scriptpubkey.is_v0_p2wpkh()) && !(msg.scriptpubkey.is_v0_p2wsh()){
+		if !Channel::is_valid_shutdown_scriptpubkey(&msg.scriptpubkey) {
 			return Err(HandleError{err: "Got an invalid scriptpubkey from remote peer", action: Some(msgs::ErrorAction::DisconnectPeer{ msg: None })});
 		}
 
 		if self.their_shutdown_scriptpubkey.is_some() {
 			if Some(&msg.scriptpubkey) != self.their_shutdown_scriptpubkey.as_ref() {
-				return Err(HandleError{err: "Got shutdown request with a scriptpubkey which did not match their previous scriptpubkey", action: None});
+				// This can only happen if they committed to a script via
+				// option_upfront_shutdown_script, in which case BOLT 2 says we must fail the channel.
+				return Err(HandleError{err: "Got shutdown request with a scriptpubkey which did not match their upfront shutdown script", action: Some(msgs::ErrorAction::SendErrorMessage{msg: msgs::ErrorMessage{channel_id: self.channel_id, data: "Got shutdown request with a scriptpubkey which did not match their upfront shutdown script".to_string()}})});
 			}
 		} else {
 			self.their_shutdown_scriptpubkey = Some(msg.scriptpubkey.clone());
@@ -2253,20 +2664,16 @@ impl Channel {
 
 		let our_closing_script = self.get_closing_scriptpubkey();
 
-		let (proposed_feerate, proposed_fee, our_sig) = if self.channel_outbound && self.pending_inbound_htlcs.is_empty() && self.pending_outbound_htlcs.is_empty() {
-			let mut proposed_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background);
-			if self.feerate_per_kw > proposed_feerate {
-				proposed_feerate = self.feerate_per_kw;
-			}
+		let (proposed_fee, our_sig) = if self.channel_outbound && self.pending_inbound_htlcs.is_empty() && self.pending_outbound_htlcs.is_empty() {
 			let tx_weight = Self::get_closing_transaction_weight(&our_closing_script, &msg.scriptpubkey);
-			let proposed_total_fee_satoshis = proposed_feerate * tx_weight / 1000;
+			let proposed_total_fee_satoshis = self.get_initial_closing_fee(fee_estimator, tx_weight);
 
 			let (closing_tx, total_fee_satoshis) = self.build_closing_transaction(proposed_total_fee_satoshis, false);
 			let funding_redeemscript = self.get_funding_redeemscript();
 			let sighash = Message::from_slice(&bip143::SighashComponents::new(&closing_tx).sighash_all(&closing_tx.input[0], &funding_redeemscript, self.channel_value_satoshis)[..]).unwrap();
 
-			(Some(proposed_feerate), Some(total_fee_satoshis), Some(self.secp_ctx.sign(&sighash, &self.local_keys.funding_key)))
-		} else { (None, None, None) };
+			(Some(total_fee_satoshis), Some(self.secp_ctx.sign(&sighash, &self.local_keys.funding_key)))
+		} else { (None, None) };
 
 		// From here on out, we may not fail!
 
@@ -2306,7 +2713,7 @@ impl Channel {
 		if self.pending_inbound_htlcs.is_empty() && self.pending_outbound_htlcs.is_empty() && self.channel_outbound {
 			// There are no more HTLCs and we're the funder, this means we start the closing_signed
 			// dance with an initial fee proposal!
-			self.last_sent_closing_fee = Some((proposed_feerate.unwrap(), proposed_fee.unwrap()));
+			self.last_sent_closing_fee = Some(proposed_fee.unwrap());
 			Ok((our_shutdown, Some(msgs::ClosingSigned {
 				channel_id: self.channel_id,
 				fee_satoshis: proposed_fee.unwrap(),
@@ -2349,7 +2756,7 @@ impl Channel {
 			},
 		};
 
-		if let Some((_, last_fee)) = self.last_sent_closing_fee {
+		if let Some(last_fee) = self.last_sent_closing_fee {
 			if last_fee == msg.fee_satoshis {
 				self.sign_commitment_transaction(&mut closing_tx, &msg.signature);
 				self.channel_state = ChannelState::ShutdownComplete as u32;
@@ -2358,42 +2765,33 @@ impl Channel {
 			}
 		}
 
-		macro_rules! propose_new_feerate {
-			($new_feerate: expr) => {
-				let closing_tx_max_weight = Self::get_closing_transaction_weight(&self.get_closing_scriptpubkey(), self.their_shutdown_scriptpubkey.as_ref().unwrap());
-				let (closing_tx, used_total_fee) = self.build_closing_transaction($new_feerate * closing_tx_max_weight / 1000, false);
-				sighash = Message::from_slice(&bip143::SighashComponents::new(&closing_tx).sighash_all(&closing_tx.input[0], &funding_redeemscript, self.channel_value_satoshis)[..]).unwrap();
-				let our_sig = self.secp_ctx.sign(&sighash, &self.local_keys.funding_key);
-				self.last_sent_closing_fee = Some(($new_feerate, used_total_fee));
-				return Ok((Some(msgs::ClosingSigned {
-					channel_id: self.channel_id,
-					fee_satoshis: used_total_fee,
-					signature: our_sig,
-				}), None))
-			}
-		}
-
-		let proposed_sat_per_kw = msg.fee_satoshis * 1000 / closing_tx.get_weight();
-		if self.channel_outbound {
-			let our_max_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal);
-			if proposed_sat_per_kw > our_max_feerate {
-				if let Some((last_feerate, _)) = self.last_sent_closing_fee {
-					if our_max_feerate <= last_feerate {
-						return Err(HandleError{err: "Unable to come to consensus about closing feerate, remote wanted something higher than our Normal feerate", action: None});
-					}
-				}
-				propose_new_feerate!(our_max_feerate);
-			}
-		} else {
-			let our_min_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background);
-			if proposed_sat_per_kw < our_min_feerate {
-				if let Some((last_feerate, _)) = self.last_sent_closing_fee {
-					if our_min_feerate >= last_feerate {
-						return Err(HandleError{err: "Unable to come to consensus about closing feerate, remote wanted something lower than our Background feerate", action: None});
-					}
+		let closing_tx_max_weight = Self::get_closing_transaction_weight(&self.get_closing_scriptpubkey(), self.their_shutdown_scriptpubkey.as_ref().unwrap());
+		let (min_fee, max_fee) = self.get_closing_fee_range(fee_estimator, closing_tx_max_weight);
+		if msg.fee_satoshis < min_fee || msg.fee_satoshis > max_fee {
+			// Their proposal is outside of what we're willing to sign, so counter-propose a fee
+			// halfway between our last proposal and theirs (or our bound if we haven't proposed
+			// anything yet), staying within our bounds so that we converge towards each other.
+			let bound_fee = if msg.fee_satoshis < min_fee { min_fee } else { max_fee };
+			let new_fee = match self.last_sent_closing_fee {
+				Some(last_fee) => cmp::min(cmp::max((last_fee + msg.fee_satoshis) / 2, min_fee), max_fee),
+				None => bound_fee,
+			};
+			let (closing_tx, used_total_fee) = self.build_closing_transaction(new_fee, false);
+			if Some(used_total_fee) == self.last_sent_closing_fee {
+				if msg.fee_satoshis > max_fee {
+					return Err(HandleError{err: "Unable to come to consensus about closing fee, remote wanted something higher than our maximum", action: None});
+				} else {
+					return Err(HandleError{err: "Unable to come to consensus about closing fee, remote wanted something lower than our Background feerate", action: None});
 				}
-				propose_new_feerate!(our_min_feerate);
 			}
+			sighash = Message::from_slice(&bip143::SighashComponents::new(&closing_tx).sighash_all(&closing_tx.input[0], &funding_redeemscript, self.channel_value_satoshis)[..]).unwrap();
+			let our_sig = self.secp_ctx.sign(&sighash, &self.local_keys.funding_key);
+			self.last_sent_closing_fee = Some(used_total_fee);
+			return Ok((Some(msgs::ClosingSigned {
+				channel_id: self.channel_id,
+				fee_satoshis: used_total_fee,
+				signature: our_sig,
+			}), None));
 		}
 
 		let our_sig = self.sign_commitment_transaction(&mut closing_tx, &msg.signature);
@@ -2419,6 +2817,13 @@ impl Channel {
 		self.user_id
 	}
 
+	/// Notes that the inbound HTLC with the given id, which we are claiming, was forwarded over
+	/// next_channel_id with the given amount, so that revoke_and_ack can report it once the claim
+	/// is irrevocably committed.
+	pub fn track_forwarded_htlc_claim(&mut self, htlc_id: u64, next_channel_id: [u8; 32], outgoing_amt_msat: u64) {
+		self.claimed_forwarded_htlcs.insert(htlc_id, (next_channel_id, outgoing_amt_msat));
+	}
+
 	/// May only be called after funding has been initiated (ie is_funding_initiated() is true)
 	pub fn channel_monitor(&self) -> ChannelMonitor {
 		if self.channel_state < ChannelState::FundingCreated as u32 {
@@ -2445,6 +2850,17 @@ impl Channel {
 		self.their_node_id
 	}
 
+	/// Gets the script our funds will be paid to on cooperative close.
+	pub fn get_shutdown_scriptpubkey(&self) -> Script {
+		self.shutdown_scriptpubkey.clone()
+	}
+
+	/// Gets the script our peer's funds will be paid to on cooperative close, if they have
+	/// committed to one (either upfront or by sending shutdown).
+	pub fn get_their_shutdown_scriptpubkey(&self) -> Option<Script> {
+		self.their_shutdown_scriptpubkey.clone()
+	}
+
 	/// Allowed in any state (including after shutdown)
 	pub fn get_our_htlc_minimum_msat(&self) -> u64 {
 		self.our_htlc_minimum_msat
@@ -2454,7 +2870,6 @@ impl Channel {
 		self.channel_value_satoshis
 	}
 
-	#[cfg(test)]
 	pub fn get_feerate(&self) -> u64 {
 		self.feerate_per_kw
 	}
@@ -2494,6 +2909,82 @@ impl Channel {
 		self.channel_update_count
 	}
 
+	/// Gets the forwarding config set via set_forwarding_config, if any.
+	pub fn get_forwarding_config(&self) -> Option<ForwardingConfig> {
+		self.forwarding_config
+	}
+
+	/// Gets the forwarding config which applied before the last set_forwarding_config, if its
+	/// grace period hasn't yet passed.
+	pub fn get_prev_forwarding_config(&self) -> Option<ForwardingConfig> {
+		match self.prev_forwarding_config {
+			Some((config, valid_until)) if Instant::now() < valid_until => Some(config),
+			_ => None,
+		}
+	}
+
+	/// Returns true if our channel_update for this channel announces it as enabled.
+	/// Allowed in any state (including after shutdown)
+	pub fn is_announced_enabled(&self) -> bool {
+		match self.update_status {
+			ChannelUpdateStatus::Enabled | ChannelUpdateStatus::DisabledStaged(_) => true,
+			ChannelUpdateStatus::Disabled | ChannelUpdateStatus::EnabledStaged(_) => false,
+		}
+	}
+
+	/// Moves the enabled/disabled status we announce for this channel towards is_live(), flipping
+	/// it once the channel has been (dis)connected for at least disable_timeout. Returns true if
+	/// it flipped, in which case the channel_update timestamp has been bumped and a new
+	/// channel_update should be broadcast.
+	pub fn update_announced_status(&mut self, disable_timeout: Duration) -> bool {
+		let live = self.is_live();
+		let now = Instant::now();
+		self.update_status = match self.update_status {
+			ChannelUpdateStatus::Enabled if !live => ChannelUpdateStatus::DisabledStaged(now),
+			ChannelUpdateStatus::DisabledStaged(_) if live => ChannelUpdateStatus::Enabled,
+			ChannelUpdateStatus::Disabled if live => ChannelUpdateStatus::EnabledStaged(now),
+			ChannelUpdateStatus::EnabledStaged(_) if !live => ChannelUpdateStatus::Disabled,
+			status => status,
+		};
+		self.update_status = match self.update_status {
+			ChannelUpdateStatus::DisabledStaged(since) if now.duration_since(since) >= disable_timeout => ChannelUpdateStatus::Disabled,
+			ChannelUpdateStatus::EnabledStaged(since) if now.duration_since(since) >= disable_timeout => ChannelUpdateStatus::Enabled,
+			_ => return false,
+		};
+		self.channel_update_count += 1;
+		true
+	}
+
+	/// Sets the config we forward HTLCs over this channel with, keeping prev_config (the one which
+	/// applied until now) around until prev_valid_until. Bumps the channel_update timestamp so the
+	/// new config can be announced.
+	pub fn set_forwarding_config(&mut self, config: ForwardingConfig, prev_config: ForwardingConfig, prev_valid_until: Instant) {
+		self.forwarding_config = Some(config);
+		self.prev_forwarding_config = Some((prev_config, prev_valid_until));
+		self.channel_update_count += 1;
+	}
+
+	/// Stores the (already-verified) announcement_signatures our peer sent us, so that we can build
+	/// the full channel_announcement and stop re-sending ours.
+	pub fn set_their_announcement_sigs(&mut self, node_signature: Signature, bitcoin_signature: Signature) {
+		self.their_announcement_sigs = Some((node_signature, bitcoin_signature));
+	}
+
+	/// Gets the (node, bitcoin) announcement_signatures our peer sent us, if we've received them.
+	/// If we have, both signatures have been exchanged and the channel_announcement is complete.
+	pub fn get_their_announcement_sigs(&self) -> Option<(Signature, Signature)> {
+		self.their_announcement_sigs
+	}
+
+	/// Returns true if we've sent our announcement_signatures since the last channel_reestablish.
+	pub fn announcement_sigs_sent(&self) -> bool {
+		self.announcement_sigs_sent
+	}
+
+	pub fn set_announcement_sigs_sent(&mut self) {
+		self.announcement_sigs_sent = true;
+	}
+
 	pub fn should_announce(&self) -> bool {
 		self.announce_publicly
 	}
@@ -2538,7 +3029,13 @@ impl Channel {
 	/// is_usable() and considers things like the channel being temporarily disabled.
 	/// Allowed in any state (including after shutdown)
 	pub fn is_live(&self) -> bool {
-		self.is_usable() && (self.channel_state & (ChannelState::PeerDisconnected as u32) == 0)
+		self.is_usable() && (self.channel_state & (ChannelState::PeerDisconnected as u32 | ChannelState::MonitorUpdateFailed as u32) == 0)
+	}
+
+	/// Returns true if a ChannelMonitor update for this channel failed with a TemporaryFailure and
+	/// we're waiting on ChannelManager::test_restore_channel_monitor to store it.
+	pub fn is_awaiting_monitor_update(&self) -> bool {
+		(self.channel_state & ChannelState::MonitorUpdateFailed as u32) != 0
 	}
 
 	/// Returns true if funding_created was sent/received.
@@ -2653,7 +3150,9 @@ impl Channel {
 	// Methods to get unprompted messages to send to the remote end (or where we already returned
 	// something in the handler for the message that prompted this message):
 
-	pub fn get_open_channel(&self, chain_hash: Sha256dHash, fee_estimator: &FeeEstimator) -> msgs::OpenChannel {
+	/// Only commits to our shutdown_scriptpubkey if their_features signals
+	/// option_upfront_shutdown_script.
+	pub fn get_open_channel(&self, chain_hash: Sha256dHash, fee_estimator: &FeeEstimator, their_features: &msgs::LocalFeatures) -> msgs::OpenChannel {
 		if !self.channel_outbound {
 			panic!("Tried to open a channel for an inbound channel?");
 		}
@@ -2686,11 +3185,13 @@ impl Channel {
 			htlc_basepoint: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.htlc_base_key),
 			first_per_commitment_point: PublicKey::from_secret_key(&self.secp_ctx, &local_commitment_secret),
 			channel_flags: if self.announce_publicly {1} else {0},
-			shutdown_scriptpubkey: None,
+			shutdown_scriptpubkey: self.get_upfront_shutdown_scriptpubkey_for(their_features),
 		}
 	}
 
-	pub fn get_accept_channel(&self) -> msgs::AcceptChannel {
+	/// Only commits to our shutdown_scriptpubkey if their_features signals
+	/// option_upfront_shutdown_script.
+	pub fn get_accept_channel(&self, their_features: &msgs::LocalFeatures) -> msgs::AcceptChannel {
 		if self.channel_outbound {
 			panic!("Tried to send accept_channel for an outbound channel?");
 		}
@@ -2718,10 +3219,16 @@ impl Channel {
 			delayed_payment_basepoint: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.delayed_payment_base_key),
 			htlc_basepoint: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.htlc_base_key),
 			first_per_commitment_point: PublicKey::from_secret_key(&self.secp_ctx, &local_commitment_secret),
-			shutdown_scriptpubkey: None,
+			shutdown_scriptpubkey: self.get_upfront_shutdown_scriptpubkey_for(their_features),
 		}
 	}
 
+	fn get_upfront_shutdown_scriptpubkey_for(&self, their_features: &msgs::LocalFeatures) -> Option<Script> {
+		if their_features.supports_upfront_shutdown_script() {
+			Some(self.shutdown_scriptpubkey.clone())
+		} else { None }
+	}
+
 	fn get_outbound_funding_created_signature(&mut self) -> Result<(Signature, Transaction), HandleError> {
 		let funding_script = self.get_funding_redeemscript();
 
@@ -2821,13 +3328,23 @@ impl Channel {
 
 	/// May panic if called on a channel that wasn't immediately-previously
 	/// self.remove_uncommitted_htlcs_and_mark_paused()'d
-	pub fn get_channel_reestablish(&self) -> msgs::ChannelReestablish {
+	/// their_features are the local features our peer sent in its init message, as we only
+	/// include the option_data_loss_protect fields if it signaled support for it (we always do).
+	pub fn get_channel_reestablish(&self, their_features: &msgs::LocalFeatures) -> msgs::ChannelReestablish {
 		assert_eq!(self.channel_state & ChannelState::PeerDisconnected as u32, ChannelState::PeerDisconnected as u32);
+		let data_loss_protect = if their_features.supports_data_loss_protect() {
+			Some(msgs::DataLossProtect {
+				your_last_per_commitment_secret: if self.cur_remote_commitment_transaction_number + 2 <= INITIAL_COMMITMENT_NUMBER {
+					self.channel_monitor.get_secret(self.cur_remote_commitment_transaction_number + 2).unwrap()
+				} else { [0; 32] },
+				my_current_per_commitment_point: PublicKey::from_secret_key(&self.secp_ctx, &self.build_local_commitment_secret(self.cur_local_commitment_transaction_number + 1)),
+			})
+		} else { None };
 		msgs::ChannelReestablish {
 			channel_id: self.channel_id(),
 			next_local_commitment_number: INITIAL_COMMITMENT_NUMBER - self.cur_local_commitment_transaction_number,
 			next_remote_commitment_number: INITIAL_COMMITMENT_NUMBER - self.cur_remote_commitment_transaction_number,
-			data_loss_protect: None,
+			data_loss_protect,
 		}
 	}
 
@@ -2891,7 +3408,7 @@ impl Channel {
 		//TODO: Check cltv_expiry? Do this in channel manager?
 
 		// Now update local state:
-		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == (ChannelState::AwaitingRemoteRevoke as u32) {
+		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32 | ChannelState::MonitorUpdateFailed as u32)) != 0 {
 			//TODO: Check the limits *including* other pending holding cell HTLCs!
 			self.holding_cell_htlc_updates.push(HTLCUpdateAwaitingACK::AddHTLC {
 				amount_msat: amount_msat,
@@ -2988,12 +3505,10 @@ impl Channel {
 	fn send_commitment_no_state_update(&self) -> Result<(msgs::CommitmentSigned, (Transaction, Vec<HTLCOutputInCommitment>)), HandleError> {
 		let funding_script = self.get_funding_redeemscript();
 
-		let mut feerate_per_kw = self.feerate_per_kw;
-		if let Some(feerate) = self.pending_update_fee {
-			if self.channel_outbound {
-				feerate_per_kw = feerate;
-			}
-		}
+		let feerate_per_kw = match self.pending_update_fee {
+			Some((feerate, FeeUpdateState::Outbound)) => feerate,
+			_ => self.feerate_per_kw,
+		};
 
 		let remote_keys = self.build_remote_transaction_keys()?;
 		let remote_commitment_tx = self.build_commitment_transaction(self.cur_remote_commitment_transaction_number, &remote_keys, false, true, feerate_per_kw);
@@ -3034,7 +3549,9 @@ impl Channel {
 
 	/// Begins the shutdown process, getting a message for the remote peer and returning all
 	/// holding cell HTLCs for payment failure.
-	pub fn get_shutdown(&mut self) -> Result<(msgs::Shutdown, Vec<(HTLCSource, [u8; 32])>), APIError> {
+	/// If we're the funder, target_closing_feerate and max_closing_fee_satoshis (if set) drive
+	/// our closing fee negotiation instead of our fee estimator.
+	pub fn get_shutdown(&mut self, target_closing_feerate: Option<u64>, max_closing_fee_satoshis: Option<u64>) -> Result<(msgs::Shutdown, Vec<(HTLCSource, [u8; 32])>), APIError> {
 		for htlc in self.pending_outbound_htlcs.iter() {
 			if let OutboundHTLCState::LocalAnnounced(_) = htlc.state {
 				return Err(APIError::APIMisuseError{err: "Cannot begin shutdown with pending HTLCs. Process pending events first"});
@@ -3056,6 +3573,8 @@ impl Channel {
 		let our_closing_script = self.get_closing_scriptpubkey();
 
 		// From here on out, we may not fail!
+		self.target_closing_feerate = target_closing_feerate;
+		self.max_closing_fee_satoshis = max_closing_fee_satoshis;
 		if self.channel_state < ChannelState::FundingSent as u32 {
 			self.channel_state = ChannelState::ShutdownComplete as u32;
 		} else {
@@ -3088,7 +3607,9 @@ impl Channel {
 	/// those explicitly stated to be allowed after shutdown completes, eg some simple getters).
 	/// Also returns the list of payment_hashes for channels which we can safely fail backwards
 	/// immediately (others we will have to allow to time out).
-	pub fn force_shutdown(&mut self) -> (Vec<Transaction>, Vec<(HTLCSource, [u8; 32])>) {
+	/// If should_broadcast is false, the latest local commitment transaction is not returned (eg
+	/// because our peer proved it is stale and broadcasting it would lose us funds).
+	pub fn force_shutdown(&mut self, should_broadcast: bool) -> (Vec<Transaction>, Vec<(HTLCSource, [u8; 32])>) {
 		assert!(self.channel_state != ChannelState::ShutdownComplete as u32);
 
 		// We go ahead and "free" any holding cell HTLCs or HTLCs we haven't yet committed to and
@@ -3113,6 +3634,9 @@ impl Channel {
 		self.channel_update_count += 1;
 		let mut res = Vec::new();
 		mem::swap(&mut res, &mut self.last_local_commitment_txn);
+		if !should_broadcast {
+			res.clear();
+		}
 		(res, dropped_outbound_htlcs)
 	}
 }
@@ -3161,6 +3685,7 @@ mod tests {
 		let logger : Arc<Logger> = Arc::new(test_utils::TestLogger::new());
 		let secp_ctx = Secp256k1::new();
 
+		#[allow(deprecated)]
 		let chan_keys = ChannelKeys {
 			funding_key: SecretKey::from_slice(&secp_ctx, &hex::decode("30ff4956bbdd3222d44cc5e8a1261dab1e07957bdac5ae88fe3261ef321f3749").unwrap()[..]).unwrap(),
 			payment_base_key: SecretKey::from_slice(&secp_ctx, &hex::decode("1111111111111111111111111111111111111111111111111111111111111111").unwrap()[..]).unwrap(),
@@ -3177,7 +3702,8 @@ mod tests {
 				hex::decode("023da092f6980e58d2c037173180e9a465476026ee50f96695963e8efe436f54eb").unwrap()[..]);
 
 		let their_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap());
-		let mut chan = Channel::new_outbound(&feeest, chan_keys, their_node_id, 10000000, 100000, false, 42, Arc::clone(&logger)).unwrap(); // Nothing uses their network key in this test
+		let shutdown_scriptpubkey = Script::from(hex::decode("00144d5d6c8a4ad2a3e1ea7f3a4e8c6d4dbf0d3b5d0c").unwrap()); // Nothing closes the channel in this test
+		let mut chan = Channel::new_outbound(&feeest, chan_keys, their_node_id, 10000000, 100000, false, 42, shutdown_scriptpubkey, Arc::clone(&logger)).unwrap(); // Nothing uses their network key in this test
 		chan.their_to_self_delay = 144;
 		chan.our_dust_limit_satoshis = 546;
 
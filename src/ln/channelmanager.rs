@@ -10,6 +10,7 @@
 
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::script::Script;
 use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::network::constants::Network;
 use bitcoin::network::serialize::BitcoinHash;
@@ -20,16 +21,17 @@ use secp256k1::{Secp256k1,Message};
 use secp256k1::ecdh::SharedSecret;
 use secp256k1;
 
-use chain::chaininterface::{BroadcasterInterface,ChainListener,ChainWatchInterface,FeeEstimator};
+use chain::chaininterface::{BroadcasterInterface,ChainListener,ChainWatchInterface,FeeEstimator,WalletInterface};
 use chain::transaction::OutPoint;
-use ln::channel::{Channel, ChannelError, ChannelKeys};
-use ln::channelmonitor::ManyChannelMonitor;
-use ln::router::{Route,RouteHop};
+use ln::channel::{Channel, ChannelError, ChannelKeys, ForwardingConfig, RAACommitmentOrder};
+use ln::channelmonitor::{ChannelMonitorUpdateErr, ManyChannelMonitor, CLTV_CLAIM_BUFFER};
+use ln::invoice::{Invoice, InvoiceError, RawInvoice};
+use ln::router::{Route,RouteHop,RouteHint,Router};
 use ln::msgs;
 use ln::msgs::{HandleError,ChannelMessageHandler};
 use util::{byte_utils, events, internal_traits, rng};
 use util::sha2::Sha256;
-use util::ser::{Readable, Writeable};
+use util::ser::{Readable, Writeable, Writer};
 use util::chacha20poly1305rfc::ChaCha20;
 use util::logger::Logger;
 use util::errors::APIError;
@@ -40,13 +42,13 @@ use crypto::hmac::Hmac;
 use crypto::digest::Digest;
 use crypto::symmetriccipher::SynchronousStreamCipher;
 
-use std::{ptr, mem};
+use std::{cmp, ptr, mem};
 use std::collections::HashMap;
 use std::collections::hash_map;
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::sync::{Mutex,MutexGuard,Arc};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::{Instant,Duration};
+use std::time::{Instant,Duration,SystemTime,UNIX_EPOCH};
 
 /// We hold various information about HTLC relay in the HTLC objects in Channel itself:
 ///
@@ -75,6 +77,13 @@ mod channel_held_info {
 		pub(super) short_channel_id: u64,
 		pub(super) amt_to_forward: u64,
 		pub(super) outgoing_cltv_value: u32,
+		/// The value and cltv_expiry of the HTLC we received, used to describe forwards to our
+		/// ForwardingPolicy.
+		pub(super) incoming_amt_msat: u64,
+		pub(super) incoming_cltv_expiry: u32,
+		/// The payment_secret the sender included in the onion, if we are the final hop and there
+		/// was one.
+		pub(super) payment_secret: Option<[u8; 32]>,
 	}
 
 	#[derive(Clone)] // See Channel::revoke_and_ack for why, tl;dr: Rust bug
@@ -130,6 +139,17 @@ mod channel_held_info {
 }
 pub(super) use self::channel_held_info::*;
 
+/// What we learned from an HTLC failure for one of our outbound payments.
+struct DecodedOnionFailure {
+	/// An update to hand to the router, if the failure implies a change in the network graph.
+	network_update: Option<msgs::HTLCFailChannelUpdate>,
+	rejected_by_dest: bool,
+	failing_node: Option<PublicKey>,
+	failing_short_channel_id: Option<u64>,
+	error_code: Option<u16>,
+	channel_update: Option<msgs::ChannelUpdate>,
+}
+
 struct MsgHandleErrInternal {
 	err: msgs::HandleError,
 	needs_channel_force_close: bool,
@@ -181,7 +201,7 @@ impl MsgHandleErrInternal {
 					err: msg,
 					action: Some(msgs::ErrorAction::IgnoreError),
 				},
-				ChannelError::Close(msg) => HandleError {
+				ChannelError::Close(msg) | ChannelError::CloseDelayBroadcast { msg, .. } => HandleError {
 					err: msg,
 					action: Some(msgs::ErrorAction::SendErrorMessage {
 						msg: msgs::ErrorMessage {
@@ -202,7 +222,7 @@ impl MsgHandleErrInternal {
 					err: msg,
 					action: Some(msgs::ErrorAction::IgnoreError),
 				},
-				ChannelError::Close(msg) => HandleError {
+				ChannelError::Close(msg) | ChannelError::CloseDelayBroadcast { msg, .. } => HandleError {
 					err: msg,
 					action: Some(msgs::ErrorAction::SendErrorMessage {
 						msg: msgs::ErrorMessage {
@@ -227,6 +247,128 @@ struct HTLCForwardInfo {
 	prev_short_channel_id: u64,
 	prev_htlc_id: u64,
 	forward_info: PendingForwardHTLCInfo,
+	/// Set once the user released the HTLC via forward_intercepted_htlc, after which our
+	/// ForwardingPolicy is no longer consulted for it.
+	released: bool,
+}
+
+impl HTLCForwardInfo {
+	fn prev_hop_source(&self) -> HTLCSource {
+		HTLCSource::PreviousHopData(HTLCPreviousHopData {
+			short_channel_id: self.prev_short_channel_id,
+			htlc_id: self.prev_htlc_id,
+			incoming_packet_shared_secret: self.forward_info.incoming_shared_secret,
+		})
+	}
+}
+
+/// Holds on to an HTLC forward until the user decides what to do with it, returning the
+/// HTLCIntercepted event to give them.
+fn intercept_htlc_forward(intercepted_htlcs: &mut HashMap<[u8; 32], HTLCForwardInfo>, htlc: HTLCForwardInfo) -> events::Event {
+	let intercept_id = rng::rand_u832();
+	let event = events::Event::HTLCIntercepted {
+		intercept_id,
+		requested_next_short_channel_id: htlc.forward_info.short_channel_id,
+		payment_hash: htlc.forward_info.payment_hash,
+		inbound_amount_msat: htlc.forward_info.incoming_amt_msat,
+		expected_outbound_amount_msat: htlc.forward_info.amt_to_forward,
+		inbound_cltv_expiry: htlc.forward_info.incoming_cltv_expiry,
+		outbound_cltv_expiry: htlc.forward_info.outgoing_cltv_value,
+	};
+	intercepted_htlcs.insert(intercept_id, htlc);
+	event
+}
+
+/// The hashes of the shared secrets of onions we've recently processed, used to detect onion
+/// replays (see BOLT 4). Each onion is kept until the CLTV expiry of the HTLC it came in: after
+/// that the HTLC would be rejected as expired anyway. To bound memory usage, at most
+/// max_onions_per_channel are kept for each channel, beyond which new HTLCs on that channel (but
+/// no other) are rejected until some of its onions expire.
+struct SeenOnions {
+	onions: HashMap<[u8; 32], SeenOnion>,
+	/// The number of onions in onions which came in on each channel.
+	onions_per_channel: HashMap<[u8; 32], usize>,
+	max_onions_per_channel: usize,
+	/// The onions recorded since we last wrote them out, see ChannelManager::write_new_seen_onions.
+	unwritten: Vec<[u8; 32]>,
+}
+
+/// The HTLC an onion in SeenOnions came in.
+struct SeenOnion {
+	channel_id: [u8; 32],
+	htlc_id: u64,
+	cltv_expiry: u32,
+}
+
+impl SeenOnions {
+	fn new(max_onions_per_channel: usize) -> Self {
+		SeenOnions {
+			onions: HashMap::new(),
+			onions_per_channel: HashMap::new(),
+			max_onions_per_channel,
+			unwritten: Vec::new(),
+		}
+	}
+
+	/// Returns true if the onion has already been seen in a different HTLC. Our peer re-sending an
+	/// HTLC we dropped on disconnection is not a replay, so it is not rejected.
+	fn is_replay(&self, onion_hash: &[u8; 32], channel_id: &[u8; 32], htlc_id: u64) -> bool {
+		match self.onions.get(onion_hash) {
+			Some(seen) => seen.channel_id != *channel_id || seen.htlc_id != htlc_id,
+			None => false,
+		}
+	}
+
+	fn is_full(&self, channel_id: &[u8; 32]) -> bool {
+		self.onions_per_channel.get(channel_id).map_or(false, |count| *count >= self.max_onions_per_channel)
+	}
+
+	/// Records the onion of an HTLC our channel has accepted.
+	fn insert(&mut self, onion_hash: [u8; 32], channel_id: [u8; 32], htlc_id: u64, cltv_expiry: u32) {
+		if self.onions.contains_key(&onion_hash) {
+			// Our peer re-sent an HTLC we've already recorded
+			return;
+		}
+		self.onions.insert(onion_hash, SeenOnion { channel_id, htlc_id, cltv_expiry });
+		*self.onions_per_channel.entry(channel_id).or_insert(0) += 1;
+		self.unwritten.push(onion_hash);
+	}
+
+	fn block_connected(&mut self, height: u32) {
+		let onions_per_channel = &mut self.onions_per_channel;
+		self.onions.retain(|_, seen| {
+			if seen.cltv_expiry > height { return true; }
+			let channel_empty = match onions_per_channel.get_mut(&seen.channel_id) {
+				Some(count) => { *count -= 1; *count == 0 },
+				None => false,
+			};
+			if channel_empty {
+				onions_per_channel.remove(&seen.channel_id);
+			}
+			false
+		});
+	}
+
+	/// Writes the given onions as a single batch, skipping any which have since expired.
+	fn write_batch<'a, W: Writer, I: Iterator<Item=&'a [u8; 32]>>(&self, onion_hashes: I, writer: &mut W) -> Result<(), ::std::io::Error> {
+		let onions: Vec<(&[u8; 32], &SeenOnion)> = onion_hashes.filter_map(|onion_hash| {
+			self.onions.get(onion_hash).map(|seen| (onion_hash, seen))
+		}).collect();
+		(onions.len() as u64).write(writer)?;
+		for (onion_hash, seen) in onions {
+			onion_hash.write(writer)?;
+			seen.channel_id.write(writer)?;
+			seen.htlc_id.write(writer)?;
+			seen.cltv_expiry.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+/// An HTLC paying to us which is waiting for the user to claim (or fail) it.
+struct ClaimableHTLC {
+	prev_hop: HTLCPreviousHopData,
+	cltv_expiry: u32,
 }
 
 struct ChannelHolder {
@@ -241,14 +383,18 @@ struct ChannelHolder {
 	/// Note that while this is held in the same mutex as the channels themselves, no consistency
 	/// guarantees are made about the channels given here actually existing anymore by the time you
 	/// go to read them!
-	claimable_htlcs: HashMap<[u8; 32], Vec<HTLCPreviousHopData>>,
+	claimable_htlcs: HashMap<[u8; 32], Vec<ClaimableHTLC>>,
+	/// HTLC forwards held (or intercepted) at the request of our ForwardingPolicy, by the
+	/// intercept_id given to the user in the HTLCIntercepted event.
+	intercepted_htlcs: HashMap<[u8; 32], HTLCForwardInfo>,
 }
 struct MutChannelHolder<'a> {
 	by_id: &'a mut HashMap<[u8; 32], Channel>,
 	short_to_id: &'a mut HashMap<u64, [u8; 32]>,
 	next_forward: &'a mut Instant,
 	forward_htlcs: &'a mut HashMap<u64, Vec<HTLCForwardInfo>>,
-	claimable_htlcs: &'a mut HashMap<[u8; 32], Vec<HTLCPreviousHopData>>,
+	claimable_htlcs: &'a mut HashMap<[u8; 32], Vec<ClaimableHTLC>>,
+	intercepted_htlcs: &'a mut HashMap<[u8; 32], HTLCForwardInfo>,
 }
 impl ChannelHolder {
 	fn borrow_parts(&mut self) -> MutChannelHolder {
@@ -258,6 +404,7 @@ impl ChannelHolder {
 			next_forward: &mut self.next_forward,
 			forward_htlcs: &mut self.forward_htlcs,
 			claimable_htlcs: &mut self.claimable_htlcs,
+			intercepted_htlcs: &mut self.intercepted_htlcs,
 		}
 	}
 }
@@ -265,17 +412,46 @@ impl ChannelHolder {
 #[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
 const ERR: () = "You need at least 32 bit pointers (well, usize, but we'll assume they're the same) for ChannelManager::latest_block_height";
 
+macro_rules! handle_monitor_err {
+	($self: ident, $err: expr, $channel_id: expr, $resend_raa: expr, $resend_commitment: expr) => {
+		handle_monitor_err!($self, $err, $channel_id, $resend_raa, $resend_commitment, Vec::new(), Vec::new())
+	};
+	($self: ident, $err: expr, $channel_id: expr, $resend_raa: expr, $resend_commitment: expr, $failed_forwards: expr, $failed_fails: expr) => {
+		match $err {
+			ChannelMonitorUpdateErr::PermanentFailure => {
+				// We can no longer update the ChannelMonitor, so the only safe thing to do is to
+				// close the channel on-chain with our latest state.
+				$self.force_close_channel_with_reason(&$channel_id, events::ClosureReason::MonitorUpdateFailed);
+				return Err(MsgHandleErrInternal::send_err_msg_no_close("ChannelMonitor storage failure", $channel_id));
+			},
+			ChannelMonitorUpdateErr::TemporaryFailure => {
+				// We must never send our peer a revocation or commitment the ChannelMonitor hasn't
+				// seen, so pause the channel and hold them until test_restore_channel_monitor.
+				if let Some(chan) = $self.channel_state.lock().unwrap().by_id.get_mut(&$channel_id) {
+					chan.monitor_update_failed($resend_raa, $resend_commitment, $failed_forwards, $failed_fails);
+				}
+				return Err(MsgHandleErrInternal::from_chan_no_close(ChannelError::Ignore("Failed to update ChannelMonitor"), $channel_id));
+			},
+		}
+	}
+}
+
 /// Manager which keeps track of a number of channels and sends messages to the appropriate
 /// channel, also tracking HTLC preimages and forwarding onion packets appropriately.
 ///
 /// Implements ChannelMessageHandler, handling the multi-channel parts and passing things through
 /// to individual Channels.
+///
+/// Onion replay protection only survives a restart if the onions written by write_seen_onions and
+/// write_new_seen_onions are persisted alongside the ChannelMonitors and loaded back with
+/// read_seen_onions.
 pub struct ChannelManager {
 	genesis_hash: Sha256dHash,
 	fee_estimator: Arc<FeeEstimator>,
 	monitor: Arc<ManyChannelMonitor>,
 	chain_monitor: Arc<ChainWatchInterface>,
 	tx_broadcaster: Arc<BroadcasterInterface>,
+	wallet: Arc<WalletInterface>,
 
 	announce_channels_publicly: bool,
 	fee_proportional_millionths: u32,
@@ -284,13 +460,78 @@ pub struct ChannelManager {
 
 	channel_state: Mutex<ChannelHolder>,
 	our_network_key: SecretKey,
+	/// The localfeatures from the init message of each connected peer.
+	peer_features: Mutex<HashMap<PublicKey, msgs::LocalFeatures>>,
+
+	node_announcement: Mutex<NodeAnnouncementState>,
 
 	pending_events: Mutex<Vec<events::Event>>,
+	/// Payments sent via send_payment_with_retries, by payment_hash. Pending payments stay here
+	/// until they succeed or fail for good, after which they are kept for
+	/// RESOLVED_PAYMENT_RETENTION_SECS so that duplicate sends and claims can be detected, and then
+	/// removed on the next timer_tick_occured.
+	tracked_payments: Mutex<HashMap<[u8; 32], TrackedPayment>>,
+	/// Key from which the payment secrets (and, for create_inbound_payment, preimages) of inbound
+	/// payments are derived, so that we don't need to store them.
+	inbound_payment_key: [u8; 32],
+	seen_onions: Mutex<SeenOnions>,
+	forwarding_policy: Mutex<Option<Arc<ForwardingPolicy>>>,
+	channel_disable_timeout: Mutex<Duration>,
+	/// When we last re-broadcast our public channels' channel_announcements, starting from when we
+	/// were created (as our peers will have just seen them when the channels were announced). Only
+	/// None if it was cleared to force a re-broadcast on the next timer tick.
+	last_channel_announcement_broadcast: Mutex<Option<Instant>>,
 
 	logger: Arc<Logger>,
 }
 
-const CLTV_EXPIRY_DELTA: u16 = 6 * 24 * 2; //TODO?
+/// The cltv_expiry_delta we forward HTLCs with, unless another is set via update_channel_config.
+const CLTV_EXPIRY_DELTA: u16 = 6 * 24 * 2;
+/// The number of blocks in the future beyond which we consider a CLTV expiry unreasonable, as it
+/// would leave funds locked up for far too long if the payment gets stuck.
+const CLTV_FAR_FAR_AWAY: u32 = 6 * 24 * 7;
+/// The maximum number of unexpired onions SeenOnions tracks for each channel, bounding its memory
+/// usage however many HTLCs a peer sends us.
+const MAX_SEEN_ONIONS_PER_CHANNEL: usize = 50_000;
+/// The number of blocks we allow for an HTLC we accept to be committed to (and, if we're
+/// forwarding it, relayed), on top of ChannelMonitor's CLTV_CLAIM_BUFFER, when deciding whether its
+/// expiry is too soon for us to be able to claim it on-chain if need be.
+const LATENCY_GRACE_PERIOD_BLOCKS: u32 = 3;
+/// HTLCs which we've received but not yet claimed, or are about to send but haven't yet committed
+/// to, are failed backwards once they expire within this many blocks, before they'd require us or
+/// our counterparty to go on-chain.
+///
+/// This must stay below the CLTV_CLAIM_BUFFER + LATENCY_GRACE_PERIOD_BLOCKS margin we require when
+/// accepting an HTLC, or a payment received at exactly the minimum final CLTV would be failed back
+/// on the very next block, leaving the user no time to claim it.
+const HTLC_FAIL_BACK_BUFFER: u32 = CLTV_CLAIM_BUFFER;
+
+/// How long HTLCs which comply with the forwarding config a channel had before the last
+/// update_channel_config are still forwarded, giving the new channel_update time to propagate.
+const CHANNEL_CONFIG_GRACE_PERIOD_SECS: u64 = 10 * 60;
+
+/// How long a channel's peer must be disconnected (or, once it was, reconnected) for by default
+/// before we announce the channel as disabled (or enabled again). See set_channel_disable_timeout.
+const DEFAULT_CHANNEL_DISABLE_TIMEOUT_SECS: u64 = 2 * 60;
+
+/// How often we re-broadcast our node_announcement, even if nothing about it has changed, so that
+/// nodes which missed it (or pruned it) eventually learn about us.
+const NODE_ANNOUNCEMENT_REBROADCAST_SECS: u64 = 24 * 60 * 60;
+
+/// How often we re-broadcast the channel_announcements (and latest channel_updates) of our public
+/// channels, so that nodes which missed or pruned them eventually learn about them.
+const CHANNEL_ANNOUNCEMENT_REBROADCAST_SECS: u64 = 24 * 60 * 60;
+
+struct NodeAnnouncementState {
+	/// The rgb color, alias and addresses set via set_node_announcement_info, if any.
+	details: Option<([u8; 3], [u8; 32], Vec<msgs::NetAddress>)>,
+	/// Set when details changed since we last broadcast a node_announcement.
+	changed: bool,
+	last_broadcast: Option<Instant>,
+	/// The timestamp of the last node_announcement we generated. Each new one must have a higher
+	/// timestamp or it will be ignored.
+	last_timestamp: u32,
+}
 
 macro_rules! secp_call {
 	( $res: expr, $err: expr ) => {
@@ -327,6 +568,147 @@ pub struct ChannelDetails {
 	pub channel_value_satoshis: u64,
 	/// The user_id passed in to create_channel, or 0 if the channel was inbound.
 	pub user_id: u64,
+	/// The script our balance will be paid to on cooperative close, as provided by our
+	/// WalletInterface when the channel was opened and committed to in open_channel/accept_channel.
+	pub shutdown_scriptpubkey: Script,
+	/// The script our counterparty's balance will be paid to on cooperative close, if they have
+	/// committed to one, either upfront in open_channel/accept_channel or by sending shutdown.
+	pub their_shutdown_scriptpubkey: Option<Script>,
+	/// True if the channel_update we broadcast for this channel announces it as enabled. This
+	/// lags our peer's connectivity, see ChannelManager::set_channel_disable_timeout.
+	pub is_enabled: bool,
+}
+
+/// Describes an HTLC we've been asked to forward, as given to ForwardingPolicy::should_forward.
+pub struct ForwardingDetails {
+	/// The short_channel_id of the channel over which we received the HTLC.
+	pub prev_short_channel_id: u64,
+	/// The short_channel_id of the channel over which we'd forward the HTLC.
+	pub next_short_channel_id: u64,
+	/// The payment_hash of the HTLC.
+	pub payment_hash: [u8; 32],
+	/// The value, in thousandths of a satoshi, of the HTLC we received.
+	pub incoming_amt_msat: u64,
+	/// The value, in thousandths of a satoshi, of the HTLC we'd forward.
+	pub outgoing_amt_msat: u64,
+	/// The cltv_expiry of the HTLC we received.
+	pub incoming_cltv_expiry: u32,
+	/// The cltv_expiry of the HTLC we'd forward.
+	pub outgoing_cltv_value: u32,
+}
+
+impl ForwardingDetails {
+	/// The fee, in thousandths of a satoshi, we'd earn by forwarding the HTLC.
+	pub fn fee_msat(&self) -> u64 {
+		self.incoming_amt_msat - self.outgoing_amt_msat
+	}
+}
+
+/// What to do with an HTLC we've been asked to forward, as decided by a ForwardingPolicy.
+pub enum ForwardingDecision {
+	/// Forward the HTLC as usual.
+	Forward,
+	/// Fail the HTLC back to the previous hop with the given BOLT 4 failure_code, for which we
+	/// build the failure data (including, if the UPDATE flag (0x1000) is set, the channel_update
+	/// for the channel we'd have forwarded over). Supported codes are 0x1000|7
+	/// temporary_channel_failure, 0x1000|11 amount_below_minimum, 0x1000|12 fee_insufficient,
+	/// 0x1000|13 incorrect_cltv_expiry, 0x1000|14 expiry_too_soon, 0x1000|20 channel_disabled,
+	/// 0x2000|2 temporary_node_failure, 0x4000|0x2000|2 permanent_node_failure, 0x4000|0x2000|3
+	/// required_node_feature_missing, 0x4000|8 permanent_channel_failure, 0x4000|9
+	/// required_channel_feature_missing and 0x4000|10 unknown_next_peer. Any other code is
+	/// replaced with 0x1000|7 temporary_channel_failure.
+	Reject {
+		/// The BOLT 4 failure code to fail the HTLC with.
+		failure_code: u16,
+	},
+	/// Hold on to the HTLC and generate an HTLCIntercepted event for it. It will be held until
+	/// ChannelManager::forward_intercepted_htlc or ChannelManager::fail_intercepted_htlc is called
+	/// or it gets close to expiring, in which case it is failed backwards in
+	/// ChannelManager::block_connected.
+	Hold,
+}
+
+/// A hook which ChannelManager consults before forwarding any HTLC, allowing users to implement
+/// their own forwarding policy (or to open channels just-in-time for HTLCs sent over
+/// short_channel_ids we don't have a channel for). See ChannelManager::set_forwarding_policy.
+///
+/// Note that these are called while ChannelManager holds internal locks, so implementations must
+/// not call back into the ChannelManager.
+pub trait ForwardingPolicy : Sync + Send {
+	/// Decides whether to forward the given HTLC over one of our channels.
+	fn should_forward(&self, htlc: &ForwardingDetails) -> ForwardingDecision;
+	/// Returns true if HTLCs asking to be forwarded over the given short_channel_id, which we have
+	/// no channel for, should be held and given to the user in an HTLCIntercepted event rather
+	/// than failed. The short_channel_id may be a "virtual" one which the user handed out to the
+	/// sender (eg in an invoice's route hints).
+	fn should_intercept(&self, requested_short_channel_id: u64) -> bool;
+}
+
+/// Describes a payment which ChannelManager should route (and re-route on failure) by itself. See
+/// ChannelManager::send_payment_with_retries.
+#[derive(Clone)]
+pub struct PaymentParameters {
+	/// The node_id of the payment recipient.
+	pub target: PublicKey,
+	/// The amount, in thousandths of a satoshi, the recipient should receive.
+	pub final_value_msat: u64,
+	/// The CLTV delta the recipient requires for the final hop.
+	pub final_cltv: u32,
+	/// Private channels to the recipient which the router should consider, as for
+	/// Router::get_route's last_hops.
+	pub last_hops: Vec<RouteHint>,
+	/// The payment_secret the recipient provided (eg in an invoice), if any, which is passed to
+	/// them in the onion to prove we know it.
+	pub payment_secret: Option<[u8; 32]>,
+}
+
+/// Limits how hard ChannelManager will try to complete a payment sent with
+/// ChannelManager::send_payment_with_retries. Once either limit is hit, the payment is failed.
+#[derive(Clone, Copy)]
+pub struct RetryBudget {
+	/// The maximum number of times the payment will be sent, including the first attempt.
+	pub max_attempts: u32,
+	/// The maximum amount of time, from the initial send, after which we will no longer retry.
+	pub max_duration: Duration,
+}
+
+#[derive(PartialEq)]
+enum PaymentResolution {
+	Succeeded,
+	Failed,
+}
+
+/// How long we remember tracked payments after they complete, see TrackedPayment::resolved.
+const RESOLVED_PAYMENT_RETENTION_SECS: u64 = 60 * 60;
+
+struct TrackedPayment {
+	params: PaymentParameters,
+	budget: RetryBudget,
+	router: Arc<Router>,
+	attempts: u32,
+	first_attempt: Instant,
+	/// Channels over which previous attempts failed, which we'll avoid in subsequent routes.
+	avoid_short_channel_ids: Vec<u64>,
+	/// Set once we've generated the payment's PaymentSent or PaymentFailed event. We keep the
+	/// payment around for a while afterwards so that we can drop duplicate events for it and
+	/// refuse to pay the same payment_hash twice.
+	resolved: Option<(PaymentResolution, Instant)>,
+}
+
+/// How an inbound payment's payment_hash is tied to its payment_secret, stored in the first byte
+/// of the metadata encrypted in the payment_secret (see create_inbound_payment).
+const INBOUND_PAYMENT_DERIVED_PREIMAGE: u8 = 0;
+const INBOUND_PAYMENT_USER_HASH: u8 = 1;
+
+/// What a payment_secret we created tells us about the inbound payment it was created for.
+struct InboundPayment {
+	/// The minimum amount we'll accept for this payment, if any.
+	min_value_msat: Option<u64>,
+	/// The time, in seconds since the UNIX epoch, from which HTLCs paying to this payment are
+	/// rejected.
+	expiry: u64,
+	/// The preimage, for payments created with create_inbound_payment.
+	payment_preimage: Option<[u8; 32]>,
 }
 
 impl ChannelManager {
@@ -338,8 +720,10 @@ impl ChannelManager {
 	/// fee_proportional_millionths is an optional fee to charge any payments routed through us.
 	/// Non-proportional fees are fixed according to our risk using the provided fee estimator.
 	///
+	/// wallet provides the scripts our balance is paid to when channels are cooperatively closed.
+	///
 	/// panics if channel_value_satoshis is >= `MAX_FUNDING_SATOSHIS`!
-	pub fn new(our_network_key: SecretKey, fee_proportional_millionths: u32, announce_channels_publicly: bool, network: Network, feeest: Arc<FeeEstimator>, monitor: Arc<ManyChannelMonitor>, chain_monitor: Arc<ChainWatchInterface>, tx_broadcaster: Arc<BroadcasterInterface>, logger: Arc<Logger>) -> Result<Arc<ChannelManager>, secp256k1::Error> {
+	pub fn new(our_network_key: SecretKey, fee_proportional_millionths: u32, announce_channels_publicly: bool, network: Network, feeest: Arc<FeeEstimator>, monitor: Arc<ManyChannelMonitor>, chain_monitor: Arc<ChainWatchInterface>, tx_broadcaster: Arc<BroadcasterInterface>, wallet: Arc<WalletInterface>, logger: Arc<Logger>) -> Result<Arc<ChannelManager>, secp256k1::Error> {
 		let secp_ctx = Secp256k1::new();
 
 		let res = Arc::new(ChannelManager {
@@ -348,6 +732,7 @@ impl ChannelManager {
 			monitor: monitor.clone(),
 			chain_monitor,
 			tx_broadcaster,
+			wallet,
 
 			announce_channels_publicly,
 			fee_proportional_millionths,
@@ -360,10 +745,31 @@ impl ChannelManager {
 				next_forward: Instant::now(),
 				forward_htlcs: HashMap::new(),
 				claimable_htlcs: HashMap::new(),
+				intercepted_htlcs: HashMap::new(),
 			}),
 			our_network_key,
+			peer_features: Mutex::new(HashMap::new()),
+
+			node_announcement: Mutex::new(NodeAnnouncementState {
+				details: None,
+				changed: false,
+				last_broadcast: None,
+				last_timestamp: 0,
+			}),
 
 			pending_events: Mutex::new(Vec::new()),
+			tracked_payments: Mutex::new(HashMap::new()),
+			inbound_payment_key: {
+				let mut hmac = Hmac::new(Sha256::new(), &our_network_key[..]);
+				hmac.input(b"inbound payment key");
+				let mut res = [0; 32];
+				hmac.raw_result(&mut res);
+				res
+			},
+			seen_onions: Mutex::new(SeenOnions::new(MAX_SEEN_ONIONS_PER_CHANNEL)),
+			forwarding_policy: Mutex::new(None),
+			channel_disable_timeout: Mutex::new(Duration::from_secs(DEFAULT_CHANNEL_DISABLE_TIMEOUT_SECS)),
+			last_channel_announcement_broadcast: Mutex::new(Some(Instant::now())),
 
 			logger,
 		});
@@ -372,6 +778,15 @@ impl ChannelManager {
 		Ok(res)
 	}
 
+	/// Gets the localfeatures the given peer sent us in its init message, or none if it isn't
+	/// connected.
+	fn get_peer_features(&self, their_node_id: &PublicKey) -> msgs::LocalFeatures {
+		match self.peer_features.lock().unwrap().get(their_node_id) {
+			Some(features) => features.clone(),
+			None => msgs::LocalFeatures::new(),
+		}
+	}
+
 	/// Creates a new outbound channel to the given remote node and with the given value.
 	///
 	/// user_id will be provided back as user_channel_id in FundingGenerationReady and
@@ -384,6 +799,7 @@ impl ChannelManager {
 	///
 	/// Raises APIError::APIMisuseError when channel_value_satoshis > 2**24 or push_msat being greater than channel_value_satoshis * 1k
 	pub fn create_channel(&self, their_network_key: PublicKey, channel_value_satoshis: u64, push_msat: u64, user_id: u64) -> Result<(), APIError> {
+		#[allow(deprecated)]
 		let chan_keys = if cfg!(feature = "fuzztarget") {
 			ChannelKeys {
 				funding_key:               SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
@@ -404,8 +820,9 @@ impl ChannelManager {
 			}
 		};
 
-		let channel = Channel::new_outbound(&*self.fee_estimator, chan_keys, their_network_key, channel_value_satoshis, push_msat, self.announce_channels_publicly, user_id, Arc::clone(&self.logger))?;
-		let res = channel.get_open_channel(self.genesis_hash.clone(), &*self.fee_estimator);
+		let channel = Channel::new_outbound(&*self.fee_estimator, chan_keys, their_network_key, channel_value_satoshis, push_msat, self.announce_channels_publicly, user_id, self.wallet.get_shutdown_scriptpubkey(), Arc::clone(&self.logger))?;
+		let their_features = self.get_peer_features(&their_network_key);
+		let res = channel.get_open_channel(self.genesis_hash.clone(), &*self.fee_estimator, &their_features);
 		let mut channel_state = self.channel_state.lock().unwrap();
 		match channel_state.by_id.entry(channel.channel_id()) {
 			hash_map::Entry::Occupied(_) => {
@@ -438,6 +855,9 @@ impl ChannelManager {
 				remote_network_id: channel.get_their_node_id(),
 				channel_value_satoshis: channel.get_value_satoshis(),
 				user_id: channel.get_user_id(),
+				shutdown_scriptpubkey: channel.get_shutdown_scriptpubkey(),
+				their_shutdown_scriptpubkey: channel.get_their_shutdown_scriptpubkey(),
+				is_enabled: channel.is_announced_enabled(),
 			});
 		}
 		res
@@ -459,6 +879,9 @@ impl ChannelManager {
 					remote_network_id: channel.get_their_node_id(),
 					channel_value_satoshis: channel.get_value_satoshis(),
 					user_id: channel.get_user_id(),
+					shutdown_scriptpubkey: channel.get_shutdown_scriptpubkey(),
+					their_shutdown_scriptpubkey: channel.get_their_shutdown_scriptpubkey(),
+					is_enabled: channel.is_announced_enabled(),
 				});
 			}
 		}
@@ -471,12 +894,29 @@ impl ChannelManager {
 	///
 	/// May generate a SendShutdown event on success, which should be relayed.
 	pub fn close_channel(&self, channel_id: &[u8; 32]) -> Result<(), APIError> {
+		self.close_channel_internal(channel_id, None, None)
+	}
+
+	/// Begins the process of closing a channel, as close_channel does, but if we funded the channel
+	/// (and thus pay the closing fee) our first closing fee proposal will be based on
+	/// target_feerate_sat_per_1000_weight and we will never agree to a closing transaction which
+	/// pays more than max_fee_satoshis in fees.
+	///
+	/// If we did not fund the channel the fee parameters are ignored, as the closing fee is paid by
+	/// our counterparty.
+	///
+	/// May generate a SendShutdown event on success, which should be relayed.
+	pub fn close_channel_with_fee(&self, channel_id: &[u8; 32], target_feerate_sat_per_1000_weight: u64, max_fee_satoshis: u64) -> Result<(), APIError> {
+		self.close_channel_internal(channel_id, Some(target_feerate_sat_per_1000_weight), Some(max_fee_satoshis))
+	}
+
+	fn close_channel_internal(&self, channel_id: &[u8; 32], target_feerate: Option<u64>, max_fee_satoshis: Option<u64>) -> Result<(), APIError> {
 		let (mut res, node_id, chan_option) = {
 			let mut channel_state_lock = self.channel_state.lock().unwrap();
 			let channel_state = channel_state_lock.borrow_parts();
 			match channel_state.by_id.entry(channel_id.clone()) {
 				hash_map::Entry::Occupied(mut chan_entry) => {
-					let res = chan_entry.get_mut().get_shutdown()?;
+					let res = chan_entry.get_mut().get_shutdown(target_feerate, max_fee_satoshis)?;
 					if chan_entry.get().is_shutdown() {
 						if let Some(short_id) = chan_entry.get().get_short_channel_id() {
 							channel_state.short_to_id.remove(&short_id);
@@ -491,8 +931,8 @@ impl ChannelManager {
 			// unknown_next_peer...I dunno who that is anymore....
 			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), htlc_source.0, &htlc_source.1, HTLCFailReason::Reason { failure_code: 0x4000 | 10, data: Vec::new() });
 		}
-		let chan_update = if let Some(chan) = chan_option {
-			if let Ok(update) = self.get_channel_update(&chan) {
+		let chan_update = if let Some(ref chan) = chan_option {
+			if let Ok(update) = self.get_channel_update(chan) {
 				Some(update)
 			} else { None }
 		} else { None };
@@ -503,6 +943,9 @@ impl ChannelManager {
 				msg: update
 			});
 		}
+		if let Some(ref chan) = chan_option {
+			events.push(Self::get_channel_closed_event(chan, events::ClosureReason::CooperativeClosure, None, None));
+		}
 		events.push(events::Event::SendShutdown {
 			node_id,
 			msg: res.0
@@ -511,6 +954,17 @@ impl ChannelManager {
 		Ok(())
 	}
 
+	#[inline]
+	fn get_channel_closed_event(chan: &Channel, reason: events::ClosureReason, closing_transaction: Option<Transaction>, fee_satoshis: Option<u64>) -> events::Event {
+		events::Event::ChannelClosed {
+			channel_id: chan.channel_id(),
+			user_channel_id: chan.get_user_id(),
+			reason,
+			closing_transaction,
+			fee_satoshis,
+		}
+	}
+
 	#[inline]
 	fn finish_force_close_channel(&self, shutdown_res: (Vec<Transaction>, Vec<(HTLCSource, [u8; 32])>)) {
 		let (local_txn, mut failed_htlcs) = shutdown_res;
@@ -533,6 +987,10 @@ impl ChannelManager {
 	/// Force closes a channel, immediately broadcasting the latest local commitment transaction to
 	/// the chain and rejecting new HTLCs on the given channel.
 	pub fn force_close_channel(&self, channel_id: &[u8; 32]) {
+		self.force_close_channel_with_reason(channel_id, events::ClosureReason::HolderForceClosed);
+	}
+
+	fn force_close_channel_with_reason(&self, channel_id: &[u8; 32], reason: events::ClosureReason) {
 		let mut chan = {
 			let mut channel_state_lock = self.channel_state.lock().unwrap();
 			let channel_state = channel_state_lock.borrow_parts();
@@ -545,13 +1003,16 @@ impl ChannelManager {
 				return;
 			}
 		};
-		self.finish_force_close_channel(chan.force_shutdown());
+		let shutdown_res = chan.force_shutdown(true);
+		let closing_tx = shutdown_res.0.first().cloned();
+		self.finish_force_close_channel(shutdown_res);
 		let mut events = self.pending_events.lock().unwrap();
 		if let Ok(update) = self.get_channel_update(&chan) {
 			events.push(events::Event::BroadcastChannelUpdate {
 				msg: update
 			});
 		}
+		events.push(Self::get_channel_closed_event(&chan, reason, closing_tx, None));
 	}
 
 	/// Force close all channels, immediately broadcasting the latest local commitment transaction
@@ -646,7 +1107,8 @@ impl ChannelManager {
 	}
 
 	/// returns the hop data, as well as the first-hop value_msat and CLTV value we should send.
-	fn build_onion_payloads(route: &Route, starting_htlc_offset: u32) -> Result<(Vec<msgs::OnionHopData>, u64, u32), APIError> {
+	/// The payment_secret, if any, is given to the final hop in its payment_data.
+	fn build_onion_payloads(route: &Route, payment_secret: &Option<[u8; 32]>, starting_htlc_offset: u32) -> Result<(Vec<msgs::OnionHopData>, u64, u32), APIError> {
 		let mut cur_value_msat = 0u64;
 		let mut cur_cltv = starting_htlc_offset;
 		let mut last_short_channel_id = 0;
@@ -667,6 +1129,12 @@ impl ChannelManager {
 					amt_to_forward: value_msat,
 					outgoing_cltv_value: cltv,
 				},
+				payment_data: if idx == route.hops.len() - 1 {
+					payment_secret.map(|payment_secret| msgs::FinalOnionHopData {
+						payment_secret,
+						total_msat: value_msat,
+					})
+				} else { None },
 				hmac: [0; 32],
 			};
 			cur_value_msat += hop.fee_msat;
@@ -702,6 +1170,9 @@ impl ChannelManager {
 	}
 
 	const ZERO:[u8; 21*65] = [0; 21*65];
+	/// All but the final hop's payloads must be 65-byte realm 0 frames, as the filler assumes so.
+	/// The final hop's may be a longer TLV payload (if it has a payment_data), as long as it
+	/// doesn't overlap the filler, which limits such routes to 19 hops.
 	fn construct_onion_packet(mut payloads: Vec<msgs::OnionHopData>, onion_keys: Vec<OnionKeys>, associated_data: &[u8; 32]) -> msgs::OnionPacket {
 		let mut buf = Vec::with_capacity(21*65);
 		buf.resize(21*65, 0);
@@ -727,7 +1198,8 @@ impl ChannelManager {
 		for (i, (payload, keys)) in payloads.iter_mut().zip(onion_keys.iter()).rev().enumerate() {
 			ChannelManager::shift_arr_right(&mut packet_data);
 			payload.hmac = hmac_res;
-			packet_data[0..65].copy_from_slice(&payload.encode()[..]);
+			let encoded_payload = payload.encode();
+			packet_data[0..encoded_payload.len()].copy_from_slice(&encoded_payload[..]);
 
 			let mut chacha = ChaCha20::new(&keys.rho, &[0u8; 8]);
 			chacha.process(&packet_data, &mut buf[0..20*65]);
@@ -795,13 +1267,107 @@ impl ChannelManager {
 		packet
 	}
 
+	/// Reads the channel_update included after the failure code (and any fields BOLT 4 places before
+	/// it) in errors with the UPDATE flag set.
+	fn read_failure_channel_update(error_code: u16, mut failure_data: &[u8]) -> Option<msgs::ChannelUpdate> {
+		let skip_len = match error_code & 0xff {
+			11|12 => 8, // htlc_msat
+			13 => 4, // cltv_expiry
+			20 => 2, // flags
+			_ => 0,
+		};
+		if failure_data.len() < skip_len { return None; }
+		failure_data = &failure_data[skip_len..];
+		if failure_data.len() < 2 { return None; }
+		let update_len = byte_utils::slice_to_be16(&failure_data[0..2]) as usize;
+		if failure_data.len() < 2 + update_len { return None; }
+		msgs::ChannelUpdate::read(&mut Cursor::new(&failure_data[2..2 + update_len])).ok()
+	}
+
+	/// Decrypts an onion error packet received for one of our outbound payments, figuring out which
+	/// hop generated it and what it tells us.
+	fn process_onion_failure(&self, route: &Route, session_priv: &SecretKey, mut packet_decrypted: Vec<u8>) -> DecodedOnionFailure {
+		let mut res = DecodedOnionFailure {
+			network_update: None,
+			rejected_by_dest: false,
+			failing_node: None,
+			failing_short_channel_id: None,
+			error_code: None,
+			channel_update: None,
+		};
+		let mut hop_idx = 0;
+		let mut found = false;
+		Self::construct_onion_keys_callback(&self.secp_ctx, route, session_priv, |shared_secret, _, _, route_hop| {
+			hop_idx += 1;
+			if found { return; }
+
+			let ammag = ChannelManager::gen_ammag_from_shared_secret(&shared_secret);
+
+			let mut decryption_tmp = Vec::with_capacity(packet_decrypted.len());
+			decryption_tmp.resize(packet_decrypted.len(), 0);
+			let mut chacha = ChaCha20::new(&ammag, &[0u8; 8]);
+			chacha.process(&packet_decrypted, &mut decryption_tmp[..]);
+			packet_decrypted = decryption_tmp;
+
+			if let Ok(err_packet) = msgs::DecodedOnionErrorPacket::read(&mut Cursor::new(&packet_decrypted)) {
+				if err_packet.failuremsg.len() >= 2 {
+					let um = ChannelManager::gen_um_from_shared_secret(&shared_secret);
+
+					let mut hmac = Hmac::new(Sha256::new(), &um);
+					hmac.input(&err_packet.encode()[32..]);
+					let mut calc_tag = [0u8; 32];
+					hmac.raw_result(&mut calc_tag);
+					if crypto::util::fixed_time_eq(&calc_tag, &err_packet.hmac) {
+						found = true;
+						// route.hops[hop_idx] is the hop after the one which failed, if any
+						let outgoing_short_channel_id = route.hops.get(hop_idx).map(|hop| hop.short_channel_id);
+						let error_code = byte_utils::slice_to_be16(&err_packet.failuremsg[0..2]);
+
+						const PERM: u16 = 0x4000;
+						const UPDATE: u16 = 0x1000;
+						const UNKNOWN_CHAN: u16 = PERM|10;
+
+						// Only a permanent failure from the recipient means it rejected the payment
+						// outright, others (eg final_expiry_too_soon) may succeed if it is re-sent
+						// differently.
+						res.rejected_by_dest = outgoing_short_channel_id.is_none() && error_code & PERM == PERM;
+						res.failing_node = Some(route_hop.pubkey);
+						res.failing_short_channel_id = outgoing_short_channel_id;
+						res.error_code = Some(error_code);
+
+						if error_code & UPDATE == UPDATE {
+							res.channel_update = ChannelManager::read_failure_channel_update(error_code, &err_packet.failuremsg[2..]);
+							if let Some(ref chan_update) = res.channel_update {
+								res.network_update = Some(msgs::HTLCFailChannelUpdate::ChannelUpdateMessage {
+									msg: chan_update.clone(),
+								});
+							}
+						} else if error_code == UNKNOWN_CHAN {
+							// No such next-hop. We know this came from the current node as the HMAC
+							// validated.
+							if let Some(short_channel_id) = outgoing_short_channel_id {
+								res.network_update = Some(msgs::HTLCFailChannelUpdate::ChannelClosed {
+									short_channel_id
+								});
+							}
+						}
+						//TODO: Enumerate the rest of these!
+					}
+				}
+			}
+		}).unwrap();
+		res
+	}
+
 	#[inline]
 	fn build_first_hop_failure_packet(shared_secret: &SharedSecret, failure_type: u16, failure_data: &[u8]) -> msgs::OnionErrorPacket {
 		let failure_packet = ChannelManager::build_failure_packet(shared_secret, failure_type, failure_data);
 		ChannelManager::encrypt_failure_packet(shared_secret, &failure_packet.encode()[..])
 	}
 
-	fn decode_update_add_htlc_onion(&self, msg: &msgs::UpdateAddHTLC) -> (PendingHTLCStatus, MutexGuard<ChannelHolder>) {
+	/// Also returns the hash of the onion's shared secret, which the caller must record in
+	/// seen_onions once the channel accepts the HTLC, if the onion got as far as the replay check.
+	fn decode_update_add_htlc_onion(&self, msg: &msgs::UpdateAddHTLC) -> (PendingHTLCStatus, Option<[u8; 32]>, MutexGuard<ChannelHolder>) {
 		macro_rules! get_onion_hash {
 			() => {
 				{
@@ -821,13 +1387,14 @@ impl ChannelManager {
 				htlc_id: msg.htlc_id,
 				sha256_of_onion: get_onion_hash!(),
 				failure_code: 0x8000 | 0x4000 | 6,
-			})), self.channel_state.lock().unwrap());
+			})), None, self.channel_state.lock().unwrap());
 		}
 
 		let shared_secret = SharedSecret::new(&self.secp_ctx, &msg.onion_routing_packet.public_key.unwrap(), &self.our_network_key);
 		let (rho, mu) = ChannelManager::gen_rho_mu_from_shared_secret(&shared_secret);
 
 		let mut channel_state = None;
+		let mut recorded_onion_hash = None;
 		macro_rules! return_err {
 			($msg: expr, $err_code: expr, $data: expr) => {
 				{
@@ -839,7 +1406,7 @@ impl ChannelManager {
 						channel_id: msg.channel_id,
 						htlc_id: msg.htlc_id,
 						reason: ChannelManager::build_first_hop_failure_packet(&shared_secret, $err_code, $data),
-					})), channel_state.unwrap());
+					})), recorded_onion_hash, channel_state.unwrap());
 				}
 			}
 		}
@@ -861,23 +1428,55 @@ impl ChannelManager {
 			return_err!("HMAC Check failed", 0x8000 | 0x4000 | 5, &get_onion_hash!());
 		}
 
-		let mut chacha = ChaCha20::new(&rho, &[0u8; 8]);
-		let next_hop_data = {
-			let mut decoded = [0; 65];
-			chacha.process(&msg.onion_routing_packet.hop_data[0..65], &mut decoded);
-			match msgs::OnionHopData::read(&mut Cursor::new(&decoded[..])) {
-				Err(err) => {
-					let error_code = match err {
-						msgs::DecodeError::UnknownVersion => 0x4000 | 1, // unknown realm byte
-						_ => 0x2000 | 2, // Should never happen
-					};
-					return_err!("Unable to decode our hop data", error_code, &[0;0]);
+		// Check for replays before looking at anything else, so that the response to a replayed
+		// onion tells its sender nothing about the state of the original payment.
+		let onion_hash = {
+			let mut sha = Sha256::new();
+			sha.input(&shared_secret[..]);
+			let mut res = [0; 32];
+			sha.result(&mut res);
+			res
+		};
+		// Take the channel_state lock before checking, as internal_update_add_htlc only records the
+		// onion once the channel has accepted the HTLC, under the same lock.
+		channel_state = Some(self.channel_state.lock().unwrap());
+		{
+			let seen_onions = self.seen_onions.lock().unwrap();
+			if seen_onions.is_replay(&onion_hash, &msg.channel_id, msg.htlc_id) {
+				return_err!("Received a replayed onion", 0x2000 | 2, &[0;0]);
+			}
+			if seen_onions.is_full(&msg.channel_id) {
+				return_err!("Too many recently seen onions on this channel to check for replays", 0x2000 | 2, &[0;0]);
+			}
+		}
+		recorded_onion_hash = Some(onion_hash);
+
+		// Our hop data may be longer than 65 bytes, so decrypt all of hop_data, followed by the
+		// stream which pads the next hop's packet out once our hop data is removed from the front.
+		let mut decoded = [0; 2*20*65];
+		{
+			let mut chacha = ChaCha20::new(&rho, &[0u8; 8]);
+			chacha.process(&msg.onion_routing_packet.hop_data, &mut decoded[0..20*65]);
+			chacha.process(&ChannelManager::ZERO[0..20*65], &mut decoded[20*65..]);
+		}
+		let (next_hop_data, next_hop_data_len) = {
+			let mut reader = Cursor::new(&decoded[..]);
+			match msgs::OnionHopData::read(&mut reader) {
+				Ok(hop_data) => {
+					if reader.position() as usize > 20*65 {
+						return_err!("Our hop data ran past the end of the onion", 0x4000 | 22, &[0;3]);
+					}
+					(hop_data, reader.position() as usize)
 				},
-				Ok(msg) => msg
+				// invalid_onion_payload, without pointing out which TLV record was invalid
+				Err(_) => return_err!("Unable to decode our hop data", 0x4000 | 22, &[0;3]),
 			}
 		};
 
-		//TODO: Check that msg.cltv_expiry is within acceptable bounds!
+		let cur_height = self.latest_block_height.load(Ordering::Acquire) as u32;
+		if next_hop_data.data.outgoing_cltv_value > cur_height + CLTV_FAR_FAR_AWAY {
+			return_err!("Outgoing CLTV value is too far in the future", 21, &[0;0]);
+		}
 
 		let pending_forward_info = if next_hop_data.hmac == [0; 32] {
 				// OUR PAYMENT!
@@ -887,6 +1486,22 @@ impl ChannelManager {
 				if next_hop_data.data.outgoing_cltv_value != msg.cltv_expiry {
 					return_err!("Upstream node set CLTV to the wrong value", 18, &byte_utils::be32_to_array(msg.cltv_expiry));
 				}
+				if msg.cltv_expiry <= cur_height + CLTV_CLAIM_BUFFER + LATENCY_GRACE_PERIOD_BLOCKS {
+					return_err!("The final CLTV expiry is too soon to handle", 17, &[0;0]);
+				}
+
+				let payment_secret = match next_hop_data.payment_data {
+					Some(ref payment_data) => {
+						if payment_data.total_msat != msg.amount_msat {
+							return_err!("Sender tried to pay us in parts, which we don't support", 0x4000 | 15, &[0;0]);
+						}
+						Some(payment_data.payment_secret)
+					},
+					None => None,
+				};
+				if let Err((err, code)) = self.check_inbound_payment(&msg.payment_hash, &payment_secret, msg.amount_msat) {
+					return_err!(err, code, &[0;0]);
+				}
 
 				// Note that we could obviously respond immediately with an update_fulfill_htlc
 				// message, however that would leak that we are the recipient of this payment, so
@@ -900,11 +1515,13 @@ impl ChannelManager {
 					incoming_shared_secret: shared_secret.clone(),
 					amt_to_forward: next_hop_data.data.amt_to_forward,
 					outgoing_cltv_value: next_hop_data.data.outgoing_cltv_value,
+					incoming_amt_msat: msg.amount_msat,
+					incoming_cltv_expiry: msg.cltv_expiry,
+					payment_secret,
 				})
 			} else {
 				let mut new_packet_data = [0; 20*65];
-				chacha.process(&msg.onion_routing_packet.hop_data[65..], &mut new_packet_data[0..19*65]);
-				chacha.process(&ChannelManager::ZERO[0..65], &mut new_packet_data[19*65..]);
+				new_packet_data.copy_from_slice(&decoded[next_hop_data_len..next_hop_data_len + 20*65]);
 
 				let mut new_pubkey = msg.onion_routing_packet.public_key.unwrap();
 
@@ -940,42 +1557,143 @@ impl ChannelManager {
 					incoming_shared_secret: shared_secret.clone(),
 					amt_to_forward: next_hop_data.data.amt_to_forward,
 					outgoing_cltv_value: next_hop_data.data.outgoing_cltv_value,
+					incoming_amt_msat: msg.amount_msat,
+					incoming_cltv_expiry: msg.cltv_expiry,
+					payment_secret: None,
 				})
 			};
 
-		channel_state = Some(self.channel_state.lock().unwrap());
 		if let &PendingHTLCStatus::Forward(PendingForwardHTLCInfo { ref onion_packet, ref short_channel_id, ref amt_to_forward, ref outgoing_cltv_value, .. }) = &pending_forward_info {
 			if onion_packet.is_some() { // If short_channel_id is 0 here, we'll reject them in the body here
 				let id_option = channel_state.as_ref().unwrap().short_to_id.get(&short_channel_id).cloned();
 				let forwarding_id = match id_option {
+					// HTLCs over short_channel_ids our ForwardingPolicy intercepts get no checks
+					// here: the user decides how (and whether) to forward them, and
+					// forward_intercepted_htlc checks the CLTV against the channel they pick.
+					None if self.should_intercept(*short_channel_id) => None,
 					None => {
 						return_err!("Don't have available channel for forwarding as requested.", 0x4000 | 10, &[0;0]);
 					},
-					Some(id) => id.clone(),
+					Some(id) => Some(id),
 				};
-				if let Some((err, code, chan_update)) = {
+				if let Some((err, code, data)) = if let Some(forwarding_id) = forwarding_id {
 					let chan = channel_state.as_mut().unwrap().by_id.get_mut(&forwarding_id).unwrap();
 					if !chan.is_live() {
-						Some(("Forwarding channel is not in a ready state.", 0x1000 | 7, self.get_channel_update(chan).unwrap()))
+						Some(("Forwarding channel is not in a ready state.", 0x1000 | 7, self.get_channel_update(chan).unwrap().encode_with_len()))
 					} else {
-						let fee = amt_to_forward.checked_mul(self.fee_proportional_millionths as u64).and_then(|prop_fee| { (prop_fee / 1000000).checked_add(chan.get_our_fee_base_msat(&*self.fee_estimator) as u64) });
-						if fee.is_none() || msg.amount_msat < fee.unwrap() || (msg.amount_msat - fee.unwrap()) < *amt_to_forward {
-							Some(("Prior hop has deviated from specified fees parameters or origin node has obsolete ones", 0x1000 | 12, self.get_channel_update(chan).unwrap()))
-						} else {
-							if (msg.cltv_expiry as u64) < (*outgoing_cltv_value) as u64 + CLTV_EXPIRY_DELTA as u64 {
-								Some(("Forwarding node has tampered with the intended HTLC values or origin node has an obsolete cltv_expiry_delta", 0x1000 | 13, self.get_channel_update(chan).unwrap()))
-							} else {
-								None
+						let mut failure = Self::check_forward_config(&self.get_forwarding_config(chan), msg, *amt_to_forward, *outgoing_cltv_value);
+						if failure.is_some() {
+							// The sender may have routed with our previous config if we only just
+							// changed it, in which case we still honour it.
+							if let Some(prev_config) = chan.get_prev_forwarding_config() {
+								if Self::check_forward_config(&prev_config, msg, *amt_to_forward, *outgoing_cltv_value).is_none() {
+									failure = None;
+								}
 							}
 						}
+						if let Some((err, code, mut data)) = failure {
+							data.extend_from_slice(&self.get_channel_update(chan).unwrap().encode_with_len()[..]);
+							Some((err, code, data))
+						} else if *outgoing_cltv_value <= cur_height + CLTV_CLAIM_BUFFER + LATENCY_GRACE_PERIOD_BLOCKS {
+							Some(("Outgoing CLTV value is too soon for us to be able to claim the HTLC on-chain", 0x1000 | 14, self.get_channel_update(chan).unwrap().encode_with_len()))
+						} else {
+							None
+						}
 					}
-				} {
-					return_err!(err, code, &chan_update.encode_with_len()[..]);
+				} else { None } {
+					return_err!(err, code, &data[..]);
 				}
 			}
 		}
 
-		(pending_forward_info, channel_state.unwrap())
+		(pending_forward_info, recorded_onion_hash, channel_state.unwrap())
+	}
+
+	/// Builds the data to fail an HTLC we were asked to forward over chan with the given BOLT 4
+	/// failure_code, or returns None if we don't know what data failure_code requires.
+	fn build_forward_failure_data(&self, failure_code: u16, chan: &Channel, forward_info: &PendingForwardHTLCInfo) -> Option<Vec<u8>> {
+		if failure_code == 0x2000 | 2 || failure_code == 0x4000 | 0x2000 | 2 || failure_code == 0x4000 | 0x2000 | 3 ||
+				failure_code == 0x4000 | 8 || failure_code == 0x4000 | 9 || failure_code == 0x4000 | 10 {
+			return Some(Vec::new());
+		}
+		let mut data = if failure_code == 0x1000 | 7 || failure_code == 0x1000 | 14 {
+			Vec::new()
+		} else if failure_code == 0x1000 | 11 || failure_code == 0x1000 | 12 {
+			byte_utils::be64_to_array(forward_info.incoming_amt_msat).to_vec()
+		} else if failure_code == 0x1000 | 13 {
+			byte_utils::be32_to_array(forward_info.incoming_cltv_expiry).to_vec()
+		} else if failure_code == 0x1000 | 20 {
+			// disable_flags, none of which are defined yet
+			vec![0; 2]
+		} else {
+			return None;
+		};
+		data.extend_from_slice(&self.get_channel_update(chan).unwrap().encode_with_len()[..]);
+		Some(data)
+	}
+
+	/// Checks an HTLC we've been asked to forward against the given forwarding config, returning
+	/// the failure message, code and data (less the channel_update) to fail it with if it doesn't
+	/// comply.
+	fn check_forward_config(config: &ForwardingConfig, msg: &msgs::UpdateAddHTLC, amt_to_forward: u64, outgoing_cltv_value: u32) -> Option<(&'static str, u16, Vec<u8>)> {
+		let fee = amt_to_forward.checked_mul(config.fee_proportional_millionths as u64).and_then(|prop_fee| { (prop_fee / 1000000).checked_add(config.fee_base_msat as u64) });
+		if amt_to_forward < config.htlc_minimum_msat {
+			Some(("Origin node tried to forward less than our htlc_minimum_msat", 0x1000 | 11, byte_utils::be64_to_array(msg.amount_msat).to_vec()))
+		} else if amt_to_forward > config.htlc_maximum_msat {
+			Some(("Origin node tried to forward more than our htlc_maximum_msat", 0x1000 | 7, Vec::new()))
+		} else if fee.is_none() || msg.amount_msat < fee.unwrap() || (msg.amount_msat - fee.unwrap()) < amt_to_forward {
+			Some(("Prior hop has deviated from specified fees parameters or origin node has obsolete ones", 0x1000 | 12, byte_utils::be64_to_array(msg.amount_msat).to_vec()))
+		} else {
+			Self::check_forward_cltv_delta(config, msg.cltv_expiry, outgoing_cltv_value)
+		}
+	}
+
+	/// Checks that an HTLC we've been asked to forward leaves us the given forwarding config's
+	/// cltv_expiry_delta, returning the failure message, code and data (less the channel_update) to
+	/// fail it with if it doesn't.
+	fn check_forward_cltv_delta(config: &ForwardingConfig, incoming_cltv_expiry: u32, outgoing_cltv_value: u32) -> Option<(&'static str, u16, Vec<u8>)> {
+		if (incoming_cltv_expiry as u64) < outgoing_cltv_value as u64 + config.cltv_expiry_delta as u64 {
+			Some(("Forwarding node has tampered with the intended HTLC values or origin node has an obsolete cltv_expiry_delta", 0x1000 | 13, byte_utils::be32_to_array(incoming_cltv_expiry).to_vec()))
+		} else {
+			None
+		}
+	}
+
+	/// Checks an HTLC paying to us against the inbound payment its payment_secret was created for,
+	/// returning the failure message and code to fail it with if it doesn't match.
+	fn check_inbound_payment(&self, payment_hash: &[u8; 32], payment_secret: &Option<[u8; 32]>, amount_msat: u64) -> Result<(), (&'static str, u16)> {
+		let payment = match *payment_secret {
+			Some(ref payment_secret) => match self.verify_payment_secret(payment_hash, payment_secret) {
+				Some(payment) => payment,
+				// Either a probe or a payment_secret for some other payment, either way we
+				// shouldn't tell the user about it.
+				None => return Err(("Received a payment_secret we didn't create for this payment_hash", 0x4000 | 15)),
+			},
+			// Without a payment_secret we can't tell which inbound payment (if any) this is for, so
+			// it's left to the user to accept or reject.
+			None => return Ok(()),
+		};
+		if payment.expiry <= ChannelManager::unix_time_secs() {
+			return Err(("Received a payment for an expired inbound payment", 0x4000 | 15));
+		}
+		if let Some(min_value_msat) = payment.min_value_msat {
+			if amount_msat < min_value_msat {
+				return Err(("Received less than the inbound payment's minimum value", 0x4000 | 16));
+			}
+		}
+		Ok(())
+	}
+
+	/// Gets the config we forward HTLCs over the given channel with: the one set via
+	/// update_channel_config, if any, or our defaults.
+	fn get_forwarding_config(&self, chan: &Channel) -> ForwardingConfig {
+		chan.get_forwarding_config().unwrap_or_else(|| ForwardingConfig {
+			fee_base_msat: chan.get_our_fee_base_msat(&*self.fee_estimator),
+			fee_proportional_millionths: self.fee_proportional_millionths,
+			cltv_expiry_delta: CLTV_EXPIRY_DELTA,
+			htlc_minimum_msat: chan.get_our_htlc_minimum_msat(),
+			htlc_maximum_msat: chan.get_value_satoshis() * 1000,
+		})
 	}
 
 	/// only fails if the channel does not yet have an assigned short_id
@@ -986,16 +1704,17 @@ impl ChannelManager {
 		};
 
 		let were_node_one = PublicKey::from_secret_key(&self.secp_ctx, &self.our_network_key).serialize()[..] < chan.get_their_node_id().serialize()[..];
+		let config = self.get_forwarding_config(chan);
 
 		let unsigned = msgs::UnsignedChannelUpdate {
 			chain_hash: self.genesis_hash,
 			short_channel_id: short_channel_id,
 			timestamp: chan.get_channel_update_count(),
-			flags: (!were_node_one) as u16 | ((!chan.is_live() as u16) << 1),
-			cltv_expiry_delta: CLTV_EXPIRY_DELTA,
-			htlc_minimum_msat: chan.get_our_htlc_minimum_msat(),
-			fee_base_msat: chan.get_our_fee_base_msat(&*self.fee_estimator),
-			fee_proportional_millionths: self.fee_proportional_millionths,
+			flags: (!were_node_one) as u16 | ((!(chan.is_usable() && chan.is_announced_enabled()) as u16) << 1),
+			cltv_expiry_delta: config.cltv_expiry_delta,
+			htlc_minimum_msat: config.htlc_minimum_msat,
+			fee_base_msat: config.fee_base_msat,
+			fee_proportional_millionths: config.fee_proportional_millionths,
 			excess_data: Vec::new(),
 		};
 
@@ -1025,9 +1744,21 @@ impl ChannelManager {
 	/// Raises APIError::RoutError when invalid route or forward parameter
 	/// (cltv_delta, fee, node public key) is specified
 	pub fn send_payment(&self, route: Route, payment_hash: [u8; 32]) -> Result<(), APIError> {
+		self.send_payment_with_secret(route, payment_hash, None)
+	}
+
+	/// Sends a payment as send_payment does, but also passes the given payment_secret to the
+	/// recipient, as required by recipients which provided one in their invoice (see
+	/// create_inbound_payment).
+	///
+	/// Routes may be at most 19 hops long if a payment_secret is provided.
+	pub fn send_payment_with_secret(&self, route: Route, payment_hash: [u8; 32], payment_secret: Option<[u8; 32]>) -> Result<(), APIError> {
 		if route.hops.len() < 1 || route.hops.len() > 20 {
 			return Err(APIError::RouteError{err: "Route didn't go anywhere/had bogus size"});
 		}
+		if payment_secret.is_some() && route.hops.len() > 19 {
+			return Err(APIError::RouteError{err: "Route too long to include a payment_secret"});
+		}
 		let our_node_id = self.get_our_node_id();
 		for (idx, hop) in route.hops.iter().enumerate() {
 			if idx != route.hops.len() - 1 && hop.pubkey == our_node_id {
@@ -1045,7 +1776,7 @@ impl ChannelManager {
 
 		let onion_keys = secp_call!(ChannelManager::construct_onion_keys(&self.secp_ctx, &route, &session_priv),
 				APIError::RouteError{err: "Pubkey along hop was maliciously selected"});
-		let (onion_payloads, htlc_msat, htlc_cltv) = ChannelManager::build_onion_payloads(&route, cur_height)?;
+		let (onion_payloads, htlc_msat, htlc_cltv) = ChannelManager::build_onion_payloads(&route, &payment_secret, cur_height)?;
 		let onion_packet = ChannelManager::construct_onion_packet(onion_payloads, onion_keys, &payment_hash);
 
 		let (first_hop_node_id, (update_add, commitment_signed, chan_monitor)) = {
@@ -1098,6 +1829,153 @@ impl ChannelManager {
 		Ok(())
 	}
 
+	/// Sends a payment to params.target, finding a route with the given router and, if the payment
+	/// fails at a node other than the recipient, retrying it over a new route which avoids the
+	/// channel that failed. Any failure reported by the recipient itself is final.
+	///
+	/// Retries continue until budget.max_attempts attempts have been made or budget.max_duration
+	/// has passed since this call. Intermediate failures are handled internally: only a single
+	/// PaymentSent or PaymentFailed event will be generated for the payment.
+	///
+	/// Returns an APIMisuseError if a payment with the same payment_hash is already pending or
+	/// recently succeeded, and otherwise any error from finding the initial route or from
+	/// send_payment.
+	pub fn send_payment_with_retries(&self, router: Arc<Router>, params: PaymentParameters, payment_hash: [u8; 32], budget: RetryBudget) -> Result<(), APIError> {
+		if budget.max_attempts < 1 {
+			return Err(APIError::APIMisuseError{err: "A payment must be allowed at least one attempt"});
+		}
+		match self.tracked_payments.lock().unwrap().get(&payment_hash) {
+			Some(&TrackedPayment { resolved: None, .. }) => {
+				return Err(APIError::APIMisuseError{err: "A payment with the given payment_hash is already pending"});
+			},
+			Some(&TrackedPayment { resolved: Some((PaymentResolution::Succeeded, _)), .. }) => {
+				return Err(APIError::APIMisuseError{err: "A payment with the given payment_hash has already succeeded"});
+			},
+			_ => {},
+		}
+
+		let route = router.get_route(&params.target, Some(&self.list_usable_channels()), &params.last_hops, params.final_value_msat, params.final_cltv)
+			.map_err(|e| APIError::RouteError{err: e.err})?;
+		let payment_secret = params.payment_secret;
+		self.tracked_payments.lock().unwrap().insert(payment_hash, TrackedPayment {
+			params,
+			budget,
+			router,
+			attempts: 1,
+			first_attempt: Instant::now(),
+			avoid_short_channel_ids: Vec::new(),
+			resolved: None,
+		});
+		let res = self.send_payment_with_secret(route, payment_hash, payment_secret);
+		if res.is_err() {
+			self.tracked_payments.lock().unwrap().remove(&payment_hash);
+		}
+		res
+	}
+
+	/// Called when an attempt at a payment we're retrying has failed, re-sending it if the budget
+	/// allows. Returns true if the payment is still in-flight (ie no PaymentFailed event should be
+	/// generated).
+	fn retry_payment(&self, payment_hash: &[u8; 32], failed_route: &Route, failure: &DecodedOnionFailure) -> bool {
+		if failure.rejected_by_dest { return false; }
+		// Other failures from the recipient (eg final_expiry_too_soon) don't blame any channel, so
+		// we'd only retry the same route and fail the same way again.
+		if failure.failing_node.is_some() && failure.failing_node == failed_route.hops.last().map(|hop| hop.pubkey) {
+			return false;
+		}
+
+		let (router, params, avoid_short_channel_ids) = {
+			let mut pending_payments = self.tracked_payments.lock().unwrap();
+			let payment = match pending_payments.get_mut(payment_hash) {
+				Some(payment) => payment,
+				None => return false,
+			};
+			if payment.resolved.is_some() || payment.attempts >= payment.budget.max_attempts || payment.first_attempt.elapsed() >= payment.budget.max_duration {
+				return false;
+			}
+			// If we can't blame another node the failure occurred on our own channel to the first hop
+			let failed_short_channel_id = match (failure.failing_node, failure.failing_short_channel_id) {
+				(None, _) => Some(failed_route.hops[0].short_channel_id),
+				(Some(_), short_channel_id) => short_channel_id,
+			};
+			if let Some(short_channel_id) = failed_short_channel_id {
+				payment.avoid_short_channel_ids.push(short_channel_id);
+			}
+			payment.attempts += 1;
+			(payment.router.clone(), payment.params.clone(), payment.avoid_short_channel_ids.clone())
+		};
+
+		let route = match router.get_route_avoiding(&params.target, Some(&self.list_usable_channels()), &params.last_hops, &avoid_short_channel_ids, params.final_value_msat, params.final_cltv) {
+			Ok(route) => route,
+			Err(e) => {
+				log_trace!(self, "Failed to find a new route for payment {}: {}", log_bytes!(payment_hash[..]), e.err);
+				return false;
+			},
+		};
+		match self.send_payment_with_secret(route, payment_hash.clone(), params.payment_secret) {
+			Ok(()) => true,
+			Err(_) => false,
+		}
+	}
+
+	/// Marks a tracked payment as resolved, returning false if an event has already been generated
+	/// for it which makes one for this resolution redundant.
+	fn resolve_tracked_payment(&self, payment_hash: &[u8; 32], resolution: PaymentResolution) -> bool {
+		let mut tracked_payments = self.tracked_payments.lock().unwrap();
+		if let Some(payment) = tracked_payments.get_mut(payment_hash) {
+			match payment.resolved {
+				// A success after a failure is still worth telling the user about
+				Some((PaymentResolution::Failed, _)) if resolution == PaymentResolution::Succeeded => {},
+				Some(_) => return false,
+				None => {},
+			}
+			payment.resolved = Some((resolution, Instant::now()));
+		}
+		true
+	}
+
+	/// Pays a BOLT 11 invoice, finding a route with the given router using the invoice's route
+	/// hints and min_final_cltv_expiry, and retrying within budget as for
+	/// send_payment_with_retries (use a budget with max_attempts of 1 to disable retries).
+	///
+	/// amount_msat must be set if the invoice doesn't specify an amount, and may be used to pay more
+	/// than the invoice requests.
+	///
+	/// Fails with an APIMisuseError if the invoice is for a different chain, has expired, has
+	/// route hints we can't use (see Invoice::last_hops) or if amount_msat is missing or lower than
+	/// the amount requested, and otherwise as
+	/// send_payment_with_retries (in particular, paying the same invoice twice is refused).
+	pub fn pay_invoice(&self, router: Arc<Router>, invoice: &Invoice, amount_msat: Option<u64>, budget: RetryBudget) -> Result<(), APIError> {
+		match invoice.currency().network() {
+			Some(network) if genesis_block(network).header.bitcoin_hash() == self.genesis_hash => {},
+			_ => return Err(APIError::APIMisuseError{err: "Invoice is for a different chain"}),
+		}
+		if invoice.is_expired() {
+			return Err(APIError::APIMisuseError{err: "Invoice has expired"});
+		}
+		let final_value_msat = match (invoice.amount_msat(), amount_msat) {
+			(Some(invoice_amount), Some(amount)) if amount < invoice_amount => {
+				return Err(APIError::APIMisuseError{err: "Cannot pay less than the invoice amount"});
+			},
+			(_, Some(amount)) => amount,
+			(Some(invoice_amount), None) => invoice_amount,
+			(None, None) => return Err(APIError::APIMisuseError{err: "An amount must be given to pay an invoice without one"}),
+		};
+		if invoice.min_final_cltv_expiry() > CLTV_FAR_FAR_AWAY as u64 {
+			return Err(APIError::APIMisuseError{err: "Invoice min_final_cltv_expiry is unreasonably large"});
+		}
+		let last_hops = invoice.last_hops().map_err(|_| APIError::APIMisuseError{err: "Invoice has route hints with more than one hop, which we can't route over"})?;
+
+		let params = PaymentParameters {
+			target: invoice.payee_pubkey().clone(),
+			final_value_msat,
+			final_cltv: invoice.min_final_cltv_expiry() as u32,
+			last_hops,
+			payment_secret: invoice.payment_secret().cloned(),
+		};
+		self.send_payment_with_retries(router, params, invoice.payment_hash().clone(), budget)
+	}
+
 	/// Call this upon creation of a funding transaction for the given channel.
 	///
 	/// Panics if a funding transaction has already been provided for this channel.
@@ -1129,6 +2007,7 @@ impl ChannelManager {
 								node_id: chan.get_their_node_id(),
 								action: e.action,
 							});
+							add_pending_event!(Self::get_channel_closed_event(&chan, events::ClosureReason::ProcessingError { err: e.err.to_string() }, None, None));
 							return;
 						},
 					}
@@ -1155,8 +2034,10 @@ impl ChannelManager {
 		}
 	}
 
-	fn get_announcement_sigs(&self, chan: &Channel) -> Option<msgs::AnnouncementSignatures> {
-		if !chan.should_announce() { return None }
+	/// Gets our announcement_signatures for the given channel, unless it is private or we've already
+	/// sent them since the last reconnect. Marks them as sent, so the result must be sent to the peer.
+	fn get_announcement_sigs(&self, chan: &mut Channel) -> Option<msgs::AnnouncementSignatures> {
+		if !chan.should_announce() || chan.announcement_sigs_sent() { return None }
 
 		let (announcement, our_bitcoin_sig) = match chan.get_channel_announcement(self.get_our_node_id(), self.genesis_hash.clone()) {
 			Ok(res) => res,
@@ -1164,6 +2045,7 @@ impl ChannelManager {
 		};
 		let msghash = Message::from_slice(&Sha256dHash::from_data(&announcement.encode()[..])[..]).unwrap();
 		let our_node_sig = self.secp_ctx.sign(&msghash, &self.our_network_key);
+		chan.set_announcement_sigs_sent();
 
 		Some(msgs::AnnouncementSignatures {
 			channel_id: chan.channel_id(),
@@ -1173,6 +2055,33 @@ impl ChannelManager {
 		})
 	}
 
+	/// Builds the full channel_announcement for the given channel, if it is public and we have our
+	/// peer's announcement_signatures (which were verified when we received them).
+	fn get_signed_channel_announcement(&self, chan: &Channel) -> Option<msgs::ChannelAnnouncement> {
+		if !chan.should_announce() { return None }
+		let (their_node_sig, their_bitcoin_sig) = match chan.get_their_announcement_sigs() {
+			Some(sigs) => sigs,
+			None => return None,
+		};
+
+		let our_node_id = self.get_our_node_id();
+		let (announcement, our_bitcoin_sig) = match chan.get_channel_announcement(our_node_id.clone(), self.genesis_hash.clone()) {
+			Ok(res) => res,
+			Err(_) => return None,
+		};
+		let were_node_one = announcement.node_id_1 == our_node_id;
+		let msghash = Message::from_slice(&Sha256dHash::from_data(&announcement.encode()[..])[..]).unwrap();
+		let our_node_sig = self.secp_ctx.sign(&msghash, &self.our_network_key);
+
+		Some(msgs::ChannelAnnouncement {
+			node_signature_1: if were_node_one { our_node_sig } else { their_node_sig },
+			node_signature_2: if were_node_one { their_node_sig } else { our_node_sig },
+			bitcoin_signature_1: if were_node_one { our_bitcoin_sig } else { their_bitcoin_sig },
+			bitcoin_signature_2: if were_node_one { their_bitcoin_sig } else { our_bitcoin_sig },
+			contents: announcement,
+		})
+	}
+
 	/// Processes HTLCs which are pending waiting on random forward delay.
 	///
 	/// Should only really ever be called in response to an PendingHTLCsForwardable event.
@@ -1193,14 +2102,13 @@ impl ChannelManager {
 					let forward_chan_id = match channel_state.short_to_id.get(&short_chan_id) {
 						Some(chan_id) => chan_id.clone(),
 						None => {
-							failed_forwards.reserve(pending_forwards.len());
-							for HTLCForwardInfo { prev_short_channel_id, prev_htlc_id, forward_info } in pending_forwards.drain(..) {
-								let htlc_source = HTLCSource::PreviousHopData(HTLCPreviousHopData {
-									short_channel_id: prev_short_channel_id,
-									htlc_id: prev_htlc_id,
-									incoming_packet_shared_secret: forward_info.incoming_shared_secret,
-								});
-								failed_forwards.push((htlc_source, forward_info.payment_hash, 0x4000 | 10, None));
+							let intercept = self.should_intercept(short_chan_id);
+							for htlc in pending_forwards.drain(..) {
+								if intercept && !htlc.released {
+									new_events.push((None, intercept_htlc_forward(channel_state.intercepted_htlcs, htlc)));
+								} else {
+									failed_forwards.push((htlc.prev_hop_source(), htlc.forward_info.payment_hash, 0x4000 | 10, Vec::new()));
+								}
 							}
 							continue;
 						}
@@ -1208,16 +2116,45 @@ impl ChannelManager {
 					let forward_chan = &mut channel_state.by_id.get_mut(&forward_chan_id).unwrap();
 
 					let mut add_htlc_msgs = Vec::new();
-					for HTLCForwardInfo { prev_short_channel_id, prev_htlc_id, forward_info } in pending_forwards.drain(..) {
-						let htlc_source = HTLCSource::PreviousHopData(HTLCPreviousHopData {
-							short_channel_id: prev_short_channel_id,
-							htlc_id: prev_htlc_id,
-							incoming_packet_shared_secret: forward_info.incoming_shared_secret,
-						});
+					for htlc in pending_forwards.drain(..) {
+						let htlc_source = htlc.prev_hop_source();
+						if !htlc.released {
+							let decision = match *self.forwarding_policy.lock().unwrap() {
+								Some(ref policy) => policy.should_forward(&ForwardingDetails {
+									prev_short_channel_id: htlc.prev_short_channel_id,
+									next_short_channel_id: short_chan_id,
+									payment_hash: htlc.forward_info.payment_hash,
+									incoming_amt_msat: htlc.forward_info.incoming_amt_msat,
+									outgoing_amt_msat: htlc.forward_info.amt_to_forward,
+									incoming_cltv_expiry: htlc.forward_info.incoming_cltv_expiry,
+									outgoing_cltv_value: htlc.forward_info.outgoing_cltv_value,
+								}),
+								None => ForwardingDecision::Forward,
+							};
+							match decision {
+								ForwardingDecision::Forward => {},
+								ForwardingDecision::Reject { failure_code } => {
+									let (failure_code, data) = match self.build_forward_failure_data(failure_code, forward_chan, &htlc.forward_info) {
+										Some(data) => (failure_code, data),
+										None => {
+											log_error!(self, "ForwardingPolicy rejected an HTLC with failure code {}, which we can't build the failure data for, failing it with temporary_channel_failure instead", failure_code);
+											(0x1000 | 7, self.get_channel_update(forward_chan).unwrap().encode_with_len())
+										},
+									};
+									failed_forwards.push((htlc_source, htlc.forward_info.payment_hash, failure_code, data));
+									continue;
+								},
+								ForwardingDecision::Hold => {
+									new_events.push((None, intercept_htlc_forward(channel_state.intercepted_htlcs, htlc)));
+									continue;
+								},
+							}
+						}
+						let forward_info = htlc.forward_info;
 						match forward_chan.send_htlc(forward_info.amt_to_forward, forward_info.payment_hash, forward_info.outgoing_cltv_value, htlc_source.clone(), forward_info.onion_packet.unwrap()) {
 							Err(_e) => {
 								let chan_update = self.get_channel_update(forward_chan).unwrap();
-								failed_forwards.push((htlc_source, forward_info.payment_hash, 0x1000 | 7, Some(chan_update)));
+								failed_forwards.push((htlc_source, forward_info.payment_hash, 0x1000 | 7, chan_update.encode_with_len()));
 								continue;
 							},
 							Ok(update_add) => {
@@ -1263,30 +2200,32 @@ impl ChannelManager {
 						}));
 					}
 				} else {
-					for HTLCForwardInfo { prev_short_channel_id, prev_htlc_id, forward_info } in pending_forwards.drain(..) {
-						let prev_hop_data = HTLCPreviousHopData {
-							short_channel_id: prev_short_channel_id,
-							htlc_id: prev_htlc_id,
-							incoming_packet_shared_secret: forward_info.incoming_shared_secret,
+					for HTLCForwardInfo { prev_short_channel_id, prev_htlc_id, forward_info, .. } in pending_forwards.drain(..) {
+						let claimable_htlc = ClaimableHTLC {
+							prev_hop: HTLCPreviousHopData {
+								short_channel_id: prev_short_channel_id,
+								htlc_id: prev_htlc_id,
+								incoming_packet_shared_secret: forward_info.incoming_shared_secret,
+							},
+							cltv_expiry: forward_info.outgoing_cltv_value,
 						};
 						match channel_state.claimable_htlcs.entry(forward_info.payment_hash) {
-							hash_map::Entry::Occupied(mut entry) => entry.get_mut().push(prev_hop_data),
-							hash_map::Entry::Vacant(entry) => { entry.insert(vec![prev_hop_data]); },
+							hash_map::Entry::Occupied(mut entry) => entry.get_mut().push(claimable_htlc),
+							hash_map::Entry::Vacant(entry) => { entry.insert(vec![claimable_htlc]); },
 						};
 						new_events.push((None, events::Event::PaymentReceived {
 							payment_hash: forward_info.payment_hash,
 							amt: forward_info.amt_to_forward,
+							payment_secret: forward_info.payment_secret,
+							payment_preimage: forward_info.payment_secret.and_then(|secret| self.get_payment_preimage(&forward_info.payment_hash, &secret)),
 						}));
 					}
 				}
 			}
 		}
 
-		for (htlc_source, payment_hash, failure_code, update) in failed_forwards.drain(..) {
-			match update {
-				None => self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), htlc_source, &payment_hash, HTLCFailReason::Reason { failure_code, data: Vec::new() }),
-				Some(chan_update) => self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), htlc_source, &payment_hash, HTLCFailReason::Reason { failure_code, data: chan_update.encode_with_len() }),
-			};
+		for (htlc_source, payment_hash, failure_code, data) in failed_forwards.drain(..) {
+			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), htlc_source, &payment_hash, HTLCFailReason::Reason { failure_code, data });
 		}
 
 		if new_events.is_empty() { return }
@@ -1307,35 +2246,226 @@ impl ChannelManager {
 		}
 	}
 
-	/// Indicates that the preimage for payment_hash is unknown after a PaymentReceived event.
-	pub fn fail_htlc_backwards(&self, payment_hash: &[u8; 32]) -> bool {
-		let mut channel_state = Some(self.channel_state.lock().unwrap());
-		let removed_source = channel_state.as_mut().unwrap().claimable_htlcs.remove(payment_hash);
-		if let Some(mut sources) = removed_source {
-			for htlc_with_hash in sources.drain(..) {
-				if channel_state.is_none() { channel_state = Some(self.channel_state.lock().unwrap()); }
-				self.fail_htlc_backwards_internal(channel_state.take().unwrap(), HTLCSource::PreviousHopData(htlc_with_hash), payment_hash, HTLCFailReason::Reason { failure_code: 0x4000 | 15, data: Vec::new() });
-			}
-			true
-		} else { false }
+	/// Sets the fees, cltv_expiry_delta and HTLC value limits we forward HTLCs over the given
+	/// channel with, replacing our defaults: a base fee covering the on-chain cost of claiming
+	/// the HTLC, the fee_proportional_millionths given to ChannelManager::new, a cltv_expiry_delta
+	/// of two days, and the HTLC minimum we gave our peer when opening the channel.
+	///
+	/// A new channel_update is broadcast (via a BroadcastChannelUpdate event) if the channel's
+	/// funding transaction has confirmed. As it takes a while to propagate, HTLCs complying with
+	/// the previous config are still forwarded for some time.
+	///
+	/// htlc_minimum_msat may not be below the HTLC minimum we gave our peer when opening the
+	/// channel. htlc_maximum_msat is enforced but not announced, as our channel_update doesn't
+	/// support option_channel_htlc_max.
+	pub fn update_channel_config(&self, channel_id: &[u8; 32], fee_base_msat: u32, fee_proportional_millionths: u32, cltv_expiry_delta: u16, htlc_minimum_msat: u64, htlc_maximum_msat: u64) -> Result<(), APIError> {
+		if (cltv_expiry_delta as u32) < CLTV_CLAIM_BUFFER + LATENCY_GRACE_PERIOD_BLOCKS {
+			return Err(APIError::APIMisuseError { err: "cltv_expiry_delta is too small to safely claim HTLCs on-chain" });
+		}
+		if htlc_maximum_msat < htlc_minimum_msat {
+			return Err(APIError::APIMisuseError { err: "htlc_maximum_msat may not be below htlc_minimum_msat" });
+		}
+		let mut channel_state = self.channel_state.lock().unwrap();
+		let chan = match channel_state.by_id.get_mut(channel_id) {
+			Some(chan) => chan,
+			None => return Err(APIError::ChannelUnavailable { err: "No such channel" }),
+		};
+		if htlc_minimum_msat < chan.get_our_htlc_minimum_msat() {
+			return Err(APIError::APIMisuseError { err: "htlc_minimum_msat may not be below the HTLC minimum we gave our peer" });
+		}
+		let prev_config = self.get_forwarding_config(chan);
+		chan.set_forwarding_config(ForwardingConfig {
+			fee_base_msat,
+			fee_proportional_millionths,
+			cltv_expiry_delta,
+			htlc_minimum_msat,
+			htlc_maximum_msat,
+		}, prev_config, Instant::now() + Duration::from_secs(CHANNEL_CONFIG_GRACE_PERIOD_SECS));
+		if let Ok(update) = self.get_channel_update(chan) {
+			self.pending_events.lock().unwrap().push(events::Event::BroadcastChannelUpdate {
+				msg: update
+			});
+		}
+		Ok(())
 	}
 
-	/// Fails an HTLC backwards to the sender of it to us.
-	/// Note that while we take a channel_state lock as input, we do *not* assume consistency here.
-	/// There are several callsites that do stupid things like loop over a list of payment_hashes
-	/// to fail and take the channel_state lock for each iteration (as we take ownership and may
-	/// drop it). In other words, no assumptions are made that entries in claimable_htlcs point to
-	/// still-available channels.
-	fn fail_htlc_backwards_internal(&self, mut channel_state: MutexGuard<ChannelHolder>, source: HTLCSource, payment_hash: &[u8; 32], onion_error: HTLCFailReason) {
-		match source {
-			HTLCSource::OutboundRoute { .. } => {
-				mem::drop(channel_state);
+	/// Installs a ForwardingPolicy which is consulted before we forward any HTLC, replacing any
+	/// previously installed one. Without one, all HTLCs which pass our own checks are forwarded.
+	pub fn set_forwarding_policy(&self, policy: Option<Arc<ForwardingPolicy>>) {
+		*self.forwarding_policy.lock().unwrap() = policy;
+	}
 
-				let mut pending_events = self.pending_events.lock().unwrap();
-				pending_events.push(events::Event::PaymentFailed {
-					payment_hash: payment_hash.clone()
-				});
-			},
+	fn should_intercept(&self, requested_short_channel_id: u64) -> bool {
+		match *self.forwarding_policy.lock().unwrap() {
+			Some(ref policy) => policy.should_intercept(requested_short_channel_id),
+			None => false,
+		}
+	}
+
+	/// Forwards an HTLC we're holding after an HTLCIntercepted event over the channel with the
+	/// given channel_id, which must be usable (eg one just opened to the next hop), sending
+	/// amt_to_forward_msat along. This may be less than the expected_outbound_amount_msat in the
+	/// event (eg to take a fee for opening the channel) but not more than the HTLC we received.
+	///
+	/// Our ForwardingPolicy is not consulted again for the HTLC. As with any other forward, it is
+	/// sent on in the process_pending_htlc_forwards call prompted by the PendingHTLCsForwardable
+	/// event this may generate.
+	///
+	/// If the HTLC doesn't leave us the channel's cltv_expiry_delta, or now expires too soon for us
+	/// to forward it safely, it is failed back and APIError::ChannelUnavailable is returned.
+	pub fn forward_intercepted_htlc(&self, intercept_id: &[u8; 32], next_channel_id: &[u8; 32], amt_to_forward_msat: u64) -> Result<(), APIError> {
+		let mut forward_event = None;
+		{
+			let mut channel_state_lock = self.channel_state.lock().unwrap();
+			let short_channel_id = match channel_state_lock.by_id.get(next_channel_id) {
+				None => return Err(APIError::ChannelUnavailable { err: "No such channel" }),
+				Some(chan) => match chan.get_short_channel_id() {
+					Some(short_channel_id) if chan.is_usable() => short_channel_id,
+					_ => return Err(APIError::ChannelUnavailable { err: "Channel is not yet usable for forwarding" }),
+				},
+			};
+			let (incoming_amt_msat, incoming_cltv_expiry, outgoing_cltv_value) = match channel_state_lock.intercepted_htlcs.get(intercept_id) {
+				Some(htlc) => (htlc.forward_info.incoming_amt_msat, htlc.forward_info.incoming_cltv_expiry, htlc.forward_info.outgoing_cltv_value),
+				None => return Err(APIError::APIMisuseError { err: "No HTLC is being held with the given intercept_id" }),
+			};
+			if amt_to_forward_msat > incoming_amt_msat {
+				return Err(APIError::APIMisuseError { err: "Cannot forward more than the value of the HTLC we received" });
+			}
+
+			// The fee is up to the user, but decode_update_add_htlc_onion left the CLTV checks for
+			// intercepted HTLCs until we knew which channel they'd be forwarded over.
+			let failure = {
+				let chan = channel_state_lock.by_id.get(next_channel_id).unwrap();
+				let mut failure = Self::check_forward_cltv_delta(&self.get_forwarding_config(chan), incoming_cltv_expiry, outgoing_cltv_value);
+				if failure.is_some() {
+					if let Some(prev_config) = chan.get_prev_forwarding_config() {
+						if Self::check_forward_cltv_delta(&prev_config, incoming_cltv_expiry, outgoing_cltv_value).is_none() {
+							failure = None;
+						}
+					}
+				}
+				let cur_height = self.latest_block_height.load(Ordering::Acquire) as u32;
+				if failure.is_none() && outgoing_cltv_value <= cur_height + CLTV_CLAIM_BUFFER + LATENCY_GRACE_PERIOD_BLOCKS {
+					failure = Some(("Outgoing CLTV value is too soon for us to be able to claim the HTLC on-chain", 0x1000 | 14, Vec::new()));
+				}
+				failure.map(|(err, code, mut data)| {
+					data.extend_from_slice(&self.get_channel_update(chan).unwrap().encode_with_len()[..]);
+					(err, code, data)
+				})
+			};
+			let mut htlc = channel_state_lock.intercepted_htlcs.remove(intercept_id).unwrap();
+			if let Some((err, code, data)) = failure {
+				log_info!(self, "Failed to forward intercepted HTLC: {}", err);
+				self.fail_htlc_backwards_internal(channel_state_lock, htlc.prev_hop_source(), &htlc.forward_info.payment_hash, HTLCFailReason::Reason { failure_code: code, data });
+				return Err(APIError::ChannelUnavailable { err });
+			}
+
+			let channel_state = channel_state_lock.borrow_parts();
+			htlc.forward_info.short_channel_id = short_channel_id;
+			htlc.forward_info.amt_to_forward = amt_to_forward_msat;
+			htlc.released = true;
+
+			if channel_state.forward_htlcs.is_empty() {
+				forward_event = Some(Instant::now() + Duration::from_millis(((rng::rand_f32() * 4.0 + 1.0) * MIN_HTLC_RELAY_HOLDING_CELL_MILLIS as f32) as u64));
+				*channel_state.next_forward = forward_event.unwrap();
+			}
+			match channel_state.forward_htlcs.entry(short_channel_id) {
+				hash_map::Entry::Occupied(mut entry) => entry.get_mut().push(htlc),
+				hash_map::Entry::Vacant(entry) => { entry.insert(vec![htlc]); },
+			}
+		}
+		if let Some(time) = forward_event {
+			self.pending_events.lock().unwrap().push(events::Event::PendingHTLCsForwardable {
+				time_forwardable: time
+			});
+		}
+		Ok(())
+	}
+
+	/// Fails an HTLC we're holding after an HTLCIntercepted event back to the previous hop.
+	/// Returns false if no HTLC is being held with the given intercept_id (any more).
+	///
+	/// The HTLC is failed with a temporary error, so that the sender doesn't stop routing through
+	/// us: temporary_channel_failure if it was sent over one of our channels, or
+	/// temporary_node_failure if it was sent over a short_channel_id we have no channel for.
+	pub fn fail_intercepted_htlc(&self, intercept_id: &[u8; 32]) -> bool {
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		let (htlc, reason) = {
+			let channel_state = channel_state_lock.borrow_parts();
+			let htlc = match channel_state.intercepted_htlcs.remove(intercept_id) {
+				Some(htlc) => htlc,
+				None => return false,
+			};
+			let chan_update = channel_state.short_to_id.get(&htlc.forward_info.short_channel_id)
+				.and_then(|chan_id| channel_state.by_id.get(chan_id))
+				.and_then(|chan| self.get_channel_update(chan).ok());
+			let reason = match chan_update {
+				Some(chan_update) => HTLCFailReason::Reason { failure_code: 0x1000 | 7, data: chan_update.encode_with_len() },
+				None => HTLCFailReason::Reason { failure_code: 0x2000 | 2, data: Vec::new() },
+			};
+			(htlc, reason)
+		};
+		self.fail_htlc_backwards_internal(channel_state_lock, htlc.prev_hop_source(), &htlc.forward_info.payment_hash, reason);
+		true
+	}
+
+	/// Indicates that the preimage for payment_hash is unknown after a PaymentReceived event.
+	pub fn fail_htlc_backwards(&self, payment_hash: &[u8; 32]) -> bool {
+		let mut channel_state = Some(self.channel_state.lock().unwrap());
+		let removed_source = channel_state.as_mut().unwrap().claimable_htlcs.remove(payment_hash);
+		if let Some(mut sources) = removed_source {
+			for htlc_with_hash in sources.drain(..) {
+				if channel_state.is_none() { channel_state = Some(self.channel_state.lock().unwrap()); }
+				self.fail_htlc_backwards_internal(channel_state.take().unwrap(), HTLCSource::PreviousHopData(htlc_with_hash.prev_hop), payment_hash, HTLCFailReason::Reason { failure_code: 0x4000 | 15, data: Vec::new() });
+			}
+			true
+		} else { false }
+	}
+
+	/// Fails an HTLC backwards to the sender of it to us.
+	/// Note that while we take a channel_state lock as input, we do *not* assume consistency here.
+	/// There are several callsites that do stupid things like loop over a list of payment_hashes
+	/// to fail and take the channel_state lock for each iteration (as we take ownership and may
+	/// drop it). In other words, no assumptions are made that entries in claimable_htlcs point to
+	/// still-available channels.
+	fn fail_htlc_backwards_internal(&self, mut channel_state: MutexGuard<ChannelHolder>, source: HTLCSource, payment_hash: &[u8; 32], onion_error: HTLCFailReason) {
+		match source {
+			HTLCSource::OutboundRoute { ref route, ref session_priv } => {
+				mem::drop(channel_state);
+
+				let failure = match onion_error {
+					HTLCFailReason::ErrorPacket { err } => self.process_onion_failure(route, session_priv, err.data),
+					HTLCFailReason::Reason { failure_code, data } => {
+						// We failed the HTLC ourselves (or our peer told us it couldn't parse the
+						// onion), so there's no other node to blame.
+						DecodedOnionFailure {
+							network_update: None,
+							rejected_by_dest: false,
+							failing_node: None,
+							failing_short_channel_id: None,
+							error_code: Some(failure_code),
+							channel_update: if failure_code & 0x1000 == 0x1000 { ChannelManager::read_failure_channel_update(failure_code, &data[..]) } else { None },
+						}
+					},
+				};
+
+				if self.retry_payment(payment_hash, route, &failure) {
+					return;
+				}
+				if !self.resolve_tracked_payment(payment_hash, PaymentResolution::Failed) {
+					return;
+				}
+
+				let mut pending_events = self.pending_events.lock().unwrap();
+				pending_events.push(events::Event::PaymentFailed {
+					payment_hash: payment_hash.clone(),
+					rejected_by_dest: failure.rejected_by_dest,
+					failing_node: failure.failing_node,
+					failing_short_channel_id: failure.failing_short_channel_id,
+					error_code: failure.error_code,
+					channel_update: failure.channel_update,
+				});
+			},
 			HTLCSource::PreviousHopData(HTLCPreviousHopData { short_channel_id, htlc_id, incoming_packet_shared_secret }) => {
 				let err_packet = match onion_error {
 					HTLCFailReason::Reason { failure_code, data } => {
@@ -1406,15 +2536,25 @@ impl ChannelManager {
 		if let Some(mut sources) = removed_source {
 			for htlc_with_hash in sources.drain(..) {
 				if channel_state.is_none() { channel_state = Some(self.channel_state.lock().unwrap()); }
-				self.claim_funds_internal(channel_state.take().unwrap(), HTLCSource::PreviousHopData(htlc_with_hash), payment_preimage);
+				self.claim_funds_internal(channel_state.take().unwrap(), HTLCSource::PreviousHopData(htlc_with_hash.prev_hop), payment_preimage, None);
 			}
 			true
 		} else { false }
 	}
-	fn claim_funds_internal(&self, mut channel_state: MutexGuard<ChannelHolder>, source: HTLCSource, payment_preimage: [u8; 32]) {
+	/// If the HTLC being claimed was forwarded by us, next_hop_data should contain the channel_id and
+	/// amount (in msat) of the outbound HTLC which was fulfilled.
+	fn claim_funds_internal(&self, mut channel_state: MutexGuard<ChannelHolder>, source: HTLCSource, payment_preimage: [u8; 32], next_hop_data: Option<([u8; 32], u64)>) {
 		match source {
 			HTLCSource::OutboundRoute { .. } => {
 				mem::drop(channel_state);
+				let mut sha = Sha256::new();
+				sha.input(&payment_preimage);
+				let mut payment_hash = [0; 32];
+				sha.result(&mut payment_hash);
+				if !self.resolve_tracked_payment(&payment_hash, PaymentResolution::Succeeded) {
+					return;
+				}
+
 				let mut pending_events = self.pending_events.lock().unwrap();
 				pending_events.push(events::Event::PaymentSent {
 					payment_preimage
@@ -1435,7 +2575,14 @@ impl ChannelManager {
 
 					let chan = channel_state.by_id.get_mut(&chan_id).unwrap();
 					match chan.get_update_fulfill_htlc_and_commit(htlc_id, payment_preimage) {
-						Ok(msg) => (chan.get_their_node_id(), msg),
+						Ok(msg) => {
+							// PaymentForwarded is generated in internal_revoke_and_ack once our
+							// peer has irrevocably committed to the HTLC's removal.
+							if let Some((next_channel_id, outgoing_amt_msat)) = next_hop_data {
+								chan.track_forwarded_htlc_claim(htlc_id, next_channel_id, outgoing_amt_msat);
+							}
+							(chan.get_their_node_id(), msg)
+						},
 						Err(_e) => {
 							// TODO: There is probably a channel manager somewhere that needs to
 							// learn the preimage as the channel may be about to hit the chain.
@@ -1452,8 +2599,8 @@ impl ChannelManager {
 					}
 				}
 
+				let mut pending_events = self.pending_events.lock().unwrap();
 				if let Some((msg, commitment_msg)) = fulfill_msgs.0 {
-					let mut pending_events = self.pending_events.lock().unwrap();
 					pending_events.push(events::Event::UpdateHTLCs {
 						node_id: node_id,
 						updates: msgs::CommitmentUpdate {
@@ -1475,11 +2622,422 @@ impl ChannelManager {
 		PublicKey::from_secret_key(&self.secp_ctx, &self.our_network_key)
 	}
 
+	/// Signs the given invoice with our node_id's key, so that payers can check it came from us.
+	///
+	/// Fails if the invoice cannot be encoded, see RawInvoice::sign.
+	pub fn sign_invoice(&self, invoice: RawInvoice) -> Result<Invoice, InvoiceError> {
+		invoice.sign(&self.secp_ctx, &self.our_network_key)
+	}
+
+	/// Creates a payment we expect to receive, returning its payment_hash and payment_secret, both
+	/// of which should be included in the invoice given to the payer.
+	///
+	/// Nothing about the payment is stored: min_value_msat and the expiry are encrypted into the
+	/// payment_secret, and the payment preimage is derived from it and a key only we know. The
+	/// preimage is provided in the PaymentReceived event (and may be re-derived with
+	/// get_payment_preimage) and can be passed straight to claim_funds.
+	///
+	/// HTLCs carrying the returned payment_secret are rejected before a PaymentReceived event is
+	/// generated unless they are for at least min_value_msat (if set) and arrive within
+	/// invoice_expiry_delta_secs. Note that, as nothing is stored, HTLCs may be paid to it more than
+	/// once until it expires.
+	pub fn create_inbound_payment(&self, min_value_msat: Option<u64>, invoice_expiry_delta_secs: u32) -> ([u8; 32], [u8; 32]) {
+		let mut nonce = [0; 16];
+		rng::fill_bytes(&mut nonce);
+		let metadata = ChannelManager::inbound_payment_metadata(INBOUND_PAYMENT_DERIVED_PREIMAGE, min_value_msat, invoice_expiry_delta_secs);
+
+		let mut sha = Sha256::new();
+		sha.input(&self.inbound_payment_hmac(b"payment preimage", &[&nonce, &metadata]));
+		let mut payment_hash = [0; 32];
+		sha.result(&mut payment_hash);
+		(payment_hash, self.encrypt_inbound_payment_metadata(&nonce, &metadata))
+	}
+
+	/// Creates a payment we expect to receive for a payment_hash whose preimage the caller keeps
+	/// track of, returning the payment_secret to include in the invoice. HTLCs paying to it are
+	/// checked as for create_inbound_payment.
+	pub fn create_inbound_payment_for_hash(&self, payment_hash: [u8; 32], min_value_msat: Option<u64>, invoice_expiry_delta_secs: u32) -> [u8; 32] {
+		let metadata = ChannelManager::inbound_payment_metadata(INBOUND_PAYMENT_USER_HASH, min_value_msat, invoice_expiry_delta_secs);
+		// Tie the payment_secret to the payment_hash, as we can't derive one from the other
+		let mut nonce = [0; 16];
+		nonce.copy_from_slice(&self.inbound_payment_hmac(b"payment hash nonce", &[&metadata, &payment_hash])[..16]);
+		self.encrypt_inbound_payment_metadata(&nonce, &metadata)
+	}
+
+	/// Gets the payment preimage for a payment created with create_inbound_payment from its
+	/// payment_hash and payment_secret, or None if the pair didn't come from create_inbound_payment.
+	pub fn get_payment_preimage(&self, payment_hash: &[u8; 32], payment_secret: &[u8; 32]) -> Option<[u8; 32]> {
+		self.verify_payment_secret(payment_hash, payment_secret).and_then(|payment| payment.payment_preimage)
+	}
+
+	/// The metadata encrypted into an inbound payment's payment_secret: the method byte, the expiry
+	/// in seconds since the UNIX epoch as 7 bytes, and min_value_msat (or 0 if unset) as 8 bytes.
+	fn inbound_payment_metadata(method: u8, min_value_msat: Option<u64>, invoice_expiry_delta_secs: u32) -> [u8; 16] {
+		let mut metadata = [0; 16];
+		let expiry = ChannelManager::unix_time_secs() + invoice_expiry_delta_secs as u64;
+		metadata[0..8].copy_from_slice(&byte_utils::be64_to_array(expiry));
+		metadata[0] = method;
+		metadata[8..16].copy_from_slice(&byte_utils::be64_to_array(min_value_msat.unwrap_or(0)));
+		metadata
+	}
+
+	/// Builds a payment_secret from a nonce and the metadata, encrypted with a key derived from the
+	/// nonce.
+	fn encrypt_inbound_payment_metadata(&self, nonce: &[u8; 16], metadata: &[u8; 16]) -> [u8; 32] {
+		let mut payment_secret = [0; 32];
+		payment_secret[0..16].copy_from_slice(nonce);
+		payment_secret[16..32].copy_from_slice(metadata);
+		ChannelManager::xor_bufs(&mut payment_secret[16..32], &self.inbound_payment_hmac(b"metadata key", &[nonce])[..16]);
+		payment_secret
+	}
+
+	/// Decrypts the metadata in a payment_secret, returning the inbound payment it describes if it
+	/// was created by create_inbound_payment(_for_hash) for the given payment_hash.
+	fn verify_payment_secret(&self, payment_hash: &[u8; 32], payment_secret: &[u8; 32]) -> Option<InboundPayment> {
+		let nonce = &payment_secret[0..16];
+		let mut metadata = [0; 16];
+		metadata.copy_from_slice(&payment_secret[16..32]);
+		ChannelManager::xor_bufs(&mut metadata, &self.inbound_payment_hmac(b"metadata key", &[nonce])[..16]);
+
+		let payment_preimage = match metadata[0] {
+			INBOUND_PAYMENT_DERIVED_PREIMAGE => {
+				let payment_preimage = self.inbound_payment_hmac(b"payment preimage", &[nonce, &metadata]);
+				let mut sha = Sha256::new();
+				sha.input(&payment_preimage);
+				let mut res = [0; 32];
+				sha.result(&mut res);
+				if !crypto::util::fixed_time_eq(&res, payment_hash) {
+					return None;
+				}
+				Some(payment_preimage)
+			},
+			INBOUND_PAYMENT_USER_HASH => {
+				let expected_nonce = self.inbound_payment_hmac(b"payment hash nonce", &[&metadata, payment_hash]);
+				if !crypto::util::fixed_time_eq(&expected_nonce[..16], nonce) {
+					return None;
+				}
+				None
+			},
+			_ => return None,
+		};
+
+		let mut expiry = [0; 8];
+		expiry[1..8].copy_from_slice(&metadata[1..8]);
+		Some(InboundPayment {
+			min_value_msat: match byte_utils::slice_to_be64(&metadata[8..16]) {
+				0 => None,
+				min_value_msat => Some(min_value_msat),
+			},
+			expiry: byte_utils::slice_to_be64(&expiry),
+			payment_preimage,
+		})
+	}
+
+	fn inbound_payment_hmac(&self, label: &[u8], data: &[&[u8]]) -> [u8; 32] {
+		let mut hmac = Hmac::new(Sha256::new(), &self.inbound_payment_key);
+		hmac.input(label);
+		for d in data.iter() {
+			hmac.input(d);
+		}
+		let mut res = [0; 32];
+		hmac.raw_result(&mut res);
+		res
+	}
+
+	fn unix_time_secs() -> u64 {
+		match SystemTime::now().duration_since(UNIX_EPOCH) {
+			Ok(time) => time.as_secs(),
+			Err(_) => 0,
+		}
+	}
+
+	/// Writes out the hashes of all the unexpired onions we've seen, which we use to reject onion
+	/// replays, replacing anything previously persisted. Onions we've seen since should then be
+	/// appended with write_new_seen_onions, which writes far less. Calling this again, eg once per
+	/// block, drops onions which have since expired from the persisted set.
+	///
+	/// All of these must be loaded back with read_seen_onions after a restart, before connecting
+	/// to any peers. Otherwise, onions we processed before the restart may be replayed to us.
+	pub fn write_seen_onions<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		let mut seen_onions = self.seen_onions.lock().unwrap();
+		seen_onions.write_batch(seen_onions.onions.keys(), writer)?;
+		seen_onions.unwritten.clear();
+		Ok(())
+	}
+
+	/// Writes out the hashes of the onions we've seen since write_seen_onions or
+	/// write_new_seen_onions were last called, to be appended to what they wrote before. An onion
+	/// is only committed to once the ChannelMonitor update for its HTLC is, so this should be
+	/// called (and its output persisted) each time a ChannelMonitor update is stored.
+	pub fn write_new_seen_onions<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		let mut seen_onions = self.seen_onions.lock().unwrap();
+		seen_onions.write_batch(seen_onions.unwritten.iter(), writer)?;
+		seen_onions.unwritten.clear();
+		Ok(())
+	}
+
+	/// Replaces the set of onions we've recently seen with those written by write_seen_onions,
+	/// followed by any number of calls to write_new_seen_onions. Onions whose HTLCs have expired
+	/// are dropped the next time a block is connected.
+	pub fn read_seen_onions<R: Read>(&self, reader: &mut R) -> Result<(), msgs::DecodeError> {
+		let mut seen_onions = self.seen_onions.lock().unwrap();
+		let mut read_onions = SeenOnions::new(seen_onions.max_onions_per_channel);
+		loop {
+			let count: u64 = match Readable::read(reader) {
+				Ok(count) => count,
+				Err(msgs::DecodeError::ShortRead) => break,
+				Err(e) => return Err(e),
+			};
+			for _ in 0..count {
+				let onion_hash: [u8; 32] = Readable::read(reader)?;
+				let channel_id: [u8; 32] = Readable::read(reader)?;
+				let htlc_id: u64 = Readable::read(reader)?;
+				let cltv_expiry: u32 = Readable::read(reader)?;
+				read_onions.insert(onion_hash, channel_id, htlc_id, cltv_expiry);
+			}
+		}
+		// Everything we just read is already persisted
+		read_onions.unwritten.clear();
+		*seen_onions = read_onions;
+		Ok(())
+	}
+
+	/// Sets the color, alias and addresses (which may include onion addresses, see
+	/// net::onion::parse_onion_address) we announce for our node in node_announcement messages.
+	///
+	/// Note that you may only announce up to one address of each type - any additional addresses
+	/// of the same type will be dropped when the message is serialized.
+	///
+	/// A node_announcement will be broadcast (via a BroadcastNodeAnnouncement event) immediately if
+	/// we have a public channel, or as soon as we get one, as peers ignore node_announcements for
+	/// nodes without public channels. It will then be re-broadcast whenever this is called again
+	/// and periodically in timer_tick_occured.
+	pub fn set_node_announcement_info(&self, rgb: [u8; 3], alias: [u8; 32], addresses: Vec<msgs::NetAddress>) {
+		{
+			let mut state = self.node_announcement.lock().unwrap();
+			state.details = Some((rgb, alias, addresses));
+			state.changed = true;
+		}
+		let have_public_channel = self.have_public_channel();
+		if let Some(msg) = self.get_node_announcement_for_broadcast(have_public_channel, false) {
+			self.pending_events.lock().unwrap().push(events::Event::BroadcastNodeAnnouncement { msg });
+		}
+	}
+
+	/// Generates a signed node_announcement for our node, using the details set via
+	/// set_node_announcement_info. Returns None if they have not been set.
+	///
+	/// You don't need to call this if you're handling BroadcastNodeAnnouncement events (eg via
+	/// PeerManager), but it may be useful to send our node_announcement elsewhere.
+	pub fn get_node_announcement(&self) -> Option<msgs::NodeAnnouncement> {
+		let mut state = self.node_announcement.lock().unwrap();
+		self.sign_node_announcement(&mut state)
+	}
+
+	fn sign_node_announcement(&self, state: &mut NodeAnnouncementState) -> Option<msgs::NodeAnnouncement> {
+		let (rgb, alias, addresses) = match state.details {
+			Some((rgb, alias, ref addresses)) => (rgb, alias, addresses.clone()),
+			None => return None,
+		};
+		let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+			Ok(time) => time.as_secs() as u32,
+			Err(_) => 0,
+		};
+		state.last_timestamp = cmp::max(now, state.last_timestamp + 1);
+
+		let unsigned = msgs::UnsignedNodeAnnouncement {
+			features: msgs::GlobalFeatures::new(),
+			timestamp: state.last_timestamp,
+			node_id: self.get_our_node_id(),
+			rgb,
+			alias,
+			addresses,
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		};
+
+		let msg_hash = Sha256dHash::from_data(&unsigned.encode()[..]);
+		let sig = self.secp_ctx.sign(&Message::from_slice(&msg_hash[..]).unwrap(), &self.our_network_key);
+
+		Some(msgs::NodeAnnouncement {
+			signature: sig,
+			contents: unsigned
+		})
+	}
+
+	/// Returns true if we have a channel which we have (or are about to have) announced, and thus
+	/// peers will accept our node_announcement.
+	fn have_public_channel(&self) -> bool {
+		let channel_state = self.channel_state.lock().unwrap();
+		channel_state.by_id.values().any(|chan| chan.should_announce() && chan.is_usable())
+	}
+
+	/// Generates a node_announcement to broadcast if we have a public channel and either force is
+	/// set, our details changed, or we haven't broadcast one in NODE_ANNOUNCEMENT_REBROADCAST_SECS.
+	fn get_node_announcement_for_broadcast(&self, have_public_channel: bool, force: bool) -> Option<msgs::NodeAnnouncement> {
+		if !have_public_channel {
+			return None;
+		}
+		let mut state = self.node_announcement.lock().unwrap();
+		let due = force || state.changed || match state.last_broadcast {
+			Some(last_broadcast) => last_broadcast.elapsed() >= Duration::from_secs(NODE_ANNOUNCEMENT_REBROADCAST_SECS),
+			None => true,
+		};
+		if !due {
+			return None;
+		}
+		let res = self.sign_node_announcement(&mut state);
+		if res.is_some() {
+			state.changed = false;
+			state.last_broadcast = Some(Instant::now());
+		}
+		res
+	}
+
+	/// Sets how long a channel's peer must be disconnected for before we broadcast a
+	/// channel_update announcing the channel as disabled, and how long it must then be reconnected
+	/// for before we announce it as enabled again. Defaults to two minutes. As this is checked in
+	/// timer_tick_occured, it is only as precise as the interval that is called at.
+	pub fn set_channel_disable_timeout(&self, timeout: Duration) {
+		*self.channel_disable_timeout.lock().unwrap() = timeout;
+	}
+
+	/// Re-broadcasts our node_announcement if it is due (see set_node_announcement_info) and our
+	/// public channels' channel_announcements once a day, sends update_fee on any channels we
+	/// funded whose feerate has drifted too far from our fee estimate, announces channels as
+	/// disabled or enabled once their peers have been disconnected or reconnected for a while (see
+	/// set_channel_disable_timeout) and forgets about payments which completed a while ago or, for
+	/// inbound payments, expired. Should be called roughly once a minute.
+	pub fn timer_tick_occured(&self) {
+		self.update_channel_feerates();
+
+		{
+			let disable_timeout = *self.channel_disable_timeout.lock().unwrap();
+			let rebroadcast_announcements = {
+				let mut last_broadcast = self.last_channel_announcement_broadcast.lock().unwrap();
+				let due = match *last_broadcast {
+					Some(last) => last.elapsed() >= Duration::from_secs(CHANNEL_ANNOUNCEMENT_REBROADCAST_SECS),
+					None => true,
+				};
+				if due { *last_broadcast = Some(Instant::now()); }
+				due
+			};
+			let mut channel_state = self.channel_state.lock().unwrap();
+			let mut pending_events = self.pending_events.lock().unwrap();
+			for (channel_id, chan) in channel_state.by_id.iter_mut() {
+				if !chan.is_usable() { continue; }
+				let status_changed = chan.update_announced_status(disable_timeout);
+				if status_changed {
+					log_debug!(self, "Announcing channel {} as {}", log_bytes!(channel_id[..]), if chan.is_announced_enabled() { "enabled" } else { "disabled" });
+				}
+				if rebroadcast_announcements {
+					if let Some(announcement) = self.get_signed_channel_announcement(chan) {
+						log_trace!(self, "Re-broadcasting channel_announcement for channel {}", log_bytes!(channel_id[..]));
+						pending_events.push(events::Event::BroadcastChannelAnnouncement {
+							msg: announcement,
+							update_msg: self.get_channel_update(chan).unwrap(), // can only fail if !is_usable()
+						});
+						continue;
+					}
+				}
+				if status_changed {
+					if let Ok(update) = self.get_channel_update(chan) {
+						pending_events.push(events::Event::BroadcastChannelUpdate {
+							msg: update
+						});
+					}
+				}
+			}
+		}
+
+		self.tracked_payments.lock().unwrap().retain(|_, payment| {
+			match payment.resolved {
+				Some((_, resolved_at)) => resolved_at.elapsed() < Duration::from_secs(RESOLVED_PAYMENT_RETENTION_SECS),
+				None => true,
+			}
+		});
+
+		let have_public_channel = self.have_public_channel();
+		if let Some(msg) = self.get_node_announcement_for_broadcast(have_public_channel, false) {
+			log_trace!(self, "Re-broadcasting our node_announcement");
+			self.pending_events.lock().unwrap().push(events::Event::BroadcastNodeAnnouncement { msg });
+		}
+	}
+
 	/// Used to restore channels to normal operation after a
 	/// ChannelMonitorUpdateErr::TemporaryFailure was returned from a channel monitor update
 	/// operation.
+	///
+	/// Retries storing the latest ChannelMonitor of each paused channel, sending the messages we
+	/// held back for any which now succeed. Channels which still return a TemporaryFailure stay
+	/// paused until the next call, while a PermanentFailure closes the channel.
 	pub fn test_restore_channel_monitor(&self) {
-		unimplemented!();
+		let mut failed_channels = Vec::new();
+		let mut pending_forwards = Vec::new();
+		let mut pending_failures = Vec::new();
+		{
+			let mut channel_state = self.channel_state.lock().unwrap();
+			let mut pending_events = self.pending_events.lock().unwrap();
+			for (channel_id, chan) in channel_state.by_id.iter_mut() {
+				if !chan.is_awaiting_monitor_update() {
+					continue;
+				}
+				match self.monitor.add_update_monitor(chan.get_funding_txo().unwrap(), chan.channel_monitor()) {
+					Ok(()) => {},
+					Err(ChannelMonitorUpdateErr::TemporaryFailure) => continue,
+					Err(ChannelMonitorUpdateErr::PermanentFailure) => {
+						failed_channels.push(channel_id.clone());
+						continue;
+					},
+				}
+				log_debug!(self, "Restored ChannelMonitor updating on channel {}", log_bytes!(channel_id[..]));
+
+				let (raa, mut commitment_update, order, chan_monitor, forwards, mut failures) = match chan.monitor_updating_restored() {
+					Ok(res) => res,
+					Err(e) => {
+						log_error!(self, "Failed to free holding cell after restoring ChannelMonitor updating on channel {}: {}", log_bytes!(channel_id[..]), e.err);
+						failed_channels.push(channel_id.clone());
+						continue;
+					},
+				};
+				if let Some(monitor) = chan_monitor {
+					// Freeing the holding cell generated a new commitment, which needs another update
+					match self.monitor.add_update_monitor(monitor.get_funding_txo().unwrap(), monitor) {
+						Ok(()) => {},
+						Err(ChannelMonitorUpdateErr::TemporaryFailure) => {
+							chan.monitor_update_failed(false, true, Vec::new(), Vec::new());
+							commitment_update = None;
+						},
+						Err(ChannelMonitorUpdateErr::PermanentFailure) => {
+							failed_channels.push(channel_id.clone());
+							continue;
+						},
+					}
+				}
+
+				let node_id = chan.get_their_node_id();
+				if order == RAACommitmentOrder::CommitmentFirst {
+					if let Some(updates) = commitment_update.take() {
+						pending_events.push(events::Event::UpdateHTLCs { node_id, updates });
+					}
+				}
+				if let Some(msg) = raa {
+					pending_events.push(events::Event::SendRevokeAndACK { node_id, msg });
+				}
+				if let Some(updates) = commitment_update {
+					pending_events.push(events::Event::UpdateHTLCs { node_id, updates });
+				}
+				pending_forwards.push((chan.get_short_channel_id().expect("Channels only pause once they have a short id"), forwards));
+				pending_failures.append(&mut failures);
+			}
+		}
+		for failure in pending_failures.drain(..) {
+			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), failure.0, &failure.1, failure.2);
+		}
+		self.forward_htlcs(&mut pending_forwards);
+		for channel_id in failed_channels.drain(..) {
+			self.force_close_channel_with_reason(&channel_id, events::ClosureReason::MonitorUpdateFailed);
+		}
 	}
 
 	fn internal_open_channel(&self, their_node_id: &PublicKey, msg: &msgs::OpenChannel) -> Result<msgs::AcceptChannel, MsgHandleErrInternal> {
@@ -1491,6 +3049,7 @@ impl ChannelManager {
 			return Err(MsgHandleErrInternal::send_err_msg_no_close("temporary_channel_id collision!", msg.temporary_channel_id.clone()));
 		}
 
+		#[allow(deprecated)]
 		let chan_keys = if cfg!(feature = "fuzztarget") {
 			ChannelKeys {
 				funding_key:               SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0]).unwrap(),
@@ -1511,9 +3070,9 @@ impl ChannelManager {
 			}
 		};
 
-		let channel = Channel::new_from_req(&*self.fee_estimator, chan_keys, their_node_id.clone(), msg, 0, false, self.announce_channels_publicly, Arc::clone(&self.logger))
+		let channel = Channel::new_from_req(&*self.fee_estimator, chan_keys, their_node_id.clone(), msg, 0, false, self.announce_channels_publicly, self.wallet.get_shutdown_scriptpubkey(), Arc::clone(&self.logger))
 			.map_err(|e| MsgHandleErrInternal::from_chan_no_close(e, msg.temporary_channel_id))?;
-		let accept_msg = channel.get_accept_channel();
+		let accept_msg = channel.get_accept_channel(&self.get_peer_features(their_node_id));
 		channel_state.by_id.insert(channel.channel_id(), channel);
 		Ok(accept_msg)
 	}
@@ -1572,15 +3131,24 @@ impl ChannelManager {
 		if let Err(_e) = self.monitor.add_update_monitor(monitor_update.get_funding_txo().unwrap(), monitor_update) {
 			unimplemented!();
 		}
-		let mut channel_state = self.channel_state.lock().unwrap();
-		match channel_state.by_id.entry(funding_msg.channel_id) {
-			hash_map::Entry::Occupied(_) => {
-				return Err(MsgHandleErrInternal::send_err_msg_no_close("Already had channel with the new channel_id", funding_msg.channel_id))
-			},
-			hash_map::Entry::Vacant(e) => {
-				e.insert(chan);
+		let pending_event = events::Event::ChannelPending {
+			channel_id: funding_msg.channel_id,
+			user_channel_id: chan.get_user_id(),
+			counterparty_node_id: chan.get_their_node_id(),
+			funding_txo: chan.get_funding_txo().unwrap(),
+		};
+		{
+			let mut channel_state = self.channel_state.lock().unwrap();
+			match channel_state.by_id.entry(funding_msg.channel_id) {
+				hash_map::Entry::Occupied(_) => {
+					return Err(MsgHandleErrInternal::send_err_msg_no_close("Already had channel with the new channel_id", funding_msg.channel_id))
+				},
+				hash_map::Entry::Vacant(e) => {
+					e.insert(chan);
+				}
 			}
 		}
+		self.pending_events.lock().unwrap().push(pending_event);
 		Ok(funding_msg)
 	}
 
@@ -1607,6 +3175,12 @@ impl ChannelManager {
 			funding_txo: funding_txo,
 			user_channel_id: user_id,
 		});
+		pending_events.push(events::Event::ChannelPending {
+			channel_id: msg.channel_id,
+			user_channel_id: user_id,
+			counterparty_node_id: their_node_id.clone(),
+			funding_txo: funding_txo,
+		});
 		Ok(())
 	}
 
@@ -1618,8 +3192,20 @@ impl ChannelManager {
 					//TODO: here and below MsgHandleErrInternal, #153 case
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 				}
+				let was_usable = chan.is_usable();
 				chan.funding_locked(&msg)
 					.map_err(|e| MsgHandleErrInternal::from_chan_maybe_close(e, msg.channel_id))?;
+				if !was_usable && chan.is_usable() {
+					self.pending_events.lock().unwrap().push(events::Event::ChannelReady {
+						channel_id: msg.channel_id,
+						user_channel_id: chan.get_user_id(),
+						counterparty_node_id: their_node_id.clone(),
+					});
+				}
+				if chan.get_their_announcement_sigs().is_some() {
+					// Both announcement_signatures were already exchanged, eg before a reconnect
+					return Ok(None);
+				}
 				return Ok(self.get_announcement_sigs(chan));
 			},
 			None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
@@ -1653,12 +3239,13 @@ impl ChannelManager {
 			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), htlc_source.0, &htlc_source.1, HTLCFailReason::Reason { failure_code: 0x4000 | 10, data: Vec::new() });
 		}
 		if let Some(chan) = chan_option {
+			let mut events = self.pending_events.lock().unwrap();
 			if let Ok(update) = self.get_channel_update(&chan) {
-				let mut events = self.pending_events.lock().unwrap();
 				events.push(events::Event::BroadcastChannelUpdate {
 					msg: update
 				});
 			}
+			events.push(Self::get_channel_closed_event(&chan, events::ClosureReason::CooperativeClosure, None, None));
 		}
 		Ok((res.0, res.1))
 	}
@@ -1689,31 +3276,35 @@ impl ChannelManager {
 				hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 			}
 		};
-		if let Some(broadcast_tx) = res.1 {
-			self.tx_broadcaster.broadcast_transaction(&broadcast_tx);
+		if let Some(ref broadcast_tx) = res.1 {
+			self.tx_broadcaster.broadcast_transaction(broadcast_tx);
 		}
 		if let Some(chan) = chan_option {
+			let mut events = self.pending_events.lock().unwrap();
 			if let Ok(update) = self.get_channel_update(&chan) {
-				let mut events = self.pending_events.lock().unwrap();
 				events.push(events::Event::BroadcastChannelUpdate {
 					msg: update
 				});
 			}
+			if let Some(closing_tx) = res.1 {
+				let fee_satoshis = chan.get_value_satoshis() - closing_tx.output.iter().map(|output| output.value).sum::<u64>();
+				events.push(Self::get_channel_closed_event(&chan, events::ClosureReason::CooperativeClosure, Some(closing_tx), Some(fee_satoshis)));
+			}
 		}
 		Ok(res.0)
 	}
 
 	fn internal_update_add_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateAddHTLC) -> Result<(), MsgHandleErrInternal> {
-		//TODO: BOLT 4 points out a specific attack where a peer may re-send an onion packet and
-		//determine the state of the payment based on our response/if we forward anything/the time
-		//we take to respond. We should take care to avoid allowing such an attack.
+		// Note that BOLT 4 points out a specific attack where a peer may re-send an onion packet and
+		// determine the state of the payment based on our response/if we forward anything/the time
+		// we take to respond. decode_update_add_htlc_onion rejects such replays using seen_onions.
 		//
 		//TODO: There exists a further attack where a node may garble the onion data, forward it to
 		//us repeatedly garbled in different ways, and compare our error messages, which are
 		//encrypted with the same key. Its not immediately obvious how to usefully exploit that,
 		//but we should prevent it anyway.
 
-		let (pending_forward_info, mut channel_state_lock) = self.decode_update_add_htlc_onion(msg);
+		let (pending_forward_info, onion_hash, mut channel_state_lock) = self.decode_update_add_htlc_onion(msg);
 		let channel_state = channel_state_lock.borrow_parts();
 
 		match channel_state.by_id.get_mut(&msg.channel_id) {
@@ -1725,26 +3316,32 @@ impl ChannelManager {
 				if !chan.is_usable() {
 					return Err(MsgHandleErrInternal::from_no_close(HandleError{err: "Channel not yet available for receiving HTLCs", action: Some(msgs::ErrorAction::IgnoreError)}));
 				}
-				chan.update_add_htlc(&msg, pending_forward_info).map_err(|e| MsgHandleErrInternal::from_maybe_close(e))
+				chan.update_add_htlc(&msg, pending_forward_info).map_err(|e| MsgHandleErrInternal::from_maybe_close(e))?;
 			},
 			None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 		}
+		// Only record the onion once the channel has accepted the HTLC, so that messages we reject
+		// don't take up space in seen_onions.
+		if let Some(onion_hash) = onion_hash {
+			self.seen_onions.lock().unwrap().insert(onion_hash, msg.channel_id, msg.htlc_id, msg.cltv_expiry);
+		}
+		Ok(())
 	}
 
 	fn internal_update_fulfill_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFulfillHTLC) -> Result<(), MsgHandleErrInternal> {
 		let mut channel_state = self.channel_state.lock().unwrap();
-		let htlc_source = match channel_state.by_id.get_mut(&msg.channel_id) {
+		let (htlc_source, outgoing_amt_msat) = match channel_state.by_id.get_mut(&msg.channel_id) {
 			Some(chan) => {
 				if chan.get_their_node_id() != *their_node_id {
 					//TODO: here and below MsgHandleErrInternal, #153 case
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 				}
 				chan.update_fulfill_htlc(&msg)
-					.map_err(|e| MsgHandleErrInternal::from_chan_maybe_close(e, msg.channel_id))?.clone()
+					.map_err(|e| MsgHandleErrInternal::from_chan_maybe_close(e, msg.channel_id))?
 			},
 			None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 		};
-		self.claim_funds_internal(channel_state, htlc_source, msg.payment_preimage.clone());
+		self.claim_funds_internal(channel_state, htlc_source, msg.payment_preimage.clone(), Some((msg.channel_id, outgoing_amt_msat)));
 		Ok(())
 	}
 
@@ -1765,57 +3362,7 @@ impl ChannelManager {
 		match htlc_source {
 			&HTLCSource::OutboundRoute { ref route, ref session_priv, .. } => {
 				// Handle packed channel/node updates for passing back for the route handler
-				let mut packet_decrypted = msg.reason.data.clone();
-				let mut res = None;
-				Self::construct_onion_keys_callback(&self.secp_ctx, &route, &session_priv, |shared_secret, _, _, route_hop| {
-					if res.is_some() { return; }
-
-					let ammag = ChannelManager::gen_ammag_from_shared_secret(&shared_secret);
-
-					let mut decryption_tmp = Vec::with_capacity(packet_decrypted.len());
-					decryption_tmp.resize(packet_decrypted.len(), 0);
-					let mut chacha = ChaCha20::new(&ammag, &[0u8; 8]);
-					chacha.process(&packet_decrypted, &mut decryption_tmp[..]);
-					packet_decrypted = decryption_tmp;
-
-					if let Ok(err_packet) = msgs::DecodedOnionErrorPacket::read(&mut Cursor::new(&packet_decrypted)) {
-						if err_packet.failuremsg.len() >= 2 {
-							let um = ChannelManager::gen_um_from_shared_secret(&shared_secret);
-
-							let mut hmac = Hmac::new(Sha256::new(), &um);
-							hmac.input(&err_packet.encode()[32..]);
-							let mut calc_tag = [0u8; 32];
-							hmac.raw_result(&mut calc_tag);
-							if crypto::util::fixed_time_eq(&calc_tag, &err_packet.hmac) {
-								const UNKNOWN_CHAN: u16 = 0x4000|10;
-								const TEMP_CHAN_FAILURE: u16 = 0x4000|7;
-								match byte_utils::slice_to_be16(&err_packet.failuremsg[0..2]) {
-									TEMP_CHAN_FAILURE => {
-										if err_packet.failuremsg.len() >= 4 {
-											let update_len = byte_utils::slice_to_be16(&err_packet.failuremsg[2..4]) as usize;
-											if err_packet.failuremsg.len() >= 4 + update_len {
-												if let Ok(chan_update) = msgs::ChannelUpdate::read(&mut Cursor::new(&err_packet.failuremsg[4..4 + update_len])) {
-													res = Some(msgs::HTLCFailChannelUpdate::ChannelUpdateMessage {
-														msg: chan_update,
-													});
-												}
-											}
-										}
-									},
-									UNKNOWN_CHAN => {
-										// No such next-hop. We know this came from the
-										// current node as the HMAC validated.
-										res = Some(msgs::HTLCFailChannelUpdate::ChannelClosed {
-											short_channel_id: route_hop.short_channel_id
-										});
-									},
-									_ => {}, //TODO: Enumerate all of these!
-								}
-							}
-						}
-					}
-				}).unwrap();
-				Ok(res)
+				Ok(self.process_onion_failure(route, session_priv, msg.reason.data.clone()).network_update)
 			},
 			_ => { Ok(None) },
 		}
@@ -1849,20 +3396,28 @@ impl ChannelManager {
 						//TODO: here and below MsgHandleErrInternal, #153 case
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 					}
-					chan.commitment_signed(&msg).map_err(|e| MsgHandleErrInternal::from_maybe_close(e))?
+					let was_paused = chan.is_awaiting_monitor_update();
+					let (revoke_and_ack, commitment_signed, chan_monitor) = chan.commitment_signed(&msg).map_err(|e| MsgHandleErrInternal::from_maybe_close(e))?;
+					if was_paused {
+						// The messages held back by an earlier ChannelMonitor update failure have to
+						// go out first, test_restore_channel_monitor will store this update too.
+						chan.monitor_update_failed(true, commitment_signed.is_some(), Vec::new(), Vec::new());
+						return Err(MsgHandleErrInternal::from_chan_no_close(ChannelError::Ignore("Previous monitor update failure prevented sending revoke_and_ack"), msg.channel_id));
+					}
+					(revoke_and_ack, commitment_signed, chan_monitor)
 				},
 				None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 			}
 		};
-		if let Err(_e) = self.monitor.add_update_monitor(chan_monitor.get_funding_txo().unwrap(), chan_monitor) {
-			unimplemented!();
+		if let Err(e) = self.monitor.add_update_monitor(chan_monitor.get_funding_txo().unwrap(), chan_monitor) {
+			handle_monitor_err!(self, e, msg.channel_id, true, commitment_signed.is_some());
 		}
 
 		Ok((revoke_and_ack, commitment_signed))
 	}
 
 	fn internal_revoke_and_ack(&self, their_node_id: &PublicKey, msg: &msgs::RevokeAndACK) -> Result<Option<msgs::CommitmentUpdate>, MsgHandleErrInternal> {
-		let ((res, mut pending_forwards, mut pending_failures, chan_monitor), short_channel_id) = {
+		let ((res, pending_forwards, mut pending_failures, chan_monitor), short_channel_id) = {
 			let mut channel_state = self.channel_state.lock().unwrap();
 			match channel_state.by_id.get_mut(&msg.channel_id) {
 				Some(chan) => {
@@ -1870,20 +3425,49 @@ impl ChannelManager {
 						//TODO: here and below MsgHandleErrInternal, #153 case
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 					}
-					(chan.revoke_and_ack(&msg).map_err(|e| MsgHandleErrInternal::from_maybe_close(e))?, chan.get_short_channel_id().expect("RAA should only work on a short-id-available channel"))
+					let was_paused = chan.is_awaiting_monitor_update();
+					let (res, pending_forwards, pending_failures, chan_monitor, forwarded_claims) = chan.revoke_and_ack(&msg).map_err(|e| MsgHandleErrInternal::from_maybe_close(e))?;
+					if !forwarded_claims.is_empty() {
+						// Our peer can no longer broadcast a commitment transaction which includes
+						// the HTLCs we claimed, so we've earned our fee.
+						let mut pending_events = self.pending_events.lock().unwrap();
+						for (next_channel_id, incoming_amt_msat, outgoing_amt_msat) in forwarded_claims {
+							pending_events.push(events::Event::PaymentForwarded {
+								prev_channel_id: msg.channel_id,
+								next_channel_id,
+								incoming_amt_msat,
+								outgoing_amt_msat,
+								fee_earned_msat: incoming_amt_msat - outgoing_amt_msat,
+							});
+						}
+					}
+					if was_paused {
+						// As in internal_commitment_signed, wait for test_restore_channel_monitor
+						chan.monitor_update_failed(false, res.is_some(), pending_forwards, pending_failures);
+						return Err(MsgHandleErrInternal::from_chan_no_close(ChannelError::Ignore("Previous monitor update failure prevented responding to revoke_and_ack"), msg.channel_id));
+					}
+					((res, pending_forwards, pending_failures, chan_monitor), chan.get_short_channel_id().expect("RAA should only work on a short-id-available channel"))
 				},
 				None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 			}
 		};
-		if let Err(_e) = self.monitor.add_update_monitor(chan_monitor.get_funding_txo().unwrap(), chan_monitor) {
-			unimplemented!();
+		if let Err(e) = self.monitor.add_update_monitor(chan_monitor.get_funding_txo().unwrap(), chan_monitor) {
+			handle_monitor_err!(self, e, msg.channel_id, false, res.is_some(), pending_forwards, pending_failures);
 		}
 		for failure in pending_failures.drain(..) {
 			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), failure.0, &failure.1, failure.2);
 		}
+		self.forward_htlcs(&mut [(short_channel_id, pending_forwards)]);
 
+		Ok(res)
+	}
+
+	/// Queues up HTLCs which were irrevocably committed to on the given (previous hop) channels to
+	/// be forwarded, generating a PendingHTLCsForwardable event if there were none already queued.
+	fn forward_htlcs(&self, per_source_pending_forwards: &mut [(u64, Vec<(PendingForwardHTLCInfo, u64)>)]) {
 		let mut forward_event = None;
-		if !pending_forwards.is_empty() {
+		for &mut (prev_short_channel_id, ref mut pending_forwards) in per_source_pending_forwards {
+			if pending_forwards.is_empty() { continue; }
 			let mut channel_state = self.channel_state.lock().unwrap();
 			if channel_state.forward_htlcs.is_empty() {
 				forward_event = Some(Instant::now() + Duration::from_millis(((rng::rand_f32() * 4.0 + 1.0) * MIN_HTLC_RELAY_HOLDING_CELL_MILLIS as f32) as u64));
@@ -1892,10 +3476,10 @@ impl ChannelManager {
 			for (forward_info, prev_htlc_id) in pending_forwards.drain(..) {
 				match channel_state.forward_htlcs.entry(forward_info.short_channel_id) {
 					hash_map::Entry::Occupied(mut entry) => {
-						entry.get_mut().push(HTLCForwardInfo { prev_short_channel_id: short_channel_id, prev_htlc_id, forward_info });
+						entry.get_mut().push(HTLCForwardInfo { prev_short_channel_id, prev_htlc_id, forward_info, released: false });
 					},
 					hash_map::Entry::Vacant(entry) => {
-						entry.insert(vec!(HTLCForwardInfo { prev_short_channel_id: short_channel_id, prev_htlc_id, forward_info }));
+						entry.insert(vec!(HTLCForwardInfo { prev_short_channel_id, prev_htlc_id, forward_info, released: false }));
 					}
 				}
 			}
@@ -1909,8 +3493,6 @@ impl ChannelManager {
 			}
 			None => {},
 		}
-
-		Ok(res)
 	}
 
 	fn internal_update_fee(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFee) -> Result<(), MsgHandleErrInternal> {
@@ -1928,7 +3510,7 @@ impl ChannelManager {
 	}
 
 	fn internal_announcement_signatures(&self, their_node_id: &PublicKey, msg: &msgs::AnnouncementSignatures) -> Result<(), MsgHandleErrInternal> {
-		let (chan_announcement, chan_update) = {
+		let (chan_announcement, chan_update, had_their_sigs, our_sigs) = {
 			let mut channel_state = self.channel_state.lock().unwrap();
 			match channel_state.by_id.get_mut(&msg.channel_id) {
 				Some(chan) => {
@@ -1949,6 +3531,12 @@ impl ChannelManager {
 					secp_call!(self.secp_ctx.verify(&msghash, &msg.node_signature, if were_node_one { &announcement.node_id_2 } else { &announcement.node_id_1 }), bad_sig_action);
 					secp_call!(self.secp_ctx.verify(&msghash, &msg.bitcoin_signature, if were_node_one { &announcement.bitcoin_key_2 } else { &announcement.bitcoin_key_1 }), bad_sig_action);
 
+					// If we already had their signatures, our peer is re-sending theirs after a
+					// reconnect as it never got ours, so reply with ours (once per connection, so we
+					// can't end up in a loop) instead of announcing again.
+					let had_their_sigs = chan.get_their_announcement_sigs().is_some();
+					chan.set_their_announcement_sigs(msg.node_signature, msg.bitcoin_signature);
+
 					let our_node_sig = self.secp_ctx.sign(&msghash, &self.our_network_key);
 
 					(msgs::ChannelAnnouncement {
@@ -1957,29 +3545,66 @@ impl ChannelManager {
 						bitcoin_signature_1: if were_node_one { our_bitcoin_sig } else { msg.bitcoin_signature },
 						bitcoin_signature_2: if were_node_one { msg.bitcoin_signature } else { our_bitcoin_sig },
 						contents: announcement,
-					}, self.get_channel_update(chan).unwrap()) // can only fail if we're not in a ready state
+					}, self.get_channel_update(chan).unwrap(), // can only fail if we're not in a ready state
+					had_their_sigs, if had_their_sigs { self.get_announcement_sigs(chan) } else { None })
 				},
 				None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 			}
 		};
-		let mut pending_events = self.pending_events.lock().unwrap();
-		pending_events.push(events::Event::BroadcastChannelAnnouncement { msg: chan_announcement, update_msg: chan_update });
+		if had_their_sigs {
+			if let Some(our_sigs) = our_sigs {
+				self.pending_events.lock().unwrap().push(events::Event::SendAnnouncementSignatures {
+					node_id: their_node_id.clone(),
+					msg: our_sigs,
+				});
+			}
+			return Ok(());
+		}
+		// Now that we have a public channel peers will accept our node_announcement, so send it
+		// right after the channel_announcement.
+		let node_announcement = self.get_node_announcement_for_broadcast(true, true);
+		let mut pending_events = self.pending_events.lock().unwrap();
+		pending_events.push(events::Event::BroadcastChannelAnnouncement { msg: chan_announcement, update_msg: chan_update });
+		if let Some(msg) = node_announcement {
+			pending_events.push(events::Event::BroadcastNodeAnnouncement { msg });
+		}
 		Ok(())
 	}
 
 	fn internal_channel_reestablish(&self, their_node_id: &PublicKey, msg: &msgs::ChannelReestablish) -> Result<(Option<msgs::FundingLocked>, Option<msgs::RevokeAndACK>, Option<msgs::CommitmentUpdate>), MsgHandleErrInternal> {
 		let (res, chan_monitor) = {
-			let mut channel_state = self.channel_state.lock().unwrap();
-			match channel_state.by_id.get_mut(&msg.channel_id) {
+			let mut channel_state_lock = self.channel_state.lock().unwrap();
+			let channel_state = channel_state_lock.borrow_parts();
+			let reestablish_res = match channel_state.by_id.get_mut(&msg.channel_id) {
 				Some(chan) => {
 					if chan.get_their_node_id() != *their_node_id {
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 					}
-					let (funding_locked, revoke_and_ack, commitment_update, channel_monitor) = chan.channel_reestablish(msg)
-						.map_err(|e| MsgHandleErrInternal::from_chan_maybe_close(e, msg.channel_id))?;
-					(Ok((funding_locked, revoke_and_ack, commitment_update)), channel_monitor)
+					let res = chan.channel_reestablish(msg);
+					// Per BOLT 7 we re-send our announcement_signatures on each reconnection until we
+					// receive our peer's, as they may not have gotten ours before we disconnected.
+					if res.is_ok() && chan.is_usable() && chan.get_their_announcement_sigs().is_none() {
+						if let Some(announcement_sigs) = self.get_announcement_sigs(chan) {
+							self.pending_events.lock().unwrap().push(events::Event::SendAnnouncementSignatures {
+								node_id: their_node_id.clone(),
+								msg: announcement_sigs,
+							});
+						}
+					}
+					res
 				},
 				None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+			};
+			match reestablish_res {
+				Ok((funding_locked, revoke_and_ack, commitment_update, channel_monitor)) => (Ok((funding_locked, revoke_and_ack, commitment_update)), channel_monitor),
+				Err(ChannelError::CloseDelayBroadcast { msg: err, update }) => {
+					let chan = channel_state.by_id.remove(&msg.channel_id).unwrap();
+					if let Some(short_id) = chan.get_short_channel_id() {
+						channel_state.short_to_id.remove(&short_id);
+					}
+					(Err((err, chan)), Some(update))
+				},
+				Err(e) => return Err(MsgHandleErrInternal::from_chan_maybe_close(e, msg.channel_id)),
 			}
 		};
 		if let Some(monitor) = chan_monitor {
@@ -1987,7 +3612,23 @@ impl ChannelManager {
 				unimplemented!();
 			}
 		}
-		res
+		match res {
+			Ok(res) => Ok(res),
+			Err((err, mut chan)) => {
+				// Our peer proved our latest local commitment transaction is revoked, so we must
+				// not broadcast it. Instead, our ChannelMonitor will claim our funds once they
+				// broadcast theirs.
+				self.finish_force_close_channel(chan.force_shutdown(false));
+				let mut events = self.pending_events.lock().unwrap();
+				if let Ok(update) = self.get_channel_update(&chan) {
+					events.push(events::Event::BroadcastChannelUpdate {
+						msg: update
+					});
+				}
+				events.push(Self::get_channel_closed_event(&chan, events::ClosureReason::ProcessingError { err: err.to_string() }, None, None));
+				Err(MsgHandleErrInternal::send_err_msg_no_close(err, msg.channel_id))
+			},
+		}
 	}
 
 	/// Begin Update fee process. Allowed only on an outbound channel.
@@ -2027,6 +3668,68 @@ impl ChannelManager {
 		}
 		Ok(())
 	}
+
+	/// Sends update_fee on each channel we funded whose feerate has moved far enough from our
+	/// current fee estimate (see Channel::get_update_fee_feerate).
+	fn update_channel_feerates(&self) {
+		let mut new_events = Vec::new();
+		let mut failed_channels = Vec::new();
+		{
+			let mut channel_state = self.channel_state.lock().unwrap();
+			for (channel_id, chan) in channel_state.by_id.iter_mut() {
+				let new_feerate = match chan.get_update_fee_feerate(&*self.fee_estimator) {
+					Some(feerate) => feerate,
+					None => continue,
+				};
+				log_debug!(self, "Updating feerate on channel {} from {} to {}", log_bytes!(channel_id[..]), chan.get_feerate(), new_feerate);
+				match chan.send_update_fee_and_commit(new_feerate) {
+					Ok(Some((update_fee, commitment_signed, chan_monitor))) => {
+						match self.monitor.add_update_monitor(chan_monitor.get_funding_txo().unwrap(), chan_monitor) {
+							Ok(()) => {},
+							Err(ChannelMonitorUpdateErr::TemporaryFailure) => {
+								// We can't send a commitment_signed the monitor hasn't seen, so hold it
+								// (and the update_fee) until test_restore_channel_monitor.
+								log_debug!(self, "Failed to update ChannelMonitor for update_fee on channel {}, pausing it", log_bytes!(channel_id[..]));
+								chan.monitor_update_failed(false, true, Vec::new(), Vec::new());
+								continue;
+							},
+							Err(ChannelMonitorUpdateErr::PermanentFailure) => {
+								// We'll never be able to send the commitment_signed, so our only option is
+								// to close the channel with the latest state the monitor does know about.
+								log_error!(self, "Failed to update ChannelMonitor for update_fee on channel {}, closing it", log_bytes!(channel_id[..]));
+								failed_channels.push(channel_id.clone());
+								continue;
+							},
+						}
+						new_events.push(events::Event::UpdateHTLCs {
+							node_id: chan.get_their_node_id(),
+							updates: msgs::CommitmentUpdate {
+								update_add_htlcs: Vec::new(),
+								update_fulfill_htlcs: Vec::new(),
+								update_fail_htlcs: Vec::new(),
+								update_fail_malformed_htlcs: Vec::new(),
+								update_fee: Some(update_fee),
+								commitment_signed,
+							},
+						});
+					},
+					// The update is sitting in the holding cell and will go out with the next
+					// commitment_signed
+					Ok(None) => {},
+					Err(e) => {
+						log_error!(self, "Failed to send update_fee on channel {}: {}", log_bytes!(channel_id[..]), e.err);
+					},
+				}
+			}
+		}
+		for channel_id in failed_channels.drain(..) {
+			self.force_close_channel_with_reason(&channel_id, events::ClosureReason::MonitorUpdateFailed);
+		}
+		let mut pending_events = self.pending_events.lock().unwrap();
+		for event in new_events.drain(..) {
+			pending_events.push(event);
+		}
+	}
 }
 
 impl events::EventsProvider for ChannelManager {
@@ -2042,11 +3745,13 @@ impl ChainListener for ChannelManager {
 	fn block_connected(&self, header: &BlockHeader, height: u32, txn_matched: &[&Transaction], indexes_of_txn_matched: &[u32]) {
 		let mut new_events = Vec::new();
 		let mut failed_channels = Vec::new();
+		let mut timed_out_htlcs = Vec::new();
 		{
 			let mut channel_lock = self.channel_state.lock().unwrap();
 			let channel_state = channel_lock.borrow_parts();
 			let short_to_id = channel_state.short_to_id;
 			channel_state.by_id.retain(|_, channel| {
+				let was_usable = channel.is_usable();
 				let chan_res = channel.block_connected(header, height, txn_matched, indexes_of_txn_matched);
 				if let Ok(Some(funding_locked)) = chan_res {
 					let announcement_sigs = self.get_announcement_sigs(channel);
@@ -2055,6 +3760,13 @@ impl ChainListener for ChannelManager {
 						msg: funding_locked,
 						announcement_sigs: announcement_sigs
 					});
+					if !was_usable && channel.is_usable() {
+						new_events.push(events::Event::ChannelReady {
+							channel_id: channel.channel_id(),
+							user_channel_id: channel.get_user_id(),
+							counterparty_node_id: channel.get_their_node_id(),
+						});
+					}
 					short_to_id.insert(channel.get_short_channel_id().unwrap(), channel.channel_id());
 				} else if let Err(e) = chan_res {
 					new_events.push(events::Event::HandleError {
@@ -2062,6 +3774,7 @@ impl ChainListener for ChannelManager {
 						action: e.action,
 					});
 					if channel.is_shutdown() {
+						new_events.push(Self::get_channel_closed_event(channel, events::ClosureReason::ProcessingError { err: e.err.to_string() }, None, None));
 						return false;
 					}
 				}
@@ -2075,12 +3788,15 @@ impl ChainListener for ChannelManager {
 								// It looks like our counterparty went on-chain. We go ahead and
 								// broadcast our latest local state as well here, just in case its
 								// some kind of SPV attack, though we expect these to be dropped.
-								failed_channels.push(channel.force_shutdown());
+								let shutdown_res = channel.force_shutdown(true);
+								let closing_tx = shutdown_res.0.first().cloned();
+								failed_channels.push(shutdown_res);
 								if let Ok(update) = self.get_channel_update(&channel) {
 									new_events.push(events::Event::BroadcastChannelUpdate {
 										msg: update
 									});
 								}
+								new_events.push(Self::get_channel_closed_event(channel, events::ClosureReason::CounterpartyForceClosed { peer_msg: None }, closing_tx, None));
 								return false;
 							}
 						}
@@ -2090,29 +3806,62 @@ impl ChainListener for ChannelManager {
 					if let Some(short_id) = channel.get_short_channel_id() {
 						short_to_id.remove(&short_id);
 					}
-					failed_channels.push(channel.force_shutdown());
+					failed_channels.push(channel.force_shutdown(true));
 					// If would_broadcast_at_height() is true, the channel_monitor will broadcast
 					// the latest local tx for us, so we should skip that here (it doesn't really
 					// hurt anything, but does make tests a bit simpler).
-					failed_channels.last_mut().unwrap().0 = Vec::new();
+					let closing_tx = failed_channels.last_mut().unwrap().0.drain(..).next();
 					if let Ok(update) = self.get_channel_update(&channel) {
 						new_events.push(events::Event::BroadcastChannelUpdate {
 							msg: update
 						});
 					}
+					new_events.push(Self::get_channel_closed_event(channel, events::ClosureReason::HTLCsTimedOut, closing_tx, None));
 					return false;
 				}
+				let timed_out_holding_cell_htlcs = channel.time_out_holding_cell_htlcs(height + HTLC_FAIL_BACK_BUFFER);
+				if !timed_out_holding_cell_htlcs.is_empty() {
+					let data = match self.get_channel_update(channel) {
+						Ok(update) => update.encode_with_len(),
+						Err(_) => Vec::new(),
+					};
+					for (source, payment_hash) in timed_out_holding_cell_htlcs {
+						timed_out_htlcs.push((source, payment_hash, HTLCFailReason::Reason { failure_code: 0x1000 | 14, data: data.clone() }));
+					}
+				}
 				true
 			});
+			channel_state.claimable_htlcs.retain(|payment_hash, htlcs| {
+				if htlcs.iter().any(|htlc| htlc.cltv_expiry <= height + HTLC_FAIL_BACK_BUFFER) {
+					log_info!(self, "Failing back unclaimed payment {} as it is about to expire", log_bytes!(payment_hash[..]));
+					for htlc in htlcs.drain(..) {
+						timed_out_htlcs.push((HTLCSource::PreviousHopData(htlc.prev_hop), payment_hash.clone(), HTLCFailReason::Reason { failure_code: 0x4000 | 15, data: Vec::new() }));
+					}
+					false
+				} else { true }
+			});
+			channel_state.intercepted_htlcs.retain(|_, htlc| {
+				if htlc.forward_info.outgoing_cltv_value <= height + HTLC_FAIL_BACK_BUFFER {
+					timed_out_htlcs.push((htlc.prev_hop_source(), htlc.forward_info.payment_hash, HTLCFailReason::Reason { failure_code: 0x2000 | 2, data: Vec::new() }));
+					false
+				} else { true }
+			});
 		}
 		for failure in failed_channels.drain(..) {
 			self.finish_force_close_channel(failure);
 		}
-		let mut pending_events = self.pending_events.lock().unwrap();
-		for funding_locked in new_events.drain(..) {
-			pending_events.push(funding_locked);
+		for (source, payment_hash, reason) in timed_out_htlcs.drain(..) {
+			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), source, &payment_hash, reason);
 		}
+		{
+			let mut pending_events = self.pending_events.lock().unwrap();
+			for funding_locked in new_events.drain(..) {
+				pending_events.push(funding_locked);
+			}
+		}
+		self.seen_onions.lock().unwrap().block_connected(height);
 		self.latest_block_height.store(height as usize, Ordering::Release);
+		self.update_channel_feerates();
 	}
 
 	/// We force-close the channel without letting our counterparty participate in the shutdown
@@ -2128,12 +3877,15 @@ impl ChainListener for ChannelManager {
 					if let Some(short_id) = v.get_short_channel_id() {
 						short_to_id.remove(&short_id);
 					}
-					failed_channels.push(v.force_shutdown());
+					let shutdown_res = v.force_shutdown(true);
+					let closing_tx = shutdown_res.0.first().cloned();
+					failed_channels.push(shutdown_res);
 					if let Ok(update) = self.get_channel_update(&v) {
 						new_events.push(events::Event::BroadcastChannelUpdate {
 							msg: update
 						});
 					}
+					new_events.push(Self::get_channel_closed_event(v, events::ClosureReason::FundingTxUnconfirmed, closing_tx, None));
 					false
 				} else {
 					true
@@ -2164,7 +3916,7 @@ macro_rules! handle_error {
 							if msg.channel_id == [0; 32] {
 								$self.peer_disconnected(&$their_node_id, true);
 							} else {
-								$self.force_close_channel(&msg.channel_id);
+								$self.force_close_channel_with_reason(&msg.channel_id, events::ClosureReason::ProcessingError { err: err.err.to_string() });
 							}
 						},
 						&Some(msgs::ErrorAction::DisconnectPeer { msg: None }) => {},
@@ -2173,7 +3925,7 @@ macro_rules! handle_error {
 							if msg.channel_id == [0; 32] {
 								$self.peer_disconnected(&$their_node_id, true);
 							} else {
-								$self.force_close_channel(&msg.channel_id);
+								$self.force_close_channel_with_reason(&msg.channel_id, events::ClosureReason::ProcessingError { err: err.err.to_string() });
 							}
 						},
 						&None => {},
@@ -2252,6 +4004,7 @@ impl ChannelMessageHandler for ChannelManager {
 	}
 
 	fn peer_disconnected(&self, their_node_id: &PublicKey, no_connection_possible: bool) {
+		self.peer_features.lock().unwrap().remove(their_node_id);
 		let mut new_events = Vec::new();
 		let mut failed_channels = Vec::new();
 		let mut failed_payments = Vec::new();
@@ -2265,12 +4018,15 @@ impl ChannelMessageHandler for ChannelManager {
 						if let Some(short_id) = chan.get_short_channel_id() {
 							short_to_id.remove(&short_id);
 						}
-						failed_channels.push(chan.force_shutdown());
+						let shutdown_res = chan.force_shutdown(true);
+						let closing_tx = shutdown_res.0.first().cloned();
+						failed_channels.push(shutdown_res);
 						if let Ok(update) = self.get_channel_update(&chan) {
 							new_events.push(events::Event::BroadcastChannelUpdate {
 								msg: update
 							});
 						}
+						new_events.push(Self::get_channel_closed_event(chan, events::ClosureReason::DisconnectedPeer, closing_tx, None));
 						false
 					} else {
 						true
@@ -2279,7 +4035,8 @@ impl ChannelMessageHandler for ChannelManager {
 			} else {
 				channel_state.by_id.retain(|_, chan| {
 					if chan.get_their_node_id() == *their_node_id {
-						//TODO: mark channel disabled (and maybe announce such after a timeout).
+						// The channel is announced as disabled in timer_tick_occured if our peer
+						// doesn't come back within the channel disable timeout.
 						let failed_adds = chan.remove_uncommitted_htlcs_and_mark_paused();
 						if !failed_adds.is_empty() {
 							let chan_update = self.get_channel_update(&chan).map(|u| u.encode_with_len()).unwrap(); // Cannot add/recv HTLCs before we have a short_id so unwrap is safe
@@ -2289,6 +4046,7 @@ impl ChannelMessageHandler for ChannelManager {
 							if let Some(short_id) = chan.get_short_channel_id() {
 								short_to_id.remove(&short_id);
 							}
+							new_events.push(Self::get_channel_closed_event(chan, events::ClosureReason::DisconnectedPeer, None, None));
 							return false;
 						}
 					}
@@ -2312,36 +4070,51 @@ impl ChannelMessageHandler for ChannelManager {
 		}
 	}
 
-	fn peer_connected(&self, their_node_id: &PublicKey) -> Vec<msgs::ChannelReestablish> {
+	fn peer_connected(&self, their_node_id: &PublicKey, their_features: &msgs::LocalFeatures) -> Vec<msgs::ChannelReestablish> {
+		self.peer_features.lock().unwrap().insert(their_node_id.clone(), their_features.clone());
 		let mut res = Vec::new();
-		let mut channel_state = self.channel_state.lock().unwrap();
-		channel_state.by_id.retain(|_, chan| {
-			if chan.get_their_node_id() == *their_node_id {
-				if !chan.have_received_message() {
-					// If we created this (outbound) channel while we were disconnected from the
-					// peer we probably failed to send the open_channel message, which is now
-					// lost. We can't have had anything pending related to this channel, so we just
-					// drop it.
-					false
-				} else {
-					res.push(chan.get_channel_reestablish());
-					true
-				}
-			} else { true }
-		});
-		//TODO: Also re-broadcast announcement_signatures
+		let mut closed_events = Vec::new();
+		{
+			let mut channel_state = self.channel_state.lock().unwrap();
+			channel_state.by_id.retain(|_, chan| {
+				if chan.get_their_node_id() == *their_node_id {
+					if !chan.have_received_message() {
+						// If we created this (outbound) channel while we were disconnected from the
+						// peer we probably failed to send the open_channel message, which is now
+						// lost. We can't have had anything pending related to this channel, so we just
+						// drop it.
+						closed_events.push(Self::get_channel_closed_event(chan, events::ClosureReason::DisconnectedPeer, None, None));
+						false
+					} else {
+						res.push(chan.get_channel_reestablish(their_features));
+						true
+					}
+				} else { true }
+			});
+		}
+		if !closed_events.is_empty() {
+			let mut pending_events = self.pending_events.lock().unwrap();
+			for event in closed_events.drain(..) {
+				pending_events.push(event);
+			}
+		}
 		res
 	}
 
+	fn has_funded_channels(&self, their_node_id: &PublicKey) -> bool {
+		let channel_state = self.channel_state.lock().unwrap();
+		channel_state.by_id.values().any(|chan| chan.get_their_node_id() == *their_node_id && chan.is_funding_initiated())
+	}
+
 	fn handle_error(&self, their_node_id: &PublicKey, msg: &msgs::ErrorMessage) {
 		if msg.channel_id == [0; 32] {
 			for chan in self.list_channels() {
 				if chan.remote_network_id == *their_node_id {
-					self.force_close_channel(&chan.channel_id);
+					self.force_close_channel_with_reason(&chan.channel_id, events::ClosureReason::CounterpartyForceClosed { peer_msg: Some(msg.data.clone()) });
 				}
 			}
 		} else {
-			self.force_close_channel(&msg.channel_id);
+			self.force_close_channel_with_reason(&msg.channel_id, events::ClosureReason::CounterpartyForceClosed { peer_msg: Some(msg.data.clone()) });
 		}
 	}
 }
@@ -2351,18 +4124,25 @@ mod tests {
 	use chain::chaininterface;
 	use chain::transaction::OutPoint;
 	use chain::chaininterface::ChainListener;
-	use ln::channelmanager::{ChannelManager,OnionKeys};
+	use ln::channelmanager::{ChannelManager,ForwardingDecision,ForwardingDetails,ForwardingPolicy,HTLCFailureMsg,HTLCSource,OnionKeys,PaymentParameters,PendingHTLCStatus,RetryBudget,SeenOnions,CLTV_FAR_FAR_AWAY,HTLC_FAIL_BACK_BUFFER,LATENCY_GRACE_PERIOD_BLOCKS,MAX_SEEN_ONIONS_PER_CHANNEL};
+	use ln::invoice::{Currency, Invoice, RawInvoice, DEFAULT_EXPIRY_TIME, DEFAULT_MIN_FINAL_CLTV_EXPIRY};
+	use ln::channel::INITIAL_COMMITMENT_NUMBER;
+	use ln::channelmonitor::{ChannelMonitorUpdateErr, CLTV_CLAIM_BUFFER};
+	use ln::chan_utils;
 	use ln::router::{Route, RouteHop, Router};
 	use ln::msgs;
 	use ln::msgs::{ChannelMessageHandler,RoutingMessageHandler};
+	use net::onion;
 	use util::test_utils;
-	use util::events::{Event, EventsProvider};
+	use util::events::{ClosureReason, Event, EventsProvider};
 	use util::errors::APIError;
 	use util::logger::Logger;
-	use util::ser::Writeable;
+	use util::ser::{Readable, Writeable};
 
-	use bitcoin::util::hash::Sha256dHash;
+	use bitcoin::util::hash::{Hash160, Sha256dHash};
 	use bitcoin::blockdata::block::{Block, BlockHeader};
+	use bitcoin::blockdata::script::Builder;
+	use bitcoin::blockdata::opcodes;
 	use bitcoin::blockdata::transaction::{Transaction, TxOut};
 	use bitcoin::blockdata::constants::genesis_block;
 	use bitcoin::network::constants::Network;
@@ -2373,6 +4153,7 @@ mod tests {
 
 	use secp256k1::{Secp256k1, Message};
 	use secp256k1::key::{PublicKey,SecretKey};
+	use secp256k1::ecdh::SharedSecret;
 
 	use crypto::sha2::Sha256;
 	use crypto::digest::Digest;
@@ -2382,9 +4163,12 @@ mod tests {
 	use std::cell::RefCell;
 	use std::collections::{BTreeSet, HashMap};
 	use std::default::Default;
+	use std::io::Cursor;
 	use std::rc::Rc;
 	use std::sync::{Arc, Mutex};
-	use std::time::Instant;
+	use std::sync::atomic::Ordering;
+	use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+	use std::str::FromStr;
 	use std::mem;
 
 	fn build_test_onion_keys() -> Vec<OnionKeys> {
@@ -2467,6 +4251,7 @@ mod tests {
 					amt_to_forward: 0,
 					outgoing_cltv_value: 0,
 				},
+				payment_data: None,
 				hmac: [0; 32],
 			},
 			msgs::OnionHopData {
@@ -2476,6 +4261,7 @@ mod tests {
 					amt_to_forward: 0x0100000001,
 					outgoing_cltv_value: 0,
 				},
+				payment_data: None,
 				hmac: [0; 32],
 			},
 			msgs::OnionHopData {
@@ -2485,6 +4271,7 @@ mod tests {
 					amt_to_forward: 0x0200000002,
 					outgoing_cltv_value: 0,
 				},
+				payment_data: None,
 				hmac: [0; 32],
 			},
 			msgs::OnionHopData {
@@ -2494,6 +4281,7 @@ mod tests {
 					amt_to_forward: 0x0300000003,
 					outgoing_cltv_value: 0,
 				},
+				payment_data: None,
 				hmac: [0; 32],
 			},
 			msgs::OnionHopData {
@@ -2503,6 +4291,7 @@ mod tests {
 					amt_to_forward: 0x0400000004,
 					outgoing_cltv_value: 0,
 				},
+				payment_data: None,
 				hmac: [0; 32],
 			},
 		);
@@ -2548,11 +4337,13 @@ mod tests {
 	}
 
 	struct Node {
+		fee_estimator: Arc<test_utils::TestFeeEstimator>,
 		chain_monitor: Arc<chaininterface::ChainWatchInterfaceUtil>,
 		tx_broadcaster: Arc<test_utils::TestBroadcaster>,
 		chan_monitor: Arc<test_utils::TestChannelMonitor>,
+		wallet: Arc<test_utils::TestWallet>,
 		node: Arc<ChannelManager>,
-		router: Router,
+		router: Arc<Router>,
 		network_payment_count: Rc<RefCell<u8>>,
 		network_chan_count: Rc<RefCell<u32>>,
 	}
@@ -2626,6 +4417,16 @@ mod tests {
 				assert_eq!(added_monitors.len(), 1);
 				assert_eq!(added_monitors[0].0, funding_output);
 				added_monitors.clear();
+				let bs_events = node_b.node.get_and_clear_pending_events();
+				assert_eq!(bs_events.len(), 1);
+				match bs_events[0] {
+					Event::ChannelPending { ref counterparty_node_id, ref funding_txo, user_channel_id, .. } => {
+						assert_eq!(*counterparty_node_id, node_a.node.get_our_node_id());
+						assert_eq!(*funding_txo, funding_output);
+						assert_eq!(user_channel_id, 0);
+					},
+					_ => panic!("Unexpected event"),
+				}
 				res
 			},
 			_ => panic!("Unexpected event"),
@@ -2640,7 +4441,7 @@ mod tests {
 		}
 
 		let events_4 = node_a.node.get_and_clear_pending_events();
-		assert_eq!(events_4.len(), 1);
+		assert_eq!(events_4.len(), 2);
 		match events_4[0] {
 			Event::FundingBroadcastSafe { ref funding_txo, user_channel_id } => {
 				assert_eq!(user_channel_id, 42);
@@ -2648,6 +4449,14 @@ mod tests {
 			},
 			_ => panic!("Unexpected event"),
 		};
+		match events_4[1] {
+			Event::ChannelPending { ref counterparty_node_id, ref funding_txo, user_channel_id, .. } => {
+				assert_eq!(*counterparty_node_id, node_b.node.get_our_node_id());
+				assert_eq!(*funding_txo, funding_output);
+				assert_eq!(user_channel_id, 42);
+			},
+			_ => panic!("Unexpected event"),
+		};
 
 		tx
 	}
@@ -2669,15 +4478,23 @@ mod tests {
 
 		confirm_transaction(&node_a.chain_monitor, &tx, tx.version);
 		let events_6 = node_a.node.get_and_clear_pending_events();
-		assert_eq!(events_6.len(), 1);
-		(match events_6[0] {
+		assert_eq!(events_6.len(), 2);
+		let as_funding_msgs = match events_6[0] {
 			Event::SendFundingLocked { ref node_id, ref msg, ref announcement_sigs } => {
 				channel_id = msg.channel_id.clone();
 				assert_eq!(*node_id, node_b.node.get_our_node_id());
 				(msg.clone(), announcement_sigs.clone().unwrap())
 			},
 			_ => panic!("Unexpected event"),
-		}, channel_id)
+		};
+		match events_6[1] {
+			Event::ChannelReady { channel_id: ref ready_id, ref counterparty_node_id, .. } => {
+				assert_eq!(*ready_id, channel_id);
+				assert_eq!(*counterparty_node_id, node_b.node.get_our_node_id());
+			},
+			_ => panic!("Unexpected event"),
+		}
+		(as_funding_msgs, channel_id)
 	}
 
 	fn create_chan_between_nodes_with_value_a(node_a: &Node, node_b: &Node, channel_value: u64, push_msat: u64) -> ((msgs::FundingLocked, msgs::AnnouncementSignatures), [u8; 32], Transaction) {
@@ -2694,8 +4511,15 @@ mod tests {
 		};
 
 		let events_7 = node_b.node.get_and_clear_pending_events();
-		assert_eq!(events_7.len(), 1);
-		let (announcement, bs_update) = match events_7[0] {
+		assert_eq!(events_7.len(), 2);
+		match events_7[0] {
+			Event::ChannelReady { ref channel_id, ref counterparty_node_id, .. } => {
+				assert_eq!(*channel_id, as_funding_msgs.0.channel_id);
+				assert_eq!(*counterparty_node_id, node_a.node.get_our_node_id());
+			},
+			_ => panic!("Unexpected event"),
+		}
+		let (announcement, bs_update) = match events_7[1] {
 			Event::BroadcastChannelAnnouncement { ref msg, ref update_msg } => {
 				(msg, update_msg)
 			},
@@ -2732,6 +4556,17 @@ mod tests {
 		(chan_announcement.1, chan_announcement.2, chan_announcement.3, chan_announcement.4)
 	}
 
+	macro_rules! check_closed_event {
+		($event: expr, $reason: expr) => {
+			match $event {
+				Event::ChannelClosed { ref reason, .. } => {
+					assert_eq!(*reason, $reason);
+				},
+				_ => panic!("Unexpected event"),
+			}
+		}
+	}
+
 	macro_rules! check_spends {
 		($tx: expr, $spends_tx: expr) => {
 			{
@@ -2787,24 +4622,43 @@ mod tests {
 		}
 		assert_eq!(tx_a, tx_b);
 		check_spends!(tx_a, funding_tx);
+		for outp in tx_a.output.iter() {
+			assert!(outp.script_pubkey == outbound_node.wallet.shutdown_scriptpubkey || outp.script_pubkey == inbound_node.wallet.shutdown_scriptpubkey);
+		}
 
 		let events_2 = node_a.get_and_clear_pending_events();
-		assert_eq!(events_2.len(), 1);
+		assert_eq!(events_2.len(), 2);
 		let as_update = match events_2[0] {
 			Event::BroadcastChannelUpdate { ref msg } => {
 				msg.clone()
 			},
 			_ => panic!("Unexpected event"),
 		};
+		match events_2[1] {
+			Event::ChannelClosed { channel_id: ref closed_id, ref reason, ref closing_transaction, .. } => {
+				assert_eq!(closed_id, channel_id);
+				assert_eq!(*reason, ClosureReason::CooperativeClosure);
+				assert_eq!(*closing_transaction.as_ref().unwrap(), tx_a);
+			},
+			_ => panic!("Unexpected event"),
+		}
 
 		let events_3 = node_b.get_and_clear_pending_events();
-		assert_eq!(events_3.len(), 1);
+		assert_eq!(events_3.len(), 2);
 		let bs_update = match events_3[0] {
 			Event::BroadcastChannelUpdate { ref msg } => {
 				msg.clone()
 			},
 			_ => panic!("Unexpected event"),
 		};
+		match events_3[1] {
+			Event::ChannelClosed { channel_id: ref closed_id, ref reason, ref closing_transaction, .. } => {
+				assert_eq!(closed_id, channel_id);
+				assert_eq!(*reason, ClosureReason::CooperativeClosure);
+				assert_eq!(*closing_transaction.as_ref().unwrap(), tx_b);
+			},
+			_ => panic!("Unexpected event"),
+		}
 
 		(as_update, bs_update)
 	}
@@ -2886,7 +4740,7 @@ mod tests {
 	fn send_along_route(origin_node: &Node, route: Route, expected_route: &[&Node], recv_value: u64) -> ([u8; 32], [u8; 32]) {
 		let (our_payment_preimage, our_payment_hash) = get_payment_preimage_hash!(origin_node);
 
-		let mut payment_event = {
+		let payment_event = {
 			origin_node.node.send_payment(route, our_payment_hash).unwrap();
 			check_added_monitors!(origin_node, 1);
 
@@ -2894,6 +4748,12 @@ mod tests {
 			assert_eq!(events.len(), 1);
 			SendEvent::from_event(events.remove(0))
 		};
+		pass_along_route(origin_node, payment_event, expected_route, recv_value, our_payment_hash);
+
+		(our_payment_preimage, our_payment_hash)
+	}
+
+	fn pass_along_route(origin_node: &Node, mut payment_event: SendEvent, expected_route: &[&Node], recv_value: u64, our_payment_hash: [u8; 32]) {
 		let mut prev_node = origin_node;
 
 		for (idx, &node) in expected_route.iter().enumerate() {
@@ -2917,7 +4777,7 @@ mod tests {
 			assert_eq!(events_2.len(), 1);
 			if idx == expected_route.len() - 1 {
 				match events_2[0] {
-					Event::PaymentReceived { ref payment_hash, amt } => {
+					Event::PaymentReceived { ref payment_hash, amt, .. } => {
 						assert_eq!(our_payment_hash, *payment_hash);
 						assert_eq!(amt, recv_value);
 					},
@@ -2931,8 +4791,6 @@ mod tests {
 
 			prev_node = node;
 		}
-
-		(our_payment_preimage, our_payment_hash)
 	}
 
 	fn claim_payment_along_route(origin_node: &Node, expected_route: &[&Node], skip_last: bool, our_payment_preimage: [u8; 32]) {
@@ -2941,7 +4799,7 @@ mod tests {
 
 		let mut next_msgs: Option<(msgs::UpdateFulfillHTLC, msgs::CommitmentSigned)> = None;
 		macro_rules! update_fulfill_dance {
-			($node: expr, $prev_node: expr, $last_node: expr) => {
+			($node: expr, $prev_node: expr, $last_node: expr, $prev_node_forwarded: expr) => {
 				{
 					$node.node.handle_update_fulfill_htlc(&$prev_node.node.get_our_node_id(), &next_msgs.as_ref().unwrap().0).unwrap();
 					if $last_node {
@@ -2950,6 +4808,19 @@ mod tests {
 						check_added_monitors!($node, 1);
 					}
 					commitment_signed_dance!($node, $prev_node, next_msgs.as_ref().unwrap().1, false);
+					if $prev_node_forwarded {
+						// Every hop but the recipient forwarded the payment and gets a PaymentForwarded
+						// once its claim is irrevocably committed
+						let events = $prev_node.node.get_and_clear_pending_events();
+						assert_eq!(events.len(), 1);
+						match events[0] {
+							Event::PaymentForwarded { prev_channel_id, next_channel_id, incoming_amt_msat, outgoing_amt_msat, fee_earned_msat } => {
+								assert!(prev_channel_id != next_channel_id);
+								assert_eq!(fee_earned_msat, incoming_amt_msat - outgoing_amt_msat);
+							},
+							_ => panic!("Unexpected event"),
+						}
+					}
 				}
 			}
 		}
@@ -2959,7 +4830,7 @@ mod tests {
 		for (idx, node) in expected_route.iter().rev().enumerate() {
 			assert_eq!(expected_next_node, node.node.get_our_node_id());
 			if next_msgs.is_some() {
-				update_fulfill_dance!(node, prev_node, false);
+				update_fulfill_dance!(node, prev_node, false, idx != 1);
 			}
 
 			let events = node.node.get_and_clear_pending_events();
@@ -2988,7 +4859,7 @@ mod tests {
 		}
 
 		if !skip_last {
-			update_fulfill_dance!(origin_node, expected_route.first().unwrap(), true);
+			update_fulfill_dance!(origin_node, expected_route.first().unwrap(), true, expected_route.len() > 1);
 			let events = origin_node.node.get_and_clear_pending_events();
 			assert_eq!(events.len(), 1);
 			match events[0] {
@@ -3093,8 +4964,12 @@ mod tests {
 			let events = origin_node.node.get_and_clear_pending_events();
 			assert_eq!(events.len(), 1);
 			match events[0] {
-				Event::PaymentFailed { payment_hash } => {
+				Event::PaymentFailed { payment_hash, rejected_by_dest, ref failing_node, failing_short_channel_id, error_code, .. } => {
 					assert_eq!(payment_hash, our_payment_hash);
+					assert!(rejected_by_dest);
+					assert_eq!(*failing_node, Some(expected_route.last().unwrap().node.get_our_node_id()));
+					assert!(failing_short_channel_id.is_none());
+					assert_eq!(error_code, Some(0x4000 | 15));
 				},
 				_ => panic!("Unexpected event"),
 			}
@@ -3105,6 +4980,28 @@ mod tests {
 		fail_payment_along_route(origin_node, expected_route, false, our_payment_hash);
 	}
 
+	/// Delivers payment_event from origin_node to next_node, which fails the HTLC back as soon as it
+	/// is irrevocably committed, and has origin_node handle the failure, returning the network
+	/// update it gave.
+	fn fail_htlc_at_next_hop(origin_node: &Node, next_node: &Node, payment_event: SendEvent, fail_backwards: bool) -> Option<msgs::HTLCFailChannelUpdate> {
+		next_node.node.handle_update_add_htlc(&origin_node.node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		let (bs_revoke_and_ack, bs_commitment_signed) = next_node.node.handle_commitment_signed(&origin_node.node.get_our_node_id(), &payment_event.commitment_msg).unwrap();
+		check_added_monitors!(next_node, 1);
+		assert!(origin_node.node.handle_revoke_and_ack(&next_node.node.get_our_node_id(), &bs_revoke_and_ack).unwrap().is_none());
+		check_added_monitors!(origin_node, 1);
+		let (as_revoke_and_ack, as_none) = origin_node.node.handle_commitment_signed(&next_node.node.get_our_node_id(), &bs_commitment_signed.unwrap()).unwrap();
+		assert!(as_none.is_none());
+		check_added_monitors!(origin_node, 1);
+		let bs_fail = next_node.node.handle_revoke_and_ack(&origin_node.node.get_our_node_id(), &as_revoke_and_ack).unwrap().unwrap();
+		check_added_monitors!(next_node, 1);
+		assert_eq!(bs_fail.update_fail_htlcs.len(), 1);
+		assert!(next_node.node.get_and_clear_pending_events().is_empty());
+
+		let network_update = origin_node.node.handle_update_fail_htlc(&next_node.node.get_our_node_id(), &bs_fail.update_fail_htlcs[0]).unwrap();
+		commitment_signed_dance!(origin_node, next_node, bs_fail.commitment_signed, fail_backwards);
+		network_update
+	}
+
 	fn create_network(node_count: usize) -> Vec<Node> {
 		let mut nodes = Vec::new();
 		let mut rng = thread_rng();
@@ -3115,23 +5012,33 @@ mod tests {
 		let payment_count = Rc::new(RefCell::new(0));
 
 		for _ in 0..node_count {
-			let feeest = Arc::new(test_utils::TestFeeEstimator { sat_per_kw: 253 });
+			let feeest = Arc::new(test_utils::TestFeeEstimator::new(253));
 			let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
 			let tx_broadcaster = Arc::new(test_utils::TestBroadcaster{txn_broadcasted: Mutex::new(Vec::new())});
-			let chan_monitor = Arc::new(test_utils::TestChannelMonitor::new(chain_monitor.clone(), tx_broadcaster.clone()));
+			let chan_monitor = Arc::new(test_utils::TestChannelMonitor::new(chain_monitor.clone(), tx_broadcaster.clone(), feeest.clone()));
 			let node_id = {
 				let mut key_slice = [0; 32];
 				rng.fill_bytes(&mut key_slice);
 				SecretKey::from_slice(&secp_ctx, &key_slice).unwrap()
 			};
-			let node = ChannelManager::new(node_id.clone(), 0, true, Network::Testnet, feeest.clone(), chan_monitor.clone(), chain_monitor.clone(), tx_broadcaster.clone(), Arc::clone(&logger)).unwrap();
-			let router = Router::new(PublicKey::from_secret_key(&secp_ctx, &node_id), chain_monitor.clone(), Arc::clone(&logger));
-			nodes.push(Node { chain_monitor, tx_broadcaster, chan_monitor, node, router,
+			let wallet = Arc::new(test_utils::TestWallet::new(Builder::new().push_opcode(opcodes::All::OP_PUSHBYTES_0)
+				.push_slice(&Hash160::from_data(&PublicKey::from_secret_key(&secp_ctx, &node_id).serialize())[..]).into_script()));
+			let node = ChannelManager::new(node_id.clone(), 0, true, Network::Testnet, feeest.clone(), chan_monitor.clone(), chain_monitor.clone(), tx_broadcaster.clone(), wallet.clone(), Arc::clone(&logger)).unwrap();
+			let router = Arc::new(Router::new(PublicKey::from_secret_key(&secp_ctx, &node_id), chain_monitor.clone(), Arc::clone(&logger)));
+			nodes.push(Node { fee_estimator: feeest, chain_monitor, tx_broadcaster, chan_monitor, wallet, node, router,
 				network_payment_count: payment_count.clone(),
 				network_chan_count: chan_count.clone(),
 			});
 		}
 
+		for a in 0..node_count {
+			for b in 0..node_count {
+				if a != b {
+					assert!(nodes[a].node.peer_connected(&nodes[b].node.get_our_node_id(), &test_local_features()).is_empty());
+				}
+			}
+		}
+
 		nodes
 	}
 
@@ -3558,6 +5465,128 @@ mod tests {
 		close_channel(&nodes[0], &nodes[1], &chan.2, chan.3, true);
 	}
 
+	/// Delivers the update_fee generated by nodes[0] on its only pending event and completes the
+	/// commitment dance, checking both nodes end up at expected_feerate.
+	fn commit_automatic_update_fee(nodes: &Vec<Node>, channel_id: &[u8; 32], expected_feerate: u64) {
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let (update_fee, commitment_signed) = match events[0] {
+			Event::UpdateHTLCs { ref node_id, updates: msgs::CommitmentUpdate { ref update_add_htlcs, ref update_fee, ref commitment_signed, .. } } => {
+				assert_eq!(*node_id, nodes[1].node.get_our_node_id());
+				assert!(update_add_htlcs.is_empty());
+				(update_fee.as_ref().unwrap(), commitment_signed)
+			},
+			_ => panic!("Unexpected event"),
+		};
+		assert_eq!(update_fee.feerate_per_kw as u64, expected_feerate);
+		check_added_monitors!(nodes[0], 1);
+
+		nodes[1].node.handle_update_fee(&nodes[0].node.get_our_node_id(), update_fee).unwrap();
+		let (revoke_msg, commitment_signed) = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), commitment_signed).unwrap();
+		check_added_monitors!(nodes[1], 1);
+		assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &revoke_msg).unwrap().is_none());
+		check_added_monitors!(nodes[0], 1);
+		let (revoke_msg, commitment_signed) = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &commitment_signed.unwrap()).unwrap();
+		assert!(commitment_signed.is_none());
+		check_added_monitors!(nodes[0], 1);
+		assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &revoke_msg).unwrap().is_none());
+		check_added_monitors!(nodes[1], 1);
+
+		for node in nodes.iter() {
+			let chan_lock = node.node.channel_state.lock().unwrap();
+			assert_eq!(chan_lock.by_id.get(channel_id).unwrap().get_feerate(), expected_feerate);
+		}
+	}
+
+	#[test]
+	fn test_automatic_update_fee() {
+		let nodes = create_network(2);
+		let channel_id = create_announced_chan_between_nodes(&nodes, 0, 1).2;
+
+		// Small moves in our fee estimate are ignored
+		*nodes[0].fee_estimator.sat_per_kw.lock().unwrap() = 280;
+		nodes[0].node.timer_tick_occured();
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		*nodes[0].fee_estimator.sat_per_kw.lock().unwrap() = 400;
+		nodes[0].node.timer_tick_occured();
+		commit_automatic_update_fee(&nodes, &channel_id, 400);
+		nodes[0].node.timer_tick_occured();
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		// Only the funder sends update_fee
+		*nodes[1].fee_estimator.sat_per_kw.lock().unwrap() = 200;
+		nodes[1].node.timer_tick_occured();
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+
+		// Fee decreases are also picked up on block connection
+		*nodes[0].fee_estimator.sat_per_kw.lock().unwrap() = 300;
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		nodes[0].node.block_connected(&header, 1, &[], &[]);
+		commit_automatic_update_fee(&nodes, &channel_id, 300);
+	}
+
+	#[test]
+	fn test_automatic_update_fee_reserve() {
+		// nodes[0] only has 5000 sat, 1000 sat of which is the reserve required by nodes[1], so can
+		// afford a commitment transaction fee of at most 4000 sat, ie a feerate of 4000000/724.
+		let nodes = create_network(2);
+		let channel_id = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 95000000).2;
+		*nodes[1].fee_estimator.sat_per_kw.lock().unwrap() = 3000;
+
+		*nodes[0].fee_estimator.sat_per_kw.lock().unwrap() = 10000;
+		nodes[0].node.timer_tick_occured();
+		commit_automatic_update_fee(&nodes, &channel_id, 4000000 / 724);
+
+		// We're already at the highest feerate we can afford, so don't bother updating again
+		nodes[0].node.timer_tick_occured();
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn test_automatic_update_fee_monitor_failure() {
+		// If the monitor update for an automatic update_fee temporarily fails, we hold the
+		// update_fee and commitment_signed until test_restore_channel_monitor stores it.
+		let nodes = create_network(2);
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let channel_id = chan.2;
+
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::TemporaryFailure);
+		*nodes[0].fee_estimator.sat_per_kw.lock().unwrap() = 400;
+		nodes[0].node.timer_tick_occured();
+		check_added_monitors!(nodes[0], 1);
+		assert_eq!(nodes[0].node.list_channels().len(), 1);
+		assert!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		// We don't try to update the fee again while the channel is paused
+		nodes[0].node.timer_tick_occured();
+		check_added_monitors!(nodes[0], 0);
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Ok(());
+		nodes[0].node.test_restore_channel_monitor();
+		commit_automatic_update_fee(&nodes, &channel_id, 400);
+	}
+
+	#[test]
+	fn test_automatic_update_fee_permanent_monitor_failure() {
+		// If we can never persist the monitor update for an automatic update_fee we can't send the
+		// commitment_signed, so the channel gets closed instead.
+		let nodes = create_network(2);
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1);
+
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::PermanentFailure);
+		*nodes[0].fee_estimator.sat_per_kw.lock().unwrap() = 400;
+		nodes[0].node.timer_tick_occured();
+		check_added_monitors!(nodes[0], 1);
+		assert_eq!(nodes[0].node.list_channels().len(), 0);
+		test_txn_broadcast(&nodes[0], &chan, None, HTLCType::NONE);
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		check_closed_event!(events[1], ClosureReason::MonitorUpdateFailed);
+	}
+
 	#[test]
 	fn test_update_fee() {
 		let nodes = create_network(2);
@@ -3888,22 +5917,30 @@ mod tests {
 
 	fn get_announce_close_broadcast_events(nodes: &Vec<Node>, a: usize, b: usize) {
 		let events_1 = nodes[a].node.get_and_clear_pending_events();
-		assert_eq!(events_1.len(), 1);
+		assert_eq!(events_1.len(), 2);
 		let as_update = match events_1[0] {
 			Event::BroadcastChannelUpdate { ref msg } => {
 				msg.clone()
 			},
 			_ => panic!("Unexpected event"),
 		};
+		match events_1[1] {
+			Event::ChannelClosed { .. } => {},
+			_ => panic!("Unexpected event"),
+		}
 
 		let events_2 = nodes[b].node.get_and_clear_pending_events();
-		assert_eq!(events_2.len(), 1);
+		assert_eq!(events_2.len(), 2);
 		let bs_update = match events_2[0] {
 			Event::BroadcastChannelUpdate { ref msg } => {
 				msg.clone()
 			},
 			_ => panic!("Unexpected event"),
 		};
+		match events_2[1] {
+			Event::ChannelClosed { .. } => {},
+			_ => panic!("Unexpected event"),
+		}
 
 		for node in nodes {
 			node.router.handle_channel_update(&as_update).unwrap();
@@ -3971,7 +6008,7 @@ mod tests {
 				let events = $node.node.get_and_clear_pending_events();
 				assert_eq!(events.len(), 1);
 				match events[0] {
-					Event::PaymentReceived { ref payment_hash, amt } => {
+					Event::PaymentReceived { ref payment_hash, amt, .. } => {
 						assert_eq!($expected_payment_hash, *payment_hash);
 						assert_eq!($expected_recv_value, amt);
 					},
@@ -4071,7 +6108,7 @@ mod tests {
 
 			let cur_height = nodes[0].node.latest_block_height.load(Ordering::Acquire) as u32 + 1;
 			let onion_keys = ChannelManager::construct_onion_keys(&secp_ctx, &route, &session_priv).unwrap();
-			let (onion_payloads, htlc_msat, htlc_cltv) = ChannelManager::build_onion_payloads(&route, cur_height).unwrap();
+			let (onion_payloads, htlc_msat, htlc_cltv) = ChannelManager::build_onion_payloads(&route, &None, cur_height).unwrap();
 			let onion_packet = ChannelManager::construct_onion_packet(onion_payloads, onion_keys, &our_payment_hash);
 			let msg = msgs::UpdateAddHTLC {
 				channel_id: chan_1.2,
@@ -4159,14 +6196,14 @@ mod tests {
 		let events = nodes[2].node.get_and_clear_pending_events();
 		assert_eq!(events.len(), 2);
 		match events[0] {
-			Event::PaymentReceived { ref payment_hash, amt } => {
+			Event::PaymentReceived { ref payment_hash, amt, .. } => {
 				assert_eq!(our_payment_hash_21, *payment_hash);
 				assert_eq!(recv_value_21, amt);
 			},
 			_ => panic!("Unexpected event"),
 		}
 		match events[1] {
-			Event::PaymentReceived { ref payment_hash, amt } => {
+			Event::PaymentReceived { ref payment_hash, amt, .. } => {
 				assert_eq!(our_payment_hash_22, *payment_hash);
 				assert_eq!(recv_value_22, amt);
 			},
@@ -4269,6 +6306,13 @@ mod tests {
 		assert_eq!(nodes[2].node.list_channels().len(), 0);
 		assert_eq!(nodes[3].node.list_channels().len(), 1);
 
+		// nodes[3] is now at height 1, so move nodes[4] there too or it'll reject the HTLC below as
+		// expiring too soon.
+		{
+			let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+			nodes[4].chain_monitor.block_connected_checked(&header, 1, &Vec::new()[..], &[0; 0]);
+		}
+
 		// One pending HTLC to time out:
 		let payment_preimage_2 = route_payment(&nodes[3], &vec!(&nodes[4])[..], 3000000).0;
 
@@ -4523,13 +6567,14 @@ mod tests {
 		nodes[0].node.force_close_channel(&nodes[0].node.list_channels()[0].channel_id);
 		{
 			let events = nodes[0].node.get_and_clear_pending_events();
-			assert_eq!(events.len(), 1);
+			assert_eq!(events.len(), 2);
 			match events[0] {
 				Event::BroadcastChannelUpdate { msg: msgs::ChannelUpdate { contents: msgs::UnsignedChannelUpdate { flags, .. }, .. } } => {
 					assert_eq!(flags & 0b10, 0b10);
 				},
 				_ => panic!("Unexpected event"),
 			}
+			check_closed_event!(events[1], ClosureReason::HolderForceClosed);
 		}
 
 		let node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
@@ -4540,13 +6585,14 @@ mod tests {
 
 		{
 			let events = nodes[1].node.get_and_clear_pending_events();
-			assert_eq!(events.len(), 1);
+			assert_eq!(events.len(), 2);
 			match events[0] {
 				Event::BroadcastChannelUpdate { msg: msgs::ChannelUpdate { contents: msgs::UnsignedChannelUpdate { flags, .. }, .. } } => {
 					assert_eq!(flags & 0b10, 0b10);
 				},
 				_ => panic!("Unexpected event"),
 			}
+			check_closed_event!(events[1], ClosureReason::CounterpartyForceClosed { peer_msg: None });
 		}
 
 		// Duplicate the block_connected call since this may happen due to other listeners
@@ -4603,13 +6649,14 @@ mod tests {
 
 		nodes[2].node.force_close_channel(&payment_event.commitment_msg.channel_id);
 		let events_3 = nodes[2].node.get_and_clear_pending_events();
-		assert_eq!(events_3.len(), 1);
+		assert_eq!(events_3.len(), 2);
 		match events_3[0] {
 			Event::BroadcastChannelUpdate { msg: msgs::ChannelUpdate { contents: msgs::UnsignedChannelUpdate { flags, .. }, .. } } => {
 				assert_eq!(flags & 0b10, 0b10);
 			},
 			_ => panic!("Unexpected event"),
 		}
+		check_closed_event!(events_3[1], ClosureReason::HolderForceClosed);
 
 		let tx = {
 			let mut node_txn = nodes[2].tx_broadcaster.txn_broadcasted.lock().unwrap();
@@ -4625,13 +6672,14 @@ mod tests {
 
 		let events_4 = nodes[1].node.get_and_clear_pending_events();
 		// Note no UpdateHTLCs event here from nodes[1] to nodes[0]!
-		assert_eq!(events_4.len(), 1);
+		assert_eq!(events_4.len(), 2);
 		match events_4[0] {
 			Event::BroadcastChannelUpdate { msg: msgs::ChannelUpdate { contents: msgs::UnsignedChannelUpdate { flags, .. }, .. } } => {
 				assert_eq!(flags & 0b10, 0b10);
 			},
 			_ => panic!("Unexpected event"),
 		}
+		check_closed_event!(events_4[1], ClosureReason::CounterpartyForceClosed { peer_msg: None });
 
 		// Now check that if we add the preimage to ChannelMonitor it broadcasts our HTLC-Success..
 		{
@@ -4673,24 +6721,61 @@ mod tests {
 		}
 		{
 			let events = nodes[0].node.get_and_clear_pending_events();
-			assert_eq!(events.len(), 1);
+			assert_eq!(events.len(), 2);
 			match events[0] {
 				Event::BroadcastChannelUpdate { msg: msgs::ChannelUpdate { contents: msgs::UnsignedChannelUpdate { flags, .. }, .. } } => {
 					assert_eq!(flags & 0b10, 0b10);
 				},
 				_ => panic!("Unexpected event"),
 			}
+			check_closed_event!(events[1], ClosureReason::FundingTxUnconfirmed);
 		}
 		let channel_state = nodes[0].node.channel_state.lock().unwrap();
 		assert_eq!(channel_state.by_id.len(), 0);
 		assert_eq!(channel_state.short_to_id.len(), 0);
 	}
 
+	/// The local features our test nodes send each other in their init messages
+	fn test_local_features() -> msgs::LocalFeatures {
+		let mut features = msgs::LocalFeatures::new();
+		features.set_supports_data_loss_protect();
+		features.set_supports_upfront_shutdown_script();
+		features
+	}
+
 	/// pending_htlc_adds includes both the holding cell and in-flight update_add_htlcs, whereas
 	/// for claims/fails they are separated out.
 	fn reconnect_nodes(node_a: &Node, node_b: &Node, pre_all_htlcs: bool, pending_htlc_adds: (i64, i64), pending_htlc_claims: (usize, usize), pending_cell_htlc_claims: (usize, usize), pending_cell_htlc_fails: (usize, usize), pending_raa: (bool, bool)) {
-		let reestablish_1 = node_a.node.peer_connected(&node_b.node.get_our_node_id());
-		let reestablish_2 = node_b.node.peer_connected(&node_a.node.get_our_node_id());
+		// Pulls out any announcement_signatures the node wants to send, leaving other events alone
+		macro_rules! take_announcement_sigs {
+			($node: expr, $sigs: expr) => { {
+				for event in $node.node.get_and_clear_pending_events() {
+					if let Event::SendAnnouncementSignatures { ref msg, .. } = event {
+						$sigs.push(msg.clone());
+						continue;
+					}
+					$node.node.pending_events.lock().unwrap().push(event);
+				}
+			} }
+		}
+		// Feeds any channel_announcement the node broadcast into both nodes' routers
+		macro_rules! handle_channel_announcements {
+			($node: expr) => { {
+				for event in $node.node.get_and_clear_pending_events() {
+					if let Event::BroadcastChannelAnnouncement { ref msg, ref update_msg } = event {
+						for router_node in [node_a, node_b].iter() {
+							let _ = router_node.router.handle_channel_announcement(msg);
+							let _ = router_node.router.handle_channel_update(update_msg);
+						}
+						continue;
+					}
+					$node.node.pending_events.lock().unwrap().push(event);
+				}
+			} }
+		}
+
+		let reestablish_1 = node_a.node.peer_connected(&node_b.node.get_our_node_id(), &test_local_features());
+		let reestablish_2 = node_b.node.peer_connected(&node_a.node.get_our_node_id(), &test_local_features());
 
 		let mut resp_1 = Vec::new();
 		for msg in reestablish_1 {
@@ -4712,15 +6797,20 @@ mod tests {
 			check_added_monitors!(node_a, 0);
 		}
 
+		let mut as_announcement_sigs = Vec::new();
+		let mut bs_announcement_sigs = Vec::new();
+		take_announcement_sigs!(node_a, as_announcement_sigs);
+		take_announcement_sigs!(node_b, bs_announcement_sigs);
+
 		// We dont yet support both needing updates, as that would require a different commitment dance:
 		assert!((pending_htlc_adds.0 == 0 && pending_htlc_claims.0 == 0 && pending_cell_htlc_claims.0 == 0 && pending_cell_htlc_fails.0 == 0) ||
 		        (pending_htlc_adds.1 == 0 && pending_htlc_claims.1 == 0 && pending_cell_htlc_claims.1 == 0 && pending_cell_htlc_fails.1 == 0));
 
 		for chan_msgs in resp_1.drain(..) {
 			if pre_all_htlcs {
-				let a = node_a.node.handle_funding_locked(&node_b.node.get_our_node_id(), &chan_msgs.0.unwrap());
-				let _announcement_sigs_opt = a.unwrap();
-				//TODO: Test announcement_sigs re-sending when we've implemented it
+				if let Some(announcement_sigs) = node_a.node.handle_funding_locked(&node_b.node.get_our_node_id(), &chan_msgs.0.unwrap()).unwrap() {
+					as_announcement_sigs.push(announcement_sigs);
+				}
 			} else {
 				assert!(chan_msgs.0.is_none());
 			}
@@ -4766,8 +6856,9 @@ mod tests {
 
 		for chan_msgs in resp_2.drain(..) {
 			if pre_all_htlcs {
-				let _announcement_sigs_opt = node_b.node.handle_funding_locked(&node_a.node.get_our_node_id(), &chan_msgs.0.unwrap()).unwrap();
-				//TODO: Test announcement_sigs re-sending when we've implemented it
+				if let Some(announcement_sigs) = node_b.node.handle_funding_locked(&node_a.node.get_our_node_id(), &chan_msgs.0.unwrap()).unwrap() {
+					bs_announcement_sigs.push(announcement_sigs);
+				}
 			} else {
 				assert!(chan_msgs.0.is_none());
 			}
@@ -4808,6 +6899,16 @@ mod tests {
 				assert!(chan_msgs.2.is_none());
 			}
 		}
+
+		// Finish exchanging announcement_signatures for any channels which didn't before
+		for announcement_sigs in as_announcement_sigs.drain(..) {
+			node_b.node.handle_announcement_signatures(&node_a.node.get_our_node_id(), &announcement_sigs).unwrap();
+		}
+		for announcement_sigs in bs_announcement_sigs.drain(..) {
+			node_a.node.handle_announcement_signatures(&node_b.node.get_our_node_id(), &announcement_sigs).unwrap();
+		}
+		handle_channel_announcements!(node_a);
+		handle_channel_announcements!(node_b);
 	}
 
 	#[test]
@@ -4852,11 +6953,19 @@ mod tests {
 				_ => panic!("Unexpected event"),
 			}
 			match events[1] {
-				Event::PaymentFailed { payment_hash } => {
+				Event::PaymentFailed { payment_hash, rejected_by_dest, .. } => {
 					assert_eq!(payment_hash, payment_hash_5);
+					assert!(rejected_by_dest);
 				},
 				_ => panic!("Unexpected event"),
 			}
+			// nodes[1]'s claim of payment_preimage_3 was only committed after the reconnect
+			let events = nodes[1].node.get_and_clear_pending_events();
+			assert_eq!(events.len(), 1);
+			match events[0] {
+				Event::PaymentForwarded { .. } => {},
+				_ => panic!("Unexpected event"),
+			}
 		}
 
 		claim_payment(&nodes[0], &vec!(&nodes[1], &nodes[2]), payment_preimage_4);
@@ -4930,8 +7039,17 @@ mod tests {
 			reconnect_nodes(&nodes[0], &nodes[1], false, (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
 		}
 
-		let events_1 = nodes[1].node.get_and_clear_pending_events();
-		assert_eq!(events_1.len(), 1);
+		let mut events_1 = nodes[1].node.get_and_clear_pending_events();
+		if messages_delivered == 0 {
+			// nodes[1] only now received nodes[0]'s funding_locked
+			assert_eq!(events_1.len(), 2);
+			match events_1.remove(0) {
+				Event::ChannelReady { .. } => {},
+				_ => panic!("Unexpected event"),
+			}
+		} else {
+			assert_eq!(events_1.len(), 1);
+		}
 		match events_1[0] {
 			Event::PendingHTLCsForwardable { .. } => { },
 			_ => panic!("Unexpected event"),
@@ -4947,7 +7065,7 @@ mod tests {
 		let events_2 = nodes[1].node.get_and_clear_pending_events();
 		assert_eq!(events_2.len(), 1);
 		match events_2[0] {
-			Event::PaymentReceived { ref payment_hash, amt } => {
+			Event::PaymentReceived { ref payment_hash, amt, .. } => {
 				assert_eq!(payment_hash_1, *payment_hash);
 				assert_eq!(amt, 1000000);
 			},
@@ -5044,14 +7162,539 @@ mod tests {
 		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage_2);
 	}
 
-	#[test]
-	fn test_drop_messages_peer_disconnect_a() {
-		do_test_drop_messages_peer_disconnect(0);
-		do_test_drop_messages_peer_disconnect(1);
-		do_test_drop_messages_peer_disconnect(2);
-	}
+	fn do_test_update_fee_peer_disconnect(messages_delivered: u8) {
+		// Test that an update_fee survives the loss of any of the messages in its commitment dance
+		// across a reconnection, ending with both sides at the new feerate.
+		let nodes = create_network(2);
+		let channel_id = create_announced_chan_between_nodes(&nodes, 0, 1).2;
 
-	#[test]
+		macro_rules! get_feerate {
+			($node: expr) => {{
+				let chan_lock = $node.node.channel_state.lock().unwrap();
+				let chan = chan_lock.by_id.get(&channel_id).unwrap();
+				chan.get_feerate()
+			}}
+		}
+
+		let new_feerate = get_feerate!(nodes[0]) + 20;
+		nodes[0].node.update_fee(channel_id, new_feerate).unwrap();
+		check_added_monitors!(nodes[0], 1);
+
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let (update_fee, commitment_signed) = match events[0] {
+			Event::UpdateHTLCs { updates: msgs::CommitmentUpdate { ref update_fee, ref commitment_signed, .. }, .. } => {
+				(update_fee.as_ref().unwrap(), commitment_signed)
+			},
+			_ => panic!("Unexpected event"),
+		};
+
+		if messages_delivered >= 1 {
+			nodes[1].node.handle_update_fee(&nodes[0].node.get_our_node_id(), update_fee).unwrap();
+			if messages_delivered >= 2 {
+				let (bs_revoke_and_ack, bs_commitment_signed) = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), commitment_signed).unwrap();
+				check_added_monitors!(nodes[1], 1);
+				assert_eq!(get_feerate!(nodes[1]), new_feerate);
+				if messages_delivered >= 3 {
+					assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &bs_revoke_and_ack).unwrap().is_none());
+					check_added_monitors!(nodes[0], 1);
+					if messages_delivered >= 4 {
+						let (as_revoke_and_ack, as_commitment_signed) = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &bs_commitment_signed.unwrap()).unwrap();
+						assert!(as_commitment_signed.is_none());
+						check_added_monitors!(nodes[0], 1);
+						if messages_delivered >= 5 {
+							assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &as_revoke_and_ack).unwrap().is_none());
+							check_added_monitors!(nodes[1], 1);
+						}
+					}
+				}
+			}
+		}
+
+		nodes[0].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+		nodes[1].node.peer_disconnected(&nodes[0].node.get_our_node_id(), false);
+
+		let as_reestablish = nodes[0].node.peer_connected(&nodes[1].node.get_our_node_id(), &test_local_features());
+		let bs_reestablish = nodes[1].node.peer_connected(&nodes[0].node.get_our_node_id(), &test_local_features());
+		assert_eq!(as_reestablish.len(), 1);
+		assert_eq!(bs_reestablish.len(), 1);
+		let (bs_funding_locked, bs_revoke_and_ack, bs_commitment_update) = nodes[1].node.handle_channel_reestablish(&nodes[0].node.get_our_node_id(), &as_reestablish[0]).unwrap();
+		let (as_funding_locked, as_revoke_and_ack, as_commitment_update) = nodes[0].node.handle_channel_reestablish(&nodes[1].node.get_our_node_id(), &bs_reestablish[0]).unwrap();
+		check_added_monitors!(nodes[0], 0);
+		check_added_monitors!(nodes[1], 0);
+		// Until a side has received its first commitment_signed the funding_locked messages are
+		// re-sent as well
+		if let Some(msg) = bs_funding_locked {
+			nodes[0].node.handle_funding_locked(&nodes[1].node.get_our_node_id(), &msg).unwrap();
+		}
+		if let Some(msg) = as_funding_locked {
+			nodes[1].node.handle_funding_locked(&nodes[0].node.get_our_node_id(), &msg).unwrap();
+		}
+
+		if messages_delivered < 2 {
+			// nodes[1] never got our commitment_signed, so nodes[0] re-sends it along with the
+			// update_fee and we run the whole dance again
+			assert!(as_revoke_and_ack.is_none() && bs_revoke_and_ack.is_none() && bs_commitment_update.is_none());
+			let commitment_update = as_commitment_update.unwrap();
+			assert!(commitment_update.update_add_htlcs.is_empty() && commitment_update.update_fulfill_htlcs.is_empty());
+			assert!(commitment_update.update_fail_htlcs.is_empty() && commitment_update.update_fail_malformed_htlcs.is_empty());
+			assert_eq!(commitment_update.update_fee.as_ref().unwrap().feerate_per_kw as u64, new_feerate);
+			nodes[1].node.handle_update_fee(&nodes[0].node.get_our_node_id(), commitment_update.update_fee.as_ref().unwrap()).unwrap();
+			let (bs_revoke_and_ack, bs_commitment_signed) = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &commitment_update.commitment_signed).unwrap();
+			check_added_monitors!(nodes[1], 1);
+			assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &bs_revoke_and_ack).unwrap().is_none());
+			check_added_monitors!(nodes[0], 1);
+			let (as_revoke_and_ack, as_commitment_signed) = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &bs_commitment_signed.unwrap()).unwrap();
+			assert!(as_commitment_signed.is_none());
+			check_added_monitors!(nodes[0], 1);
+			assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &as_revoke_and_ack).unwrap().is_none());
+			check_added_monitors!(nodes[1], 1);
+		} else if messages_delivered < 4 {
+			// nodes[0] still wants nodes[1]'s commitment_signed (and its RAA if it was lost)
+			assert!(as_revoke_and_ack.is_none() && as_commitment_update.is_none());
+			if messages_delivered == 2 {
+				assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &bs_revoke_and_ack.unwrap()).unwrap().is_none());
+				check_added_monitors!(nodes[0], 1);
+			} else {
+				assert!(bs_revoke_and_ack.is_none());
+			}
+			let commitment_update = bs_commitment_update.unwrap();
+			assert!(commitment_update.update_fee.is_none());
+			let (as_revoke_and_ack, as_commitment_signed) = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &commitment_update.commitment_signed).unwrap();
+			assert!(as_commitment_signed.is_none());
+			check_added_monitors!(nodes[0], 1);
+			assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &as_revoke_and_ack).unwrap().is_none());
+			check_added_monitors!(nodes[1], 1);
+		} else if messages_delivered == 4 {
+			// nodes[1] still wants its final RAA
+			assert!(bs_revoke_and_ack.is_none() && bs_commitment_update.is_none() && as_commitment_update.is_none());
+			assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &as_revoke_and_ack.unwrap()).unwrap().is_none());
+			check_added_monitors!(nodes[1], 1);
+		} else {
+			// Everything was delivered...
+			assert!(as_revoke_and_ack.is_none() && bs_revoke_and_ack.is_none());
+			assert!(as_commitment_update.is_none() && bs_commitment_update.is_none());
+		}
+
+		assert_eq!(get_feerate!(nodes[0]), new_feerate);
+		assert_eq!(get_feerate!(nodes[1]), new_feerate);
+
+		// Channel should still work fine...
+		let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 1000000).0;
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	}
+
+	#[test]
+	fn test_update_fee_peer_disconnect() {
+		do_test_update_fee_peer_disconnect(0);
+		do_test_update_fee_peer_disconnect(1);
+		do_test_update_fee_peer_disconnect(2);
+		do_test_update_fee_peer_disconnect(3);
+		do_test_update_fee_peer_disconnect(4);
+		do_test_update_fee_peer_disconnect(5);
+	}
+
+	#[test]
+	fn test_close_channel_fee_negotiation() {
+		// Test that close_channel_with_fee drives the funder's closing fee proposals and that the
+		// funder moves halfway towards the fundee's counter-proposals, within its maximum fee.
+		// With two P2WPKH outputs the closing transaction weight we use is 602.
+		let nodes = create_network(2);
+		let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 0, 1);
+
+		macro_rules! get_shutdown {
+			($node: expr) => {
+				{
+					let mut events = $node.node.get_and_clear_pending_events();
+					assert_eq!(events.len(), 1);
+					match events.pop().unwrap() {
+						Event::SendShutdown { msg, .. } => msg,
+						_ => panic!("Unexpected event"),
+					}
+				}
+			}
+		}
+
+		// nodes[1] wants at least 700 sat in fees (its Background feerate)
+		*nodes[1].fee_estimator.sat_per_kw.lock().unwrap() = 1163;
+		nodes[0].node.close_channel_with_fee(&chan_1.2, 830, 650).unwrap();
+		let shutdown_a = get_shutdown!(nodes[0]);
+		let (shutdown_b, closing_signed_b) = nodes[1].node.handle_shutdown(&nodes[0].node.get_our_node_id(), &shutdown_a).unwrap();
+		assert!(closing_signed_b.is_none());
+		let (empty_a, closing_signed_a) = nodes[0].node.handle_shutdown(&nodes[1].node.get_our_node_id(), &shutdown_b.unwrap()).unwrap();
+		assert!(empty_a.is_none());
+		let closing_signed_a = closing_signed_a.unwrap();
+		assert_eq!(closing_signed_a.fee_satoshis, 499);
+
+		let closing_signed_b = nodes[1].node.handle_closing_signed(&nodes[0].node.get_our_node_id(), &closing_signed_a).unwrap().unwrap();
+		assert_eq!(closing_signed_b.fee_satoshis, 700);
+		let closing_signed_a = nodes[0].node.handle_closing_signed(&nodes[1].node.get_our_node_id(), &closing_signed_b).unwrap().unwrap();
+		assert_eq!(closing_signed_a.fee_satoshis, 599);
+		assert!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+		assert!(nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+
+		// Once nodes[1]'s fee estimate drops it'll accept the proposal
+		*nodes[1].fee_estimator.sat_per_kw.lock().unwrap() = 913;
+		let closing_signed_b = nodes[1].node.handle_closing_signed(&nodes[0].node.get_our_node_id(), &closing_signed_a).unwrap().unwrap();
+		assert_eq!(closing_signed_b.fee_satoshis, 599);
+		assert!(nodes[0].node.handle_closing_signed(&nodes[1].node.get_our_node_id(), &closing_signed_b).unwrap().is_none());
+
+		for node in nodes.iter() {
+			let closing_tx = node.tx_broadcaster.txn_broadcasted.lock().unwrap().split_off(0);
+			assert_eq!(closing_tx.len(), 1);
+			check_spends!(closing_tx[0], chan_1.3.clone());
+			let events = node.node.get_and_clear_pending_events();
+			assert_eq!(events.len(), 2);
+			match events[0] {
+				Event::BroadcastChannelUpdate { .. } => {},
+				_ => panic!("Unexpected event"),
+			}
+			match events[1] {
+				Event::ChannelClosed { ref channel_id, ref closing_transaction, fee_satoshis, .. } => {
+					assert_eq!(*channel_id, chan_1.2);
+					assert_eq!(*closing_transaction.as_ref().unwrap(), closing_tx[0]);
+					// nodes[1]'s balance is dust, so it's added to the negotiated fee
+					assert_eq!(fee_satoshis.unwrap(), chan_1.3.output[0].value - closing_tx[0].output[0].value);
+					assert!(fee_satoshis.unwrap() > 599);
+				},
+				_ => panic!("Unexpected event"),
+			}
+		}
+
+		// If nodes[1] insists on more than nodes[0]'s maximum fee, negotiation fails once neither
+		// side can move any further
+		*nodes[1].fee_estimator.sat_per_kw.lock().unwrap() = 2000;
+		nodes[0].node.close_channel_with_fee(&chan_2.2, 830, 650).unwrap();
+		let shutdown_a = get_shutdown!(nodes[0]);
+		let (shutdown_b, _) = nodes[1].node.handle_shutdown(&nodes[0].node.get_our_node_id(), &shutdown_a).unwrap();
+		let closing_signed_a = nodes[0].node.handle_shutdown(&nodes[1].node.get_our_node_id(), &shutdown_b.unwrap()).unwrap().1.unwrap();
+		assert_eq!(closing_signed_a.fee_satoshis, 499);
+		let closing_signed_b = nodes[1].node.handle_closing_signed(&nodes[0].node.get_our_node_id(), &closing_signed_a).unwrap().unwrap();
+		assert_eq!(closing_signed_b.fee_satoshis, 1204);
+		let closing_signed_a = nodes[0].node.handle_closing_signed(&nodes[1].node.get_our_node_id(), &closing_signed_b).unwrap().unwrap();
+		assert_eq!(closing_signed_a.fee_satoshis, 650);
+		match nodes[1].node.handle_closing_signed(&nodes[0].node.get_our_node_id(), &closing_signed_a) {
+			Err(msgs::HandleError { err, .. }) => assert_eq!(err, "Unable to come to consensus about closing fee, remote wanted something lower than our Background feerate"),
+			_ => panic!("Unexpected result"),
+		}
+	}
+
+	#[test]
+	fn test_channel_closed_reasons() {
+		// Test the ChannelClosed reasons for channels closed by our counterparty's error message and
+		// by a permanent ChannelMonitor update failure.
+		let nodes = create_network(2);
+		let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 0, 1);
+
+		nodes[0].node.handle_error(&nodes[1].node.get_our_node_id(), &msgs::ErrorMessage { channel_id: chan_1.2, data: "Oops".to_string() });
+		let local_txn = test_txn_broadcast(&nodes[0], &chan_1, None, HTLCType::NONE);
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		match events[1] {
+			Event::ChannelClosed { ref channel_id, ref reason, ref closing_transaction, fee_satoshis, .. } => {
+				assert_eq!(*channel_id, chan_1.2);
+				assert_eq!(*reason, ClosureReason::CounterpartyForceClosed { peer_msg: Some("Oops".to_string()) });
+				assert_eq!(*closing_transaction, Some(local_txn[0].clone()));
+				assert!(fee_satoshis.is_none());
+			},
+			_ => panic!("Unexpected event"),
+		}
+		nodes[1].node.force_close_channel(&chan_1.2);
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		check_closed_event!(events[1], ClosureReason::HolderForceClosed);
+
+		*nodes[1].chan_monitor.update_ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::PermanentFailure);
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), Some(&nodes[0].node.list_usable_channels()), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+		nodes[0].node.send_payment(route, payment_hash).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		let payment_event = {
+			let mut events = nodes[0].node.get_and_clear_pending_events();
+			assert_eq!(events.len(), 1);
+			SendEvent::from_event(events.remove(0))
+		};
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		assert!(nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &payment_event.commitment_msg).is_err());
+		check_added_monitors!(nodes[1], 1);
+		assert_eq!(nodes[1].node.list_channels().len(), 0);
+		test_txn_broadcast(&nodes[1], &chan_2, None, HTLCType::NONE);
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		match events[1] {
+			Event::ChannelClosed { ref channel_id, ref reason, .. } => {
+				assert_eq!(*channel_id, chan_2.2);
+				assert_eq!(*reason, ClosureReason::MonitorUpdateFailed);
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	#[test]
+	fn test_temporary_monitor_failure_pauses_channel() {
+		// A TemporaryFailure while handling a peer's revoke_and_ack pauses the channel, holding
+		// back the revoke_and_ack for their next commitment_signed until
+		// test_restore_channel_monitor manages to store the ChannelMonitor.
+		let nodes = create_network(2);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let (payment_preimage, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+		nodes[0].node.send_payment(route, payment_hash).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		let payment_event = {
+			let mut events = nodes[0].node.get_and_clear_pending_events();
+			assert_eq!(events.len(), 1);
+			SendEvent::from_event(events.remove(0))
+		};
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		let (revoke_and_ack, commitment_signed) = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &payment_event.commitment_msg).unwrap();
+		check_added_monitors!(nodes[1], 1);
+
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::TemporaryFailure);
+		assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &revoke_and_ack).is_err());
+		check_added_monitors!(nodes[0], 1);
+		assert_eq!(nodes[0].node.list_channels().len(), 1);
+		assert!(nodes[0].node.list_usable_channels().is_empty());
+		assert!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		// Their commitment_signed is processed, but we can't revoke our previous commitment until
+		// the ChannelMonitor has seen the new one.
+		assert!(nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &commitment_signed.unwrap()).is_err());
+		check_added_monitors!(nodes[0], 0);
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		// Still failing, so we stay paused
+		nodes[0].node.test_restore_channel_monitor();
+		check_added_monitors!(nodes[0], 1);
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Ok(());
+		nodes[0].node.test_restore_channel_monitor();
+		check_added_monitors!(nodes[0], 1);
+		assert_eq!(nodes[0].node.list_usable_channels().len(), 1);
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let revoke_and_ack = match events.remove(0) {
+			Event::SendRevokeAndACK { node_id, msg } => {
+				assert_eq!(node_id, nodes[1].node.get_our_node_id());
+				msg
+			},
+			_ => panic!("Unexpected event"),
+		};
+		assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &revoke_and_ack).unwrap().is_none());
+		check_added_monitors!(nodes[1], 1);
+
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PendingHTLCsForwardable { .. } => { },
+			_ => panic!("Unexpected event"),
+		};
+		nodes[1].node.channel_state.lock().unwrap().next_forward = Instant::now();
+		nodes[1].node.process_pending_htlc_forwards();
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentReceived { payment_hash: ref received_hash, amt, .. } => {
+				assert_eq!(*received_hash, payment_hash);
+				assert_eq!(amt, 1000000);
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	}
+
+	#[test]
+	fn test_upfront_shutdown_script() {
+		// Test that both sides commit to their wallet's shutdown script in open_channel and
+		// accept_channel, and that a shutdown with a different script fails the channel.
+		let nodes = create_network(3);
+		let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2);
+
+		let channels = nodes[0].node.list_channels();
+		assert_eq!(channels.len(), 1);
+		assert_eq!(channels[0].shutdown_scriptpubkey, nodes[0].wallet.shutdown_scriptpubkey);
+		assert_eq!(channels[0].their_shutdown_scriptpubkey, Some(nodes[1].wallet.shutdown_scriptpubkey.clone()));
+		let channel = nodes[1].node.list_channels().into_iter().find(|chan| chan.channel_id == chan_1.2).unwrap();
+		assert_eq!(channel.shutdown_scriptpubkey, nodes[1].wallet.shutdown_scriptpubkey);
+		assert_eq!(channel.their_shutdown_scriptpubkey, Some(nodes[0].wallet.shutdown_scriptpubkey.clone()));
+
+		// A cooperative close pays out to the committed scripts (checked in close_channel)
+		close_channel(&nodes[0], &nodes[1], &chan_1.2, chan_1.3, true);
+
+		// nodes[2] tries to shut down to a script other than the one it committed to
+		nodes[2].node.close_channel(&chan_2.2).unwrap();
+		let events = nodes[2].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let shutdown = match events[0] {
+			Event::SendShutdown { ref msg, .. } => {
+				assert_eq!(msg.scriptpubkey, nodes[2].wallet.shutdown_scriptpubkey);
+				msg.clone()
+			},
+			_ => panic!("Unexpected event"),
+		};
+		let bad_shutdown = msgs::Shutdown {
+			channel_id: shutdown.channel_id,
+			scriptpubkey: nodes[0].wallet.shutdown_scriptpubkey.clone(),
+		};
+		match nodes[1].node.handle_shutdown(&nodes[2].node.get_our_node_id(), &bad_shutdown) {
+			Err(msgs::HandleError { action: Some(msgs::ErrorAction::SendErrorMessage { .. }), .. }) => {},
+			_ => panic!("Unexpected result"),
+		}
+		assert_eq!(nodes[1].node.list_channels().len(), 0);
+		test_txn_broadcast(&nodes[1], &chan_2, None, HTLCType::NONE);
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		match events[0] {
+			Event::BroadcastChannelUpdate { .. } => {},
+			_ => panic!("Unexpected event"),
+		}
+		check_closed_event!(events[1], ClosureReason::ProcessingError { err: "Got shutdown request with a scriptpubkey which did not match their upfront shutdown script".to_string() });
+
+		// If our peer didn't signal option_upfront_shutdown_script we don't commit to a script
+		nodes[0].node.peer_connected(&nodes[2].node.get_our_node_id(), &msgs::LocalFeatures::new());
+		nodes[2].node.peer_connected(&nodes[0].node.get_our_node_id(), &msgs::LocalFeatures::new());
+		nodes[0].node.create_channel(nodes[2].node.get_our_node_id(), 100000, 10001, 42).unwrap();
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let accept_chan = match events[0] {
+			Event::SendOpenChannel { ref msg, .. } => {
+				assert!(msg.shutdown_scriptpubkey.is_none());
+				nodes[2].node.handle_open_channel(&nodes[0].node.get_our_node_id(), msg).unwrap()
+			},
+			_ => panic!("Unexpected event"),
+		};
+		assert!(accept_chan.shutdown_scriptpubkey.is_none());
+		nodes[0].node.handle_accept_channel(&nodes[2].node.get_our_node_id(), &accept_chan).unwrap();
+		let channel = nodes[0].node.list_channels().into_iter().find(|chan| chan.remote_network_id == nodes[2].node.get_our_node_id()).unwrap();
+		assert_eq!(channel.their_shutdown_scriptpubkey, None);
+		match nodes[0].node.get_and_clear_pending_events()[0] {
+			Event::FundingGenerationReady { .. } => {},
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	#[test]
+	fn test_data_loss_protect() {
+		// Test that channel_reestablish messages carry option_data_loss_protect fields and that if
+		// our peer proves we have fallen behind we refuse to broadcast our (stale) latest local
+		// commitment transaction, but still claim our funds once they broadcast theirs.
+		let nodes = create_network(3);
+		let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2);
+
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000);
+		send_payment(&nodes[1], &vec!(&nodes[2])[..], 8000000);
+
+		nodes[0].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+		nodes[1].node.peer_disconnected(&nodes[0].node.get_our_node_id(), false);
+		nodes[1].node.peer_disconnected(&nodes[2].node.get_our_node_id(), false);
+		nodes[2].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+
+		let commitment_seed = nodes[0].node.channel_state.lock().unwrap().by_id.get(&chan_1.2).unwrap().get_local_keys().commitment_seed;
+		let reestablish = nodes[1].node.peer_connected(&nodes[0].node.get_our_node_id(), &test_local_features());
+		let reestablish = reestablish.iter().find(|msg| msg.channel_id == chan_1.2).unwrap();
+		let their_current_point = {
+			// nodes[1] hands back the last per_commitment_secret nodes[0] revealed to it
+			let data_loss = reestablish.data_loss_protect.as_ref().unwrap();
+			assert!(reestablish.next_remote_commitment_number > 1);
+			assert_eq!(data_loss.your_last_per_commitment_secret,
+				chan_utils::build_commitment_secret(commitment_seed, INITIAL_COMMITMENT_NUMBER - reestablish.next_remote_commitment_number + 2));
+			data_loss.my_current_per_commitment_point
+		};
+
+		// The option_data_loss_protect fields are only included if our peer supports them
+		let reestablish_2 = nodes[2].node.peer_connected(&nodes[1].node.get_our_node_id(), &msgs::LocalFeatures::new());
+		assert_eq!(reestablish_2.len(), 1);
+		assert!(reestablish_2[0].data_loss_protect.is_none());
+
+		// A channel_reestablish with an incorrect secret causes the channel to be closed normally
+		let reestablish_2 = nodes[2].node.peer_connected(&nodes[1].node.get_our_node_id(), &test_local_features());
+		assert_eq!(reestablish_2.len(), 1);
+		let mut bad_secret = reestablish_2[0].data_loss_protect.as_ref().unwrap().your_last_per_commitment_secret;
+		bad_secret[0] ^= 1;
+		let bad_reestablish = msgs::ChannelReestablish {
+			channel_id: chan_2.2,
+			next_local_commitment_number: reestablish_2[0].next_local_commitment_number,
+			next_remote_commitment_number: reestablish_2[0].next_remote_commitment_number,
+			data_loss_protect: Some(msgs::DataLossProtect {
+				your_last_per_commitment_secret: bad_secret,
+				my_current_per_commitment_point: reestablish_2[0].data_loss_protect.as_ref().unwrap().my_current_per_commitment_point,
+			}),
+		};
+		assert!(nodes[1].node.handle_channel_reestablish(&nodes[2].node.get_our_node_id(), &bad_reestablish).is_err());
+		test_txn_broadcast(&nodes[1], &chan_2, None, HTLCType::NONE);
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		check_closed_event!(events[1], ClosureReason::ProcessingError { err: "Peer sent a garbage channel_reestablish with secret key not matching the commitment height provided".to_string() });
+
+		// Now pretend nodes[1] has received one more revocation from nodes[0] than nodes[0]
+		// remembers sending, as would happen if nodes[0] had been restored from an old backup.
+		let behind_reestablish = msgs::ChannelReestablish {
+			channel_id: chan_1.2,
+			next_local_commitment_number: reestablish.next_local_commitment_number,
+			next_remote_commitment_number: reestablish.next_remote_commitment_number + 1,
+			data_loss_protect: Some(msgs::DataLossProtect {
+				your_last_per_commitment_secret: chan_utils::build_commitment_secret(commitment_seed, INITIAL_COMMITMENT_NUMBER - reestablish.next_remote_commitment_number + 1),
+				my_current_per_commitment_point: their_current_point,
+			}),
+		};
+		nodes[0].node.peer_connected(&nodes[1].node.get_our_node_id(), &test_local_features());
+		match nodes[0].node.handle_channel_reestablish(&nodes[1].node.get_our_node_id(), &behind_reestablish) {
+			Err(msgs::HandleError { action: Some(msgs::ErrorAction::SendErrorMessage { .. }), .. }) => {},
+			_ => panic!("Unexpected result"),
+		}
+		check_added_monitors!(nodes[0], 1);
+		assert_eq!(nodes[0].node.list_channels().len(), 0);
+		assert!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		match events[0] {
+			Event::BroadcastChannelUpdate { .. } => {},
+			_ => panic!("Unexpected event"),
+		}
+		match events[1] {
+			Event::ChannelClosed { ref reason, ref closing_transaction, .. } => {
+				assert_eq!(*reason, ClosureReason::ProcessingError { err: "We have fallen behind - our peer proved they have a later state than us".to_string() });
+				assert!(closing_transaction.is_none());
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		// Once nodes[1] broadcasts its latest commitment transaction, nodes[0] claims its output,
+		// at the feerate we estimate by then.
+		*nodes[0].fee_estimator.sat_per_kw.lock().unwrap() = 1000;
+		nodes[1].node.force_close_channel(&chan_1.2);
+		assert_eq!(nodes[1].node.get_and_clear_pending_events().len(), 2);
+		let remote_txn = test_txn_broadcast(&nodes[1], &chan_1, None, HTLCType::NONE);
+		assert_eq!(remote_txn.len(), 1);
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		nodes[0].chain_monitor.block_connected_with_filtering(&Block { header, txdata: vec![remote_txn[0].clone()] }, 1);
+		let node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().clone();
+		assert_eq!(node_txn.len(), 2);
+		assert_eq!(node_txn[0], node_txn[1]); // claim tx is duplicated due to block re-scanning
+		check_spends!(node_txn[0], remote_txn[0].clone());
+		let to_remote_value = remote_txn[0].output.iter().find(|outp| outp.script_pubkey.is_v0_p2wpkh()).unwrap().value;
+		let feerate = *nodes[0].fee_estimator.sat_per_kw.lock().unwrap();
+		let fee = to_remote_value - node_txn[0].output[0].value;
+		assert!(fee >= feerate * node_txn[0].get_weight() / 1000);
+		assert!(fee <= feerate * (node_txn[0].get_weight() + 2) / 1000);
+	}
+
+	#[test]
+	fn test_drop_messages_peer_disconnect_a() {
+		do_test_drop_messages_peer_disconnect(0);
+		do_test_drop_messages_peer_disconnect(1);
+		do_test_drop_messages_peer_disconnect(2);
+	}
+
+	#[test]
 	fn test_drop_messages_peer_disconnect_b() {
 		do_test_drop_messages_peer_disconnect(3);
 		do_test_drop_messages_peer_disconnect(4);
@@ -5090,14 +7733,23 @@ mod tests {
 		}
 
 		reconnect_nodes(&nodes[0], &nodes[1], true, (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
+		for node in nodes.iter() {
+			let events = node.node.get_and_clear_pending_events();
+			assert_eq!(events.len(), 1);
+			match events[0] {
+				Event::ChannelReady { ref channel_id, .. } => assert_eq!(*channel_id, node.node.list_channels()[0].channel_id),
+				_ => panic!("Unexpected event"),
+			}
+		}
+		// Re-sending funding_locked upon reconnection doesn't generate another ChannelReady
 		nodes[0].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
 		nodes[1].node.peer_disconnected(&nodes[0].node.get_our_node_id(), false);
 		reconnect_nodes(&nodes[0], &nodes[1], true, (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
 
-		// TODO: We shouldn't need to manually pass list_usable_chanels here once we support
-		// rebroadcasting announcement_signatures upon reconnect.
-
-		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), Some(&nodes[0].node.list_usable_channels()), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		// The channel was announced to our routers once announcement_signatures were exchanged
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
 		let (payment_preimage, _) = send_along_route(&nodes[0], route, &[&nodes[1]], 1000000);
 		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
 	}
@@ -5175,4 +7827,1114 @@ mod tests {
 		sign_msg!(unsigned_msg);
 		assert!(nodes[0].router.handle_channel_announcement(&chan_announcement).is_err());
 	}
+
+	#[test]
+	fn test_node_announcement() {
+		// Check we only broadcast our node_announcement once we have a public channel, and then
+		// again whenever our details change or it's time to re-announce.
+		let nodes = create_network(2);
+		let addresses = vec![msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 },
+			onion::parse_onion_address("2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion", 9735).unwrap()];
+		nodes[0].node.set_node_announcement_info([1, 2, 3], [4; 32], addresses.clone());
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+		assert!(nodes[1].node.get_node_announcement().is_none());
+
+		let (as_funding_msgs, _, _) = create_chan_between_nodes_with_value_a(&nodes[0], &nodes[1], 100000, 10001);
+		let bs_announcement_sigs = nodes[1].node.handle_funding_locked(&nodes[0].node.get_our_node_id(), &as_funding_msgs.0).unwrap().unwrap();
+		nodes[1].node.handle_announcement_signatures(&nodes[0].node.get_our_node_id(), &as_funding_msgs.1).unwrap();
+		// nodes[1] hasn't set any details so doesn't announce itself (but the channel is ready)
+		assert_eq!(nodes[1].node.get_and_clear_pending_events().len(), 2);
+
+		nodes[0].node.handle_announcement_signatures(&nodes[1].node.get_our_node_id(), &bs_announcement_sigs).unwrap();
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		match events[0] {
+			Event::BroadcastChannelAnnouncement { ref msg, .. } => {
+				assert!(nodes[1].router.handle_channel_announcement(msg).unwrap());
+			},
+			_ => panic!("Unexpected event"),
+		}
+		let node_announcement = match events[1] {
+			Event::BroadcastNodeAnnouncement { ref msg } => msg.encode(),
+			_ => panic!("Unexpected event"),
+		};
+		let decoded: msgs::NodeAnnouncement = Readable::read(&mut ::std::io::Cursor::new(&node_announcement)).unwrap();
+		assert_eq!(decoded.contents.node_id, nodes[0].node.get_our_node_id());
+		assert!(nodes[1].router.handle_node_announcement(&decoded).unwrap());
+		let learned_addresses = nodes[1].router.get_addresses(&nodes[0].node.get_our_node_id()).unwrap();
+		assert_eq!(learned_addresses, addresses);
+		assert_eq!(onion::onion_hostname(&learned_addresses[1]).unwrap(), "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion");
+
+		// Nothing changed, so no need to re-announce
+		nodes[0].node.timer_tick_occured();
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		// Changing our details results in a new announcement, which replaces the old one
+		nodes[0].node.set_node_announcement_info([1, 2, 3], [4; 32], Vec::new());
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::BroadcastNodeAnnouncement { ref msg } => {
+				assert!(msg.contents.timestamp > decoded.contents.timestamp);
+				assert!(nodes[1].router.handle_node_announcement(msg).unwrap());
+			},
+			_ => panic!("Unexpected event"),
+		}
+		assert!(nodes[1].router.get_addresses(&nodes[0].node.get_our_node_id()).unwrap().is_empty());
+
+		// Once the rebroadcast interval passes we announce again
+		nodes[0].node.node_announcement.lock().unwrap().last_broadcast = None;
+		nodes[0].node.timer_tick_occured();
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::BroadcastNodeAnnouncement { .. } => {},
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	#[test]
+	fn test_payment_forwarded_event() {
+		// Test that an intermediary node gets a PaymentForwarded event with the fee it earned once
+		// its claim of the payment it relayed is irrevocably committed by the previous hop.
+		let nodes = create_network(3);
+		let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2);
+
+		let route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let expected_fee = route.hops[0].fee_msat;
+		assert!(expected_fee > 0);
+		let (payment_preimage, _) = send_along_route(&nodes[0], route, &[&nodes[1], &nodes[2]], 1000000);
+
+		assert!(nodes[2].node.claim_funds(payment_preimage));
+		check_added_monitors!(nodes[2], 1);
+		let events = nodes[2].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let (update_fulfill, commitment_signed) = match events[0] {
+			Event::UpdateHTLCs { ref updates, .. } => (updates.update_fulfill_htlcs[0].clone(), updates.commitment_signed.clone()),
+			_ => panic!("Unexpected event"),
+		};
+
+		nodes[1].node.handle_update_fulfill_htlc(&nodes[2].node.get_our_node_id(), &update_fulfill).unwrap();
+		check_added_monitors!(nodes[1], 1);
+		commitment_signed_dance!(nodes[1], nodes[2], commitment_signed, false);
+		// nodes[1] has only sent its update_fulfill_htlc upstream, so it hasn't earned anything yet
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let (update_fulfill, commitment_signed) = match events[0] {
+			Event::UpdateHTLCs { ref updates, .. } => (updates.update_fulfill_htlcs[0].clone(), updates.commitment_signed.clone()),
+			_ => panic!("Unexpected event"),
+		};
+
+		nodes[0].node.handle_update_fulfill_htlc(&nodes[1].node.get_our_node_id(), &update_fulfill).unwrap();
+		let (as_revoke_and_ack, as_commitment_signed) = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &commitment_signed).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+
+		// Once nodes[0] revokes the commitment transaction including the HTLC, nodes[1] has earned
+		// its fee
+		assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &as_revoke_and_ack).unwrap().is_none());
+		check_added_monitors!(nodes[1], 1);
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentForwarded { ref prev_channel_id, ref next_channel_id, incoming_amt_msat, outgoing_amt_msat, fee_earned_msat } => {
+				assert_eq!(*prev_channel_id, chan_1.2);
+				assert_eq!(*next_channel_id, chan_2.2);
+				assert_eq!(incoming_amt_msat, 1000000 + expected_fee);
+				assert_eq!(outgoing_amt_msat, 1000000);
+				assert_eq!(fee_earned_msat, expected_fee);
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		let (bs_revoke_and_ack, bs_none) = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &as_commitment_signed.unwrap()).unwrap();
+		assert!(bs_none.is_none());
+		check_added_monitors!(nodes[1], 1);
+		assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &bs_revoke_and_ack).unwrap().is_none());
+		check_added_monitors!(nodes[0], 1);
+		// The PaymentForwarded event is only generated once
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentSent { payment_preimage: ref preimage } => assert_eq!(*preimage, payment_preimage),
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	#[test]
+	fn test_payment_failed_intermediate_hop() {
+		// Test that a failure generated by an intermediary node (here because its next hop is
+		// disconnected) is attributed to it and passed along with the channel_update it included.
+		let nodes = create_network(3);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2);
+
+		nodes[1].node.peer_disconnected(&nodes[2].node.get_our_node_id(), false);
+		nodes[2].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+
+		let route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+		nodes[0].node.send_payment(route, payment_hash).unwrap();
+		check_added_monitors!(nodes[0], 1);
+
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+		match fail_htlc_at_next_hop(&nodes[0], &nodes[1], payment_event, false) {
+			Some(msgs::HTLCFailChannelUpdate::ChannelUpdateMessage { ref msg }) => assert_eq!(msg.contents.short_channel_id, chan_2.0.contents.short_channel_id),
+			_ => panic!("Unexpected network update"),
+		}
+
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentFailed { payment_hash: ref hash, rejected_by_dest, ref failing_node, failing_short_channel_id, error_code, ref channel_update } => {
+				assert_eq!(*hash, payment_hash);
+				assert!(!rejected_by_dest);
+				assert_eq!(*failing_node, Some(nodes[1].node.get_our_node_id()));
+				assert_eq!(failing_short_channel_id, Some(chan_2.0.contents.short_channel_id));
+				assert_eq!(error_code, Some(0x1000 | 7));
+				assert_eq!(channel_update.as_ref().unwrap().contents.short_channel_id, chan_2.0.contents.short_channel_id);
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	#[test]
+	fn test_payment_retries() {
+		// Test that payments sent with send_payment_with_retries are re-routed around a failed
+		// channel, and that the failure is only surfaced once the retry budget is exhausted.
+		let nodes = create_network(4);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		create_announced_chan_between_nodes(&nodes, 1, 3);
+		create_announced_chan_between_nodes(&nodes, 0, 2);
+		create_announced_chan_between_nodes(&nodes, 2, 3);
+
+		// Disconnect whichever intermediary the router prefers from the recipient
+		let route = nodes[0].router.get_route(&nodes[3].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let (failing, other) = if route.hops[0].pubkey == nodes[1].node.get_our_node_id() { (1, 2) } else { (2, 1) };
+		nodes[failing].node.peer_disconnected(&nodes[3].node.get_our_node_id(), false);
+		nodes[3].node.peer_disconnected(&nodes[failing].node.get_our_node_id(), false);
+
+		let params = PaymentParameters {
+			target: nodes[3].node.get_our_node_id(),
+			final_value_msat: 1000000,
+			final_cltv: TEST_FINAL_CLTV,
+			last_hops: Vec::new(),
+			payment_secret: None,
+		};
+
+		macro_rules! fail_first_hop {
+			($fail_backwards: expr) => { {
+				let mut events = nodes[0].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				let payment_event = SendEvent::from_event(events.remove(0));
+				assert_eq!(payment_event.node_id, nodes[failing].node.get_our_node_id());
+				fail_htlc_at_next_hop(&nodes[0], &nodes[failing], payment_event, $fail_backwards);
+			} }
+		}
+
+		// With a single attempt allowed, the failure is passed straight to the user
+		let (_, payment_hash_1) = get_payment_preimage_hash!(nodes[0]);
+		nodes[0].node.send_payment_with_retries(nodes[0].router.clone(), params.clone(), payment_hash_1, RetryBudget { max_attempts: 1, max_duration: Duration::from_secs(60) }).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		fail_first_hop!(false);
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentFailed { payment_hash, rejected_by_dest, .. } => {
+				assert_eq!(payment_hash, payment_hash_1);
+				assert!(!rejected_by_dest);
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		// With a second attempt, we route around the disconnected channel and succeed, with the
+		// re-send going out over our other channel as we process the failure.
+		let (payment_preimage_2, payment_hash_2) = get_payment_preimage_hash!(nodes[0]);
+		nodes[0].node.send_payment_with_retries(nodes[0].router.clone(), params.clone(), payment_hash_2, RetryBudget { max_attempts: 2, max_duration: Duration::from_secs(60) }).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		assert!(nodes[0].node.send_payment_with_retries(nodes[0].router.clone(), params.clone(), payment_hash_2, RetryBudget { max_attempts: 2, max_duration: Duration::from_secs(60) }).is_err());
+		fail_first_hop!(true);
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+		assert_eq!(payment_event.node_id, nodes[other].node.get_our_node_id());
+		pass_along_route(&nodes[0], payment_event, &[&nodes[other], &nodes[3]], 1000000, payment_hash_2);
+		claim_payment(&nodes[0], &[&nodes[other], &nodes[3]], payment_preimage_2);
+	}
+
+	#[test]
+	fn test_payment_retries_recipient_failure() {
+		// Test that a non-permanent failure from the recipient, which doesn't blame any channel,
+		// isn't retried over the same route but passed straight to the user.
+		let nodes = create_network(2);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+
+		let params = PaymentParameters {
+			target: nodes[1].node.get_our_node_id(),
+			final_value_msat: 1000000,
+			// Too soon for nodes[1] to accept, it fails the HTLC with final_expiry_too_soon
+			final_cltv: 1,
+			last_hops: Vec::new(),
+			payment_secret: None,
+		};
+		let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+		nodes[0].node.send_payment_with_retries(nodes[0].router.clone(), params, payment_hash, RetryBudget { max_attempts: 3, max_duration: Duration::from_secs(60) }).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		fail_htlc_at_next_hop(&nodes[0], &nodes[1], SendEvent::from_event(events.remove(0)), false);
+
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentFailed { payment_hash: ref hash, rejected_by_dest, ref failing_node, error_code, .. } => {
+				assert_eq!(*hash, payment_hash);
+				assert!(!rejected_by_dest);
+				assert_eq!(*failing_node, Some(nodes[1].node.get_our_node_id()));
+				assert_eq!(error_code, Some(17));
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	#[test]
+	fn test_pay_invoice() {
+		// Test that we can pay an invoice (passing its payment_secret to the payee), that we refuse
+		// to pay invoices we shouldn't and that we never generate more than one PaymentSent for the
+		// same payment_hash.
+		let nodes = create_network(2);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		let budget = RetryBudget { max_attempts: 1, max_duration: Duration::from_secs(60) };
+
+		let (payment_preimage, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+		let raw_invoice = RawInvoice {
+			currency: Currency::BitcoinTestnet,
+			amount_msat: Some(1000000),
+			timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+			payment_hash,
+			payment_secret: Some(nodes[1].node.create_inbound_payment_for_hash(payment_hash, Some(1000000), 3600)),
+			description: Some("coffee".to_string()),
+			description_hash: None,
+			payee_pubkey: None,
+			expiry_time: None,
+			min_final_cltv_expiry: Some(TEST_FINAL_CLTV as u64),
+			fallbacks: Vec::new(),
+			route_hints: Vec::new(),
+			features: None,
+		};
+
+		let mut wrong_chain = raw_invoice.clone();
+		wrong_chain.currency = Currency::Bitcoin;
+		let wrong_chain = nodes[1].node.sign_invoice(wrong_chain).unwrap();
+		assert!(nodes[0].node.pay_invoice(nodes[0].router.clone(), &wrong_chain, None, budget).is_err());
+
+		let mut expired = raw_invoice.clone();
+		expired.timestamp -= DEFAULT_EXPIRY_TIME + 1;
+		let expired = nodes[1].node.sign_invoice(expired).unwrap();
+		assert!(nodes[0].node.pay_invoice(nodes[0].router.clone(), &expired, None, budget).is_err());
+
+		let mut amountless = raw_invoice.clone();
+		amountless.amount_msat = None;
+		let amountless = nodes[1].node.sign_invoice(amountless).unwrap();
+		assert!(nodes[0].node.pay_invoice(nodes[0].router.clone(), &amountless, None, budget).is_err());
+
+		let invoice = Invoice::from_str(&nodes[1].node.sign_invoice(raw_invoice).unwrap().to_string()).unwrap();
+		assert!(nodes[0].node.pay_invoice(nodes[0].router.clone(), &invoice, Some(999999), budget).is_err());
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		nodes[0].node.pay_invoice(nodes[0].router.clone(), &invoice, None, budget).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		// While the payment is in flight we refuse to start it again
+		assert!(nodes[0].node.pay_invoice(nodes[0].router.clone(), &invoice, None, budget).is_err());
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		pass_along_route(&nodes[0], SendEvent::from_event(events.remove(0)), &[&nodes[1]], 1000000, payment_hash);
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+
+		// Once it has succeeded we refuse to pay it again, and a duplicate claim is not surfaced
+		assert!(nodes[0].node.pay_invoice(nodes[0].router.clone(), &invoice, None, budget).is_err());
+		nodes[0].node.claim_funds_internal(nodes[0].node.channel_state.lock().unwrap(), HTLCSource::dummy(), payment_preimage, None);
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn test_inbound_payment_secrets() {
+		// Test that payments paid with a payment_secret are only surfaced to the user if it came from
+		// create_inbound_payment(_for_hash) for the payment_hash, and the payment is for the right
+		// amount and arrives before it expired.
+		let nodes = create_network(2);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+
+		macro_rules! send_to_node_1 {
+			($payment_hash: expr, $payment_secret: expr, $amount: expr) => { {
+				let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &Vec::new(), $amount, TEST_FINAL_CLTV).unwrap();
+				nodes[0].node.send_payment_with_secret(route, $payment_hash, $payment_secret).unwrap();
+				check_added_monitors!(nodes[0], 1);
+				let mut events = nodes[0].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				SendEvent::from_event(events.remove(0))
+			} }
+		}
+
+		macro_rules! expect_rejection {
+			($payment_hash: expr, $payment_secret: expr, $amount: expr, $error_code: expr) => { {
+				let payment_event = send_to_node_1!($payment_hash, $payment_secret, $amount);
+				fail_htlc_at_next_hop(&nodes[0], &nodes[1], payment_event, false);
+				let events = nodes[0].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				match events[0] {
+					Event::PaymentFailed { ref payment_hash, rejected_by_dest, error_code, .. } => {
+						assert_eq!(*payment_hash, $payment_hash);
+						assert!(rejected_by_dest);
+						assert_eq!(error_code, Some($error_code));
+					},
+					_ => panic!("Unexpected event"),
+				}
+			} }
+		}
+
+		let (payment_hash, payment_secret) = nodes[1].node.create_inbound_payment(Some(100000), 3600);
+		expect_rejection!(payment_hash, Some([42; 32]), 100000, 0x4000 | 15);
+		expect_rejection!(payment_hash, Some(payment_secret), 99999, 0x4000 | 16);
+
+		let payment_event = send_to_node_1!(payment_hash, Some(payment_secret), 100000);
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PendingHTLCsForwardable { .. } => { },
+			_ => panic!("Unexpected event"),
+		};
+		nodes[1].node.channel_state.lock().unwrap().next_forward = Instant::now();
+		nodes[1].node.process_pending_htlc_forwards();
+		let payment_preimage = nodes[1].node.get_payment_preimage(&payment_hash, &payment_secret).unwrap();
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentReceived { payment_hash: ref hash, amt, payment_secret: ref secret, payment_preimage: ref preimage } => {
+				assert_eq!(*hash, payment_hash);
+				assert_eq!(amt, 100000);
+				assert_eq!(*secret, Some(payment_secret));
+				assert_eq!(*preimage, Some(payment_preimage));
+			},
+			_ => panic!("Unexpected event"),
+		}
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+
+		// A payment_secret only works for the payment_hash it was created for, even if it's ours
+		let (other_payment_hash, _) = nodes[1].node.create_inbound_payment(Some(100000), 3600);
+		expect_rejection!(other_payment_hash, Some(payment_secret), 100000, 0x4000 | 15);
+		assert!(nodes[1].node.get_payment_preimage(&other_payment_hash, &payment_secret).is_none());
+
+		// Payments for a hash the user provides are checked the same way, but we can't derive
+		// their preimage
+		let (payment_preimage, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+		let payment_secret = nodes[1].node.create_inbound_payment_for_hash(payment_hash, Some(100000), 3600);
+		assert!(nodes[1].node.get_payment_preimage(&payment_hash, &payment_secret).is_none());
+		expect_rejection!(payment_hash, Some([42; 32]), 100000, 0x4000 | 15);
+		expect_rejection!(payment_hash, Some(payment_secret), 99999, 0x4000 | 16);
+		let payment_event = send_to_node_1!(payment_hash, Some(payment_secret), 100000);
+		pass_along_route(&nodes[0], payment_event, &[&nodes[1]], 100000, payment_hash);
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+
+		// Payments without a payment_secret can't be checked, so are left to the user
+		let (payment_preimage, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+		let payment_event = send_to_node_1!(payment_hash, None, 100000);
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PendingHTLCsForwardable { .. } => { },
+			_ => panic!("Unexpected event"),
+		};
+		nodes[1].node.channel_state.lock().unwrap().next_forward = Instant::now();
+		nodes[1].node.process_pending_htlc_forwards();
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentReceived { payment_hash: ref hash, payment_secret: None, payment_preimage: None, .. } => assert_eq!(*hash, payment_hash),
+			_ => panic!("Unexpected event"),
+		}
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+
+		// Expiry is checked against the time encrypted in the payment_secret
+		let (payment_hash, payment_secret) = nodes[1].node.create_inbound_payment(None, 0);
+		expect_rejection!(payment_hash, Some(payment_secret), 100000, 0x4000 | 15);
+	}
+
+	#[test]
+	fn test_htlc_cltv_expiry_checks() {
+		// Test that HTLCs whose CLTV expiry is too soon or too far in the future relative to the
+		// receiving node's block height are failed back with the right BOLT 4 error.
+		let nodes = create_network(3);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		create_announced_chan_between_nodes(&nodes, 1, 2);
+		let cur_height = nodes[0].node.latest_block_height.load(Ordering::Acquire);
+
+		macro_rules! expect_node_1_failure {
+			($route: expr, $node_1_height: expr, $error_code: expr) => { {
+				nodes[1].node.latest_block_height.store($node_1_height, Ordering::Release);
+				let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+				nodes[0].node.send_payment($route, payment_hash).unwrap();
+				check_added_monitors!(nodes[0], 1);
+				let mut events = nodes[0].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				let payment_event = SendEvent::from_event(events.remove(0));
+
+				fail_htlc_at_next_hop(&nodes[0], &nodes[1], payment_event, false);
+				let events = nodes[0].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				match events[0] {
+					Event::PaymentFailed { payment_hash: ref hash, rejected_by_dest, ref failing_node, error_code, ref channel_update, .. } => {
+						assert_eq!(*hash, payment_hash);
+						// None of these are permanent failures, even if nodes[1] is the recipient
+						assert!(!rejected_by_dest);
+						assert_eq!(*failing_node, Some(nodes[1].node.get_our_node_id()));
+						assert_eq!(error_code, Some($error_code));
+						assert_eq!(channel_update.is_some(), $error_code & 0x1000 == 0x1000);
+					},
+					_ => panic!("Unexpected event"),
+				}
+				nodes[1].node.latest_block_height.store(cur_height, Ordering::Release);
+			} }
+		}
+
+		// nodes[1] would have to forward an HTLC expiring at cur_height + 1 + TEST_FINAL_CLTV, which
+		// is too soon once it is CLTV_CLAIM_BUFFER + LATENCY_GRACE_PERIOD_BLOCKS blocks ahead of it.
+		let route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let node_1_height = cur_height + 1 + TEST_FINAL_CLTV as usize - (CLTV_CLAIM_BUFFER + LATENCY_GRACE_PERIOD_BLOCKS) as usize;
+		expect_node_1_failure!(route.clone(), node_1_height, 0x1000 | 14);
+
+		// ...and the same applies to HTLCs paying nodes[1] itself.
+		let route_to_1 = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		expect_node_1_failure!(route_to_1, node_1_height, 17);
+
+		let mut far_route = route.clone();
+		far_route.hops[1].cltv_expiry_delta = CLTV_FAR_FAR_AWAY;
+		expect_node_1_failure!(far_route, cur_height, 21);
+
+		// One block earlier everything is fine.
+		nodes[1].node.latest_block_height.store(node_1_height - 1, Ordering::Release);
+		send_along_route(&nodes[0], route, &[&nodes[1], &nodes[2]], 1000000);
+		nodes[1].node.latest_block_height.store(cur_height, Ordering::Release);
+	}
+
+	#[test]
+	fn test_fail_back_expiring_htlcs() {
+		// Test that received payments which the user doesn't claim and HTLCs stuck in the holding
+		// cell are failed backwards when they get close to expiring, without closing the channel.
+		let nodes = create_network(2);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		let cur_height = nodes[0].node.latest_block_height.load(Ordering::Acquire) as u32;
+		// Our payments expire at cur_height + 1 + TEST_FINAL_CLTV
+		let fail_height = cur_height + 1 + TEST_FINAL_CLTV - HTLC_FAIL_BACK_BUFFER;
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+
+		let (payment_preimage, payment_hash) = route_payment(&nodes[0], &[&nodes[1]], 1000000);
+		nodes[1].node.block_connected(&header, fail_height - 1, &[], &[]);
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+		nodes[1].node.block_connected(&header, fail_height, &[], &[]);
+		check_added_monitors!(nodes[1], 1);
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::UpdateHTLCs { ref node_id, updates: msgs::CommitmentUpdate { ref update_add_htlcs, ref update_fulfill_htlcs, ref update_fail_htlcs, ref update_fail_malformed_htlcs, ref update_fee, ref commitment_signed } } => {
+				assert_eq!(*node_id, nodes[0].node.get_our_node_id());
+				assert!(update_add_htlcs.is_empty());
+				assert!(update_fulfill_htlcs.is_empty());
+				assert_eq!(update_fail_htlcs.len(), 1);
+				assert!(update_fail_malformed_htlcs.is_empty());
+				assert!(update_fee.is_none());
+				nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &update_fail_htlcs[0]).unwrap();
+				commitment_signed_dance!(nodes[0], nodes[1], commitment_signed, false);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentFailed { payment_hash: ref hash, rejected_by_dest, error_code, .. } => {
+				assert_eq!(*hash, payment_hash);
+				assert!(rejected_by_dest);
+				assert_eq!(error_code, Some(0x4000 | 15));
+			},
+			_ => panic!("Unexpected event"),
+		}
+		assert!(!nodes[1].node.claim_funds(payment_preimage));
+		// Catch nodes[0] up with nodes[1] so that nodes[1] accepts its next payment
+		nodes[0].node.block_connected(&header, fail_height, &[], &[]);
+
+		// Send one payment and, while we wait for nodes[1] to revoke, put a second in the holding
+		// cell, which is then dropped as it gets too close to expiring.
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let (payment_preimage_1, payment_hash_1) = get_payment_preimage_hash!(nodes[0]);
+		nodes[0].node.send_payment(route.clone(), payment_hash_1).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+
+		let (_, payment_hash_2) = get_payment_preimage_hash!(nodes[0]);
+		nodes[0].node.send_payment(route, payment_hash_2).unwrap();
+		check_added_monitors!(nodes[0], 0);
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		let fail_height = nodes[0].node.latest_block_height.load(Ordering::Acquire) as u32 + 1 + TEST_FINAL_CLTV - HTLC_FAIL_BACK_BUFFER;
+		nodes[0].node.block_connected(&header, fail_height, &[], &[]);
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentFailed { payment_hash: ref hash, rejected_by_dest, ref failing_node, error_code, .. } => {
+				assert_eq!(*hash, payment_hash_2);
+				assert!(!rejected_by_dest);
+				assert!(failing_node.is_none());
+				assert_eq!(error_code, Some(0x1000 | 14));
+			},
+			_ => panic!("Unexpected event"),
+		}
+		assert_eq!(nodes[0].node.list_channels().len(), 1);
+
+		// The first payment is unaffected (and, as pass_along_route checks, nodes[0] has nothing
+		// left to send once nodes[1] revokes).
+		pass_along_route(&nodes[0], payment_event, &[&nodes[1]], 1000000, payment_hash_1);
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage_1);
+	}
+
+	#[test]
+	fn test_claim_payment_at_min_final_cltv() {
+		// Test that a payment received with the default invoice min_final_cltv_expiry isn't failed
+		// back as soon as the next block connects, but can still be claimed.
+		let nodes = create_network(2);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		let cur_height = nodes[1].node.latest_block_height.load(Ordering::Acquire) as u32;
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, DEFAULT_MIN_FINAL_CLTV_EXPIRY as u32).unwrap();
+		assert_eq!(route.hops[0].cltv_expiry_delta, DEFAULT_MIN_FINAL_CLTV_EXPIRY as u32);
+		let (payment_preimage, _) = send_along_route(&nodes[0], route, &[&nodes[1]], 1000000);
+
+		nodes[1].node.block_connected(&header, cur_height + 1, &[], &[]);
+		check_added_monitors!(nodes[1], 0);
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	}
+
+	#[test]
+	fn test_onion_replay_protection() {
+		// Test that an onion we've already processed is rejected if it shows up again in another
+		// HTLC, with an error that doesn't depend on the original payment, across restarts.
+		let nodes = create_network(2);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let (payment_preimage, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+		nodes[0].node.send_payment(route, payment_hash).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+		let update_add = payment_event.msgs[0].clone();
+		pass_along_route(&nodes[0], payment_event, &[&nodes[1]], 1000000, payment_hash);
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+
+		let mut replayed = update_add.clone();
+		replayed.htlc_id += 1;
+		macro_rules! is_rejected_as_replay {
+			($msg: expr) => {
+				{
+					let shared_secret = SharedSecret::new(&nodes[1].node.secp_ctx, &$msg.onion_routing_packet.public_key.unwrap(), &nodes[1].node.our_network_key);
+					let replay_error = ChannelManager::build_first_hop_failure_packet(&shared_secret, 0x2000 | 2, &[0; 0]);
+					match nodes[1].node.decode_update_add_htlc_onion(&$msg).0 {
+						PendingHTLCStatus::Fail(HTLCFailureMsg::Relay(ref fail)) => fail.reason.data == replay_error.data,
+						_ => false,
+					}
+				}
+			}
+		}
+
+		// Our peer re-sending the same HTLC (eg after a reconnection) is fine, but a new one is not
+		assert!(!is_rejected_as_replay!(update_add));
+		assert!(is_rejected_as_replay!(replayed));
+
+		// Onions are only recorded once the channel accepts their HTLC, so merely decoding one
+		// doesn't make a later HTLC carrying it look like a replay
+		let mut persisted_onions = Vec::new();
+		nodes[1].node.write_seen_onions(&mut persisted_onions).unwrap();
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let (payment_preimage_2, payment_hash_2) = get_payment_preimage_hash!(nodes[0]);
+		nodes[0].node.send_payment(route, payment_hash_2).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event_2 = SendEvent::from_event(events.remove(0));
+		let update_add_2 = payment_event_2.msgs[0].clone();
+		let mut replayed_2 = update_add_2.clone();
+		replayed_2.htlc_id += 1;
+		assert!(!is_rejected_as_replay!(update_add_2));
+		assert!(!is_rejected_as_replay!(replayed_2));
+		pass_along_route(&nodes[0], payment_event_2, &[&nodes[1]], 1000000, payment_hash_2);
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage_2);
+		assert!(is_rejected_as_replay!(replayed_2));
+
+		// Replays continue to be rejected once we've written the seen onions out, appended the
+		// ones seen since, and read them all back in
+		nodes[1].node.write_new_seen_onions(&mut persisted_onions).unwrap();
+		*nodes[1].node.seen_onions.lock().unwrap() = SeenOnions::new(MAX_SEEN_ONIONS_PER_CHANNEL);
+		nodes[1].node.read_seen_onions(&mut Cursor::new(&persisted_onions)).unwrap();
+		assert!(is_rejected_as_replay!(replayed));
+		assert!(is_rejected_as_replay!(replayed_2));
+		assert!(!is_rejected_as_replay!(update_add_2));
+		assert!(nodes[1].node.seen_onions.lock().unwrap().unwritten.is_empty());
+
+		// Once an onion's HTLC has expired it is forgotten, as it would now be rejected anyway
+		nodes[1].node.seen_onions.lock().unwrap().block_connected(update_add.cltv_expiry - 1);
+		assert!(is_rejected_as_replay!(replayed));
+		nodes[1].node.seen_onions.lock().unwrap().block_connected(update_add_2.cltv_expiry);
+		assert!(!is_rejected_as_replay!(replayed));
+		assert!(!is_rejected_as_replay!(replayed_2));
+		assert!(nodes[1].node.seen_onions.lock().unwrap().onions_per_channel.is_empty());
+
+		// Once a channel has too many unexpired onions, new HTLCs on it are rejected until some of
+		// them expire, while other channels are unaffected
+		*nodes[1].node.seen_onions.lock().unwrap() = SeenOnions::new(1);
+		nodes[1].node.seen_onions.lock().unwrap().insert([0xff; 32], [0; 32], 0, update_add_2.cltv_expiry + 1);
+		assert!(!is_rejected_as_replay!(update_add_2));
+		nodes[1].node.seen_onions.lock().unwrap().insert([0xfe; 32], update_add_2.channel_id, 0, update_add_2.cltv_expiry + 2);
+		assert!(is_rejected_as_replay!(update_add_2));
+		nodes[1].node.seen_onions.lock().unwrap().block_connected(update_add_2.cltv_expiry + 1);
+		assert!(is_rejected_as_replay!(update_add_2));
+		nodes[1].node.seen_onions.lock().unwrap().block_connected(update_add_2.cltv_expiry + 2);
+		assert!(!is_rejected_as_replay!(update_add_2));
+	}
+
+	struct TestForwardingPolicy {
+		/// The decisions to return from should_forward, in order.
+		decisions: Mutex<Vec<ForwardingDecision>>,
+		/// The (prev_short_channel_id, next_short_channel_id, fee) of each forward we were asked about.
+		forwards: Mutex<Vec<(u64, u64, u64)>>,
+		intercept_short_channel_id: u64,
+	}
+	impl ForwardingPolicy for TestForwardingPolicy {
+		fn should_forward(&self, htlc: &ForwardingDetails) -> ForwardingDecision {
+			self.forwards.lock().unwrap().push((htlc.prev_short_channel_id, htlc.next_short_channel_id, htlc.fee_msat()));
+			self.decisions.lock().unwrap().remove(0)
+		}
+		fn should_intercept(&self, requested_short_channel_id: u64) -> bool {
+			requested_short_channel_id == self.intercept_short_channel_id
+		}
+	}
+
+	#[test]
+	fn test_forwarding_policy() {
+		// Test that a ForwardingPolicy can approve, reject and hold forwards, and intercept HTLCs
+		// sent over a short_channel_id we have no channel for, which can then be forwarded over a
+		// channel of the user's choosing.
+		let nodes = create_network(3);
+		let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2);
+		let fake_scid = 0xdeadbeef;
+		let policy = Arc::new(TestForwardingPolicy {
+			decisions: Mutex::new(vec![ForwardingDecision::Forward, ForwardingDecision::Reject { failure_code: 0x2000 | 2 },
+				ForwardingDecision::Reject { failure_code: 0x1000 | 12 }, ForwardingDecision::Reject { failure_code: 0x4000 | 15 }, ForwardingDecision::Hold]),
+			forwards: Mutex::new(Vec::new()),
+			intercept_short_channel_id: fake_scid,
+		});
+		nodes[1].node.set_forwarding_policy(Some(policy.clone()));
+		let cur_height = nodes[0].node.latest_block_height.load(Ordering::Acquire) as u32;
+
+		macro_rules! expect_pending_htlcs_forwardable {
+			($node: expr) => {{
+				let events = $node.node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				match events[0] {
+					Event::PendingHTLCsForwardable { .. } => { },
+					_ => panic!("Unexpected event"),
+				};
+				$node.node.channel_state.lock().unwrap().next_forward = Instant::now();
+				$node.node.process_pending_htlc_forwards();
+			}}
+		}
+		macro_rules! send_to_node_1 {
+			($route: expr) => {{
+				let (payment_preimage, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+				nodes[0].node.send_payment($route, payment_hash).unwrap();
+				check_added_monitors!(nodes[0], 1);
+				let mut events = nodes[0].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				let payment_event = SendEvent::from_event(events.remove(0));
+				nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+				commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+				expect_pending_htlcs_forwardable!(nodes[1]);
+				(payment_preimage, payment_hash)
+			}}
+		}
+		macro_rules! expect_failure {
+			($payment_hash: expr, $error_code: expr) => {{
+				let events = nodes[1].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				check_added_monitors!(nodes[1], 1);
+				let (update_fail, commitment_signed) = match events[0] {
+					Event::UpdateHTLCs { updates: msgs::CommitmentUpdate { ref update_fail_htlcs, ref commitment_signed, .. }, .. } => {
+						assert_eq!(update_fail_htlcs.len(), 1);
+						(update_fail_htlcs[0].clone(), commitment_signed.clone())
+					},
+					_ => panic!("Unexpected event"),
+				};
+				nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &update_fail).unwrap();
+				commitment_signed_dance!(nodes[0], nodes[1], commitment_signed, false);
+				let events = nodes[0].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				match events[0] {
+					Event::PaymentFailed { ref payment_hash, error_code, ref failing_node, ref channel_update, .. } => {
+						assert_eq!(*payment_hash, $payment_hash);
+						assert_eq!(error_code, Some($error_code));
+						assert_eq!(*failing_node, Some(nodes[1].node.get_our_node_id()));
+						// UPDATE errors come with a channel_update which the sender could parse
+						assert_eq!(channel_update.as_ref().map(|update| update.contents.short_channel_id),
+							if $error_code & 0x1000 != 0 { Some(chan_2.0.contents.short_channel_id) } else { None });
+					},
+					_ => panic!("Unexpected event"),
+				}
+			}}
+		}
+		macro_rules! expect_intercepted {
+			($payment_hash: expr, $requested_scid: expr) => {{
+				let events = nodes[1].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				check_added_monitors!(nodes[1], 0);
+				match events[0] {
+					Event::HTLCIntercepted { intercept_id, requested_next_short_channel_id, ref payment_hash, inbound_amount_msat, expected_outbound_amount_msat, inbound_cltv_expiry, outbound_cltv_expiry } => {
+						assert_eq!(requested_next_short_channel_id, $requested_scid);
+						assert_eq!(*payment_hash, $payment_hash);
+						assert_eq!(expected_outbound_amount_msat, 1000000);
+						assert!(inbound_amount_msat > expected_outbound_amount_msat);
+						assert_eq!(outbound_cltv_expiry, cur_height + 1 + TEST_FINAL_CLTV);
+						assert!(inbound_cltv_expiry > outbound_cltv_expiry);
+						intercept_id
+					},
+					_ => panic!("Unexpected event"),
+				}
+			}}
+		}
+
+		let route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let fee = route.hops[0].fee_msat;
+
+		// Approved forwards go through as usual, with the policy told the channels and fee
+		send_payment(&nodes[0], &[&nodes[1], &nodes[2]], 1000000);
+		assert_eq!(*policy.forwards.lock().unwrap(), vec![(chan_1.0.contents.short_channel_id, chan_2.0.contents.short_channel_id, fee)]);
+
+		// Rejected ones are failed back with the policy's failure code, along with the data it
+		// requires, or temporary_channel_failure if we don't know how to build that data
+		let (_, payment_hash) = send_to_node_1!(route.clone());
+		expect_failure!(payment_hash, 0x2000 | 2);
+		let (_, payment_hash) = send_to_node_1!(route.clone());
+		expect_failure!(payment_hash, 0x1000 | 12);
+		let (_, payment_hash) = send_to_node_1!(route.clone());
+		expect_failure!(payment_hash, 0x1000 | 7);
+
+		// Held ones are handed to the user until they fail them, which doesn't tell the sender our
+		// channel is gone...
+		let (_, payment_hash) = send_to_node_1!(route.clone());
+		let intercept_id = expect_intercepted!(payment_hash, chan_2.0.contents.short_channel_id);
+		assert!(nodes[1].node.fail_intercepted_htlc(&intercept_id));
+		assert!(!nodes[1].node.fail_intercepted_htlc(&intercept_id));
+		expect_failure!(payment_hash, 0x1000 | 7);
+		assert_eq!(policy.forwards.lock().unwrap().len(), 5);
+
+		// ...or forward them, here over a short_channel_id we don't know but the policy intercepts
+		let mut virtual_route = route.clone();
+		virtual_route.hops[1].short_channel_id = fake_scid;
+		let (payment_preimage, payment_hash) = send_to_node_1!(virtual_route.clone());
+		let intercept_id = expect_intercepted!(payment_hash, fake_scid);
+		match nodes[1].node.forward_intercepted_htlc(&intercept_id, &chan_2.2, 1000000 + fee + 1) {
+			Err(APIError::APIMisuseError { .. }) => {},
+			_ => panic!("Forwarding more than we received should fail"),
+		}
+		nodes[1].node.forward_intercepted_htlc(&intercept_id, &chan_2.2, 1000000).unwrap();
+		expect_pending_htlcs_forwardable!(nodes[1]);
+		check_added_monitors!(nodes[1], 1);
+		let mut events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		pass_along_route(&nodes[1], SendEvent::from_event(events.remove(0)), &[&nodes[2]], 1000000, payment_hash);
+		claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_preimage);
+		// The released HTLC wasn't passed to the policy again
+		assert_eq!(policy.forwards.lock().unwrap().len(), 5);
+
+		// Failing an HTLC sent over a short_channel_id we have no channel for gives the sender no
+		// channel_update, so it is failed with temporary_node_failure
+		let (_, payment_hash) = send_to_node_1!(virtual_route.clone());
+		let intercept_id = expect_intercepted!(payment_hash, fake_scid);
+		assert!(nodes[1].node.fail_intercepted_htlc(&intercept_id));
+		expect_failure!(payment_hash, 0x2000 | 2);
+
+		// Intercepted HTLCs which don't leave us the chosen channel's cltv_expiry_delta are failed
+		// back rather than forwarded...
+		let mut short_delta_route = virtual_route.clone();
+		short_delta_route.hops[0].cltv_expiry_delta -= 1;
+		let (_, payment_hash) = send_to_node_1!(short_delta_route);
+		let intercept_id = expect_intercepted!(payment_hash, fake_scid);
+		match nodes[1].node.forward_intercepted_htlc(&intercept_id, &chan_2.2, 1000000) {
+			Err(APIError::ChannelUnavailable { .. }) => {},
+			_ => panic!("Forwarding with too small a CLTV delta should fail"),
+		}
+		expect_failure!(payment_hash, 0x1000 | 13);
+
+		// ...as are those which have come too close to expiring while we held them
+		let (_, payment_hash) = send_to_node_1!(virtual_route);
+		let intercept_id = expect_intercepted!(payment_hash, fake_scid);
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		nodes[1].node.block_connected(&header, cur_height + 1 + TEST_FINAL_CLTV - CLTV_CLAIM_BUFFER - LATENCY_GRACE_PERIOD_BLOCKS, &[], &[]);
+		match nodes[1].node.forward_intercepted_htlc(&intercept_id, &chan_2.2, 1000000) {
+			Err(APIError::ChannelUnavailable { .. }) => {},
+			_ => panic!("Forwarding an HTLC which expires too soon should fail"),
+		}
+		expect_failure!(payment_hash, 0x1000 | 14);
+	}
+
+	#[test]
+	fn test_update_channel_config() {
+		// Test that update_channel_config changes the forwarding fees we announce and enforce, while
+		// still honouring the previous config for payments routed with it.
+		let nodes = create_network(3);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2);
+
+		macro_rules! update_config {
+			($fee_base_msat: expr, $fee_proportional_millionths: expr) => {{
+				nodes[1].node.update_channel_config(&chan_2.2, $fee_base_msat, $fee_proportional_millionths, 100, 5000, 2000000).unwrap();
+				let events = nodes[1].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				match events[0] {
+					Event::BroadcastChannelUpdate { ref msg } => {
+						assert_eq!(msg.contents.short_channel_id, chan_2.0.contents.short_channel_id);
+						assert_eq!(msg.contents.fee_base_msat, $fee_base_msat);
+						assert_eq!(msg.contents.fee_proportional_millionths, $fee_proportional_millionths);
+						assert_eq!(msg.contents.cltv_expiry_delta, 100);
+						assert_eq!(msg.contents.htlc_minimum_msat, 5000);
+						assert!(nodes[0].router.handle_channel_update(msg).unwrap());
+					},
+					_ => panic!("Unexpected event"),
+				}
+			}}
+		}
+
+		match nodes[1].node.update_channel_config(&chan_2.2, 1000, 500, 2, 5000, 2000000) {
+			Err(APIError::APIMisuseError { .. }) => {},
+			_ => panic!("A tiny cltv_expiry_delta should be rejected"),
+		}
+		match nodes[1].node.update_channel_config(&chan_2.2, 1000, 500, 100, 5000, 4000) {
+			Err(APIError::APIMisuseError { .. }) => {},
+			_ => panic!("An htlc_maximum_msat below htlc_minimum_msat should be rejected"),
+		}
+
+		let old_route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		update_config!(1000, 500);
+		let route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		assert_eq!(route.hops[0].fee_msat, 1000 + 500);
+		assert_eq!(route.hops[0].cltv_expiry_delta, 100);
+		send_along_route(&nodes[0], route, &[&nodes[1], &nodes[2]], 1000000);
+
+		// Payments routed with the old config still go through during the grace period...
+		send_along_route(&nodes[0], old_route.clone(), &[&nodes[1], &nodes[2]], 1000000);
+
+		// ...but once it's been replaced again, they're failed with the new channel_update
+		update_config!(2000, 1000);
+		let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+		nodes[0].node.send_payment(old_route, payment_hash).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+		fail_htlc_at_next_hop(&nodes[0], &nodes[1], payment_event, false);
+
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentFailed { payment_hash: ref hash, error_code, ref channel_update, .. } => {
+				assert_eq!(*hash, payment_hash);
+				assert_eq!(error_code, Some(0x1000 | 12));
+				assert_eq!(channel_update.as_ref().unwrap().contents.fee_base_msat, 2000);
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	#[test]
+	fn test_channel_disabled_while_peer_offline() {
+		// Test that a channel is announced as disabled once its peer has been disconnected for the
+		// channel disable timeout, and only re-enabled once the peer has been back for as long.
+		let nodes = create_network(3);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2);
+
+		macro_rules! is_enabled {
+			() => {
+				nodes[1].node.list_channels().iter().find(|chan| chan.channel_id == chan_2.2).unwrap().is_enabled
+			}
+		}
+		macro_rules! is_usable {
+			() => {
+				nodes[1].node.list_usable_channels().iter().any(|chan| chan.channel_id == chan_2.2)
+			}
+		}
+		macro_rules! expect_channel_update {
+			($enabled: expr) => {{
+				let events = nodes[1].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				match events[0] {
+					Event::BroadcastChannelUpdate { ref msg } => {
+						assert_eq!(msg.contents.short_channel_id, chan_2.0.contents.short_channel_id);
+						assert_eq!(msg.contents.flags & (1 << 1) == 0, $enabled);
+						nodes[0].router.handle_channel_update(msg).unwrap();
+					},
+					_ => panic!("Unexpected event"),
+				}
+				assert_eq!(is_enabled!(), $enabled);
+			}}
+		}
+		let can_route = || nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).is_ok();
+		assert!(is_enabled!());
+		assert!(can_route());
+
+		nodes[1].node.peer_disconnected(&nodes[2].node.get_our_node_id(), false);
+		nodes[2].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+
+		// The channel can't be used, but we don't announce that until the timeout passes
+		nodes[1].node.timer_tick_occured();
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+		assert!(!is_usable!());
+		assert!(is_enabled!());
+
+		nodes[1].node.set_channel_disable_timeout(Duration::from_secs(0));
+		nodes[1].node.timer_tick_occured();
+		expect_channel_update!(false);
+		assert!(!can_route());
+		nodes[1].node.timer_tick_occured();
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+
+		// Once the peer is back the channel is usable again, but only announced as such once it's
+		// been connected for the timeout
+		nodes[1].node.set_channel_disable_timeout(Duration::from_secs(60 * 60));
+		reconnect_nodes(&nodes[1], &nodes[2], true, (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
+		nodes[1].node.timer_tick_occured();
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+		assert!(is_usable!());
+		assert!(!is_enabled!());
+
+		nodes[1].node.set_channel_disable_timeout(Duration::from_secs(0));
+		nodes[1].node.timer_tick_occured();
+		expect_channel_update!(true);
+		assert!(can_route());
+		send_payment(&nodes[0], &[&nodes[1], &nodes[2]], 1000000);
+	}
+
+	#[test]
+	fn test_announcement_sigs_resent_on_reconnect() {
+		// Test that announcement_signatures which got lost are re-sent upon reconnection, that a
+		// re-sent announcement_signatures is answered (only once) and that we periodically
+		// re-broadcast channel_announcements for our public channels.
+		let nodes = create_network(2);
+		let (as_funding_msgs, _, _) = create_chan_between_nodes_with_value_a(&nodes[0], &nodes[1], 100000, 10001);
+		// nodes[1] gets nodes[0]'s funding_locked and announcement_signatures, but its own
+		// announcement_signatures are lost.
+		assert!(nodes[1].node.handle_funding_locked(&nodes[0].node.get_our_node_id(), &as_funding_msgs.0).unwrap().is_some());
+		nodes[1].node.handle_announcement_signatures(&nodes[0].node.get_our_node_id(), &as_funding_msgs.1).unwrap();
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		match events[0] {
+			Event::ChannelReady { .. } => {},
+			_ => panic!("Unexpected event"),
+		}
+		let announcement = match events[1] {
+			Event::BroadcastChannelAnnouncement { ref msg, .. } => msg.clone(),
+			_ => panic!("Unexpected event"),
+		};
+
+		nodes[0].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+		nodes[1].node.peer_disconnected(&nodes[0].node.get_our_node_id(), false);
+
+		let reestablish_1 = nodes[0].node.peer_connected(&nodes[1].node.get_our_node_id(), &test_local_features());
+		let reestablish_2 = nodes[1].node.peer_connected(&nodes[0].node.get_our_node_id(), &test_local_features());
+		assert_eq!(reestablish_1.len(), 1);
+		assert_eq!(reestablish_2.len(), 1);
+		let bs_resp = nodes[1].node.handle_channel_reestablish(&nodes[0].node.get_our_node_id(), &reestablish_1[0]).unwrap();
+		let as_resp = nodes[0].node.handle_channel_reestablish(&nodes[1].node.get_our_node_id(), &reestablish_2[0]).unwrap();
+		check_added_monitors!(nodes[0], 0);
+		check_added_monitors!(nodes[1], 0);
+
+		// nodes[1] already has both signatures, nodes[0] never got nodes[1]'s so re-sends its own
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let as_announcement_sigs = match events[0] {
+			Event::SendAnnouncementSignatures { ref node_id, ref msg } => {
+				assert_eq!(*node_id, nodes[1].node.get_our_node_id());
+				msg.clone()
+			},
+			_ => panic!("Unexpected event"),
+		};
+
+		// The re-sent funding_locked messages don't result in announcement_signatures being sent again
+		assert!(nodes[0].node.handle_funding_locked(&nodes[1].node.get_our_node_id(), &bs_resp.0.unwrap()).unwrap().is_none());
+		assert!(nodes[1].node.handle_funding_locked(&nodes[0].node.get_our_node_id(), &as_resp.0.unwrap()).unwrap().is_none());
+
+		// nodes[1] responds to the re-sent announcement_signatures with its own...
+		nodes[1].node.handle_announcement_signatures(&nodes[0].node.get_our_node_id(), &as_announcement_sigs).unwrap();
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let bs_announcement_sigs = match events[0] {
+			Event::SendAnnouncementSignatures { ref node_id, ref msg } => {
+				assert_eq!(*node_id, nodes[0].node.get_our_node_id());
+				msg.clone()
+			},
+			_ => panic!("Unexpected event"),
+		};
+		// ...but only to the first one after a reconnect
+		nodes[1].node.handle_announcement_signatures(&nodes[0].node.get_our_node_id(), &as_announcement_sigs).unwrap();
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+
+		// ...allowing nodes[0] to complete the channel_announcement
+		nodes[0].node.handle_announcement_signatures(&nodes[1].node.get_our_node_id(), &bs_announcement_sigs).unwrap();
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::BroadcastChannelAnnouncement { ref msg, .. } => assert!(*msg == announcement),
+			_ => panic!("Unexpected event"),
+		}
+		// A duplicate doesn't result in another channel_announcement (or a response, as we already
+		// sent ours since reconnecting)
+		nodes[0].node.handle_announcement_signatures(&nodes[1].node.get_our_node_id(), &bs_announcement_sigs).unwrap();
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		// Nothing is re-sent on the next reconnect now that both sides have both signatures
+		nodes[0].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+		nodes[1].node.peer_disconnected(&nodes[0].node.get_our_node_id(), false);
+		reconnect_nodes(&nodes[0], &nodes[1], true, (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+
+		// Once due, timer_tick_occured re-broadcasts the channel_announcement, but only once
+		nodes[0].node.timer_tick_occured();
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+		*nodes[0].node.last_channel_announcement_broadcast.lock().unwrap() = None;
+		nodes[0].node.timer_tick_occured();
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::BroadcastChannelAnnouncement { ref msg, ref update_msg } => {
+				assert!(*msg == announcement);
+				assert_eq!(update_msg.contents.short_channel_id, announcement.contents.short_channel_id);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		nodes[0].node.timer_tick_occured();
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+	}
 }
@@ -14,7 +14,8 @@
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::blockdata::transaction::{TxIn,TxOut,SigHashType,Transaction};
 use bitcoin::blockdata::transaction::OutPoint as BitcoinOutPoint;
-use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::script::{Script, Builder};
+use bitcoin::blockdata::opcodes;
 use bitcoin::network::serialize;
 use bitcoin::util::hash::Sha256dHash;
 use bitcoin::util::bip143;
@@ -28,7 +29,7 @@ use secp256k1;
 use ln::msgs::{DecodeError, HandleError};
 use ln::chan_utils;
 use ln::chan_utils::HTLCOutputInCommitment;
-use chain::chaininterface::{ChainListener, ChainWatchInterface, BroadcasterInterface};
+use chain::chaininterface::{ChainListener, ChainWatchInterface, BroadcasterInterface, FeeEstimator, ConfirmationTarget};
 use chain::transaction::OutPoint;
 use util::ser::{Readable, Writer};
 use util::sha2::Sha256;
@@ -39,6 +40,7 @@ use std::sync::{Arc,Mutex};
 use std::{hash,cmp};
 
 /// An error enum representing a failure to persist a channel monitor update.
+#[derive(Clone)]
 pub enum ChannelMonitorUpdateErr {
 	/// Used to indicate a temporary failure (eg connection to a watchtower failed, but is expected
 	/// to succeed at some point in the future).
@@ -89,14 +91,15 @@ pub struct SimpleManyChannelMonitor<Key> {
 	#[cfg(not(test))]
 	monitors: Mutex<HashMap<Key, ChannelMonitor>>,
 	chain_monitor: Arc<ChainWatchInterface>,
-	broadcaster: Arc<BroadcasterInterface>
+	broadcaster: Arc<BroadcasterInterface>,
+	fee_estimator: Arc<FeeEstimator>,
 }
 
 impl<Key : Send + cmp::Eq + hash::Hash> ChainListener for SimpleManyChannelMonitor<Key> {
 	fn block_connected(&self, _header: &BlockHeader, height: u32, txn_matched: &[&Transaction], _indexes_of_txn_matched: &[u32]) {
 		let monitors = self.monitors.lock().unwrap();
 		for monitor in monitors.values() {
-			let txn_outputs = monitor.block_connected(txn_matched, height, &*self.broadcaster);
+			let txn_outputs = monitor.block_connected(txn_matched, height, &*self.broadcaster, &*self.fee_estimator);
 			for (ref txid, ref outputs) in txn_outputs {
 				for (idx, output) in outputs.iter().enumerate() {
 					self.chain_monitor.install_watch_outpoint((txid.clone(), idx as u32), &output.script_pubkey);
@@ -110,12 +113,14 @@ impl<Key : Send + cmp::Eq + hash::Hash> ChainListener for SimpleManyChannelMonit
 
 impl<Key : Send + cmp::Eq + hash::Hash + 'static> SimpleManyChannelMonitor<Key> {
 	/// Creates a new object which can be used to monitor several channels given the chain
-	/// interface with which to register to receive notifications.
-	pub fn new(chain_monitor: Arc<ChainWatchInterface>, broadcaster: Arc<BroadcasterInterface>) -> Arc<SimpleManyChannelMonitor<Key>> {
+	/// interface with which to register to receive notifications and the fee estimator used to
+	/// pick the feerate of our claim transactions.
+	pub fn new(chain_monitor: Arc<ChainWatchInterface>, broadcaster: Arc<BroadcasterInterface>, fee_estimator: Arc<FeeEstimator>) -> Arc<SimpleManyChannelMonitor<Key>> {
 		let res = Arc::new(SimpleManyChannelMonitor {
 			monitors: Mutex::new(HashMap::new()),
 			chain_monitor,
-			broadcaster
+			broadcaster,
+			fee_estimator,
 		});
 		let weak_res = Arc::downgrade(&res);
 		res.chain_monitor.register_listener(weak_res);
@@ -155,7 +160,16 @@ impl ManyChannelMonitor for SimpleManyChannelMonitor<OutPoint> {
 const CLTV_SHARED_CLAIM_BUFFER: u32 = 12;
 /// If an HTLC expires within this many blocks, force-close the channel to broadcast the
 /// HTLC-Success transaction.
-const CLTV_CLAIM_BUFFER: u32 = 6;
+pub(crate) const CLTV_CLAIM_BUFFER: u32 = 6;
+
+/// The (maximum) weight of a transaction spending a single P2WPKH output to destination_script.
+fn to_remote_claim_tx_weight(destination_script: &Script) -> u64 {
+	// version: 4, input count: 1, prevout: 36, script len: 1, nSequence: 4, output count: 1,
+	// value: 8, script len: 1, lock_time: 4
+	let base_size = 60 + destination_script.len() as u64;
+	// segwit marker+flag: 2, witness element count: 1, sig: 1+73, pubkey: 1+33
+	base_size * 4 + 2 + 1 + 74 + 34
+}
 
 #[derive(Clone, PartialEq)]
 enum KeyStorage {
@@ -229,6 +243,11 @@ pub struct ChannelMonitor {
 	payment_preimages: HashMap<[u8; 32], [u8; 32]>,
 
 	destination_script: Script,
+	/// Set once our counterparty has proven (via option_data_loss_protect) that we are running on
+	/// stale channel state. Holds the script of our to_remote output in their latest commitment
+	/// transaction and the private key which can spend it. While set, we will never broadcast our
+	/// (revoked, as far as the counterparty is concerned) local commitment transactions.
+	to_remote_rescue: Option<(Script, SecretKey)>,
 	secp_ctx: Secp256k1<secp256k1::All>, //TODO: dedup this a bit...
 }
 impl Clone for ChannelMonitor {
@@ -257,6 +276,7 @@ impl Clone for ChannelMonitor {
 			payment_preimages: self.payment_preimages.clone(),
 
 			destination_script: self.destination_script.clone(),
+			to_remote_rescue: self.to_remote_rescue.clone(),
 			secp_ctx: self.secp_ctx.clone(),
 		}
 	}
@@ -281,7 +301,8 @@ impl PartialEq for ChannelMonitor {
 			self.prev_local_signed_commitment_tx != other.prev_local_signed_commitment_tx ||
 			self.current_local_signed_commitment_tx != other.current_local_signed_commitment_tx ||
 			self.payment_preimages != other.payment_preimages ||
-			self.destination_script != other.destination_script
+			self.destination_script != other.destination_script ||
+			self.to_remote_rescue != other.to_remote_rescue
 		{
 			false
 		} else {
@@ -326,6 +347,7 @@ impl ChannelMonitor {
 			payment_preimages: HashMap::new(),
 
 			destination_script: destination_script,
+			to_remote_rescue: None,
 			secp_ctx: Secp256k1::new(),
 		}
 	}
@@ -467,6 +489,14 @@ impl ChannelMonitor {
 		self.payment_preimages.insert(payment_hash.clone(), payment_preimage.clone());
 	}
 
+	/// Informs this monitor that our counterparty has proven we are running on stale channel state
+	/// and provides the information required to claim our to_remote output when they broadcast
+	/// their latest commitment transaction. From now on our own local commitment transactions will
+	/// never be broadcast, as doing so would allow our counterparty to claim all channel funds.
+	pub(super) fn provide_rescue_remote_commitment_tx_info(&mut self, to_remote_script: Script, to_remote_key: SecretKey) {
+		self.to_remote_rescue = Some((to_remote_script, to_remote_key));
+	}
+
 	/// Combines this ChannelMonitor with the information contained in the other ChannelMonitor.
 	/// After a successful call this ChannelMonitor is up-to-date and is safe to use to monitor the
 	/// chain for new blocks/transactions.
@@ -498,6 +528,9 @@ impl ChannelMonitor {
 			}
 			self.payment_preimages = other.payment_preimages;
 		}
+		if other.to_remote_rescue.is_some() {
+			self.to_remote_rescue = other.to_remote_rescue;
+		}
 		Ok(())
 	}
 
@@ -683,6 +716,15 @@ impl ChannelMonitor {
 		writer.write_all(&byte_utils::be64_to_array(self.destination_script.len() as u64))?;
 		writer.write_all(&self.destination_script[..])?;
 
+		if let Some((ref to_remote_script, ref to_remote_key)) = self.to_remote_rescue {
+			writer.write_all(&[1; 1])?;
+			writer.write_all(&byte_utils::be64_to_array(to_remote_script.len() as u64))?;
+			writer.write_all(&to_remote_script[..])?;
+			writer.write_all(&to_remote_key[..])?;
+		} else {
+			writer.write_all(&[0; 1])?;
+		}
+
 		Ok(())
 	}
 
@@ -725,7 +767,7 @@ impl ChannelMonitor {
 	/// data in remote_claimable_outpoints. Will directly claim any HTLC outputs which expire at a
 	/// height > height + CLTV_SHARED_CLAIM_BUFFER. In any case, will install monitoring for
 	/// HTLC-Success/HTLC-Timeout transactions.
-	fn check_spend_remote_transaction(&self, tx: &Transaction, height: u32) -> (Vec<Transaction>, (Sha256dHash, Vec<TxOut>)) {
+	fn check_spend_remote_transaction(&self, tx: &Transaction, height: u32, fee_estimator: &FeeEstimator) -> (Vec<Transaction>, (Sha256dHash, Vec<TxOut>)) {
 		// Most secp and related errors trying to create keys means we have no hope of constructing
 		// a spend transaction...so we return no transactions to broadcast
 		let mut txn_to_broadcast = Vec::new();
@@ -744,6 +786,51 @@ impl ChannelMonitor {
 		}
 
 		let commitment_number = 0xffffffffffff - ((((tx.input[0].sequence as u64 & 0xffffff) << 3*8) | (tx.lock_time as u64 & 0xffffff)) ^ self.commitment_transaction_number_obscure_factor);
+		if commitment_number < self.get_min_seen_secret() {
+			if let Some((ref to_remote_script, ref to_remote_key)) = self.to_remote_rescue {
+				// We've fallen behind, so this may well be our counterparty's latest commitment
+				// transaction, which we know nothing about except the per_commitment_point they gave
+				// us in channel_reestablish. We can, however, still claim our to_remote output.
+				for (idx, outp) in tx.output.iter().enumerate() {
+					if outp.script_pubkey == *to_remote_script {
+						let fee = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal) * to_remote_claim_tx_weight(&self.destination_script) / 1000;
+						if outp.value <= fee { continue; } // Not worth claiming
+						let mut spend_tx = Transaction {
+							version: 2,
+							lock_time: 0,
+							input: vec![TxIn {
+								previous_output: BitcoinOutPoint {
+									txid: commitment_txid,
+									vout: idx as u32,
+								},
+								script_sig: Script::new(),
+								sequence: 0xfffffffd,
+								witness: Vec::new(),
+							}],
+							output: vec!(TxOut {
+								script_pubkey: self.destination_script.clone(),
+								value: outp.value - fee,
+							}),
+						};
+						// The BIP 143 scriptCode for a P2WPKH output is the equivalent P2PKH script
+						let script_code = Builder::new().push_opcode(opcodes::All::OP_DUP)
+						                                .push_opcode(opcodes::All::OP_HASH160)
+						                                .push_slice(&to_remote_script[2..])
+						                                .push_opcode(opcodes::All::OP_EQUALVERIFY)
+						                                .push_opcode(opcodes::All::OP_CHECKSIG)
+						                                .into_script();
+						let sighash = ignore_error!(Message::from_slice(&bip143::SighashComponents::new(&spend_tx).sighash_all(&spend_tx.input[0], &script_code, outp.value)[..]));
+						let sig = self.secp_ctx.sign(&sighash, to_remote_key);
+						spend_tx.input[0].witness.push(sig.serialize_der(&self.secp_ctx).to_vec());
+						spend_tx.input[0].witness[0].push(SigHashType::All as u8);
+						spend_tx.input[0].witness.push(PublicKey::from_secret_key(&self.secp_ctx, to_remote_key).serialize().to_vec());
+						txn_to_broadcast.push(spend_tx);
+						break; // There can only be one of these
+					}
+				}
+			}
+		}
+
 		if commitment_number >= self.get_min_seen_secret() {
 			let secret = self.get_secret(commitment_number).unwrap();
 			let per_commitment_key = ignore_error!(SecretKey::from_slice(&self.secp_ctx, &secret));
@@ -1151,7 +1238,7 @@ impl ChannelMonitor {
 		Vec::new()
 	}
 
-	fn block_connected(&self, txn_matched: &[&Transaction], height: u32, broadcaster: &BroadcasterInterface)-> Vec<(Sha256dHash, Vec<TxOut>)> {
+	fn block_connected(&self, txn_matched: &[&Transaction], height: u32, broadcaster: &BroadcasterInterface, fee_estimator: &FeeEstimator)-> Vec<(Sha256dHash, Vec<TxOut>)> {
 		let mut watch_outputs = Vec::new();
 		for tx in txn_matched {
 			if tx.input.len() == 1 {
@@ -1162,7 +1249,7 @@ impl ChannelMonitor {
 				let prevout = &tx.input[0].previous_output;
 				let mut txn: Vec<Transaction> = Vec::new();
 				if self.funding_txo.is_none() || (prevout.txid == self.funding_txo.as_ref().unwrap().0.txid && prevout.vout == self.funding_txo.as_ref().unwrap().0.index as u32) {
-					let (remote_txn, new_outputs) = self.check_spend_remote_transaction(tx, height);
+					let (remote_txn, new_outputs) = self.check_spend_remote_transaction(tx, height, fee_estimator);
 					txn = remote_txn;
 					if !new_outputs.1.is_empty() {
 						watch_outputs.push(new_outputs);
@@ -1183,6 +1270,11 @@ impl ChannelMonitor {
 				}
 			}
 		}
+		if self.to_remote_rescue.is_some() {
+			// Our local commitment transactions are stale, broadcasting them would only hand our
+			// counterparty the entire channel balance.
+			return watch_outputs;
+		}
 		if let Some(ref cur_local_tx) = self.current_local_signed_commitment_tx {
 			let mut needs_broadcast = false;
 			for &(ref htlc, _, _) in cur_local_tx.htlc_outputs.iter() {
@@ -1204,6 +1296,9 @@ impl ChannelMonitor {
 	}
 
 	pub(super) fn would_broadcast_at_height(&self, height: u32) -> bool {
+		if self.to_remote_rescue.is_some() {
+			return false;
+		}
 		if let Some(ref cur_local_tx) = self.current_local_signed_commitment_tx {
 			for &(ref htlc, _, _) in cur_local_tx.htlc_outputs.iter() {
 				if htlc.cltv_expiry <= height + CLTV_CLAIM_BUFFER {
@@ -1432,6 +1527,17 @@ impl<R: ::std::io::Read> Readable<R> for ChannelMonitor {
 		let destination_script_len = byte_utils::slice_to_be64(read_bytes!(8));
 		let destination_script = Script::from(read_bytes!(destination_script_len).to_vec());
 
+		let to_remote_rescue = match read_bytes!(1)[0] {
+			0 => None,
+			1 => {
+				let to_remote_script_len = byte_utils::slice_to_be64(read_bytes!(8));
+				let to_remote_script = Script::from(read_bytes!(to_remote_script_len).to_vec());
+				let to_remote_key = unwrap_obj!(SecretKey::from_slice(&secp_ctx, read_bytes!(32)));
+				Some((to_remote_script, to_remote_key))
+			},
+			_ => return Err(DecodeError::InvalidValue),
+		};
+
 		Ok(ChannelMonitor {
 			funding_txo,
 			commitment_transaction_number_obscure_factor,
@@ -1456,6 +1562,7 @@ impl<R: ::std::io::Read> Readable<R> for ChannelMonitor {
 			payment_preimages,
 
 			destination_script,
+			to_remote_rescue,
 			secp_ctx,
 		})
 	}
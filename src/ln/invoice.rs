@@ -0,0 +1,982 @@
+//! Reading, writing and signing of BOLT 11 invoices.
+//!
+//! An Invoice is a signed, bech32-encoded request for payment which tells the payer everything
+//! Router::get_route and ChannelManager::send_payment need: the payee's node_id, the payment_hash,
+//! (optionally) the amount, the min_final_cltv_expiry and route hints (as RouteHints) through any
+//! private channels required to reach the payee.
+//!
+//! Payees build a RawInvoice and sign it with ChannelManager::sign_invoice (or RawInvoice::sign),
+//! handing the Invoice's string form to the payer, who reads it back with Invoice::from_str.
+
+use bech32::{Bech32, u5, ToBase32};
+use bech32;
+
+use bitcoin::network::constants::Network;
+
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::{Message, RecoverableSignature, RecoveryId, Secp256k1};
+use secp256k1;
+
+use crypto::digest::Digest;
+
+use ln::router::RouteHint;
+use util::byte_utils;
+use util::sha2::Sha256;
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The expiry time, in seconds, of invoices which do not specify one.
+pub const DEFAULT_EXPIRY_TIME: u64 = 3600;
+/// The min_final_cltv_expiry of invoices which do not specify one.
+pub const DEFAULT_MIN_FINAL_CLTV_EXPIRY: u64 = 9;
+
+/// Timestamps are encoded as 35-bit integers.
+const MAX_TIMESTAMP: u64 = (1 << 35) - 1;
+/// Tagged fields have a 10-bit length, in 5-bit groups.
+const MAX_FIELD_LEN: usize = (1 << 10) - 1;
+/// A recoverable signature is 65 bytes, which is exactly 104 5-bit groups.
+const SIGNATURE_LEN: usize = 104;
+/// The length, in bytes, of each hop in a route hint ('r') field.
+const ROUTE_HINT_HOP_LEN: usize = 33 + 8 + 4 + 4 + 2;
+
+// Tagged field types, as indexes into the bech32 charset
+const TAG_PAYMENT_HASH: u8 = 1; // 'p'
+const TAG_ROUTE_HINT: u8 = 3; // 'r'
+const TAG_FEATURES: u8 = 5; // '9'
+const TAG_EXPIRY_TIME: u8 = 6; // 'x'
+const TAG_FALLBACK: u8 = 9; // 'f'
+const TAG_DESCRIPTION: u8 = 13; // 'd'
+const TAG_PAYMENT_SECRET: u8 = 16; // 's'
+const TAG_PAYEE_PUBKEY: u8 = 19; // 'n'
+const TAG_DESCRIPTION_HASH: u8 = 23; // 'h'
+const TAG_MIN_FINAL_CLTV_EXPIRY: u8 = 24; // 'c'
+
+/// An error in reading, signing or using an invoice.
+#[derive(Debug, PartialEq)]
+pub enum InvoiceError {
+	/// The string was not valid bech32
+	Bech32Error,
+	/// The human-readable part did not start with "ln" followed by a known currency prefix
+	UnknownCurrency,
+	/// The amount was malformed, zero, overflowed or was not a whole number of msat
+	InvalidAmount,
+	/// The data part was too short to contain a timestamp and signature
+	TooShort,
+	/// The timestamp did not fit in 35 bits
+	InvalidTimestamp,
+	/// A tagged field's contents could not be encoded (eg a fallback with a witness version above
+	/// 16). Malformed fields are skipped when reading an invoice.
+	InvalidField,
+	/// A tagged field was too long to be encoded (eg a description of more than 639 bytes)
+	FieldTooLong,
+	/// The invoice did not contain a payment_hash
+	MissingPaymentHash,
+	/// The invoice did not contain exactly one of a description and a description_hash
+	InvalidDescription,
+	/// The signature was malformed, did not match the payee_pubkey, or the payee_pubkey did not
+	/// match the key we were asked to sign with
+	InvalidSignature,
+	/// A route hint had more than one hop, which Router::get_route cannot make use of
+	UnsupportedRouteHint,
+}
+
+impl Error for InvoiceError {
+	fn description(&self) -> &str {
+		match *self {
+			InvoiceError::Bech32Error => "Invoice was not valid bech32",
+			InvoiceError::UnknownCurrency => "Invoice had an unknown currency prefix",
+			InvoiceError::InvalidAmount => "Invoice amount was malformed or not a whole number of msat",
+			InvoiceError::TooShort => "Invoice data was too short",
+			InvoiceError::InvalidTimestamp => "Invoice timestamp did not fit in 35 bits",
+			InvoiceError::InvalidField => "Invoice contained a malformed tagged field",
+			InvoiceError::FieldTooLong => "Invoice field was too long to be encoded",
+			InvoiceError::MissingPaymentHash => "Invoice did not contain a payment_hash",
+			InvoiceError::InvalidDescription => "Invoice did not contain exactly one of a description and a description_hash",
+			InvoiceError::InvalidSignature => "Invoice signature was invalid or did not match the payee",
+			InvoiceError::UnsupportedRouteHint => "Invoice had a route hint with more than one hop",
+		}
+	}
+}
+impl fmt::Display for InvoiceError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.description())
+	}
+}
+
+/// The currency (ie chain) an invoice is for, as encoded in its human-readable part.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Currency {
+	/// Bitcoin mainnet ("lnbc")
+	Bitcoin,
+	/// Bitcoin testnet ("lntb")
+	BitcoinTestnet,
+	/// Bitcoin regtest ("lnbcrt")
+	Regtest,
+	/// Bitcoin simnet ("lnsb")
+	Simnet,
+}
+
+impl Currency {
+	fn hrp_prefix(&self) -> &'static str {
+		match *self {
+			Currency::Bitcoin => "bc",
+			Currency::BitcoinTestnet => "tb",
+			Currency::Regtest => "bcrt",
+			Currency::Simnet => "sb",
+		}
+	}
+
+	/// The Network this currency is for, if any (there is no Network for simnet).
+	pub fn network(&self) -> Option<Network> {
+		match *self {
+			Currency::Bitcoin => Some(Network::Bitcoin),
+			Currency::BitcoinTestnet => Some(Network::Testnet),
+			Currency::Regtest => Some(Network::Regtest),
+			Currency::Simnet => None,
+		}
+	}
+}
+
+impl From<Network> for Currency {
+	fn from(network: Network) -> Self {
+		match network {
+			Network::Bitcoin => Currency::Bitcoin,
+			Network::Testnet => Currency::BitcoinTestnet,
+			Network::Regtest => Currency::Regtest,
+		}
+	}
+}
+
+/// An on-chain address the payer may fall back to if the payment cannot be made over lightning.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fallback {
+	/// A segwit output with the given witness version and program
+	SegWitProgram {
+		/// The witness version, from 0 to 16
+		version: u8,
+		/// The witness program
+		program: Vec<u8>,
+	},
+	/// A P2PKH output paying to the given hash160 of a public key
+	PubKeyHash([u8; 20]),
+	/// A P2SH output paying to the given hash160 of a script
+	ScriptHash([u8; 20]),
+}
+
+/// The contents of a BOLT 11 invoice, prior to signing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawInvoice {
+	/// The chain the payment should be made on
+	pub currency: Currency,
+	/// The amount requested, in thousandths of a satoshi, or None if the payer may pick one.
+	pub amount_msat: Option<u64>,
+	/// The time the invoice was created, in seconds since the UNIX epoch. Must fit in 35 bits.
+	pub timestamp: u64,
+	/// The hash of the preimage the payee will reveal when claiming the payment
+	pub payment_hash: [u8; 32],
+	/// A secret the payer must pass along to the payee to prove it saw this invoice, if any
+	pub payment_secret: Option<[u8; 32]>,
+	/// A short, human-readable description of what the payment is for. Exactly one of description
+	/// and description_hash must be set.
+	pub description: Option<String>,
+	/// The SHA256 of a (longer) description of what the payment is for, provided out-of-band.
+	pub description_hash: Option<[u8; 32]>,
+	/// The payee's node_id. If this is None when signing it is left out of the invoice, in which
+	/// case readers recover it from the signature.
+	pub payee_pubkey: Option<PublicKey>,
+	/// The number of seconds after timestamp at which the invoice expires, if not the default of
+	/// DEFAULT_EXPIRY_TIME.
+	pub expiry_time: Option<u64>,
+	/// The CLTV delta the payee requires for the final hop, if not the default of
+	/// DEFAULT_MIN_FINAL_CLTV_EXPIRY.
+	pub min_final_cltv_expiry: Option<u64>,
+	/// On-chain addresses the payer may use instead, in order of preference
+	pub fallbacks: Vec<Fallback>,
+	/// Paths through private channels which lead to the payee, each ending with a channel to the
+	/// payee. Invoices have no way to express htlc_minimum_msat, so it is always read as 0.
+	pub route_hints: Vec<Vec<RouteHint>>,
+	/// The features the payee supports, as a big-endian bitfield (ie bit 0 is the low bit of the
+	/// last byte), or None if the invoice has no features field.
+	pub features: Option<Vec<u8>>,
+}
+
+/// A signed BOLT 11 invoice. Use to_string() to get its bech32 form and Invoice::from_str to read
+/// one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invoice {
+	raw: RawInvoice,
+	payee_pubkey: PublicKey,
+	signature: RecoverableSignature,
+	/// The bech32 encoding, kept around as it covers any fields we didn't understand when reading.
+	encoded: String,
+}
+
+fn encode_amount(amount_msat: u64) -> Result<String, InvoiceError> {
+	// There are 10^11 msat in a bitcoin, and amounts are given in bitcoin with an optional
+	// multiplier, so use the largest unit which represents the amount exactly.
+	if amount_msat == 0 {
+		return Err(InvoiceError::InvalidAmount);
+	}
+	Ok(if amount_msat % 100_000_000_000 == 0 {
+		format!("{}", amount_msat / 100_000_000_000)
+	} else if amount_msat % 100_000_000 == 0 {
+		format!("{}m", amount_msat / 100_000_000)
+	} else if amount_msat % 100_000 == 0 {
+		format!("{}u", amount_msat / 100_000)
+	} else if amount_msat % 100 == 0 {
+		format!("{}n", amount_msat / 100)
+	} else {
+		format!("{}p", amount_msat.checked_mul(10).ok_or(InvoiceError::InvalidAmount)?)
+	})
+}
+
+fn decode_amount(amount: &str) -> Result<Option<u64>, InvoiceError> {
+	if amount.is_empty() {
+		return Ok(None);
+	}
+	let (digits, multiplier) = match amount.chars().last().unwrap() {
+		'm' => (&amount[..amount.len() - 1], 100_000_000),
+		'u' => (&amount[..amount.len() - 1], 100_000),
+		'n' => (&amount[..amount.len() - 1], 100),
+		'p' => (&amount[..amount.len() - 1], 0),
+		_ => (amount, 100_000_000_000),
+	};
+	if digits.is_empty() || !digits.bytes().all(|c| c >= b'0' && c <= b'9') {
+		return Err(InvoiceError::InvalidAmount);
+	}
+	let value = u64::from_str(digits).map_err(|_| InvoiceError::InvalidAmount)?;
+	let amount_msat = if multiplier == 0 {
+		// pico-bitcoin, of which there are 10 per msat
+		if value % 10 != 0 {
+			return Err(InvoiceError::InvalidAmount);
+		}
+		value / 10
+	} else {
+		value.checked_mul(multiplier).ok_or(InvoiceError::InvalidAmount)?
+	};
+	if amount_msat == 0 {
+		return Err(InvoiceError::InvalidAmount);
+	}
+	Ok(Some(amount_msat))
+}
+
+/// Re-groups a big-endian integer of from-bit groups into to-bit groups, dropping leading zero
+/// groups (unlike bech32::convert_bits, which pads at the end).
+fn regroup_be(data: &[u8], from: u32, to: u32) -> Vec<u8> {
+	let mut res = Vec::with_capacity(data.len() * from as usize / to as usize + 1);
+	let mut acc: u32 = 0;
+	let mut bits = 0;
+	for value in data.iter().rev() {
+		acc |= (*value as u32) << bits;
+		bits += from;
+		while bits >= to {
+			res.push((acc & ((1 << to) - 1)) as u8);
+			acc >>= to;
+			bits -= to;
+		}
+	}
+	if bits > 0 {
+		res.push(acc as u8);
+	}
+	while res.last() == Some(&0) {
+		res.pop();
+	}
+	res.reverse();
+	res
+}
+
+fn u5_vec(data: Vec<u8>) -> Vec<u5> {
+	data.iter().map(|v| u5::try_from_u8(*v).unwrap()).collect()
+}
+
+fn u8_vec(data: &[u5]) -> Vec<u8> {
+	data.iter().map(|v| v.to_u8()).collect()
+}
+
+fn encode_int(value: u64) -> Vec<u5> {
+	u5_vec(regroup_be(&byte_utils::be64_to_array(value), 8, 5))
+}
+
+fn decode_int(data: &[u5]) -> Result<u64, InvoiceError> {
+	let mut res: u64 = 0;
+	for value in data {
+		if res.leading_zeros() < 5 {
+			return Err(InvoiceError::InvalidField);
+		}
+		res = (res << 5) | value.to_u8() as u64;
+	}
+	Ok(res)
+}
+
+/// Decodes the bytes in a field, which must have been zero-padded at the end.
+fn decode_bytes(data: &[u5]) -> Result<Vec<u8>, InvoiceError> {
+	bech32::convert_bits(data, 5, 8, false).map_err(|_| InvoiceError::InvalidField)
+}
+
+fn read_32_bytes(data: &[u5]) -> Result<[u8; 32], InvoiceError> {
+	let bytes = decode_bytes(data)?;
+	let mut res = [0; 32];
+	res.copy_from_slice(&bytes[..]);
+	Ok(res)
+}
+
+fn push_field(data: &mut Vec<u5>, tag: u8, contents: Vec<u5>) -> Result<(), InvoiceError> {
+	if contents.len() > MAX_FIELD_LEN {
+		return Err(InvoiceError::FieldTooLong);
+	}
+	data.push(u5::try_from_u8(tag).unwrap());
+	data.push(u5::try_from_u8((contents.len() >> 5) as u8).unwrap());
+	data.push(u5::try_from_u8((contents.len() & 31) as u8).unwrap());
+	data.extend_from_slice(&contents[..]);
+	Ok(())
+}
+
+/// The hash which is signed: the SHA256 of the human-readable part followed by the data part
+/// (excluding the signature) padded to a byte boundary.
+fn signable_hash(hrp: &str, data: &[u5]) -> Message {
+	let mut sha = Sha256::new();
+	sha.input(hrp.as_bytes());
+	sha.input(&bech32::convert_bits(data, 5, 8, true).unwrap()[..]);
+	let mut res = [0; 32];
+	sha.result(&mut res);
+	Message::from_slice(&res).unwrap()
+}
+
+/// Reads a tagged field into raw (or payment_hash, counting descriptions in description_count),
+/// failing with InvalidField if its contents are malformed. Unknown fields, and known ones with
+/// bogus lengths, are ignored.
+fn read_field<C>(raw: &mut RawInvoice, payment_hash: &mut Option<[u8; 32]>, description_count: &mut usize, secp_ctx: &Secp256k1<C>, tag: u8, contents: &[u5]) -> Result<(), InvoiceError> {
+	let len = contents.len();
+	match tag {
+		TAG_PAYMENT_HASH if len == 52 && payment_hash.is_none() => {
+			*payment_hash = Some(read_32_bytes(contents)?);
+		},
+		TAG_PAYMENT_SECRET if len == 52 && raw.payment_secret.is_none() => {
+			raw.payment_secret = Some(read_32_bytes(contents)?);
+		},
+		TAG_DESCRIPTION => {
+			let description = String::from_utf8(decode_bytes(contents)?).map_err(|_| InvoiceError::InvalidField)?;
+			raw.description = Some(description);
+			*description_count += 1;
+		},
+		TAG_DESCRIPTION_HASH if len == 52 => {
+			raw.description_hash = Some(read_32_bytes(contents)?);
+			*description_count += 1;
+		},
+		TAG_PAYEE_PUBKEY if len == 53 && raw.payee_pubkey.is_none() => {
+			raw.payee_pubkey = Some(PublicKey::from_slice(secp_ctx, &decode_bytes(contents)?[..]).map_err(|_| InvoiceError::InvalidField)?);
+		},
+		TAG_EXPIRY_TIME => {
+			raw.expiry_time = Some(decode_int(contents)?);
+		},
+		TAG_MIN_FINAL_CLTV_EXPIRY => {
+			raw.min_final_cltv_expiry = Some(decode_int(contents)?);
+		},
+		TAG_FALLBACK if len > 0 => {
+			let program = decode_bytes(&contents[1..])?;
+			match contents[0].to_u8() {
+				0 if program.len() != 20 && program.len() != 32 => return Err(InvoiceError::InvalidField),
+				version @ 0..=16 => {
+					if program.len() < 2 || program.len() > 40 {
+						return Err(InvoiceError::InvalidField);
+					}
+					raw.fallbacks.push(Fallback::SegWitProgram { version, program })
+				},
+				version @ 17..=18 => {
+					if program.len() != 20 {
+						return Err(InvoiceError::InvalidField);
+					}
+					let mut hash = [0; 20];
+					hash.copy_from_slice(&program[..]);
+					raw.fallbacks.push(if version == 17 { Fallback::PubKeyHash(hash) } else { Fallback::ScriptHash(hash) });
+				},
+				_ => {}, // Unknown versions must be skipped
+			}
+		},
+		TAG_ROUTE_HINT => {
+			let bytes = decode_bytes(contents)?;
+			if bytes.is_empty() || bytes.len() % ROUTE_HINT_HOP_LEN != 0 {
+				return Err(InvoiceError::InvalidField);
+			}
+			let mut path = Vec::with_capacity(bytes.len() / ROUTE_HINT_HOP_LEN);
+			for hop in bytes.chunks(ROUTE_HINT_HOP_LEN) {
+				path.push(RouteHint {
+					src_node_id: PublicKey::from_slice(secp_ctx, &hop[0..33]).map_err(|_| InvoiceError::InvalidField)?,
+					short_channel_id: byte_utils::slice_to_be64(&hop[33..41]),
+					fee_base_msat: byte_utils::slice_to_be32(&hop[41..45]),
+					fee_proportional_millionths: byte_utils::slice_to_be32(&hop[45..49]),
+					cltv_expiry_delta: byte_utils::slice_to_be16(&hop[49..51]),
+					htlc_minimum_msat: 0,
+				});
+			}
+			raw.route_hints.push(path);
+		},
+		TAG_FEATURES => {
+			raw.features = Some(regroup_be(&u8_vec(contents)[..], 5, 8));
+		},
+		_ => {}, // Unknown fields (and known ones with bogus lengths) must be skipped
+	}
+	Ok(())
+}
+
+impl RawInvoice {
+	fn hrp(&self) -> Result<String, InvoiceError> {
+		let amount = match self.amount_msat {
+			Some(amount_msat) => encode_amount(amount_msat)?,
+			None => String::new(),
+		};
+		Ok(format!("ln{}{}", self.currency.hrp_prefix(), amount))
+	}
+
+	/// The timestamp and tagged fields, ie the data part without the signature.
+	fn data_part(&self) -> Result<Vec<u5>, InvoiceError> {
+		if self.timestamp > MAX_TIMESTAMP {
+			return Err(InvoiceError::InvalidTimestamp);
+		}
+		if self.description.is_some() == self.description_hash.is_some() {
+			return Err(InvoiceError::InvalidDescription);
+		}
+
+		let mut data = u5_vec(regroup_be(&byte_utils::be64_to_array(self.timestamp), 8, 5));
+		while data.len() < 7 {
+			data.insert(0, u5::try_from_u8(0).unwrap());
+		}
+
+		push_field(&mut data, TAG_PAYMENT_HASH, self.payment_hash.to_base32())?;
+		if let Some(ref payment_secret) = self.payment_secret {
+			push_field(&mut data, TAG_PAYMENT_SECRET, payment_secret.to_base32())?;
+		}
+		if let Some(ref description) = self.description {
+			push_field(&mut data, TAG_DESCRIPTION, description.as_bytes().to_base32())?;
+		}
+		if let Some(ref description_hash) = self.description_hash {
+			push_field(&mut data, TAG_DESCRIPTION_HASH, description_hash.to_base32())?;
+		}
+		if let Some(ref payee_pubkey) = self.payee_pubkey {
+			push_field(&mut data, TAG_PAYEE_PUBKEY, (&payee_pubkey.serialize()[..]).to_base32())?;
+		}
+		if let Some(expiry_time) = self.expiry_time {
+			push_field(&mut data, TAG_EXPIRY_TIME, encode_int(expiry_time))?;
+		}
+		if let Some(min_final_cltv_expiry) = self.min_final_cltv_expiry {
+			push_field(&mut data, TAG_MIN_FINAL_CLTV_EXPIRY, encode_int(min_final_cltv_expiry))?;
+		}
+		for fallback in self.fallbacks.iter() {
+			let (version, program) = match fallback {
+				&Fallback::SegWitProgram { version, ref program } => {
+					if version > 16 {
+						return Err(InvoiceError::InvalidField);
+					}
+					(version, program.to_base32())
+				},
+				&Fallback::PubKeyHash(ref hash) => (17, hash.to_base32()),
+				&Fallback::ScriptHash(ref hash) => (18, hash.to_base32()),
+			};
+			let mut contents = vec![u5::try_from_u8(version).unwrap()];
+			contents.extend_from_slice(&program[..]);
+			push_field(&mut data, TAG_FALLBACK, contents)?;
+		}
+		for path in self.route_hints.iter() {
+			let mut bytes = Vec::with_capacity(path.len() * ROUTE_HINT_HOP_LEN);
+			for hop in path.iter() {
+				bytes.extend_from_slice(&hop.src_node_id.serialize());
+				bytes.extend_from_slice(&byte_utils::be64_to_array(hop.short_channel_id));
+				bytes.extend_from_slice(&byte_utils::be32_to_array(hop.fee_base_msat));
+				bytes.extend_from_slice(&byte_utils::be32_to_array(hop.fee_proportional_millionths));
+				bytes.extend_from_slice(&byte_utils::be16_to_array(hop.cltv_expiry_delta));
+			}
+			push_field(&mut data, TAG_ROUTE_HINT, bytes.to_base32())?;
+		}
+		if let Some(ref features) = self.features {
+			push_field(&mut data, TAG_FEATURES, u5_vec(regroup_be(&features[..], 8, 5)))?;
+		}
+		Ok(data)
+	}
+
+	/// Signs the invoice with the given node secret key (which should be our node_id's, see
+	/// ChannelManager::sign_invoice).
+	///
+	/// Fails if the invoice's contents cannot be encoded (eg a missing description or a timestamp
+	/// which doesn't fit in 35 bits), or if payee_pubkey is set but doesn't match node_secret.
+	pub fn sign<C: secp256k1::Signing>(self, secp_ctx: &Secp256k1<C>, node_secret: &SecretKey) -> Result<Invoice, InvoiceError> {
+		let payee_pubkey = PublicKey::from_secret_key(secp_ctx, node_secret);
+		if let Some(pubkey) = self.payee_pubkey {
+			if pubkey != payee_pubkey {
+				return Err(InvoiceError::InvalidSignature);
+			}
+		}
+
+		let hrp = self.hrp()?;
+		let mut data = self.data_part()?;
+		let signature = secp_ctx.sign_recoverable(&signable_hash(&hrp, &data), node_secret);
+		let (recovery_id, compact_sig) = signature.serialize_compact(secp_ctx);
+		let mut sig_bytes = compact_sig.to_vec();
+		sig_bytes.push(recovery_id.to_i32() as u8);
+		data.extend_from_slice(&sig_bytes.to_base32()[..]);
+
+		let encoded = Bech32::new(hrp, data).map_err(|_| InvoiceError::Bech32Error)?.to_string();
+		Ok(Invoice {
+			raw: self,
+			payee_pubkey,
+			signature,
+			encoded,
+		})
+	}
+}
+
+impl Invoice {
+	/// The contents of this invoice
+	pub fn raw_invoice(&self) -> &RawInvoice {
+		&self.raw
+	}
+
+	/// The chain the payment should be made on
+	pub fn currency(&self) -> Currency {
+		self.raw.currency
+	}
+
+	/// The amount requested, in thousandths of a satoshi, if any
+	pub fn amount_msat(&self) -> Option<u64> {
+		self.raw.amount_msat
+	}
+
+	/// The time the invoice was created, in seconds since the UNIX epoch
+	pub fn timestamp(&self) -> u64 {
+		self.raw.timestamp
+	}
+
+	/// The payment_hash to pass to ChannelManager::send_payment
+	pub fn payment_hash(&self) -> &[u8; 32] {
+		&self.raw.payment_hash
+	}
+
+	/// The payment_secret the payer must pass along to the payee, if any
+	pub fn payment_secret(&self) -> Option<&[u8; 32]> {
+		self.raw.payment_secret.as_ref()
+	}
+
+	/// The description of what the payment is for, if one was included directly
+	pub fn description(&self) -> Option<&String> {
+		self.raw.description.as_ref()
+	}
+
+	/// The hash of the description of what the payment is for, if one was included instead of the
+	/// description itself
+	pub fn description_hash(&self) -> Option<&[u8; 32]> {
+		self.raw.description_hash.as_ref()
+	}
+
+	/// The payee's node_id, either as included in the invoice or recovered from its signature
+	pub fn payee_pubkey(&self) -> &PublicKey {
+		&self.payee_pubkey
+	}
+
+	/// The number of seconds after timestamp() at which the invoice expires
+	pub fn expiry_time(&self) -> u64 {
+		self.raw.expiry_time.unwrap_or(DEFAULT_EXPIRY_TIME)
+	}
+
+	/// Whether the invoice's expiry time has passed, according to the system clock
+	pub fn is_expired(&self) -> bool {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time must be > 1970").as_secs();
+		self.timestamp().saturating_add(self.expiry_time()) < now
+	}
+
+	/// The CLTV delta the payee requires on the final hop, to pass to Router::get_route
+	pub fn min_final_cltv_expiry(&self) -> u64 {
+		self.raw.min_final_cltv_expiry.unwrap_or(DEFAULT_MIN_FINAL_CLTV_EXPIRY)
+	}
+
+	/// On-chain addresses the payer may use instead, in order of preference
+	pub fn fallbacks(&self) -> &Vec<Fallback> {
+		&self.raw.fallbacks
+	}
+
+	/// The full route hint paths to the payee through private channels
+	pub fn route_hints(&self) -> &Vec<Vec<RouteHint>> {
+		&self.raw.route_hints
+	}
+
+	/// The route hints, ie the private channels into the payee, suitable for passing to
+	/// Router::get_route as last_hops.
+	///
+	/// Router::get_route can only make use of hints for channels directly into the payee, so this
+	/// fails with UnsupportedRouteHint if any route hint path has more than one hop.
+	pub fn last_hops(&self) -> Result<Vec<RouteHint>, InvoiceError> {
+		self.raw.route_hints.iter().map(|path| {
+			if path.len() != 1 {
+				return Err(InvoiceError::UnsupportedRouteHint);
+			}
+			Ok(path[0].clone())
+		}).collect()
+	}
+
+	/// The features the payee supports, as a big-endian bitfield, if the invoice included them
+	pub fn features(&self) -> Option<&Vec<u8>> {
+		self.raw.features.as_ref()
+	}
+
+	/// The signature over the invoice by the payee
+	pub fn signature(&self) -> &RecoverableSignature {
+		&self.signature
+	}
+}
+
+impl fmt::Display for Invoice {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.encoded)
+	}
+}
+
+impl FromStr for Invoice {
+	type Err = InvoiceError;
+
+	/// Reads a bech32-encoded invoice, checking its signature.
+	///
+	/// Tagged fields we don't understand, fields with invalid lengths and fields with malformed
+	/// contents are all skipped, as BOLT 11 requires for the first two, though they are still
+	/// covered by the signature.
+	fn from_str(s: &str) -> Result<Invoice, InvoiceError> {
+		let bech32 = Bech32::from_str_lenient(s).map_err(|_| InvoiceError::Bech32Error)?;
+		let encoded = bech32.to_string();
+		let (hrp, data) = bech32.into_parts();
+
+		if !hrp.starts_with("ln") {
+			return Err(InvoiceError::UnknownCurrency);
+		}
+		// bcrt must be checked before its prefix bc
+		let currency = [Currency::Regtest, Currency::Bitcoin, Currency::BitcoinTestnet, Currency::Simnet].iter()
+			.find(|currency| hrp[2..].starts_with(currency.hrp_prefix())).cloned()
+			.ok_or(InvoiceError::UnknownCurrency)?;
+		let amount_msat = decode_amount(&hrp[2 + currency.hrp_prefix().len()..])?;
+
+		if data.len() < 7 + SIGNATURE_LEN {
+			return Err(InvoiceError::TooShort);
+		}
+		let (data, sig_data) = data.split_at(data.len() - SIGNATURE_LEN);
+
+		let mut raw = RawInvoice {
+			currency,
+			amount_msat,
+			timestamp: decode_int(&data[0..7])?,
+			payment_hash: [0; 32],
+			payment_secret: None,
+			description: None,
+			description_hash: None,
+			payee_pubkey: None,
+			expiry_time: None,
+			min_final_cltv_expiry: None,
+			fallbacks: Vec::new(),
+			route_hints: Vec::new(),
+			features: None,
+		};
+		let mut payment_hash = None;
+		let mut description_count = 0;
+
+		let secp_ctx = Secp256k1::verification_only();
+		let mut fields = &data[7..];
+		while !fields.is_empty() {
+			if fields.len() < 3 {
+				return Err(InvoiceError::TooShort);
+			}
+			let len = ((fields[1].to_u8() as usize) << 5) | fields[2].to_u8() as usize;
+			if fields.len() < 3 + len {
+				return Err(InvoiceError::TooShort);
+			}
+			// A field we fail to read is skipped, just like an unknown one
+			let _ = read_field(&mut raw, &mut payment_hash, &mut description_count, &secp_ctx, fields[0].to_u8(), &fields[3..3 + len]);
+			fields = &fields[3 + len..];
+		}
+
+		raw.payment_hash = payment_hash.ok_or(InvoiceError::MissingPaymentHash)?;
+		if description_count != 1 {
+			return Err(InvoiceError::InvalidDescription);
+		}
+
+		let sig_bytes = decode_bytes(sig_data).map_err(|_| InvoiceError::InvalidSignature)?;
+		let recovery_id = RecoveryId::from_i32(sig_bytes[64] as i32).map_err(|_| InvoiceError::InvalidSignature)?;
+		let signature = RecoverableSignature::from_compact(&secp_ctx, &sig_bytes[0..64], recovery_id).map_err(|_| InvoiceError::InvalidSignature)?;
+		let hash = signable_hash(&hrp, data);
+		let payee_pubkey = match raw.payee_pubkey {
+			Some(pubkey) => {
+				secp_ctx.verify(&hash, &signature.to_standard(&secp_ctx), &pubkey).map_err(|_| InvoiceError::InvalidSignature)?;
+				pubkey
+			},
+			None => secp_ctx.recover(&hash, &signature).map_err(|_| InvoiceError::InvalidSignature)?,
+		};
+
+		Ok(Invoice {
+			raw,
+			payee_pubkey,
+			signature,
+			encoded,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ln::invoice::{Currency, Fallback, Invoice, InvoiceError, RawInvoice, TAG_PAYEE_PUBKEY, TAG_ROUTE_HINT, push_field, signable_hash};
+	use ln::router::RouteHint;
+
+	use secp256k1::key::{PublicKey, SecretKey};
+	use secp256k1::Secp256k1;
+
+	use bech32::{Bech32, u5, ToBase32};
+
+	use hex;
+
+	use std::str::FromStr;
+
+	fn payment_hash() -> [u8; 32] {
+		let mut res = [0; 32];
+		res.copy_from_slice(&hex::decode("0001020304050607080900010203040506070809000102030405060708090102").unwrap()[..]);
+		res
+	}
+
+	fn test_raw_invoice() -> RawInvoice {
+		RawInvoice {
+			currency: Currency::Bitcoin,
+			amount_msat: None,
+			timestamp: 1496314658,
+			payment_hash: payment_hash(),
+			payment_secret: None,
+			description: Some("Please consider supporting this project".to_string()),
+			description_hash: None,
+			payee_pubkey: None,
+			expiry_time: None,
+			min_final_cltv_expiry: None,
+			fallbacks: Vec::new(),
+			route_hints: Vec::new(),
+			features: None,
+		}
+	}
+
+	/// Signs a data part as RawInvoice::sign does, returning the bech32 invoice.
+	fn sign_data(mut data: Vec<u5>, node_secret: &SecretKey) -> String {
+		let secp_ctx = Secp256k1::new();
+		let hrp = test_raw_invoice().hrp().unwrap();
+		let signature = secp_ctx.sign_recoverable(&signable_hash(&hrp, &data), node_secret);
+		let (recovery_id, compact_sig) = signature.serialize_compact(&secp_ctx);
+		let mut sig_bytes = compact_sig.to_vec();
+		sig_bytes.push(recovery_id.to_i32() as u8);
+		data.extend_from_slice(&sig_bytes.to_base32()[..]);
+		Bech32::new(hrp, data).unwrap().to_string()
+	}
+
+	#[test]
+	fn test_bolt11_vectors() {
+		let payee = PublicKey::from_slice(&Secp256k1::without_caps(), &hex::decode("03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad").unwrap()[..]).unwrap();
+
+		// "Please make a donation of any amount using payment_hash 0001020304050607080900010203040506070809000102030405060708090102 to me @03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad"
+		let invoice = Invoice::from_str("lnbc1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2pkx2ctnv5sxxmmwwd5kgetjypeh2ursdae8g6twvus8g6rfwvs8qun0dfjkxaq8rkx3yf5tcsyz3d73gafnh3cax9rn449d9p5uxz9ezhhypd0elx87sjle52x86fux2ypatgddc6k63n7erqz25le42c4u4ecky03ylcqca784w").unwrap();
+		assert_eq!(*invoice.raw_invoice(), test_raw_invoice());
+		assert_eq!(*invoice.payee_pubkey(), payee);
+
+		// "Please send $3 for a cup of coffee to the same peer, within one minute"
+		let invoice = Invoice::from_str("lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpuaztrnwngzn3kdzw5hydlzf03qdgm2hdq27cqv3agm2awhz5se903vruatfhq77w3ls4evs3ch9zw97j25emudupq63nyw24cg27h2rspfj9srp").unwrap();
+		assert_eq!(invoice.amount_msat(), Some(250_000_000));
+		assert_eq!(invoice.description().unwrap(), "1 cup coffee");
+		assert_eq!(invoice.expiry_time(), 60);
+		assert_eq!(*invoice.payee_pubkey(), payee);
+
+		// "Now send $24 for an entire list of things (hashed)"
+		let invoice = Invoice::from_str("lnbc20m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqscc6gd6ql3jrc5yzme8v4ntcewwz5cnw92tz0pc8qcuufvq7khhr8wpald05e92xw006sq94mg8v2ndf4sefvf9sygkshp5zfem29trqq2yxxz7").unwrap();
+		assert_eq!(invoice.amount_msat(), Some(2_000_000_000));
+		assert!(invoice.description().is_none());
+		assert_eq!(&invoice.description_hash().unwrap()[..], &hex::decode("3925b6f67e2c340036ed12093dd44e0368df1b6ea26c53dbe4811f58fd5db8c1").unwrap()[..]);
+		assert_eq!(*invoice.payee_pubkey(), payee);
+
+		macro_rules! check_fallback {
+			($invoice: expr, $currency: expr, $fallback: expr) => { {
+				let invoice = Invoice::from_str($invoice).unwrap();
+				assert_eq!(invoice.currency(), $currency);
+				assert_eq!(invoice.amount_msat(), Some(2_000_000_000));
+				assert_eq!(*invoice.fallbacks(), vec![$fallback]);
+				assert_eq!(*invoice.payee_pubkey(), payee);
+				invoice
+			} }
+		}
+		fn hash(hex: &str) -> [u8; 20] {
+			let mut res = [0; 20];
+			res.copy_from_slice(&::hex::decode(hex).unwrap()[..]);
+			res
+		}
+
+		// "The same, on testnet, with a fallback address mk2QpYatsKicvFVuTAQLBryyccRXMUaGHP"
+		check_fallback!("lntb20m1pvjluezhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqfpp3x9et2e20v6pu37c5d9vax37wxq72un98kmzzhznpurw9sgl2v0nklu2g4d0keph5t7tj9tcqd8rexnd07ux4uv2cjvcqwaxgj7v4uwn5wmypjd5n69z2xm3xgksg28nwht7f6zspwp3f9t",
+			Currency::BitcoinTestnet, Fallback::PubKeyHash(hash("3172b5654f6683c8fb146959d347ce303cae4ca7")));
+
+		// "On mainnet, with fallback address 1RustyRX2oai4EYYDpQGWvEL62BBGqN9T with extra routing
+		// info to go via nodes 029e03a901b85534ff1e92c43c74431f7ce72046060fcf7a95c37e148f78c77255
+		// then 039e03a901b85534ff1e92c43c74431f7ce72046060fcf7a95c37e148f78c77255"
+		let invoice = check_fallback!("lnbc20m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqsfpp3qjmp7lwpagxun9pygexvgpjdc4jdj85fr9yq20q82gphp2nflc7jtzrcazrra7wwgzxqc8u7754cdlpfrmccae92qgzqvzq2ps8pqqqqqqpqqqqq9qqqvpeuqafqxu92d8lr6fvg0r5gv0heeeqgcrqlnm6jhphu9y00rrhy4grqszsvpcgpy9qqqqqqgqqqqq7qqzqj9n4evl6mr5aj9f58zp6fyjzup6ywn3x6sk8akg5v4tgn2q8g4fhx05wf6juaxu9760yp46454gpg5mtzgerlzezqcqvjnhjh8z3g2qqdhhwkj",
+			Currency::Bitcoin, Fallback::PubKeyHash(hash("04b61f7dc1ea0dc99424464cc4064dc564d91e89")));
+		let secp_ctx = Secp256k1::without_caps();
+		assert_eq!(*invoice.route_hints(), vec![vec![RouteHint {
+			src_node_id: PublicKey::from_slice(&secp_ctx, &hex::decode("029e03a901b85534ff1e92c43c74431f7ce72046060fcf7a95c37e148f78c77255").unwrap()[..]).unwrap(),
+			short_channel_id: 0x0102030405060708,
+			fee_base_msat: 1,
+			fee_proportional_millionths: 20,
+			cltv_expiry_delta: 3,
+			htlc_minimum_msat: 0,
+		}, RouteHint {
+			src_node_id: PublicKey::from_slice(&secp_ctx, &hex::decode("039e03a901b85534ff1e92c43c74431f7ce72046060fcf7a95c37e148f78c77255").unwrap()[..]).unwrap(),
+			short_channel_id: 0x030405060708090a,
+			fee_base_msat: 2,
+			fee_proportional_millionths: 30,
+			cltv_expiry_delta: 4,
+			htlc_minimum_msat: 0,
+		}]]);
+		assert_eq!(invoice.last_hops(), Err(InvoiceError::UnsupportedRouteHint));
+
+		// "On mainnet, with fallback (P2SH) address 3EktnHQD7RiAE6uzMj2ZifT9YgRrkSgzQX"
+		check_fallback!("lnbc20m1pvjluezhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqfppj3a24vwu6r8ejrss3axul8rxldph2q7z9kmrgvr7xlaqm47apw3d48zm203kzcq357a4ls9al2ea73r8jcceyjtya6fu5wzzpe50zrge6ulk4nvjcpxlekvmxl6qcs9j3tz0469gq5g658y",
+			Currency::Bitcoin, Fallback::ScriptHash(hash("8f55563b9a19f321c211e9b9f38cdf686ea07845")));
+
+		// "On mainnet, with fallback (P2WPKH) address bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+		check_fallback!("lnbc20m1pvjluezhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqfppqw508d6qejxtdg4y5r3zarvary0c5xw7kepvrhrm9s57hejg0p662ur5j5cr03890fa7k2pypgttmh4897d3raaq85a293e9jpuqwl0rnfuwzam7yr8e690nd2ypcq9hlkdwdvycqa0qza8",
+			Currency::Bitcoin, Fallback::SegWitProgram { version: 0, program: hex::decode("751e76e8199196d454941c45d1b3a323f1433bd6").unwrap() });
+
+		// "On mainnet, with fallback (P2WSH) address
+		// bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3"
+		check_fallback!("lnbc20m1pvjluezhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqfp4qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q28j0v3rwgy9pvjnd48ee2pl8xrpxysd5g44td63g6xcjcu003j3qe8878hluqlvl3km8rm92f5stamd3jw763n3hck0ct7p8wwj463cql26ava",
+			Currency::Bitcoin, Fallback::SegWitProgram { version: 0, program: hex::decode("1863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262").unwrap() });
+
+		// "Please send $30 for coffee beans to the same peer, which supports features 1 and 9"
+		let invoice = Invoice::from_str("lnbc25m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5vdhkven9v5sxyetpdees9qzsze992adudgku8p05pstl6zh7av6rx2f297pv89gu5q93a0hf3g7lynl3xq56t23dpvah6u7y9qey9lccrdml3gaqwc6nxsl5ktzm464sq73t7cl").unwrap();
+		assert_eq!(invoice.amount_msat(), Some(2_500_000_000));
+		assert_eq!(invoice.description().unwrap(), "coffee beans");
+		assert_eq!(*invoice.features().unwrap(), vec![0x02, 0x02]);
+		assert_eq!(*invoice.payee_pubkey(), payee);
+
+		// "Please send $30 for coffee beans to the same peer, which supports features 9, 15 and 99,
+		// using secret 0x111111111111111111111111111111111111111111111111111111111111111"
+		let invoice = Invoice::from_str("lnbc25m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5vdhkven9v5sxyetpdeessp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygs9q5sqqqqqqqqqqqqqqqpqsq67gye39hfg3zd8rgc80k32tvy9xk2xunwm5lzexnvpx6fd77en8qaq424dxgt56cag2dpt359k3ssyhetktkpqh24jqnjyw6uqd08sgptq44qu").unwrap();
+		assert_eq!(invoice.amount_msat(), Some(2_500_000_000));
+		assert_eq!(invoice.description().unwrap(), "coffee beans");
+		assert_eq!(invoice.payment_secret(), Some(&[0x11; 32]));
+		assert_eq!(*invoice.features().unwrap(), vec![0x08, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x82, 0]);
+		assert_eq!(*invoice.payee_pubkey(), payee);
+
+		// "Please send 0.00967878534 BTC for a list of items within one week, amount in pico-BTC"
+		let invoice = Invoice::from_str("lnbc9678785340p1pwmna7lpp5gc3xfm08u9qy06djf8dfflhugl6p7lgza6dsjxq454gxhj9t7a0sd8dgfkx7cmtwd68yetpd5s9xar0wfjn5gpc8qhrsdfq24f5ggrxdaezqsnvda3kkum5wfjkzmfqf3jkgem9wgsyuctwdus9xgrcyqcjcgpzgfskx6eqf9hzqnteypzxz7fzypfhg6trddjhygrcyqezcgpzfysywmm5ypxxjemgw3hxjmn8yptk7untd9hxwg3q2d6xjcmtv4ezq7pqxgsxzmnyyqcjqmt0wfjjq6t5v4khxsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygsxqyjw5qcqp2rzjq0gxwkzc8w6323m55m4jyxcjwmy7stt9hwkwe2qxmy8zpsgg7jcuwz87fcqqeuqqqyqqqqlgqqqqn3qq9q9qrsgqrvgkpnmps664wgkp43l22qsgdw4ve24aca4nymnxddlnp8vh9v2sdxlu5ywdxefsfvm0fq3sesf08uf6q9a2ke0hc9j6z6wlxg5z5kqpu2v9wz").unwrap();
+		assert_eq!(invoice.amount_msat(), Some(967_878_534));
+		assert_eq!(invoice.expiry_time(), 604800);
+		assert_eq!(invoice.min_final_cltv_expiry(), 10);
+		assert_eq!(invoice.payment_secret(), Some(&[0x11; 32]));
+		assert_eq!(*invoice.features().unwrap(), vec![0x41, 0]);
+		let last_hops = invoice.last_hops().unwrap();
+		assert_eq!(last_hops.len(), 1);
+		assert_eq!(last_hops[0].src_node_id, PublicKey::from_slice(&secp_ctx, &hex::decode("03d06758583bb5154774a6eb221b1276c9e82d65bbaceca806d90e20c108f4b1c7").unwrap()[..]).unwrap());
+		assert_eq!(last_hops[0].short_channel_id, (589390 << 40) | (3312 << 16) | 1);
+		assert_eq!(last_hops[0].fee_base_msat, 1000);
+		assert_eq!(last_hops[0].fee_proportional_millionths, 2500);
+		assert_eq!(last_hops[0].cltv_expiry_delta, 40);
+		assert_eq!(*invoice.payee_pubkey(), payee);
+
+		// Flipping a character breaks the checksum
+		assert_eq!(Invoice::from_str("lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpuaztrnwngzn3kdzw5hydlzf03qdgm2hdq27cqv3agm2awhz5se903vruatfhq77w3ls4evs3ch9zw97j25emudupq63nyw24cg27h2rspfj9srq"), Err(InvoiceError::Bech32Error));
+	}
+
+	#[test]
+	fn test_sign_round_trip() {
+		let secp_ctx = Secp256k1::new();
+		let node_secret = SecretKey::from_slice(&secp_ctx, &hex::decode("e126f68f7eafcc8b74f54d269fe206be715000f94dac067d1c04a8ca3b2db734").unwrap()[..]).unwrap();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &node_secret);
+		let hint_node = PublicKey::from_slice(&secp_ctx, &hex::decode("029e03a901b85534ff1e92c43c74431f7ce72046060fcf7a95c37e148f78c77255").unwrap()[..]).unwrap();
+
+		// Signing the first BOLT 11 vector reproduces it exactly (signatures are deterministic)
+		let invoice = test_raw_invoice().sign(&secp_ctx, &node_secret).unwrap();
+		assert_eq!(invoice.to_string(), "lnbc1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2pkx2ctnv5sxxmmwwd5kgetjypeh2ursdae8g6twvus8g6rfwvs8qun0dfjkxaq8rkx3yf5tcsyz3d73gafnh3cax9rn449d9p5uxz9ezhhypd0elx87sjle52x86fux2ypatgddc6k63n7erqz25le42c4u4ecky03ylcqca784w");
+
+		let mut raw = test_raw_invoice();
+		raw.currency = Currency::Regtest;
+		raw.amount_msat = Some(1234567);
+		raw.payment_secret = Some([42; 32]);
+		raw.description = None;
+		raw.description_hash = Some([7; 32]);
+		raw.payee_pubkey = Some(node_id);
+		raw.expiry_time = Some(7200);
+		raw.min_final_cltv_expiry = Some(144);
+		raw.fallbacks = vec![Fallback::SegWitProgram { version: 0, program: vec![3; 20] }, Fallback::PubKeyHash([4; 20]), Fallback::ScriptHash([5; 20])];
+		raw.route_hints = vec![vec![RouteHint {
+			src_node_id: hint_node,
+			short_channel_id: 0x0102030405060708,
+			fee_base_msat: 1,
+			fee_proportional_millionths: 20,
+			cltv_expiry_delta: 3,
+			htlc_minimum_msat: 0,
+		}, RouteHint {
+			src_node_id: node_id,
+			short_channel_id: 0x030405060708090a,
+			fee_base_msat: 2,
+			fee_proportional_millionths: 30,
+			cltv_expiry_delta: 4,
+			htlc_minimum_msat: 0,
+		}]];
+		raw.features = Some(vec![0x02, 0x80, 0x01]);
+
+		let invoice = raw.clone().sign(&secp_ctx, &node_secret).unwrap();
+		assert!(invoice.to_string().starts_with("lnbcrt12345670p1"));
+		let read_invoice = Invoice::from_str(&invoice.to_string()).unwrap();
+		assert_eq!(read_invoice, invoice);
+		assert_eq!(*read_invoice.raw_invoice(), raw);
+		// Router::get_route can't make use of the two-hop route hint
+		assert_eq!(read_invoice.route_hints()[0].len(), 2);
+		assert_eq!(read_invoice.last_hops(), Err(InvoiceError::UnsupportedRouteHint));
+		let mut single_hop_raw = raw.clone();
+		single_hop_raw.route_hints[0].remove(0);
+		let read_invoice = Invoice::from_str(&single_hop_raw.sign(&secp_ctx, &node_secret).unwrap().to_string()).unwrap();
+		assert_eq!(read_invoice.last_hops(), Ok(vec![raw.route_hints[0][1].clone()]));
+
+		// Malformed fallbacks are skipped without failing the whole invoice
+		let mut raw = test_raw_invoice();
+		raw.fallbacks = vec![Fallback::SegWitProgram { version: 0, program: vec![3; 5] }, Fallback::SegWitProgram { version: 1, program: vec![3; 41] }, Fallback::PubKeyHash([4; 20])];
+		let bad_fallbacks_invoice = raw.sign(&secp_ctx, &node_secret).unwrap();
+		let read_invoice = Invoice::from_str(&bad_fallbacks_invoice.to_string()).unwrap();
+		assert_eq!(*read_invoice.fallbacks(), vec![Fallback::PubKeyHash([4; 20])]);
+
+		// As are malformed route hints and payee pubkeys, which RawInvoice can't express
+		let mut bad_hop = vec![5; 33];
+		bad_hop.extend_from_slice(&[0; 18]);
+		let mut bad_fields_data = test_raw_invoice().data_part().unwrap();
+		push_field(&mut bad_fields_data, TAG_ROUTE_HINT, vec![7; 50].to_base32()).unwrap();
+		push_field(&mut bad_fields_data, TAG_ROUTE_HINT, bad_hop.to_base32()).unwrap();
+		push_field(&mut bad_fields_data, TAG_PAYEE_PUBKEY, vec![5; 33].to_base32()).unwrap();
+		let read_invoice = Invoice::from_str(&sign_data(bad_fields_data, &node_secret)).unwrap();
+		assert_eq!(*read_invoice.raw_invoice(), test_raw_invoice());
+		assert_eq!(*read_invoice.payee_pubkey(), node_id);
+
+		// Upper-case invoices are also accepted
+		assert_eq!(Invoice::from_str(&invoice.to_string().to_uppercase()).unwrap(), invoice);
+
+		// We refuse to sign for a different payee, or without exactly one description
+		let mut raw = test_raw_invoice();
+		raw.payee_pubkey = Some(hint_node);
+		assert_eq!(raw.sign(&secp_ctx, &node_secret), Err(InvoiceError::InvalidSignature));
+		let mut raw = test_raw_invoice();
+		raw.description_hash = Some([7; 32]);
+		assert_eq!(raw.sign(&secp_ctx, &node_secret), Err(InvoiceError::InvalidDescription));
+	}
+
+	#[test]
+	fn test_amounts() {
+		let secp_ctx = Secp256k1::new();
+		let node_secret = SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap();
+		for &(amount_msat, hrp) in [(100_000_000_000, "lnbc1"), (250_000_000, "lnbc2500u"), (2_000_000_000, "lnbc20m"), (1_000, "lnbc10n"), (1, "lnbc10p")].iter() {
+			let mut raw = test_raw_invoice();
+			raw.amount_msat = Some(amount_msat);
+			let invoice = raw.sign(&secp_ctx, &node_secret).unwrap();
+			assert!(invoice.to_string().starts_with(&format!("{}1", hrp)));
+			assert_eq!(Invoice::from_str(&invoice.to_string()).unwrap().amount_msat(), Some(amount_msat));
+		}
+
+		let mut raw = test_raw_invoice();
+		raw.amount_msat = Some(0);
+		assert_eq!(raw.sign(&secp_ctx, &node_secret), Err(InvoiceError::InvalidAmount));
+	}
+}
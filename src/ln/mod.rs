@@ -11,6 +11,7 @@
 
 pub mod channelmanager;
 pub mod channelmonitor;
+pub mod invoice;
 pub mod msgs;
 pub mod router;
 pub mod peer_handler;
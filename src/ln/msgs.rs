@@ -23,19 +23,19 @@ use bitcoin::blockdata::script::Script;
 
 use std::error::Error;
 use std::{cmp, fmt};
-use std::io::Read;
+use std::io::{Cursor, Read};
 use std::result::Result;
 
 use util::{byte_utils, events};
-use util::ser::{Readable, Writeable, Writer};
+use util::ser::{BigSize, Readable, Writeable, Writer};
 
 /// An error in decoding a message or struct.
 #[derive(Debug)]
 pub enum DecodeError {
 	/// A version byte specified something we don't know how to handle.
-	/// Includes unknown realm byte in an OnionHopData packet
 	UnknownVersion,
 	/// Unknown feature mandating we fail to parse message
+	/// Includes unknown even TLV types in an OnionHopData payload
 	UnknownRequiredFeature,
 	/// Value was invalid, eg a byte which was supposed to be a bool was something other than a 0
 	/// or 1, a public key/private key/signature was invalid, text wasn't UTF-8, etc
@@ -70,6 +70,13 @@ impl LocalFeatures {
 	pub(crate) fn requires_data_loss_protect(&self) -> bool {
 		self.flags.len() > 0 && (self.flags[0] & 1) != 0
 	}
+	pub(crate) fn set_supports_data_loss_protect(&mut self) {
+		if self.flags.len() == 0 {
+			self.flags.resize(1, 1 << 1);
+		} else {
+			self.flags[0] |= 1 << 1;
+		}
+	}
 
 	pub(crate) fn initial_routing_sync(&self) -> bool {
 		self.flags.len() > 0 && (self.flags[0] & (1 << 3)) != 0
@@ -88,12 +95,19 @@ impl LocalFeatures {
 	pub(crate) fn requires_upfront_shutdown_script(&self) -> bool {
 		self.flags.len() > 0 && (self.flags[0] & (1 << 4)) != 0
 	}
+	pub(crate) fn set_supports_upfront_shutdown_script(&mut self) {
+		if self.flags.len() == 0 {
+			self.flags.resize(1, 1 << 5);
+		} else {
+			self.flags[0] |= 1 << 5;
+		}
+	}
 
 	pub(crate) fn requires_unknown_bits(&self) -> bool {
 		for (idx, &byte) in self.flags.iter().enumerate() {
 			if idx != 0 && (byte & 0x55) != 0 {
 				return true;
-			} else if idx == 0 && (byte & 0x14) != 0 {
+			} else if idx == 0 && (byte & 0x04) != 0 {
 				return true;
 			}
 		}
@@ -323,7 +337,7 @@ pub struct AnnouncementSignatures {
 }
 
 /// An address which can be used to connect to a remote peer
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum NetAddress {
 	/// An IPv4 address/port on which the peer is listenting.
 	IPv4 {
@@ -540,9 +554,15 @@ pub trait ChannelMessageHandler : events::EventsProvider + Send + Sync {
 	fn peer_disconnected(&self, their_node_id: &PublicKey, no_connection_possible: bool);
 
 	/// Handle a peer reconnecting, possibly generating channel_reestablish message(s).
-	fn peer_connected(&self, their_node_id: &PublicKey) -> Vec<ChannelReestablish>;
+	/// their_features are the local features from the init message the peer sent us.
+	fn peer_connected(&self, their_node_id: &PublicKey, their_features: &LocalFeatures) -> Vec<ChannelReestablish>;
 	/// Handle an incoming channel_reestablish message from the given peer.
 	fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &ChannelReestablish) -> Result<(Option<FundingLocked>, Option<RevokeAndACK>, Option<CommitmentUpdate>), HandleError>;
+	/// Returns true if we have any channels with the given peer for which funding has been
+	/// initiated. Peers without such channels are the first to be disconnected when we have too
+	/// many connections, and peers with them are never banned for misbehaving.
+	/// Defaults to false, treating every peer as one we have no channels with.
+	fn has_funded_channels(&self, _their_node_id: &PublicKey) -> bool { false }
 
 	// Error:
 	/// Handle an incoming error message from the given peer.
@@ -579,10 +599,21 @@ mod fuzzy_internal_msgs {
 	pub struct OnionHopData {
 		pub(crate) realm: u8,
 		pub(crate) data: OnionRealm0HopData,
+		/// Only ever set for the final hop. If set, the hop data is written as a variable-length
+		/// TLV payload instead of a realm 0 frame, as payment_data can only be sent in the former.
+		pub(crate) payment_data: Option<FinalOnionHopData>,
 		pub(crate) hmac: [u8; 32],
 	}
 	unsafe impl ::util::internal_traits::NoDealloc for OnionHopData{}
 
+	/// The payment_data record of a final hop's TLV payload.
+	pub struct FinalOnionHopData {
+		pub(crate) payment_secret: [u8; 32],
+		/// The total amount of the payment, which is just this HTLC's amount as we don't do
+		/// multi-part payments.
+		pub(crate) total_msat: u64,
+	}
+
 	pub struct DecodedOnionErrorPacket {
 		pub(crate) hmac: [u8; 32],
 		pub(crate) failuremsg: Vec<u8>,
@@ -901,11 +932,45 @@ impl<R: Read> Readable<R> for OnionRealm0HopData {
 	}
 }
 
+/// Writes a TLV record whose value is an integer with its leading zero bytes dropped, as for
+/// BOLT 4's tu64 and tu32 fields.
+fn write_truncated_int_tlv(payload: &mut Vec<u8>, tlv_type: u64, value: u64, prefix: &[u8]) {
+	let bytes = byte_utils::be64_to_array(value);
+	let first_nonzero = bytes.iter().position(|b| *b != 0).unwrap_or(8);
+	BigSize(tlv_type).write(payload).unwrap();
+	BigSize((prefix.len() + 8 - first_nonzero) as u64).write(payload).unwrap();
+	payload.extend_from_slice(prefix);
+	payload.extend_from_slice(&bytes[first_nonzero..]);
+}
+
+/// Reads an integer with its leading zero bytes dropped, failing if it's longer than max_len or
+/// has a leading zero byte.
+fn read_truncated_int(bytes: &[u8], max_len: usize) -> Result<u64, DecodeError> {
+	if bytes.len() > max_len || bytes.first() == Some(&0) {
+		return Err(DecodeError::InvalidValue);
+	}
+	Ok(bytes.iter().fold(0, |res, b| (res << 8) | *b as u64))
+}
+
 impl Writeable for OnionHopData {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
-		w.size_hint(65);
-		self.realm.write(w)?;
-		self.data.write(w)?;
+		match self.payment_data {
+			None => {
+				w.size_hint(65);
+				self.realm.write(w)?;
+				self.data.write(w)?;
+			},
+			Some(ref payment_data) => {
+				let mut payload = Vec::new();
+				write_truncated_int_tlv(&mut payload, 2, self.data.amt_to_forward, &[]);
+				write_truncated_int_tlv(&mut payload, 4, self.data.outgoing_cltv_value as u64, &[]);
+				write_truncated_int_tlv(&mut payload, 8, payment_data.total_msat, &payment_data.payment_secret);
+				let len = BigSize(payload.len() as u64).encode();
+				w.size_hint(len.len() + payload.len() + 32);
+				w.write_all(&len)?;
+				w.write_all(&payload)?;
+			},
+		}
 		self.hmac.write(w)?;
 		Ok(())
 	}
@@ -913,17 +978,86 @@ impl Writeable for OnionHopData {
 
 impl<R: Read> Readable<R> for OnionHopData {
 	fn read(r: &mut R) -> Result<Self, DecodeError> {
-		Ok(OnionHopData {
-			realm: {
-				let r: u8 = Readable::read(r)?;
-				if r != 0 {
-					return Err(DecodeError::UnknownVersion);
-				}
-				r
-			},
-			data: Readable::read(r)?,
-			hmac: Readable::read(r)?,
-		})
+		// A realm 0 frame starts with a 0 byte, anything else is the length of a TLV payload
+		let first_byte: u8 = Readable::read(r)?;
+		if first_byte == 0 {
+			return Ok(OnionHopData {
+				realm: 0,
+				data: Readable::read(r)?,
+				payment_data: None,
+				hmac: Readable::read(r)?,
+			});
+		}
+
+		let len = BigSize::read_after_prefix(first_byte, r)?.0;
+		if len > 1300 {
+			return Err(DecodeError::BadLengthDescriptor);
+		}
+		let mut payload = vec![0; len as usize];
+		r.read_exact(&mut payload)?;
+
+		let mut short_channel_id = None;
+		let mut amt_to_forward = None;
+		let mut outgoing_cltv_value = None;
+		let mut payment_data = None;
+		let mut last_type = None;
+		let mut payload_reader = Cursor::new(&payload[..]);
+		while (payload_reader.position() as usize) < payload.len() {
+			let tlv_type = BigSize::read(&mut payload_reader)?.0;
+			if last_type.is_some() && last_type >= Some(tlv_type) {
+				return Err(DecodeError::InvalidValue);
+			}
+			last_type = Some(tlv_type);
+			let tlv_len = BigSize::read(&mut payload_reader)?.0;
+			if tlv_len > len {
+				return Err(DecodeError::BadLengthDescriptor);
+			}
+			let mut value = vec![0; tlv_len as usize];
+			payload_reader.read_exact(&mut value)?;
+			match tlv_type {
+				2 => amt_to_forward = Some(read_truncated_int(&value, 8)?),
+				4 => outgoing_cltv_value = Some(read_truncated_int(&value, 4)? as u32),
+				6 => {
+					if value.len() != 8 {
+						return Err(DecodeError::InvalidValue);
+					}
+					short_channel_id = Some(byte_utils::slice_to_be64(&value));
+				},
+				8 => {
+					if value.len() < 32 {
+						return Err(DecodeError::InvalidValue);
+					}
+					let mut payment_secret = [0; 32];
+					payment_secret.copy_from_slice(&value[..32]);
+					payment_data = Some(FinalOnionHopData {
+						payment_secret,
+						total_msat: read_truncated_int(&value[32..], 8)?,
+					});
+				},
+				// It's OK to be odd
+				_ if tlv_type % 2 == 1 => {},
+				_ => return Err(DecodeError::UnknownRequiredFeature),
+			}
+		}
+
+		let hmac: [u8; 32] = Readable::read(r)?;
+		// Hops we're to forward to must say where to
+		if hmac != [0; 32] && short_channel_id.is_none() {
+			return Err(DecodeError::InvalidValue);
+		}
+		match (amt_to_forward, outgoing_cltv_value) {
+			(Some(amt_to_forward), Some(outgoing_cltv_value)) => Ok(OnionHopData {
+				realm: 0,
+				data: OnionRealm0HopData {
+					short_channel_id: short_channel_id.unwrap_or(0),
+					amt_to_forward,
+					outgoing_cltv_value,
+				},
+				payment_data,
+				hmac,
+			}),
+			_ => Err(DecodeError::InvalidValue),
+		}
 	}
 }
 
@@ -1092,6 +1226,65 @@ impl<R: Read> Readable<R> for ErrorMessage {
 	}
 }
 
+impl Writeable for NetAddress {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		self.get_id().write(w)?;
+		match self {
+			&NetAddress::IPv4{ref addr, port} => {
+				w.write_all(addr)?;
+				port.write(w)?;
+			},
+			&NetAddress::IPv6{ref addr, port} => {
+				w.write_all(addr)?;
+				port.write(w)?;
+			},
+			&NetAddress::OnionV2{ref addr, port} => {
+				w.write_all(addr)?;
+				port.write(w)?;
+			},
+			&NetAddress::OnionV3{ref ed25519_pubkey, checksum, version, port} => {
+				ed25519_pubkey.write(w)?;
+				checksum.write(w)?;
+				version.write(w)?;
+				port.write(w)?;
+			},
+		}
+		Ok(())
+	}
+}
+
+impl<R: Read> Readable<R> for NetAddress {
+	fn read(r: &mut R) -> Result<Self, DecodeError> {
+		let id: u8 = Readable::read(r)?;
+		match id {
+			1 => {
+				let mut addr = [0; 4];
+				r.read_exact(&mut addr)?;
+				Ok(NetAddress::IPv4 { addr, port: Readable::read(r)? })
+			},
+			2 => {
+				let mut addr = [0; 16];
+				r.read_exact(&mut addr)?;
+				Ok(NetAddress::IPv6 { addr, port: Readable::read(r)? })
+			},
+			3 => {
+				let mut addr = [0; 10];
+				r.read_exact(&mut addr)?;
+				Ok(NetAddress::OnionV2 { addr, port: Readable::read(r)? })
+			},
+			4 => {
+				Ok(NetAddress::OnionV3 {
+					ed25519_pubkey: Readable::read(r)?,
+					checksum: Readable::read(r)?,
+					version: Readable::read(r)?,
+					port: Readable::read(r)?,
+				})
+			},
+			_ => Err(DecodeError::InvalidValue),
+		}
+	}
+}
+
 impl Writeable for UnsignedNodeAnnouncement {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
 		w.size_hint(64 + 76 + self.features.flags.len() + self.addresses.len()*38 + self.excess_address_data.len() + self.excess_data.len());
@@ -1273,10 +1466,12 @@ impl_writeable_len_match!(NodeAnnouncement, {
 mod tests {
 	use hex;
 	use ln::msgs;
-	use util::ser::Writeable;
+	use util::ser::{Readable, Writeable};
 	use secp256k1::key::{PublicKey,SecretKey};
 	use secp256k1::Secp256k1;
 
+	use std::io::Cursor;
+
 	#[test]
 	fn encoding_channel_reestablish_no_secret() {
 		let cr = msgs::ChannelReestablish {
@@ -1313,4 +1508,56 @@ mod tests {
 			vec![4, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 4, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 3, 27, 132, 197, 86, 123, 18, 100, 64, 153, 93, 62, 213, 170, 186, 5, 101, 215, 30, 24, 52, 96, 72, 25, 255, 156, 23, 245, 233, 213, 221, 7, 143]
 		);
 	}
+
+	#[test]
+	fn encoding_tlv_onion_hop_data() {
+		let hop_data = msgs::OnionHopData {
+			realm: 0,
+			data: msgs::OnionRealm0HopData {
+				short_channel_id: 0,
+				amt_to_forward: 0x0100000001,
+				outgoing_cltv_value: 0x010000,
+			},
+			payment_data: Some(msgs::FinalOnionHopData {
+				payment_secret: [0x42; 32],
+				total_msat: 0x0100000001,
+			}),
+			hmac: [0; 32],
+		};
+		let encoded_value = hop_data.encode();
+		let payload = "0205010000000104030100000825".to_owned() + &"42".repeat(32) + "0100000001";
+		assert_eq!(encoded_value, hex::decode("33".to_owned() + &payload + &"00".repeat(32)).unwrap());
+
+		let read_hop_data: msgs::OnionHopData = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+		assert_eq!(read_hop_data.data.amt_to_forward, 0x0100000001);
+		assert_eq!(read_hop_data.data.outgoing_cltv_value, 0x010000);
+		assert_eq!(read_hop_data.payment_data.as_ref().unwrap().payment_secret, [0x42; 32]);
+		assert_eq!(read_hop_data.payment_data.as_ref().unwrap().total_msat, 0x0100000001);
+		assert_eq!(read_hop_data.hmac, [0; 32]);
+
+		macro_rules! read_tlv_payload {
+			($payload: expr, $hmac: expr) => { {
+				let payload = hex::decode($payload).unwrap();
+				let mut encoded = vec![payload.len() as u8];
+				encoded.extend_from_slice(&payload[..]);
+				encoded.extend_from_slice(&$hmac);
+				<msgs::OnionHopData as Readable<Cursor<&[u8]>>>::read(&mut Cursor::new(&encoded[..]))
+			} }
+		}
+
+		// Unknown odd types are ignored, unknown even ones aren't
+		assert!(read_tlv_payload!("0201010401010b0100", [0; 32]).is_ok());
+		match read_tlv_payload!("0201010401010a0100", [0; 32]) {
+			Err(msgs::DecodeError::UnknownRequiredFeature) => {},
+			_ => panic!(),
+		}
+		// Integers must be minimally encoded and records in order
+		assert!(read_tlv_payload!("02020001040101", [0; 32]).is_err());
+		assert!(read_tlv_payload!("040101020101", [0; 32]).is_err());
+		// Hops we're to forward to must have a short_channel_id
+		assert!(read_tlv_payload!("020101040101", [1; 32]).is_err());
+		let forward = read_tlv_payload!("02010104010106080102030405060708", [1; 32]).unwrap();
+		assert_eq!(forward.data.short_channel_id, 0x0102030405060708);
+		assert!(forward.payment_data.is_none());
+	}
 }
@@ -16,8 +16,10 @@ use util::events::{EventsProvider,Event};
 use util::logger::Logger;
 
 use std::collections::{HashMap,hash_map,LinkedList};
+use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use std::{cmp,error,mem,hash,fmt};
 
 /// Provides references to trait impls which handle different types of messages.
@@ -88,10 +90,43 @@ impl error::Error for PeerHandleError {
 	}
 }
 
+/// Limits on the connections a PeerManager will accept, making it harder for a remote host to
+/// exhaust our resources by opening many connections.
+#[derive(Clone)]
+pub struct PeerLimits {
+	/// The maximum number of connections (including those which have not yet completed the
+	/// handshake). Once reached, a new inbound connection will evict the oldest inbound peer with
+	/// which we have no funded channels, or be refused if there is no such peer.
+	pub max_peers: usize,
+	/// The maximum number of inbound peers with which we have no funded channels. Once an inbound
+	/// peer beyond this limit completes the noise handshake, the oldest such peers are
+	/// disconnected.
+	pub max_channelless_peers: usize,
+	/// The maximum number of inbound connections which may be in the process of completing the
+	/// handshake at once. Further inbound connections are refused.
+	pub max_pending_handshakes: usize,
+	/// Connections which have not completed the handshake (including receipt of an Init message)
+	/// within this time are disconnected on the next timer_tick_occured call.
+	pub handshake_timeout: Duration,
+}
+
+impl Default for PeerLimits {
+	fn default() -> Self {
+		PeerLimits {
+			max_peers: 250,
+			max_channelless_peers: 100,
+			max_pending_handshakes: 50,
+			handshake_timeout: Duration::from_secs(30),
+		}
+	}
+}
+
 struct Peer {
 	channel_encryptor: PeerChannelEncryptor,
 	outbound: bool,
+	connected_at: Instant,
 	their_node_id: Option<PublicKey>,
+	their_address: Option<IpAddr>,
 	their_global_features: Option<msgs::GlobalFeatures>,
 	their_local_features: Option<msgs::LocalFeatures>,
 
@@ -122,14 +157,91 @@ impl<Descriptor: SocketDescriptor> PeerHolder<Descriptor> {
 	}
 }
 
+/// Once a peer's misbehaviour score reaches this value it is banned for PEER_BAN_DURATION_SECS.
+const MISBEHAVIOUR_BAN_THRESHOLD: u32 = 100;
+/// Misbehaviour scores are forgotten if a peer has behaved for this long.
+const MISBEHAVIOUR_SCORE_DECAY_SECS: u64 = 60 * 60;
+/// How long a peer (or remote address) stays banned once it crosses MISBEHAVIOUR_BAN_THRESHOLD.
+const PEER_BAN_DURATION_SECS: u64 = 60 * 60 * 24;
+
+/// Score for messages we couldn't read at all.
+const MISBEHAVIOUR_SCORE_INVALID: u32 = 50;
+/// Score for errors which made us disconnect the peer.
+const MISBEHAVIOUR_SCORE_DISCONNECT: u32 = 25;
+/// Score for errors which made us send the peer an error message.
+const MISBEHAVIOUR_SCORE_ERROR_MESSAGE: u32 = 10;
+
+struct MisbehaviourScore {
+	score: u32,
+	last_misbehaviour: Instant,
+}
+
+/// Misbehaviour scores and active bans for one kind of peer identifier (node_id or address).
+struct MisbehaviourBook<K: cmp::Eq + hash::Hash + Clone> {
+	scores: HashMap<K, MisbehaviourScore>,
+	/// Maps banned keys to the time at which their ban expires
+	bans: HashMap<K, Instant>,
+}
+impl<K: cmp::Eq + hash::Hash + Clone> MisbehaviourBook<K> {
+	fn new() -> Self {
+		MisbehaviourBook { scores: HashMap::new(), bans: HashMap::new() }
+	}
+
+	/// Adds score to key's misbehaviour score, returning true if key is now banned.
+	fn add_score(&mut self, key: &K, score: u32, now: Instant) -> bool {
+		if self.is_banned(key, now) {
+			return true;
+		}
+		let new_score = {
+			let entry = self.scores.entry(key.clone()).or_insert(MisbehaviourScore { score: 0, last_misbehaviour: now });
+			if now.duration_since(entry.last_misbehaviour) >= Duration::from_secs(MISBEHAVIOUR_SCORE_DECAY_SECS) {
+				entry.score = 0;
+			}
+			entry.score = entry.score.saturating_add(score);
+			entry.last_misbehaviour = now;
+			entry.score
+		};
+		if new_score >= MISBEHAVIOUR_BAN_THRESHOLD {
+			self.scores.remove(key);
+			self.bans.insert(key.clone(), now + Duration::from_secs(PEER_BAN_DURATION_SECS));
+			return true;
+		}
+		false
+	}
+
+	fn is_banned(&mut self, key: &K, now: Instant) -> bool {
+		match self.bans.get(key) {
+			Some(expiry) if *expiry > now => return true,
+			Some(_) => {},
+			None => return false,
+		}
+		self.bans.remove(key);
+		false
+	}
+
+	fn get_bans(&mut self, now: Instant) -> Vec<(K, Instant)> {
+		self.bans.retain(|_, expiry| *expiry > now);
+		self.scores.retain(|_, score| now.duration_since(score.last_misbehaviour) < Duration::from_secs(MISBEHAVIOUR_SCORE_DECAY_SECS));
+		self.bans.iter().map(|(key, expiry)| (key.clone(), *expiry)).collect()
+	}
+}
+
+struct PeerMisbehaviour {
+	by_node_id: MisbehaviourBook<PublicKey>,
+	by_address: MisbehaviourBook<IpAddr>,
+}
+
 /// A PeerManager manages a set of peers, described by their SocketDescriptor and marshalls socket
 /// events into messages which it passes on to its MessageHandlers.
 pub struct PeerManager<Descriptor: SocketDescriptor> {
 	message_handler: MessageHandler,
 	peers: Mutex<PeerHolder<Descriptor>>,
+	/// Always taken after peers if both are held
+	misbehaviour: Mutex<PeerMisbehaviour>,
 	pending_events: Mutex<Vec<Event>>,
 	our_node_secret: SecretKey,
 	initial_syncs_sent: AtomicUsize,
+	limits: PeerLimits,
 	logger: Arc<Logger>,
 }
 
@@ -159,14 +271,17 @@ const INITIAL_SYNCS_TO_SEND: usize = 5;
 /// Manages and reacts to connection events. You probably want to use file descriptors as PeerIds.
 /// PeerIds may repeat, but only after disconnect_event() has been called.
 impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
-	/// Constructs a new PeerManager with the given message handlers and node_id secret key
-	pub fn new(message_handler: MessageHandler, our_node_secret: SecretKey, logger: Arc<Logger>) -> PeerManager<Descriptor> {
+	/// Constructs a new PeerManager with the given message handlers, node_id secret key and
+	/// connection limits
+	pub fn new(message_handler: MessageHandler, our_node_secret: SecretKey, limits: PeerLimits, logger: Arc<Logger>) -> PeerManager<Descriptor> {
 		PeerManager {
 			message_handler: message_handler,
 			peers: Mutex::new(PeerHolder { peers: HashMap::new(), node_id_to_descriptor: HashMap::new() }),
+			misbehaviour: Mutex::new(PeerMisbehaviour { by_node_id: MisbehaviourBook::new(), by_address: MisbehaviourBook::new() }),
 			pending_events: Mutex::new(Vec::new()),
 			our_node_secret: our_node_secret,
 			initial_syncs_sent: AtomicUsize::new(0),
+			limits,
 			logger,
 		}
 	}
@@ -186,6 +301,66 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 		}).collect()
 	}
 
+	/// Get the list of node ids which are currently banned for misbehaviour, along with the time at
+	/// which each ban expires.
+	///
+	/// Inbound connections from banned node_ids are refused once the handshake reveals their
+	/// node_id.
+	pub fn get_banned_peers(&self) -> Vec<(PublicKey, Instant)> {
+		self.misbehaviour.lock().unwrap().by_node_id.get_bans(Instant::now())
+	}
+
+	/// Get the list of remote addresses which are currently banned for misbehaviour, along with the
+	/// time at which each ban expires.
+	///
+	/// Inbound connections from banned addresses are refused in new_inbound_connection.
+	pub fn get_banned_addresses(&self) -> Vec<(IpAddr, Instant)> {
+		self.misbehaviour.lock().unwrap().by_address.get_bans(Instant::now())
+	}
+
+	/// Adds score to the misbehaviour scores of the given node_id and/or address, returning true if
+	/// either is now banned (and thus the peer should be disconnected).
+	///
+	/// Peers we have funded channels with are never banned, as refusing their connections would
+	/// leave the channels unusable (and, eventually, force them on-chain).
+	fn record_misbehaviour(&self, node_id: Option<&PublicKey>, address: Option<&IpAddr>, score: u32) -> bool {
+		if score == 0 {
+			return false;
+		}
+		if let Some(node_id) = node_id {
+			if self.message_handler.chan_handler.has_funded_channels(node_id) {
+				return false;
+			}
+		}
+		let now = Instant::now();
+		let mut misbehaviour = self.misbehaviour.lock().unwrap();
+		let mut banned = false;
+		if let Some(node_id) = node_id {
+			if misbehaviour.by_node_id.add_score(node_id, score, now) {
+				log_info!(self, "Peer {} is banned for misbehaviour", log_pubkey!(node_id));
+				banned = true;
+			}
+		}
+		if let Some(address) = address {
+			if misbehaviour.by_address.add_score(address, score, now) {
+				log_info!(self, "Address {} is banned for misbehaviour", address);
+				banned = true;
+			}
+		}
+		banned
+	}
+
+	fn handle_error_misbehaviour_score(action: Option<&msgs::ErrorAction>) -> u32 {
+		match action {
+			Some(&msgs::ErrorAction::DisconnectPeer { .. }) => MISBEHAVIOUR_SCORE_DISCONNECT,
+			Some(&msgs::ErrorAction::IgnoreError) => 0,
+			Some(&msgs::ErrorAction::SendErrorMessage { .. }) => MISBEHAVIOUR_SCORE_ERROR_MESSAGE,
+			// Action not yet filled in - this is used for plenty of benign errors (eg disagreeing
+			// on closing fees), so it doesn't tell us anything about the peer.
+			None => 0,
+		}
+	}
+
 	/// Indicates a new outbound connection has been established to a node with the given node_id.
 	/// Note that if an Err is returned here you MUST NOT call disconnect_event for the new
 	/// descriptor but must disconnect the connection immediately.
@@ -203,7 +378,9 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 		if peers.peers.insert(descriptor, Peer {
 			channel_encryptor: peer_encryptor,
 			outbound: true,
+			connected_at: Instant::now(),
 			their_node_id: Some(their_node_id),
+			their_address: None,
 			their_global_features: None,
 			their_local_features: None,
 
@@ -227,17 +404,50 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 	/// call disconnect_event for the new descriptor but must disconnect the connection
 	/// immediately.
 	///
+	/// If their_address is provided, misbehaviour by the peer will also count against the remote
+	/// address, and connections from addresses which are currently banned are refused.
+	///
+	/// Connections are also refused (or other inbound peers evicted) as required to stay within
+	/// the PeerLimits this PeerManager was constructed with.
+	///
 	/// Panics if descriptor is duplicative with some other descriptor which has not yet has a
 	/// disconnect_event.
-	pub fn new_inbound_connection(&self, descriptor: Descriptor) -> Result<(), PeerHandleError> {
+	pub fn new_inbound_connection(&self, descriptor: Descriptor, their_address: Option<IpAddr>) -> Result<(), PeerHandleError> {
+		if let Some(ref address) = their_address {
+			if self.misbehaviour.lock().unwrap().by_address.is_banned(address, Instant::now()) {
+				log_debug!(self, "Refusing inbound connection from banned address {}", address);
+				return Err(PeerHandleError{ no_connection_possible: false });
+			}
+		}
+
 		let peer_encryptor = PeerChannelEncryptor::new_inbound(&self.our_node_secret);
 		let pending_read_buffer = [0; 50].to_vec(); // Noise act one is 50 bytes
 
-		let mut peers = self.peers.lock().unwrap();
+		let mut peers_lock = self.peers.lock().unwrap();
+		let peers = peers_lock.borrow_parts();
+		let pending_handshakes = peers.peers.values().filter(|p| !p.outbound && p.their_global_features.is_none()).count();
+		if pending_handshakes >= self.limits.max_pending_handshakes {
+			log_debug!(self, "Refusing inbound connection as {} handshakes are already in progress", pending_handshakes);
+			return Err(PeerHandleError{ no_connection_possible: false });
+		}
+		if peers.peers.len() >= self.limits.max_peers {
+			match self.channelless_inbound_peers_by_age(peers.peers, None).first() {
+				Some(descriptor) => {
+					log_debug!(self, "Evicting oldest peer without channels to make room for an inbound connection");
+					self.evict_peer(peers.peers, peers.node_id_to_descriptor, descriptor);
+				},
+				None => {
+					log_debug!(self, "Refusing inbound connection as we already have {} peers", peers.peers.len());
+					return Err(PeerHandleError{ no_connection_possible: false });
+				},
+			}
+		}
 		if peers.peers.insert(descriptor, Peer {
 			channel_encryptor: peer_encryptor,
 			outbound: false,
+			connected_at: Instant::now(),
 			their_node_id: None,
+			their_address: their_address,
 			their_global_features: None,
 			their_local_features: None,
 
@@ -254,6 +464,35 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 		Ok(())
 	}
 
+	/// Gets the inbound peers which have completed the noise handshake but with which we have no
+	/// funded channels, oldest first.
+	fn channelless_inbound_peers_by_age(&self, peers: &HashMap<Descriptor, Peer>, exclude: Option<&Descriptor>) -> Vec<Descriptor> {
+		let mut res: Vec<(&Descriptor, &Peer)> = peers.iter().filter(|&(descriptor, peer)| {
+			if peer.outbound || Some(descriptor) == exclude {
+				return false;
+			}
+			match peer.their_node_id {
+				Some(ref node_id) => !self.message_handler.chan_handler.has_funded_channels(node_id),
+				None => false,
+			}
+		}).collect();
+		res.sort_by_key(|&(_, peer)| peer.connected_at);
+		res.drain(..).map(|(descriptor, _)| descriptor.clone()).collect()
+	}
+
+	/// Disconnects the given peer ourselves, without waiting for a disconnect_event.
+	fn evict_peer(&self, peers: &mut HashMap<Descriptor, Peer>, node_id_to_descriptor: &mut HashMap<PublicKey, Descriptor>, descriptor: &Descriptor) {
+		if let Some(peer) = peers.remove(descriptor) {
+			if let Some(node_id) = peer.their_node_id {
+				if node_id_to_descriptor.get(&node_id) == Some(descriptor) {
+					node_id_to_descriptor.remove(&node_id);
+				}
+				self.message_handler.chan_handler.peer_disconnected(&node_id, false);
+			}
+		}
+		descriptor.clone().disconnect_socket();
+	}
+
 	fn do_attempt_write_data(descriptor: &mut Descriptor, peer: &mut Peer) {
 		while !peer.awaiting_write_event {
 			if {
@@ -283,11 +522,13 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 	/// new_*\_connection) before returning. Thus, be very careful with reentrancy issues! The
 	/// invariants around calling write_event in case a write did not fully complete must still
 	/// hold - be ready to call write_event again if a write call generated here isn't sufficient!
-	/// Panics if the descriptor was not previously registered in a new_\*_connection event.
+	///
+	/// Returns an Err if the descriptor is not registered, eg because we called disconnect_socket
+	/// on it while this call was in flight.
 	pub fn write_event(&self, descriptor: &mut Descriptor) -> Result<(), PeerHandleError> {
 		let mut peers = self.peers.lock().unwrap();
 		match peers.peers.get_mut(descriptor) {
-			None => panic!("Descriptor for write_event is not already known to PeerManager"),
+			None => return Err(PeerHandleError{ no_connection_possible: false }),
 			Some(peer) => {
 				peer.awaiting_write_event = false;
 				Self::do_attempt_write_data(descriptor, peer);
@@ -311,7 +552,8 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 	/// that this must be true even if a send_data call with resume_read=true was made during the
 	/// course of this function!
 	///
-	/// Panics if the descriptor was not previously registered in a new_*_connection event.
+	/// Returns an Err if the descriptor is not registered, eg because we called disconnect_socket
+	/// on it while this call was in flight.
 	pub fn read_event(&self, peer_descriptor: &mut Descriptor, data: Vec<u8>) -> Result<bool, PeerHandleError> {
 		match self.do_read_event(peer_descriptor, data) {
 			Ok(res) => Ok(res),
@@ -326,8 +568,9 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 		let pause_read = {
 			let mut peers_lock = self.peers.lock().unwrap();
 			let peers = peers_lock.borrow_parts();
+			let mut check_channelless_limit = false;
 			let pause_read = match peers.peers.get_mut(peer_descriptor) {
-				None => panic!("Descriptor for read_event is not already known to PeerManager"),
+				None => return Err(PeerHandleError{ no_connection_possible: false }),
 				Some(peer) => {
 					assert!(peer.pending_read_buffer.len() > 0);
 					assert!(peer.pending_read_buffer.len() > peer.pending_read_buffer_pos);
@@ -358,6 +601,10 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 									match $thing {
 										Ok(x) => x,
 										Err(e) => {
+											if self.record_misbehaviour(peer.their_node_id.as_ref(), peer.their_address.as_ref(), Self::handle_error_misbehaviour_score(e.action.as_ref())) {
+												log_debug!(self, "Got Err handling message, disconnecting banned peer because {}", e.err);
+												return Err(PeerHandleError{ no_connection_possible: false });
+											}
 											if let Some(action) = e.action {
 												match action {
 													msgs::ErrorAction::DisconnectPeer { msg: _ } => {
@@ -389,6 +636,12 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 									match $thing {
 										Ok(x) => x,
 										Err(e) => {
+											match e {
+												msgs::DecodeError::UnknownRequiredFeature|msgs::DecodeError::ExtraAddressesPerType => {},
+												_ => {
+													self.record_misbehaviour(peer.their_node_id.as_ref(), peer.their_address.as_ref(), MISBEHAVIOUR_SCORE_INVALID);
+												},
+											}
 											match e {
 												msgs::DecodeError::UnknownVersion => return Err(PeerHandleError{ no_connection_possible: false }),
 												msgs::DecodeError::UnknownRequiredFeature => {
@@ -436,6 +689,8 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 
 									insert_node_id!();
 									let mut local_features = msgs::LocalFeatures::new();
+									local_features.set_supports_data_loss_protect();
+									local_features.set_supports_upfront_shutdown_script();
 									if self.initial_syncs_sent.load(Ordering::Acquire) < INITIAL_SYNCS_TO_SEND {
 										self.initial_syncs_sent.fetch_add(1, Ordering::AcqRel);
 										local_features.set_initial_routing_sync();
@@ -447,10 +702,15 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 								},
 								NextNoiseStep::ActThree => {
 									let their_node_id = try_potential_handleerror!(peer.channel_encryptor.process_act_three(&peer.pending_read_buffer[..]));
+									if self.misbehaviour.lock().unwrap().by_node_id.is_banned(&their_node_id, Instant::now()) {
+										log_debug!(self, "Refusing inbound connection from banned peer {}", log_pubkey!(their_node_id));
+										return Err(PeerHandleError{ no_connection_possible: false });
+									}
 									peer.pending_read_buffer = [0; 18].to_vec(); // Message length header is 18 bytes
 									peer.pending_read_is_header = true;
 									peer.their_node_id = Some(their_node_id);
 									insert_node_id!();
+									check_channelless_limit = true;
 								},
 								NextNoiseStep::NoiseComplete => {
 									if peer.pending_read_is_header {
@@ -488,22 +748,14 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 													log_info!(self, "Peer local features required unknown version bits");
 													return Err(PeerHandleError{ no_connection_possible: true });
 												}
-												if msg.local_features.requires_data_loss_protect() {
-													log_info!(self, "Peer local features required data_loss_protect");
-													return Err(PeerHandleError{ no_connection_possible: true });
-												}
-												if msg.local_features.requires_upfront_shutdown_script() {
-													log_info!(self, "Peer local features required upfront_shutdown_script");
-													return Err(PeerHandleError{ no_connection_possible: true });
-												}
 												if peer.their_global_features.is_some() {
 													return Err(PeerHandleError{ no_connection_possible: false });
 												}
 
 												log_info!(self, "Received peer Init message: data_loss_protect: {}, initial_routing_sync: {}, upfront_shutdown_script: {}, unkown local flags: {}, unknown global flags: {}",
-													if msg.local_features.supports_data_loss_protect() { "supported" } else { "not supported"},
+													if msg.local_features.requires_data_loss_protect() { "required" } else if msg.local_features.supports_data_loss_protect() { "supported" } else { "not supported"},
 													if msg.local_features.initial_routing_sync() { "requested" } else { "not requested" },
-													if msg.local_features.supports_upfront_shutdown_script() { "supported" } else { "not supported"},
+													if msg.local_features.requires_upfront_shutdown_script() { "required" } else if msg.local_features.supports_upfront_shutdown_script() { "supported" } else { "not supported"},
 													if msg.local_features.supports_unknown_bits() { "present" } else { "none" },
 													if msg.global_features.supports_unknown_bits() { "present" } else { "none" });
 
@@ -512,6 +764,8 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 
 												if !peer.outbound {
 													let mut local_features = msgs::LocalFeatures::new();
+													local_features.set_supports_data_loss_protect();
+													local_features.set_supports_upfront_shutdown_script();
 													if self.initial_syncs_sent.load(Ordering::Acquire) < INITIAL_SYNCS_TO_SEND {
 														self.initial_syncs_sent.fetch_add(1, Ordering::AcqRel);
 														local_features.set_initial_routing_sync();
@@ -522,7 +776,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 													}, 16);
 												}
 
-												for msg in self.message_handler.chan_handler.peer_connected(&peer.their_node_id.unwrap()) {
+												for msg in self.message_handler.chan_handler.peer_connected(&peer.their_node_id.unwrap(), peer.their_local_features.as_ref().unwrap()) {
 													encode_and_send_msg!(msg, 136);
 												}
 											},
@@ -735,6 +989,16 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 				}
 			};
 
+			if check_channelless_limit {
+				let channelless_peers = self.channelless_inbound_peers_by_age(peers.peers, Some(&*peer_descriptor));
+				if channelless_peers.len() >= self.limits.max_channelless_peers {
+					for descriptor in channelless_peers[..channelless_peers.len() + 1 - cmp::max(self.limits.max_channelless_peers, 1)].iter() {
+						log_debug!(self, "Evicting oldest peer without channels as we have too many");
+						self.evict_peer(peers.peers, peers.node_id_to_descriptor, descriptor);
+					}
+				}
+			}
+
 			pause_read
 		};
 
@@ -784,6 +1048,11 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 					Event::PaymentReceived {..} => { /* Hand upstream */ },
 					Event::PaymentSent {..} => { /* Hand upstream */ },
 					Event::PaymentFailed {..} => { /* Hand upstream */ },
+					Event::PaymentForwarded {..} => { /* Hand upstream */ },
+					Event::HTLCIntercepted {..} => { /* Hand upstream */ },
+					Event::ChannelPending {..} => { /* Hand upstream */ },
+					Event::ChannelReady {..} => { /* Hand upstream */ },
+					Event::ChannelClosed {..} => { /* Hand upstream */ },
 					Event::PendingHTLCsForwardable {..} => { /* Hand upstream */ },
 
 					Event::SendOpenChannel { ref node_id, ref msg } => {
@@ -826,6 +1095,17 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 						Self::do_attempt_write_data(&mut descriptor, peer);
 						continue;
 					},
+					Event::SendAnnouncementSignatures { ref node_id, ref msg } => {
+						log_trace!(self, "Handling SendAnnouncementSignatures event in peer_handler for node {} for channel {}",
+								log_pubkey!(node_id),
+								log_bytes!(msg.channel_id));
+						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+								//TODO: Do whatever we're gonna do for handling dropped messages
+							});
+						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 259)));
+						Self::do_attempt_write_data(&mut descriptor, peer);
+						continue;
+					},
 					Event::UpdateHTLCs { ref node_id, updates: msgs::CommitmentUpdate { ref update_add_htlcs, ref update_fulfill_htlcs, ref update_fail_htlcs, ref update_fail_malformed_htlcs, ref update_fee, ref commitment_signed } } => {
 						log_trace!(self, "Handling UpdateHTLCs event in peer_handler for node {} with {} adds, {} fulfills, {} fails for channel {}",
 								log_pubkey!(node_id),
@@ -855,6 +1135,17 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 						Self::do_attempt_write_data(&mut descriptor, peer);
 						continue;
 					},
+					Event::SendRevokeAndACK { ref node_id, ref msg } => {
+						log_trace!(self, "Handling SendRevokeAndACK event in peer_handler for node {} for channel {}",
+								log_pubkey!(node_id),
+								log_bytes!(msg.channel_id));
+						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+								//TODO: Do whatever we're gonna do for handling dropped messages
+							});
+						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 133)));
+						Self::do_attempt_write_data(&mut descriptor, peer);
+						continue;
+					},
 					Event::SendShutdown { ref node_id, ref msg } => {
 						log_trace!(self, "Handling Shutdown event in peer_handler for node {} for channel {}",
 								log_pubkey!(node_id),
@@ -906,13 +1197,36 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 						}
 						continue;
 					},
+					Event::BroadcastNodeAnnouncement { ref msg } => {
+						log_trace!(self, "Handling BroadcastNodeAnnouncement event in peer_handler for node {}", log_pubkey!(msg.contents.node_id));
+						if self.message_handler.route_handler.handle_node_announcement(msg).is_ok() {
+							let encoded_msg = encode_msg!(msg, 257);
+
+							for (ref descriptor, ref mut peer) in peers.peers.iter_mut() {
+								if !peer.channel_encryptor.is_ready_for_encryption() || peer.their_global_features.is_none() {
+									continue
+								}
+								peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_msg[..]));
+								Self::do_attempt_write_data(&mut (*descriptor).clone(), peer);
+							}
+						}
+						continue;
+					},
 					Event::HandleError { ref node_id, ref action } => {
 						if let Some(ref action) = *action {
-							match *action {
-								msgs::ErrorAction::DisconnectPeer { ref msg } => {
+							let banned = {
+								let their_address = match peers.node_id_to_descriptor.get(node_id) {
+									Some(descriptor) => peers.peers.get(descriptor).and_then(|peer| peer.their_address),
+									None => None,
+								};
+								self.record_misbehaviour(Some(node_id), their_address.as_ref(), Self::handle_error_misbehaviour_score(Some(action)))
+							};
+
+							macro_rules! disconnect_peer {
+								($msg: expr) => {
 									if let Some(mut descriptor) = peers.node_id_to_descriptor.remove(node_id) {
 										if let Some(mut peer) = peers.peers.remove(&descriptor) {
-											if let Some(ref msg) = *msg {
+											if let Some(ref msg) = $msg {
 												log_trace!(self, "Handling DisconnectPeer HandleError event in peer_handler for node {} with message {}",
 														log_pubkey!(node_id),
 														msg.data);
@@ -927,11 +1241,24 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 										descriptor.disconnect_socket();
 										self.message_handler.chan_handler.peer_disconnected(&node_id, false);
 									}
+								}
+							}
+
+							match *action {
+								msgs::ErrorAction::DisconnectPeer { ref msg } => {
+									disconnect_peer!(*msg);
 								},
 								msgs::ErrorAction::IgnoreError => {
+									if banned {
+										disconnect_peer!(None::<msgs::ErrorMessage>);
+									}
 									continue;
 								},
 								msgs::ErrorAction::SendErrorMessage { ref msg } => {
+									if banned {
+										disconnect_peer!(Some(msg));
+										continue;
+									}
 									log_trace!(self, "Handling SendErrorMessage HandleError event in peer_handler for node {} with message {}",
 											log_pubkey!(node_id),
 											msg.data);
@@ -959,12 +1286,31 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 		}
 	}
 
+	/// Disconnects connections which have not completed the handshake within the handshake_timeout
+	/// set in our PeerLimits. Should be called roughly once every few seconds.
+	///
+	/// Will call disconnect_socket on the descriptors of any such connections, after which no
+	/// disconnect_event should be generated for them.
+	pub fn timer_tick_occured(&self) {
+		let mut peers_lock = self.peers.lock().unwrap();
+		let peers = peers_lock.borrow_parts();
+		let now = Instant::now();
+		let timed_out: Vec<Descriptor> = peers.peers.iter().filter(|&(_, peer)| {
+			peer.their_global_features.is_none() && now.duration_since(peer.connected_at) >= self.limits.handshake_timeout
+		}).map(|(descriptor, _)| descriptor.clone()).collect();
+		for descriptor in timed_out.iter() {
+			log_debug!(self, "Disconnecting peer which failed to complete the handshake in time");
+			self.evict_peer(peers.peers, peers.node_id_to_descriptor, descriptor);
+		}
+	}
+
 	/// Indicates that the given socket descriptor's connection is now closed.
 	///
 	/// This must be called even if a PeerHandleError was given for a read_event or write_event,
 	/// but must NOT be called if a PeerHandleError was provided out of a new_\*\_connection event!
 	///
-	/// Panics if the descriptor was not previously registered in a successful new_*_connection event.
+	/// Does nothing if the descriptor is no longer registered (eg because a read_event returned an
+	/// Err or we called disconnect_socket on it).
 	pub fn disconnect_event(&self, descriptor: &Descriptor) {
 		self.disconnect_event_internal(descriptor, false);
 	}
@@ -973,7 +1319,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 		let mut peers = self.peers.lock().unwrap();
 		let peer_option = peers.peers.remove(descriptor);
 		match peer_option {
-			None => panic!("Descriptor for disconnect_event is not already known to PeerManager"),
+			None => {},
 			Some(peer) => {
 				match peer.their_node_id {
 					Some(node_id) => {
@@ -998,7 +1344,7 @@ impl<Descriptor: SocketDescriptor> EventsProvider for PeerManager<Descriptor> {
 
 #[cfg(test)]
 mod tests {
-	use ln::peer_handler::{PeerManager, MessageHandler, SocketDescriptor};
+	use ln::peer_handler::{PeerManager, MessageHandler, SocketDescriptor, PeerHandleError, PeerLimits};
 	use ln::msgs;
 	use util::events;
 	use util::test_utils;
@@ -1009,16 +1355,41 @@ mod tests {
 
 	use rand::{thread_rng, Rng};
 
-	use std::sync::{Arc};
+	use std::mem;
+	use std::net::{IpAddr, Ipv4Addr};
+	use std::sync::{Arc, Mutex};
+	use std::time::{Duration, Instant};
+	use std::hash;
 
-	#[derive(PartialEq, Eq, Clone, Hash)]
+	#[derive(Clone)]
 	struct FileDescriptor {
 		fd: u16,
+		outbound_data: Arc<Mutex<Vec<u8>>>,
+	}
+	impl PartialEq for FileDescriptor {
+		fn eq(&self, other: &Self) -> bool {
+			self.fd == other.fd
+		}
+	}
+	impl Eq for FileDescriptor { }
+	impl hash::Hash for FileDescriptor {
+		fn hash<H: hash::Hasher>(&self, hasher: &mut H) {
+			self.fd.hash(hasher)
+		}
+	}
+	impl FileDescriptor {
+		fn new(fd: u16) -> Self {
+			FileDescriptor { fd, outbound_data: Arc::new(Mutex::new(Vec::new())) }
+		}
+		fn take_outbound_data(&self) -> Vec<u8> {
+			mem::replace(&mut *self.outbound_data.lock().unwrap(), Vec::new())
+		}
 	}
 
 	impl SocketDescriptor for FileDescriptor {
 		fn send_data(&mut self, data: &Vec<u8>, write_offset: usize, _resume_read: bool) -> usize {
 			assert!(write_offset < data.len());
+			self.outbound_data.lock().unwrap().extend_from_slice(&data[write_offset..]);
 			data.len() - write_offset
 		}
 
@@ -1026,6 +1397,10 @@ mod tests {
 	}
 
 	fn create_network(peer_count: usize) -> Vec<PeerManager<FileDescriptor>> {
+		create_network_with_limits(peer_count, PeerLimits::default())
+	}
+
+	fn create_network_with_limits(peer_count: usize, limits: PeerLimits) -> Vec<PeerManager<FileDescriptor>> {
 		let secp_ctx = Secp256k1::new();
 		let mut peers = Vec::new();
 		let mut rng = thread_rng();
@@ -1040,7 +1415,7 @@ mod tests {
 				SecretKey::from_slice(&secp_ctx, &key_slice).unwrap()
 			};
 			let msg_handler = MessageHandler { chan_handler: Arc::new(chan_handler), route_handler: Arc::new(router) };
-			let peer = PeerManager::new(msg_handler, node_id, Arc::clone(&logger));
+			let peer = PeerManager::new(msg_handler, node_id, limits.clone(), Arc::clone(&logger));
 			peers.push(peer);
 		}
 
@@ -1050,8 +1425,8 @@ mod tests {
 	fn establish_connection(peer_a: &PeerManager<FileDescriptor>, peer_b: &PeerManager<FileDescriptor>) {
 		let secp_ctx = Secp256k1::new();
 		let their_id = PublicKey::from_secret_key(&secp_ctx, &peer_b.our_node_secret);
-		let fd = FileDescriptor { fd: 1};
-		peer_a.new_inbound_connection(fd.clone()).unwrap();
+		let fd = FileDescriptor::new(1);
+		peer_a.new_inbound_connection(fd.clone(), None).unwrap();
 		peer_a.peers.lock().unwrap().node_id_to_descriptor.insert(their_id, fd.clone());
 	}
 
@@ -1077,4 +1452,225 @@ mod tests {
 		peers[0].process_events();
 		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 0);
 	}
+
+	fn do_handshake(peer_a: &PeerManager<FileDescriptor>, fd_a: u16, peer_b: &PeerManager<FileDescriptor>, address: Option<IpAddr>) -> Result<(), PeerHandleError> {
+		// peer_b connects out to peer_a
+		let secp_ctx = Secp256k1::new();
+		let a_id = PublicKey::from_secret_key(&secp_ctx, &peer_a.our_node_secret);
+		let mut fd_a = FileDescriptor::new(fd_a);
+		let mut fd_b = FileDescriptor::new(1);
+		let act_one = peer_b.new_outbound_connection(a_id, fd_b.clone()).unwrap();
+		peer_a.new_inbound_connection(fd_a.clone(), address)?;
+		peer_a.read_event(&mut fd_a, act_one).unwrap();
+		peer_b.read_event(&mut fd_b, fd_a.take_outbound_data()).unwrap();
+		peer_a.read_event(&mut fd_a, fd_b.take_outbound_data())?;
+		peer_b.read_event(&mut fd_b, fd_a.take_outbound_data()).unwrap();
+		Ok(())
+	}
+
+	fn push_handle_error(peer: &mut PeerManager<FileDescriptor>, node_id: PublicKey, action: msgs::ErrorAction) {
+		let chan_handler = test_utils::TestChannelMessageHandler::new();
+		chan_handler.pending_events.lock().unwrap().push(events::Event::HandleError {
+			node_id,
+			action: Some(action),
+		});
+		peer.message_handler.chan_handler = Arc::new(chan_handler);
+		peer.process_events();
+	}
+
+	#[test]
+	fn test_misbehaviour_ban() {
+		// Misbehaving peers should accumulate a score until banned, at which point they are
+		// disconnected and refused after act three.
+		let mut peers = create_network(2);
+		let secp_ctx = Secp256k1::new();
+		let their_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		let their_addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+		do_handshake(&peers[0], 1, &peers[1], Some(their_addr)).unwrap();
+		assert_eq!(peers[0].get_peer_node_ids(), vec![their_id]);
+
+		// Ignored errors don't count against the peer
+		for _ in 0..50 {
+			push_handle_error(&mut peers[0], their_id, msgs::ErrorAction::IgnoreError);
+		}
+		assert!(peers[0].get_banned_peers().is_empty());
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 1);
+
+		// Error messages do, until the peer is banned and disconnected
+		let error_msg = || msgs::ErrorMessage { channel_id: [0; 32], data: "test".to_owned() };
+		for _ in 0..9 {
+			push_handle_error(&mut peers[0], their_id, msgs::ErrorAction::SendErrorMessage { msg: error_msg() });
+		}
+		assert!(peers[0].get_banned_peers().is_empty());
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 1);
+		push_handle_error(&mut peers[0], their_id, msgs::ErrorAction::SendErrorMessage { msg: error_msg() });
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 0);
+
+		let bans = peers[0].get_banned_peers();
+		assert_eq!(bans.len(), 1);
+		assert_eq!(bans[0].0, their_id);
+		assert!(bans[0].1 > Instant::now());
+		let addr_bans = peers[0].get_banned_addresses();
+		assert_eq!(addr_bans.len(), 1);
+		assert_eq!(addr_bans[0].0, their_addr);
+
+		// Banned addresses are refused immediately, banned node_ids after act three
+		assert!(peers[0].new_inbound_connection(FileDescriptor::new(2), Some(their_addr)).is_err());
+		peers[1].disconnect_event(&FileDescriptor::new(1));
+		assert!(do_handshake(&peers[0], 1, &peers[1], None).is_err());
+		assert!(peers[0].peers.lock().unwrap().peers.is_empty());
+		peers[1].disconnect_event(&FileDescriptor::new(1));
+
+		// Once the bans expire, the peer may reconnect
+		{
+			let mut misbehaviour = peers[0].misbehaviour.lock().unwrap();
+			let now = Instant::now();
+			for expiry in misbehaviour.by_node_id.bans.values_mut() { *expiry = now; }
+			for expiry in misbehaviour.by_address.bans.values_mut() { *expiry = now; }
+		}
+		assert!(peers[0].get_banned_peers().is_empty());
+		assert!(peers[0].get_banned_addresses().is_empty());
+		do_handshake(&peers[0], 1, &peers[1], Some(their_addr)).unwrap();
+		assert_eq!(peers[0].get_peer_node_ids(), vec![their_id]);
+	}
+
+	#[test]
+	fn test_misbehaviour_decode_error() {
+		// Undecodable messages count heavily against the peer, so two bad messages in a row get it
+		// banned.
+		let peers = create_network(2);
+		let secp_ctx = Secp256k1::new();
+		let their_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+
+		for _ in 0..2 {
+			do_handshake(&peers[0], 1, &peers[1], None).unwrap();
+			assert!(peers[0].get_banned_peers().is_empty());
+			let mut fd_a = FileDescriptor::new(1);
+			let mut fd_b = FileDescriptor::new(1);
+			// A ping message which is too short to be read
+			let bad_msg = {
+				let mut peers_b = peers[1].peers.lock().unwrap();
+				let peer = peers_b.peers.get_mut(&fd_b).unwrap();
+				peer.channel_encryptor.encrypt_message(&[0, 18, 0])
+			};
+			assert!(peers[0].read_event(&mut fd_a, bad_msg).is_err());
+			peers[1].disconnect_event(&fd_b);
+		}
+		let bans = peers[0].get_banned_peers();
+		assert_eq!(bans.len(), 1);
+		assert_eq!(bans[0].0, their_id);
+	}
+
+	#[test]
+	fn test_misbehaviour_no_ban_with_channels() {
+		// Peers we have funded channels with are never banned, no matter how many errors they cause
+		let mut peers = create_network(2);
+		let secp_ctx = Secp256k1::new();
+		let their_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		do_handshake(&peers[0], 1, &peers[1], Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))).unwrap();
+
+		for _ in 0..20 {
+			let chan_handler = test_utils::TestChannelMessageHandler::new();
+			chan_handler.peers_with_channels.lock().unwrap().insert(their_id);
+			chan_handler.pending_events.lock().unwrap().push(events::Event::HandleError {
+				node_id: their_id,
+				action: Some(msgs::ErrorAction::SendErrorMessage { msg: msgs::ErrorMessage { channel_id: [0; 32], data: "test".to_owned() } }),
+			});
+			peers[0].message_handler.chan_handler = Arc::new(chan_handler);
+			peers[0].process_events();
+		}
+		assert!(peers[0].get_banned_peers().is_empty());
+		assert!(peers[0].get_banned_addresses().is_empty());
+		assert_eq!(peers[0].get_peer_node_ids(), vec![their_id]);
+	}
+
+	fn get_node_id(peer: &PeerManager<FileDescriptor>) -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::new(), &peer.our_node_secret)
+	}
+
+	fn set_peers_with_channels(peer: &mut PeerManager<FileDescriptor>, node_ids: &[PublicKey]) {
+		let chan_handler = test_utils::TestChannelMessageHandler::new();
+		chan_handler.peers_with_channels.lock().unwrap().extend(node_ids.iter().cloned());
+		peer.message_handler.chan_handler = Arc::new(chan_handler);
+	}
+
+	#[test]
+	fn test_handshake_limits() {
+		// Inbound connections beyond max_pending_handshakes are refused, and connections which
+		// never complete the handshake are disconnected on timer ticks.
+		let peers = create_network_with_limits(2, PeerLimits {
+			max_pending_handshakes: 2,
+			handshake_timeout: Duration::from_secs(0),
+			.. PeerLimits::default()
+		});
+
+		peers[0].new_inbound_connection(FileDescriptor::new(1), None).unwrap();
+		peers[0].new_inbound_connection(FileDescriptor::new(2), None).unwrap();
+		assert!(peers[0].new_inbound_connection(FileDescriptor::new(3), None).is_err());
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 2);
+
+		peers[0].timer_tick_occured();
+		assert!(peers[0].peers.lock().unwrap().peers.is_empty());
+
+		// Peers which complete the handshake aren't timed out
+		do_handshake(&peers[0], 1, &peers[1], None).unwrap();
+		peers[0].timer_tick_occured();
+		assert_eq!(peers[0].get_peer_node_ids(), vec![get_node_id(&peers[1])]);
+	}
+
+	#[test]
+	fn test_channelless_peer_eviction() {
+		// Once we have too many inbound peers without channels, the oldest ones are evicted, but
+		// never peers with which we have channels.
+		let mut peers = create_network_with_limits(5, PeerLimits {
+			max_channelless_peers: 2,
+			.. PeerLimits::default()
+		});
+		let node_ids: Vec<PublicKey> = peers.iter().map(|peer| get_node_id(peer)).collect();
+		set_peers_with_channels(&mut peers[0], &[node_ids[1]]);
+
+		for i in 1..4 {
+			do_handshake(&peers[0], i as u16, &peers[i], None).unwrap();
+		}
+		assert_eq!(peers[0].get_peer_node_ids().len(), 3);
+		// Make nodes[2] the oldest peer without channels, rather than relying on clock ticks
+		{
+			let mut peers_0 = peers[0].peers.lock().unwrap();
+			let peer = peers_0.peers.get_mut(&FileDescriptor::new(2)).unwrap();
+			peer.connected_at = peer.connected_at - Duration::from_secs(1);
+		}
+
+		do_handshake(&peers[0], 4, &peers[4], None).unwrap();
+		let connected = peers[0].get_peer_node_ids();
+		assert_eq!(connected.len(), 3);
+		assert!(connected.contains(&node_ids[1]));
+		assert!(!connected.contains(&node_ids[2]));
+		assert!(connected.contains(&node_ids[3]));
+		assert!(connected.contains(&node_ids[4]));
+	}
+
+	#[test]
+	fn test_max_peers_eviction() {
+		// Once we hit max_peers, new inbound connections evict the oldest channel-less peer, or are
+		// refused if there is none.
+		let mut peers = create_network_with_limits(3, PeerLimits {
+			max_peers: 2,
+			.. PeerLimits::default()
+		});
+		let node_ids: Vec<PublicKey> = peers.iter().map(|peer| get_node_id(peer)).collect();
+		set_peers_with_channels(&mut peers[0], &[node_ids[1]]);
+
+		do_handshake(&peers[0], 1, &peers[1], None).unwrap();
+		do_handshake(&peers[0], 2, &peers[2], None).unwrap();
+		assert_eq!(peers[0].get_peer_node_ids().len(), 2);
+
+		peers[0].new_inbound_connection(FileDescriptor::new(3), None).unwrap();
+		assert_eq!(peers[0].get_peer_node_ids(), vec![node_ids[1]]);
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 2);
+
+		// Neither a peer with channels nor one still completing the handshake are evicted
+		assert!(peers[0].new_inbound_connection(FileDescriptor::new(4), None).is_err());
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 2);
+	}
 }
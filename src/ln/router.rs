@@ -165,6 +165,7 @@ impl NetworkMap {
 }
 
 /// A channel descriptor which provides a last-hop route to get_route
+#[derive(Clone, Debug, PartialEq)]
 pub struct RouteHint {
 	/// The node_id of the non-target end of the route
 	pub src_node_id: PublicKey,
@@ -540,6 +541,14 @@ impl Router {
 	/// equal), however the enabled/disabled bit on such channels as well as the htlc_minimum_msat
 	/// *is* checked as they may change based on the receiving node.
 	pub fn get_route(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32) -> Result<Route, HandleError> {
+		self.get_route_avoiding(target, first_hops, last_hops, &[], final_value_msat, final_cltv)
+	}
+
+	/// Gets a route from us to the given target node which does not use any of the channels in
+	/// avoid_short_channel_ids (eg because a previous payment attempt failed over them).
+	///
+	/// See get_route for the meaning of the other parameters.
+	pub fn get_route_avoiding(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], avoid_short_channel_ids: &[u64], final_value_msat: u64, final_cltv: u32) -> Result<Route, HandleError> {
 		// TODO: Obviously *only* using total fee cost sucks. We should consider weighting by
 		// uptime/success in using a node in the past.
 		let network = self.network_map.read().unwrap();
@@ -573,6 +582,7 @@ impl Router {
 		if let Some(hops) = first_hops {
 			for chan in hops {
 				let short_channel_id = chan.short_channel_id.expect("first_hops should be filled in with usable channels, not pending ones");
+				if avoid_short_channel_ids.contains(&short_channel_id) { continue; }
 				if chan.remote_network_id == *target {
 					return Ok(Route {
 						hops: vec![RouteHop {
@@ -655,6 +665,7 @@ impl Router {
 				}
 
 				for chan_id in $node.channels.iter() {
+					if avoid_short_channel_ids.contains(NetworkMap::get_short_id(chan_id)) { continue; }
 					let chan = network.channels.get(chan_id).unwrap();
 					if chan.one_to_two.src_node_id == *$node_id {
 						// ie $node is one, ie next hop in A* is two, via the two_to_one channel
@@ -682,6 +693,7 @@ impl Router {
 		}
 
 		for hop in last_hops.iter() {
+			if avoid_short_channel_ids.contains(&hop.short_channel_id) { continue; }
 			if first_hops.is_none() || hop.src_node_id != network.our_node_id { // first_hop overrules last_hops
 				if network.nodes.get(&hop.src_node_id).is_some() {
 					if first_hops.is_some() {
@@ -736,6 +748,7 @@ mod tests {
 
 	use bitcoin::util::hash::Sha256dHash;
 	use bitcoin::network::constants::Network;
+	use bitcoin::blockdata::script::Script;
 
 	use hex;
 
@@ -1116,6 +1129,24 @@ mod tests {
 			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
 		}
 
+		{ // Route to 3 via the more expensive node8 if we're told to avoid chan4
+			let route = router.get_route_avoiding(&node3, None, &Vec::new(), &[4], 100, 42).unwrap();
+			assert_eq!(route.hops.len(), 2);
+
+			assert_eq!(route.hops[0].pubkey, node8);
+			assert_eq!(route.hops[0].short_channel_id, 12);
+			assert_eq!(route.hops[0].fee_msat, 200);
+			assert_eq!(route.hops[0].cltv_expiry_delta, (13 << 8) | 1);
+
+			assert_eq!(route.hops[1].pubkey, node3);
+			assert_eq!(route.hops[1].short_channel_id, 13);
+			assert_eq!(route.hops[1].fee_msat, 100);
+			assert_eq!(route.hops[1].cltv_expiry_delta, 42);
+
+			// ...and fail if we also have to avoid chan13
+			assert!(router.get_route_avoiding(&node3, None, &Vec::new(), &[4, 13], 100, 42).is_err());
+		}
+
 		{ // Route to 1 via 2 and 3 because our channel to 1 is disabled
 			let route = router.get_route(&node1, None, &Vec::new(), 100, 42).unwrap();
 			assert_eq!(route.hops.len(), 3);
@@ -1143,6 +1174,9 @@ mod tests {
 				remote_network_id: node8.clone(),
 				channel_value_satoshis: 0,
 				user_id: 0,
+				shutdown_scriptpubkey: Script::new(),
+				their_shutdown_scriptpubkey: None,
+				is_enabled: true,
 			}];
 			let route = router.get_route(&node3, Some(&our_chans), &Vec::new(), 100, 42).unwrap();
 			assert_eq!(route.hops.len(), 2);
@@ -1218,6 +1252,9 @@ mod tests {
 				remote_network_id: node4.clone(),
 				channel_value_satoshis: 0,
 				user_id: 0,
+				shutdown_scriptpubkey: Script::new(),
+				their_shutdown_scriptpubkey: None,
+				is_enabled: true,
 			}];
 			let route = router.get_route(&node7, Some(&our_chans), &last_hops, 100, 42).unwrap();
 			assert_eq!(route.hops.len(), 2);
@@ -0,0 +1,13 @@
+//! Reference networking drivers for PeerManager live here.
+//!
+//! These are entirely optional - PeerManager only requires that you implement SocketDescriptor
+//! and call into it as data arrives - but provide a simple way to get a node talking to peers
+//! using only the standard library. The drivers which open sockets themselves are enabled with
+//! the "std_net" feature.
+
+pub mod onion;
+pub mod reconnect;
+#[cfg(feature = "std_net")]
+pub mod socks5;
+#[cfg(feature = "std_net")]
+pub mod tcp;
@@ -0,0 +1,177 @@
+//! Conversion between Tor onion service hostnames (eg "<base32>.onion") and NetAddresses.
+//!
+//! Useful both to announce our own onion service (configured in Tor by hostname) and to hand the
+//! onion addresses of peers to a SOCKS5 proxy.
+
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+
+use ln::msgs::NetAddress;
+
+const BASE32_ALPHABET: &'static [u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// The only onion v3 address version currently defined.
+const ONION_V3_VERSION: u8 = 3;
+
+/// Encodes data as lowercase, unpadded RFC 4648 base32.
+fn base32_encode(data: &[u8]) -> String {
+	let mut res = String::with_capacity((data.len() * 8 + 4) / 5);
+	let mut buffer: u16 = 0;
+	let mut bits = 0;
+	for b in data.iter() {
+		buffer = (buffer << 8) | (*b as u16);
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			res.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+		}
+	}
+	if bits > 0 {
+		res.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+	}
+	res
+}
+
+/// Decodes unpadded RFC 4648 base32 (in either case), returning None if any invalid characters
+/// or non-zero trailing bits are present.
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+	let mut res = Vec::with_capacity(data.len() * 5 / 8);
+	let mut buffer: u16 = 0;
+	let mut bits = 0;
+	for c in data.to_lowercase().bytes() {
+		let val = match BASE32_ALPHABET.iter().position(|a| *a == c) {
+			Some(val) => val as u16,
+			None => return None,
+		};
+		buffer = (buffer << 5) | val;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			res.push((buffer >> bits) as u8);
+			buffer &= (1 << bits) - 1;
+		}
+	}
+	if bits >= 5 || buffer != 0 {
+		return None;
+	}
+	Some(res)
+}
+
+/// Calculates the checksum included in onion v3 addresses, as defined in Tor's rend-spec-v3.
+fn onion_v3_checksum(ed25519_pubkey: &[u8; 32], version: u8) -> u16 {
+	let mut sha = Sha3::sha3_256();
+	sha.input(b".onion checksum");
+	sha.input(ed25519_pubkey);
+	sha.input(&[version]);
+	let mut hash = [0; 32];
+	sha.result(&mut hash);
+	((hash[0] as u16) << 8) | (hash[1] as u16)
+}
+
+/// Parses an onion service hostname (with or without the ".onion" suffix) and port into a
+/// NetAddress.
+///
+/// Returns None if the hostname is not a valid onion v2 or v3 address (including if a v3
+/// address's checksum doesn't match).
+pub fn parse_onion_address(hostname: &str, port: u16) -> Option<NetAddress> {
+	let hostname = hostname.to_lowercase();
+	let host = if hostname.ends_with(".onion") { &hostname[..hostname.len() - 6] } else { &hostname[..] };
+	let bytes = match base32_decode(host) {
+		Some(bytes) => bytes,
+		None => return None,
+	};
+	match bytes.len() {
+		10 => {
+			let mut addr = [0; 10];
+			addr.copy_from_slice(&bytes[..]);
+			Some(NetAddress::OnionV2 { addr, port })
+		},
+		35 => {
+			let mut ed25519_pubkey = [0; 32];
+			ed25519_pubkey.copy_from_slice(&bytes[0..32]);
+			let checksum = ((bytes[32] as u16) << 8) | (bytes[33] as u16);
+			let version = bytes[34];
+			if version != ONION_V3_VERSION || checksum != onion_v3_checksum(&ed25519_pubkey, version) {
+				return None;
+			}
+			Some(NetAddress::OnionV3 { ed25519_pubkey, checksum, version, port })
+		},
+		_ => None,
+	}
+}
+
+/// Gets the hostname (including the ".onion" suffix) of an onion NetAddress, or None if the
+/// address is not an onion address.
+pub fn onion_hostname(addr: &NetAddress) -> Option<String> {
+	match addr {
+		&NetAddress::OnionV2 { ref addr, .. } => Some(base32_encode(addr) + ".onion"),
+		&NetAddress::OnionV3 { ref ed25519_pubkey, checksum, version, .. } => {
+			let mut bytes = Vec::with_capacity(35);
+			bytes.extend_from_slice(ed25519_pubkey);
+			bytes.push((checksum >> 8) as u8);
+			bytes.push(checksum as u8);
+			bytes.push(version);
+			Some(base32_encode(&bytes) + ".onion")
+		},
+		_ => None,
+	}
+}
+
+/// Returns true if the given address is a Tor onion address (and thus can only be reached via a
+/// Tor SOCKS5 proxy).
+pub fn is_onion_address(addr: &NetAddress) -> bool {
+	match addr {
+		&NetAddress::OnionV2 { .. } | &NetAddress::OnionV3 { .. } => true,
+		_ => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ln::msgs::NetAddress;
+	use net::onion::{base32_decode, base32_encode, onion_hostname, parse_onion_address};
+
+	#[test]
+	fn test_base32() {
+		// Test vectors from RFC 4648, lowercased and unpadded
+		let vectors: [(&str, &str); 7] = [("", ""), ("f", "my"), ("fo", "mzxq"), ("foo", "mzxw6"),
+			("foob", "mzxw6yq"), ("fooba", "mzxw6ytb"), ("foobar", "mzxw6ytboi")];
+		for &(raw, encoded) in vectors.iter() {
+			assert_eq!(base32_encode(raw.as_bytes()), encoded);
+			assert_eq!(base32_decode(encoded).unwrap(), raw.as_bytes());
+			assert_eq!(base32_decode(&encoded.to_uppercase()).unwrap(), raw.as_bytes());
+		}
+		assert!(base32_decode("mzxw6yr").is_none()); // Non-zero trailing bits
+		assert!(base32_decode("mzxw1").is_none()); // Invalid character
+	}
+
+	#[test]
+	fn test_onion_addresses() {
+		// The onion v3 address of the Tor Project's website
+		let v3_hostname = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+		let v3 = parse_onion_address(v3_hostname, 9735).unwrap();
+		match v3 {
+			NetAddress::OnionV3 { version, port, .. } => {
+				assert_eq!(version, 3);
+				assert_eq!(port, 9735);
+			},
+			_ => panic!(),
+		}
+		assert_eq!(onion_hostname(&v3).unwrap(), v3_hostname);
+		assert_eq!(parse_onion_address(&v3_hostname[..56], 9735).unwrap(), v3);
+
+		// Corrupting a character invalidates the checksum
+		assert!(parse_onion_address("3gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion", 9735).is_none());
+
+		let v2_hostname = "expyuzz4wqqyqhjn.onion";
+		let v2 = parse_onion_address(v2_hostname, 9735).unwrap();
+		match v2 {
+			NetAddress::OnionV2 { port, .. } => assert_eq!(port, 9735),
+			_ => panic!(),
+		}
+		assert_eq!(onion_hostname(&v2).unwrap(), v2_hostname);
+
+		assert!(parse_onion_address("example.com", 9735).is_none());
+		assert!(onion_hostname(&NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }).is_none());
+	}
+}
@@ -0,0 +1,617 @@
+//! Keeps us connected to the peers we care about.
+//!
+//! A PeerReconnector tracks the set of peers we want to stay connected to (all our channel
+//! counterparties plus any peers the user has pinned), the addresses we know for them (both from
+//! node_announcements via the Router and those provided by the user) and how long we should wait
+//! before trying each again. On each timer tick it asks a user-provided Connector to open
+//! connections to any such peers which are not currently connected.
+//!
+//! The address book can be written out with write_address_book and read back in as an
+//! AddressBook, so that we can reconnect to peers on startup before we've synced the network
+//! graph.
+
+use secp256k1::key::PublicKey;
+
+use ln::channelmanager::ChannelManager;
+use ln::msgs::{DecodeError, NetAddress};
+use ln::peer_handler::{PeerManager, SocketDescriptor};
+use ln::router::Router;
+use util::ser::{Readable, Writeable, Writer};
+use util::logger::Logger;
+
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::cmp;
+
+/// The delay before retrying a peer after our first failed connection attempt. Doubles with each
+/// further failure up to MAX_RECONNECT_BACKOFF_SECS.
+const MIN_RECONNECT_BACKOFF_SECS: u64 = 1;
+/// The maximum delay between connection attempts to a peer.
+const MAX_RECONNECT_BACKOFF_SECS: u64 = 60 * 60;
+/// The maximum number of addresses we remember for each peer. Once reached, learning a new address
+/// for a peer replaces one which wasn't given to pin_peer, preferring those we've failed to
+/// connect to.
+const MAX_ADDRESSES_PER_PEER: usize = 8;
+/// The maximum number of peers in our AddressBook. Once reached, addresses for new peers replace
+/// those of a peer we don't need to stay connected to (pinned peers and channel counterparties
+/// are always kept).
+const MAX_ADDRESS_BOOK_PEERS: usize = 1000;
+
+/// Opens connections to peers on behalf of a PeerReconnector.
+pub trait Connector : Send + Sync {
+	/// Begins opening a connection to the given node at the given address. Once the connection
+	/// is established it should be handed to PeerManager::new_outbound_connection as usual.
+	///
+	/// Returns false if the connection attempt failed immediately (eg the address type isn't
+	/// supported or the connection was refused). Later failures (eg the handshake failing) are
+	/// detected by the peer never appearing in PeerManager::get_peer_node_ids.
+	fn connect(&self, their_node_id: &PublicKey, addr: &NetAddress) -> bool;
+
+	/// Removes any addresses which this Connector cannot (or should not) connect to from the
+	/// given list, and orders the remainder with the most preferred first. Connection attempts
+	/// cycle through the remaining addresses in order.
+	fn filter_addresses(&self, addresses: &mut Vec<NetAddress>);
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct PeerAddress {
+	addr: NetAddress,
+	/// Addresses given to pin_peer are never evicted to make room for others.
+	pinned: bool,
+	/// When a connection attempt to this address last failed, if ever. Not persisted.
+	last_failed: Option<Instant>,
+}
+
+struct PeerInfo {
+	/// Pinned peers are kept connected even if we have no channels with them.
+	pinned: bool,
+	/// The addresses we know for the peer, oldest first.
+	addresses: Vec<PeerAddress>,
+	/// The number of connection attempts made since we were last connected. Not persisted.
+	connect_attempts: u32,
+	/// If set, don't try to connect again until this time. Not persisted.
+	next_attempt: Option<Instant>,
+	/// The address we last tried to connect to, if we haven't since seen whether it worked. Not
+	/// persisted.
+	last_attempt_addr: Option<NetAddress>,
+}
+
+impl PeerInfo {
+	fn new() -> Self {
+		PeerInfo {
+			pinned: false,
+			addresses: Vec::new(),
+			connect_attempts: 0,
+			next_attempt: None,
+			last_attempt_addr: None,
+		}
+	}
+
+	/// Adds an address for the peer. If we already know MAX_ADDRESSES_PER_PEER, an unpinned one is
+	/// evicted: the one whose last failure was longest ago, or the oldest if none have failed. If
+	/// all are pinned, the new address is ignored.
+	fn add_address(&mut self, addr: &NetAddress, pinned: bool) {
+		if let Some(known) = self.addresses.iter_mut().find(|known| known.addr == *addr) {
+			known.pinned |= pinned;
+			return;
+		}
+		if self.addresses.len() >= MAX_ADDRESSES_PER_PEER {
+			let evict = self.addresses.iter().enumerate()
+				.filter(|&(_, known)| !known.pinned && known.last_failed.is_some())
+				.min_by_key(|&(_, known)| known.last_failed)
+				.or_else(|| self.addresses.iter().enumerate().find(|&(_, known)| !known.pinned))
+				.map(|(idx, _)| idx);
+			match evict {
+				Some(idx) => { self.addresses.remove(idx); },
+				None => return,
+			}
+		}
+		self.addresses.push(PeerAddress { addr: addr.clone(), pinned, last_failed: None });
+	}
+
+	fn set_last_failed(&mut self, addr: &NetAddress, last_failed: Option<Instant>) {
+		if let Some(known) = self.addresses.iter_mut().find(|known| known.addr == *addr) {
+			known.last_failed = last_failed;
+		}
+	}
+}
+
+/// The set of peers a PeerReconnector keeps connected and the addresses it knows for them.
+/// Serialize this out (via PeerReconnector::write_address_book) and read it back in across
+/// restarts.
+pub struct AddressBook {
+	peers: HashMap<PublicKey, PeerInfo>,
+}
+
+impl AddressBook {
+	/// Creates a new, empty, AddressBook.
+	pub fn new() -> Self {
+		AddressBook { peers: HashMap::new() }
+	}
+}
+
+fn len_to_u16(len: usize) -> Result<u16, ::std::io::Error> {
+	if len > u16::max_value() as usize {
+		return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput, "Too many entries to serialize AddressBook"));
+	}
+	Ok(len as u16)
+}
+
+impl Writeable for AddressBook {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		len_to_u16(self.peers.len())?.write(w)?;
+		for (node_id, info) in self.peers.iter() {
+			node_id.write(w)?;
+			info.pinned.write(w)?;
+			len_to_u16(info.addresses.len())?.write(w)?;
+			for addr in info.addresses.iter() {
+				addr.pinned.write(w)?;
+				addr.addr.write(w)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl<R: Read> Readable<R> for AddressBook {
+	fn read(r: &mut R) -> Result<Self, DecodeError> {
+		let peer_count: u16 = Readable::read(r)?;
+		let mut peers = HashMap::with_capacity(peer_count as usize);
+		for _ in 0..peer_count {
+			let node_id: PublicKey = Readable::read(r)?;
+			let mut info = PeerInfo::new();
+			info.pinned = Readable::read(r)?;
+			let addr_count: u16 = Readable::read(r)?;
+			for _ in 0..addr_count {
+				let pinned: bool = Readable::read(r)?;
+				let addr: NetAddress = Readable::read(r)?;
+				info.add_address(&addr, pinned);
+			}
+			if peers.insert(node_id, info).is_some() {
+				return Err(DecodeError::InvalidValue);
+			}
+		}
+		Ok(AddressBook { peers })
+	}
+}
+
+/// Reconnects to our channel counterparties and pinned peers whenever we are disconnected from
+/// them, backing off exponentially on repeated failures. See the module-level documentation for
+/// more.
+pub struct PeerReconnector<Descriptor: SocketDescriptor> {
+	channel_manager: Arc<ChannelManager>,
+	router: Arc<Router>,
+	peer_manager: Arc<PeerManager<Descriptor>>,
+	connector: Arc<Connector>,
+	address_book: Mutex<AddressBook>,
+	logger: Arc<Logger>,
+}
+
+impl<Descriptor: SocketDescriptor> PeerReconnector<Descriptor> {
+	/// Constructs a new PeerReconnector, starting with the given AddressBook (use
+	/// AddressBook::new() if you have none saved).
+	pub fn new(channel_manager: Arc<ChannelManager>, router: Arc<Router>, peer_manager: Arc<PeerManager<Descriptor>>, connector: Arc<Connector>, address_book: AddressBook, logger: Arc<Logger>) -> PeerReconnector<Descriptor> {
+		PeerReconnector {
+			channel_manager,
+			router,
+			peer_manager,
+			connector,
+			address_book: Mutex::new(address_book),
+			logger,
+		}
+	}
+
+	/// Keeps us connected to the given peer, even if we have no channels with it, trying the
+	/// given addresses (in addition to any we learn from its node_announcement). The given
+	/// addresses are never forgotten to make room for ones we learn later.
+	pub fn pin_peer(&self, their_node_id: PublicKey, addresses: &[NetAddress]) {
+		let mut address_book = self.address_book.lock().unwrap();
+		let info = address_book.peers.entry(their_node_id).or_insert_with(|| PeerInfo::new());
+		info.pinned = true;
+		for addr in addresses {
+			info.add_address(addr, true);
+		}
+	}
+
+	/// Stops keeping us connected to the given peer unless we have channels with it. Does not
+	/// disconnect the peer if we are currently connected.
+	pub fn unpin_peer(&self, their_node_id: &PublicKey) {
+		let mut address_book = self.address_book.lock().unwrap();
+		if let Some(info) = address_book.peers.get_mut(their_node_id) {
+			info.pinned = false;
+		}
+	}
+
+	/// Adds an address at which the given peer may be reached. It will only be used if the peer is
+	/// pinned or one of our channel counterparties.
+	///
+	/// We remember at most MAX_ADDRESSES_PER_PEER addresses per peer and MAX_ADDRESS_BOOK_PEERS
+	/// peers, forgetting an address not given to pin_peer or a peer we don't need to stay connected
+	/// to as needed.
+	pub fn add_peer_address(&self, their_node_id: PublicKey, addr: NetAddress) {
+		let mut address_book = self.address_book.lock().unwrap();
+		if !address_book.peers.contains_key(&their_node_id) && address_book.peers.len() >= MAX_ADDRESS_BOOK_PEERS {
+			let desired = self.desired_peers(&address_book);
+			let evict = address_book.peers.keys().find(|node_id| !desired.contains(node_id)).cloned();
+			match evict {
+				Some(node_id) => { address_book.peers.remove(&node_id); },
+				None => {
+					log_debug!(self, "Address book full, ignoring address for peer {}", log_pubkey!(their_node_id));
+					return;
+				},
+			}
+		}
+		address_book.peers.entry(their_node_id).or_insert_with(|| PeerInfo::new()).add_address(&addr, false);
+	}
+
+	/// Gets the list of peers we are trying to keep connected to.
+	pub fn get_desired_peers(&self) -> Vec<PublicKey> {
+		let mut res: Vec<PublicKey> = self.desired_peers(&*self.address_book.lock().unwrap()).drain().collect();
+		res.sort_by_key(|node_id| node_id.serialize().to_vec());
+		res
+	}
+
+	/// Writes out the set of pinned peers and the addresses we know for our peers, to be read back
+	/// in as an AddressBook on restart.
+	pub fn write_address_book<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.address_book.lock().unwrap().write(writer)
+	}
+
+	fn desired_peers(&self, address_book: &AddressBook) -> HashSet<PublicKey> {
+		let mut res: HashSet<PublicKey> = self.channel_manager.list_channels().iter().map(|chan| chan.remote_network_id).collect();
+		for (node_id, info) in address_book.peers.iter() {
+			if info.pinned {
+				res.insert(*node_id);
+			}
+		}
+		res
+	}
+
+	/// Attempts to connect to any desired peers we aren't currently connected to and whose backoff
+	/// period has expired. Should be called roughly once a second.
+	pub fn timer_tick_occured(&self) {
+		let now = Instant::now();
+		let connected: HashSet<PublicKey> = self.peer_manager.get_peer_node_ids().drain(..).collect();
+
+		let mut to_connect = Vec::new();
+		{
+			let mut address_book = self.address_book.lock().unwrap();
+			let desired = self.desired_peers(&address_book);
+			// Forget peers we no longer care about, unless we learned addresses for them
+			address_book.peers.retain(|node_id, info| desired.contains(node_id) || !info.addresses.is_empty());
+
+			for node_id in desired.iter() {
+				let info = address_book.peers.entry(*node_id).or_insert_with(|| PeerInfo::new());
+				if let Some(addrs) = self.router.get_addresses(node_id) {
+					for addr in addrs.iter() {
+						info.add_address(addr, false);
+					}
+				}
+
+				if connected.contains(node_id) {
+					if let Some(addr) = info.last_attempt_addr.take() {
+						info.set_last_failed(&addr, None);
+					}
+					info.connect_attempts = 0;
+					info.next_attempt = None;
+					continue;
+				}
+				if let Some(next_attempt) = info.next_attempt {
+					if next_attempt > now {
+						continue;
+					}
+				}
+				// We're still not connected, so our last attempt (if any) failed
+				if let Some(addr) = info.last_attempt_addr.take() {
+					info.set_last_failed(&addr, Some(now));
+				}
+				let mut addresses: Vec<NetAddress> = info.addresses.iter().map(|known| known.addr.clone()).collect();
+				self.connector.filter_addresses(&mut addresses);
+				if addresses.is_empty() {
+					log_trace!(self, "No usable addresses to reconnect to peer {}", log_pubkey!(node_id));
+					continue;
+				}
+
+				let addr = addresses[info.connect_attempts as usize % addresses.len()].clone();
+				let backoff = cmp::min(MIN_RECONNECT_BACKOFF_SECS << cmp::min(info.connect_attempts, 32), MAX_RECONNECT_BACKOFF_SECS);
+				info.connect_attempts = info.connect_attempts.saturating_add(1);
+				info.next_attempt = Some(now + Duration::from_secs(backoff));
+				info.last_attempt_addr = Some(addr.clone());
+				to_connect.push((*node_id, addr));
+			}
+		}
+
+		for (node_id, addr) in to_connect.drain(..) {
+			log_debug!(self, "Attempting to reconnect to peer {}", log_pubkey!(node_id));
+			if !self.connector.connect(&node_id, &addr) {
+				log_debug!(self, "Failed to open connection to peer {}", log_pubkey!(node_id));
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chain::chaininterface;
+	use ln::channelmanager::ChannelManager;
+	use ln::msgs::NetAddress;
+	use ln::peer_handler::{MessageHandler, PeerLimits, PeerManager, SocketDescriptor};
+	use net::reconnect::{AddressBook, Connector, PeerReconnector, MAX_ADDRESSES_PER_PEER, MAX_ADDRESS_BOOK_PEERS};
+	use ln::router::Router;
+	use util::logger::Logger;
+	use util::ser::{Readable, Writeable};
+	use util::test_utils;
+
+	use bitcoin::blockdata::script::Script;
+	use bitcoin::network::constants::Network;
+
+	use secp256k1::Secp256k1;
+	use secp256k1::key::{PublicKey, SecretKey};
+
+	use rand::{thread_rng, Rng};
+
+	use std::collections::HashMap;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::{Arc, Mutex};
+	use std::time::Instant;
+	use std::{hash, mem};
+
+	#[derive(Clone)]
+	struct TestDescriptor {
+		id: usize,
+		outbound_data: Arc<Mutex<Vec<u8>>>,
+	}
+	impl PartialEq for TestDescriptor {
+		fn eq(&self, other: &Self) -> bool {
+			self.id == other.id
+		}
+	}
+	impl Eq for TestDescriptor {}
+	impl hash::Hash for TestDescriptor {
+		fn hash<H: hash::Hasher>(&self, hasher: &mut H) {
+			self.id.hash(hasher)
+		}
+	}
+	impl TestDescriptor {
+		fn take_outbound_data(&self) -> Vec<u8> {
+			mem::replace(&mut *self.outbound_data.lock().unwrap(), Vec::new())
+		}
+	}
+	impl SocketDescriptor for TestDescriptor {
+		fn send_data(&mut self, data: &Vec<u8>, write_offset: usize, _resume_read: bool) -> usize {
+			self.outbound_data.lock().unwrap().extend_from_slice(&data[write_offset..]);
+			data.len() - write_offset
+		}
+		fn disconnect_socket(&mut self) {}
+	}
+
+	fn create_peer_manager(node_secret: SecretKey, logger: &Arc<Logger>) -> Arc<PeerManager<TestDescriptor>> {
+		let msg_handler = MessageHandler {
+			chan_handler: Arc::new(test_utils::TestChannelMessageHandler::new()),
+			route_handler: Arc::new(test_utils::TestRoutingMessageHandler::new()),
+		};
+		Arc::new(PeerManager::new(msg_handler, node_secret, PeerLimits::default(), Arc::clone(logger)))
+	}
+
+	/// Connects our PeerManager to remote ones in memory, but only at the addresses listed as
+	/// reachable.
+	struct TestConnector {
+		local: Arc<PeerManager<TestDescriptor>>,
+		remotes: HashMap<PublicKey, Arc<PeerManager<TestDescriptor>>>,
+		reachable: Mutex<Vec<NetAddress>>,
+		attempts: Mutex<Vec<(PublicKey, NetAddress)>>,
+		next_id: AtomicUsize,
+	}
+	impl Connector for TestConnector {
+		fn connect(&self, their_node_id: &PublicKey, addr: &NetAddress) -> bool {
+			self.attempts.lock().unwrap().push((*their_node_id, addr.clone()));
+			if !self.reachable.lock().unwrap().contains(addr) {
+				return false;
+			}
+			let local = &self.local;
+			let remote = self.remotes.get(their_node_id).unwrap();
+			let id = self.next_id.fetch_add(1, Ordering::AcqRel);
+			let mut local_descriptor = TestDescriptor { id, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+			let mut remote_descriptor = TestDescriptor { id, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+			let act_one = local.new_outbound_connection(*their_node_id, local_descriptor.clone()).unwrap();
+			remote.new_inbound_connection(remote_descriptor.clone(), None).unwrap();
+			remote.read_event(&mut remote_descriptor, act_one).unwrap();
+			local.read_event(&mut local_descriptor, remote_descriptor.take_outbound_data()).unwrap();
+			remote.read_event(&mut remote_descriptor, local_descriptor.take_outbound_data()).unwrap();
+			local.read_event(&mut local_descriptor, remote_descriptor.take_outbound_data()).unwrap();
+			true
+		}
+
+		fn filter_addresses(&self, _addresses: &mut Vec<NetAddress>) {}
+	}
+
+	fn random_secret() -> SecretKey {
+		let mut key_slice = [0; 32];
+		thread_rng().fill_bytes(&mut key_slice);
+		SecretKey::from_slice(&Secp256k1::new(), &key_slice).unwrap()
+	}
+
+	fn ipv4(last_byte: u8) -> NetAddress {
+		NetAddress::IPv4 { addr: [10, 0, 0, last_byte], port: 9735 }
+	}
+
+	#[test]
+	fn test_reconnect_with_backoff() {
+		let secp_ctx = Secp256k1::new();
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let feeest = Arc::new(test_utils::TestFeeEstimator::new(253));
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let tx_broadcaster = Arc::new(test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) });
+		let chan_monitor = Arc::new(test_utils::TestChannelMonitor::new(chain_monitor.clone(), tx_broadcaster.clone(), feeest.clone()));
+		let our_secret = random_secret();
+		let wallet = Arc::new(test_utils::TestWallet::new(Script::from(vec![0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])));
+		let channel_manager = ChannelManager::new(our_secret, 0, true, Network::Testnet, feeest, chan_monitor, chain_monitor.clone(), tx_broadcaster, wallet, Arc::clone(&logger)).unwrap();
+		let router = Arc::new(Router::new(PublicKey::from_secret_key(&secp_ctx, &our_secret), chain_monitor, Arc::clone(&logger)));
+		let peer_manager = create_peer_manager(our_secret, &logger);
+
+		let chan_peer_secret = random_secret();
+		let chan_peer = PublicKey::from_secret_key(&secp_ctx, &chan_peer_secret);
+		let pinned_peer_secret = random_secret();
+		let pinned_peer = PublicKey::from_secret_key(&secp_ctx, &pinned_peer_secret);
+		let other_peer = PublicKey::from_secret_key(&secp_ctx, &random_secret());
+
+		let mut remotes = HashMap::new();
+		remotes.insert(chan_peer, create_peer_manager(chan_peer_secret, &logger));
+		remotes.insert(pinned_peer, create_peer_manager(pinned_peer_secret, &logger));
+		let connector = Arc::new(TestConnector {
+			local: peer_manager.clone(),
+			remotes,
+			reachable: Mutex::new(vec![ipv4(2)]),
+			attempts: Mutex::new(Vec::new()),
+			next_id: AtomicUsize::new(0),
+		});
+
+		let reconnector = PeerReconnector::new(channel_manager.clone(), router, peer_manager.clone(), connector.clone(), AddressBook::new(), Arc::clone(&logger));
+		channel_manager.create_channel(chan_peer, 100000, 0, 42).unwrap();
+		reconnector.pin_peer(pinned_peer, &[ipv4(2)]);
+		reconnector.add_peer_address(chan_peer, ipv4(1));
+		reconnector.add_peer_address(chan_peer, ipv4(3));
+		reconnector.add_peer_address(other_peer, ipv4(4));
+		let mut expected_desired = vec![chan_peer, pinned_peer];
+		expected_desired.sort_by_key(|node_id| node_id.serialize().to_vec());
+		assert_eq!(reconnector.get_desired_peers(), expected_desired);
+
+		// The pinned peer is reachable at its first address, the channel peer at neither, and we
+		// don't care about other_peer.
+		reconnector.timer_tick_occured();
+		assert_eq!(peer_manager.get_peer_node_ids(), vec![pinned_peer]);
+		{
+			let mut attempts = connector.attempts.lock().unwrap();
+			attempts.sort_by_key(|&(ref node_id, _)| node_id.serialize().to_vec());
+			let mut expected = vec![(pinned_peer, ipv4(2)), (chan_peer, ipv4(1))];
+			expected.sort_by_key(|&(ref node_id, _)| node_id.serialize().to_vec());
+			assert_eq!(*attempts, expected);
+			attempts.clear();
+		}
+
+		// Until the backoff expires, we don't try again
+		reconnector.timer_tick_occured();
+		assert!(connector.attempts.lock().unwrap().is_empty());
+
+		// Once it does, we try the next address
+		connector.reachable.lock().unwrap().push(ipv4(3));
+		reconnector.address_book.lock().unwrap().peers.get_mut(&chan_peer).unwrap().next_attempt = Some(Instant::now());
+		reconnector.timer_tick_occured();
+		assert_eq!(*connector.attempts.lock().unwrap(), vec![(chan_peer, ipv4(3))]);
+		assert_eq!(peer_manager.get_peer_node_ids().len(), 2);
+		{
+			let address_book = reconnector.address_book.lock().unwrap();
+			let addresses = &address_book.peers.get(&chan_peer).unwrap().addresses;
+			assert!(addresses[0].last_failed.is_some());
+			assert!(addresses[1].last_failed.is_none());
+		}
+
+		// Once connected, backoff state is reset
+		reconnector.timer_tick_occured();
+		assert_eq!(reconnector.address_book.lock().unwrap().peers.get(&chan_peer).unwrap().connect_attempts, 0);
+	}
+
+	#[test]
+	fn test_address_book_limits() {
+		// Both the addresses per peer and the number of peers we remember are capped, without ever
+		// forgetting pinned peers or the addresses given when pinning them.
+		let secp_ctx = Secp256k1::new();
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let feeest = Arc::new(test_utils::TestFeeEstimator::new(253));
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let tx_broadcaster = Arc::new(test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) });
+		let chan_monitor = Arc::new(test_utils::TestChannelMonitor::new(chain_monitor.clone(), tx_broadcaster.clone(), feeest.clone()));
+		let our_secret = random_secret();
+		let wallet = Arc::new(test_utils::TestWallet::new(Script::from(vec![0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])));
+		let channel_manager = ChannelManager::new(our_secret, 0, true, Network::Testnet, feeest, chan_monitor, chain_monitor.clone(), tx_broadcaster, wallet, Arc::clone(&logger)).unwrap();
+		let router = Arc::new(Router::new(PublicKey::from_secret_key(&secp_ctx, &our_secret), chain_monitor, Arc::clone(&logger)));
+		let peer_manager = create_peer_manager(our_secret, &logger);
+		let connector = Arc::new(TestConnector {
+			local: peer_manager.clone(),
+			remotes: HashMap::new(),
+			reachable: Mutex::new(Vec::new()),
+			attempts: Mutex::new(Vec::new()),
+			next_id: AtomicUsize::new(0),
+		});
+		let reconnector = PeerReconnector::new(channel_manager, router, peer_manager, connector, AddressBook::new(), Arc::clone(&logger));
+
+		let pinned_peer = PublicKey::from_secret_key(&secp_ctx, &random_secret());
+		reconnector.pin_peer(pinned_peer, &[ipv4(100)]);
+		for i in 0..(MAX_ADDRESSES_PER_PEER + 2) {
+			reconnector.add_peer_address(pinned_peer, ipv4(i as u8));
+		}
+		{
+			let address_book = reconnector.address_book.lock().unwrap();
+			let addresses = &address_book.peers.get(&pinned_peer).unwrap().addresses;
+			assert_eq!(addresses.len(), MAX_ADDRESSES_PER_PEER);
+			assert_eq!(addresses[0].addr, ipv4(100));
+			assert_eq!(addresses[1].addr, ipv4(3));
+			assert_eq!(addresses[MAX_ADDRESSES_PER_PEER - 1].addr, ipv4(MAX_ADDRESSES_PER_PEER as u8 + 1));
+		}
+
+		// Addresses we've failed to connect to are evicted before older ones
+		{
+			let mut address_book = reconnector.address_book.lock().unwrap();
+			let info = address_book.peers.get_mut(&pinned_peer).unwrap();
+			info.set_last_failed(&ipv4(100), Some(Instant::now()));
+			info.set_last_failed(&ipv4(5), Some(Instant::now()));
+		}
+		reconnector.add_peer_address(pinned_peer, ipv4(200));
+		{
+			let address_book = reconnector.address_book.lock().unwrap();
+			let addresses: Vec<NetAddress> = address_book.peers.get(&pinned_peer).unwrap().addresses.iter().map(|known| known.addr.clone()).collect();
+			assert_eq!(addresses.len(), MAX_ADDRESSES_PER_PEER);
+			assert!(addresses.contains(&ipv4(100)));
+			assert!(addresses.contains(&ipv4(3)));
+			assert!(!addresses.contains(&ipv4(5)));
+			assert_eq!(addresses[MAX_ADDRESSES_PER_PEER - 1], ipv4(200));
+		}
+
+		for i in 0..MAX_ADDRESS_BOOK_PEERS {
+			let mut key_slice = [1; 32];
+			key_slice[0] = (i >> 8) as u8;
+			key_slice[1] = i as u8;
+			let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&secp_ctx, &key_slice).unwrap());
+			reconnector.add_peer_address(node_id, ipv4(1));
+		}
+		let address_book = reconnector.address_book.lock().unwrap();
+		assert_eq!(address_book.peers.len(), MAX_ADDRESS_BOOK_PEERS);
+		assert!(address_book.peers.get(&pinned_peer).unwrap().pinned);
+	}
+
+	#[test]
+	fn test_address_book_serialization() {
+		let secp_ctx = Secp256k1::new();
+		let node_a = PublicKey::from_secret_key(&secp_ctx, &random_secret());
+		let node_b = PublicKey::from_secret_key(&secp_ctx, &random_secret());
+
+		let mut address_book = AddressBook::new();
+		{
+			let info = address_book.peers.entry(node_a).or_insert_with(|| super::PeerInfo::new());
+			info.pinned = true;
+			info.connect_attempts = 3;
+			info.add_address(&ipv4(1), true);
+			info.add_address(&NetAddress::IPv6 { addr: [1; 16], port: 1 }, false);
+			info.add_address(&NetAddress::OnionV2 { addr: [2; 10], port: 2 }, false);
+			info.add_address(&NetAddress::OnionV3 { ed25519_pubkey: [3; 32], checksum: 3, version: 3, port: 3 }, false);
+		}
+		address_book.peers.entry(node_b).or_insert_with(|| super::PeerInfo::new()).add_address(&ipv4(2), false);
+
+		let encoded = address_book.encode();
+		let read_book: AddressBook = Readable::read(&mut ::std::io::Cursor::new(&encoded)).unwrap();
+		assert_eq!(read_book.peers.len(), 2);
+		let info_a = read_book.peers.get(&node_a).unwrap();
+		assert!(info_a.pinned);
+		assert_eq!(info_a.connect_attempts, 0);
+		assert_eq!(info_a.addresses, address_book.peers.get(&node_a).unwrap().addresses);
+		let info_b = read_book.peers.get(&node_b).unwrap();
+		assert!(!info_b.pinned);
+		assert_eq!(info_b.addresses, vec![super::PeerAddress { addr: ipv4(2), pinned: false, last_failed: None }]);
+
+		// Truncated data should fail to read
+		let res: Result<AddressBook, _> = Readable::read(&mut ::std::io::Cursor::new(&encoded[..encoded.len() - 1]));
+		assert!(res.is_err());
+	}
+}
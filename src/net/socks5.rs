@@ -0,0 +1,220 @@
+//! A minimal SOCKS5 (RFC 1928) client, supporting only unauthenticated CONNECT requests. This is
+//! all that is needed to reach peers (including onion services) via a local Tor daemon.
+
+use ln::msgs::NetAddress;
+use net::onion;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use std::io;
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_METHOD_NONE: u8 = 0;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+fn protocol_error(err: &'static str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Writes the ATYP, address and port of a CONNECT request for the given address.
+fn write_target(req: &mut Vec<u8>, target: &NetAddress) {
+	let port = match target {
+		&NetAddress::IPv4 { ref addr, port } => {
+			req.push(ATYP_IPV4);
+			req.extend_from_slice(addr);
+			port
+		},
+		&NetAddress::IPv6 { ref addr, port } => {
+			req.push(ATYP_IPV6);
+			req.extend_from_slice(addr);
+			port
+		},
+		&NetAddress::OnionV2 { port, .. } | &NetAddress::OnionV3 { port, .. } => {
+			let hostname = onion::onion_hostname(target).unwrap();
+			req.push(ATYP_DOMAIN);
+			req.push(hostname.len() as u8);
+			req.extend_from_slice(hostname.as_bytes());
+			port
+		},
+	};
+	req.push((port >> 8) as u8);
+	req.push(port as u8);
+}
+
+/// Connects to the given target address through the SOCKS5 proxy at proxy_addr. Onion addresses
+/// are passed to the proxy by hostname so must be resolved by it (as Tor does).
+///
+/// timeout applies to connecting to the proxy and each read/write during the SOCKS handshake. The
+/// returned stream has no read/write timeouts set.
+pub fn connect_via_proxy(proxy_addr: &SocketAddr, target: &NetAddress, timeout: Duration) -> io::Result<TcpStream> {
+	let mut stream = TcpStream::connect_timeout(proxy_addr, timeout)?;
+	stream.set_read_timeout(Some(timeout))?;
+	stream.set_write_timeout(Some(timeout))?;
+
+	stream.write_all(&[SOCKS_VERSION, 1, AUTH_METHOD_NONE])?;
+	let mut auth_resp = [0; 2];
+	stream.read_exact(&mut auth_resp)?;
+	if auth_resp[0] != SOCKS_VERSION {
+		return Err(protocol_error("SOCKS proxy responded with the wrong version"));
+	}
+	if auth_resp[1] != AUTH_METHOD_NONE {
+		return Err(protocol_error("SOCKS proxy requires authentication"));
+	}
+
+	let mut req = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+	write_target(&mut req, target);
+	stream.write_all(&req)?;
+
+	let mut resp = [0; 4];
+	stream.read_exact(&mut resp)?;
+	if resp[0] != SOCKS_VERSION {
+		return Err(protocol_error("SOCKS proxy responded with the wrong version"));
+	}
+	if resp[1] != 0 {
+		return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "SOCKS proxy failed to connect to the target"));
+	}
+	// Skip over the bound address, which we have no use for
+	let bound_addr_len = match resp[3] {
+		ATYP_IPV4 => 4,
+		ATYP_IPV6 => 16,
+		ATYP_DOMAIN => {
+			let mut len = [0; 1];
+			stream.read_exact(&mut len)?;
+			len[0] as usize
+		},
+		_ => return Err(protocol_error("SOCKS proxy responded with an unknown address type")),
+	};
+	let mut bound_addr = vec![0; bound_addr_len + 2];
+	stream.read_exact(&mut bound_addr)?;
+
+	stream.set_read_timeout(None)?;
+	stream.set_write_timeout(None)?;
+	Ok(stream)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use ln::msgs::NetAddress;
+	use net::onion;
+	use net::socks5::connect_via_proxy;
+
+	use std::collections::HashMap;
+	use std::io::{Read, Write};
+	use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+	use std::time::Duration;
+	use std::io;
+
+	/// A stand-in for a Tor SOCKS5 proxy which "resolves" hostnames using a fixed map and records
+	/// the hostnames it was asked to connect to.
+	pub(crate) struct TestProxy {
+		pub(crate) addr: SocketAddr,
+		pub(crate) requested: Arc<Mutex<Vec<String>>>,
+	}
+
+	fn splice(mut from: TcpStream, mut to: TcpStream) {
+		let mut buf = [0; 4096];
+		loop {
+			match from.read(&mut buf) {
+				Ok(0) | Err(_) => break,
+				Ok(len) => if to.write_all(&buf[..len]).is_err() { break; },
+			}
+		}
+		let _ = to.shutdown(Shutdown::Write);
+	}
+
+	fn handle_client(mut client: TcpStream, hosts: &HashMap<String, SocketAddr>, requested: &Mutex<Vec<String>>) -> io::Result<()> {
+		let mut greeting = [0; 3];
+		client.read_exact(&mut greeting)?;
+		assert_eq!(greeting, [5, 1, 0]);
+		client.write_all(&[5, 0])?;
+
+		let mut req = [0; 4];
+		client.read_exact(&mut req)?;
+		assert_eq!(&req[..3], &[5, 1, 0]);
+		let target = match req[3] {
+			1 => {
+				let mut addr = [0; 6];
+				client.read_exact(&mut addr)?;
+				format!("{}.{}.{}.{}", addr[0], addr[1], addr[2], addr[3])
+			},
+			3 => {
+				let mut len = [0; 1];
+				client.read_exact(&mut len)?;
+				let mut host = vec![0; len[0] as usize + 2];
+				client.read_exact(&mut host)?;
+				String::from_utf8(host[..len[0] as usize].to_vec()).unwrap()
+			},
+			_ => panic!("Unexpected address type"),
+		};
+		requested.lock().unwrap().push(target.clone());
+
+		let server = match hosts.get(&target) {
+			Some(addr) => TcpStream::connect(addr)?,
+			None => {
+				// Host unreachable
+				client.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0])?;
+				return Ok(());
+			},
+		};
+		client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])?;
+
+		let client_read = client.try_clone()?;
+		let server_write = server.try_clone()?;
+		thread::spawn(move || splice(client_read, server_write));
+		splice(server, client);
+		Ok(())
+	}
+
+	impl TestProxy {
+		pub(crate) fn start(hosts: HashMap<String, SocketAddr>) -> TestProxy {
+			let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+			let addr = listener.local_addr().unwrap();
+			let requested = Arc::new(Mutex::new(Vec::new()));
+			let thread_requested = Arc::clone(&requested);
+			let hosts = Arc::new(hosts);
+			thread::spawn(move || {
+				for client in listener.incoming() {
+					let client = match client { Ok(client) => client, Err(_) => continue };
+					let hosts = Arc::clone(&hosts);
+					let requested = Arc::clone(&thread_requested);
+					thread::spawn(move || { let _ = handle_client(client, &hosts, &requested); });
+				}
+			});
+			TestProxy { addr, requested }
+		}
+	}
+
+	#[test]
+	fn test_connect_via_proxy() {
+		let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+		let echo_addr = echo.local_addr().unwrap();
+		thread::spawn(move || {
+			let (mut stream, _) = echo.accept().unwrap();
+			let mut buf = [0; 5];
+			stream.read_exact(&mut buf).unwrap();
+			stream.write_all(&buf).unwrap();
+		});
+
+		let onion_addr = onion::parse_onion_address("expyuzz4wqqyqhjn.onion", 9735).unwrap();
+		let mut hosts = HashMap::new();
+		hosts.insert("expyuzz4wqqyqhjn.onion".to_owned(), echo_addr);
+		let proxy = TestProxy::start(hosts);
+
+		let mut stream = connect_via_proxy(&proxy.addr, &onion_addr, Duration::from_secs(10)).unwrap();
+		stream.write_all(b"hello").unwrap();
+		let mut buf = [0; 5];
+		stream.read_exact(&mut buf).unwrap();
+		assert_eq!(&buf, b"hello");
+
+		// Unknown hosts are refused by the proxy
+		let unknown = NetAddress::IPv4 { addr: [10, 0, 0, 1], port: 9735 };
+		assert_eq!(connect_via_proxy(&proxy.addr, &unknown, Duration::from_secs(10)).err().unwrap().kind(), io::ErrorKind::ConnectionRefused);
+		assert_eq!(*proxy.requested.lock().unwrap(), vec!["expyuzz4wqqyqhjn.onion".to_owned(), "10.0.0.1".to_owned()]);
+	}
+}
@@ -0,0 +1,695 @@
+//! A thread-per-connection PeerManager driver over std::net::TcpStream.
+//!
+//! Each connection gets a reader thread, which blocks on the socket and hands data to
+//! PeerManager::read_event (pausing as PeerManager requests), and a writer thread, which flushes
+//! data handed to us in SocketDescriptor::send_data and calls PeerManager::write_event once our
+//! send buffer has drained.
+//!
+//! Note that you still need to call PeerManager::process_events after taking actions on your
+//! ChannelManager and PeerManager::timer_tick_occured periodically.
+
+use secp256k1::key::PublicKey;
+
+use ln::msgs::NetAddress;
+use ln::peer_handler::{PeerManager, SocketDescriptor};
+use net::reconnect::Connector;
+use net::{onion, socks5};
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::Duration;
+use std::{hash, io};
+
+/// Once this many bytes are waiting to be written to a socket, send_data will refuse further data
+/// until the buffer drains, causing PeerManager to ask us to pause reading from the peer.
+const MAX_BUFFERED_BYTES: usize = 64 * 1024;
+
+/// How long connect_outbound waits for the TCP connection to be established.
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+
+/// How often a listener thread checks whether it has been stopped.
+const LISTENER_POLL_MILLIS: u64 = 50;
+
+/// How long a listener thread waits before retrying after accept fails (eg because we've run out
+/// of file descriptors), so that it doesn't spin.
+const ACCEPT_ERROR_BACKOFF_MILLIS: u64 = 1000;
+
+static NEXT_CONNECTION_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+struct ConnectionState {
+	outbound_data: VecDeque<Vec<u8>>,
+	outbound_data_len: usize,
+	/// Set when send_data refused data, meaning we owe PeerManager a write_event once the buffer
+	/// drains. No read_events may be generated until then.
+	write_event_pending: bool,
+	/// Set when read_event asked us to stop reading until send_data is called with resume_read.
+	read_paused: bool,
+	/// Set once the PeerManager no longer knows about this connection (because it called
+	/// disconnect_socket, a read/write_event returned an Err, or we generated a disconnect_event),
+	/// after which we must not call into it for this connection again.
+	disconnected: bool,
+	/// Set by the writer thread when writing to the socket (or write_event) failed, telling the
+	/// reader thread to generate the disconnect_event.
+	write_failed: bool,
+}
+
+struct Connection {
+	id: usize,
+	stream: TcpStream,
+	state: Mutex<ConnectionState>,
+	/// Notified whenever a reader thread blocked on read_paused/write_event_pending may proceed.
+	read_ready: Condvar,
+	/// Notified whenever data is added to outbound_data or a write_event is required.
+	write_ready: Condvar,
+	/// Held while calling into the PeerManager for this connection, serializing read_event,
+	/// write_event and disconnect_event so that none are called after the PeerManager has
+	/// forgotten about us.
+	event_lock: Mutex<()>,
+}
+
+impl Connection {
+	/// Marks the connection disconnected and shuts the socket down, waking both threads so that
+	/// they exit. Returns true if the connection was not already marked disconnected.
+	fn close(&self, state: &mut MutexGuard<ConnectionState>) -> bool {
+		let was_connected = !state.disconnected;
+		state.disconnected = true;
+		let _ = self.stream.shutdown(Shutdown::Both);
+		self.read_ready.notify_all();
+		self.write_ready.notify_all();
+		was_connected
+	}
+
+	/// Called by the writer thread when it can no longer write to the connection. Shuts the socket
+	/// down and wakes the reader thread (which may be waiting on read_ready rather than blocked on
+	/// the socket) so that it generates the disconnect_event.
+	fn fail_write(&self) {
+		let mut state = self.state.lock().unwrap();
+		state.write_failed = true;
+		let _ = self.stream.shutdown(Shutdown::Both);
+		self.read_ready.notify_all();
+	}
+}
+
+/// A SocketDescriptor for a connection managed by this module. You should never need to construct
+/// one, they are handed to the PeerManager by connect_outbound, setup_outbound and setup_inbound.
+#[derive(Clone)]
+pub struct TcpSocketDescriptor {
+	conn: Arc<Connection>,
+}
+impl PartialEq for TcpSocketDescriptor {
+	fn eq(&self, other: &Self) -> bool {
+		self.conn.id == other.conn.id
+	}
+}
+impl Eq for TcpSocketDescriptor {}
+impl hash::Hash for TcpSocketDescriptor {
+	fn hash<H: hash::Hasher>(&self, hasher: &mut H) {
+		self.conn.id.hash(hasher)
+	}
+}
+
+impl TcpSocketDescriptor {
+	/// Gets the address of the remote end of this connection, if the socket is still open.
+	pub fn peer_addr(&self) -> Option<SocketAddr> {
+		self.conn.stream.peer_addr().ok()
+	}
+}
+
+impl SocketDescriptor for TcpSocketDescriptor {
+	fn send_data(&mut self, data: &Vec<u8>, write_offset: usize, resume_read: bool) -> usize {
+		let mut state = self.conn.state.lock().unwrap();
+		if resume_read && state.read_paused {
+			state.read_paused = false;
+			self.conn.read_ready.notify_all();
+		}
+		if state.disconnected {
+			return 0;
+		}
+		if state.outbound_data_len >= MAX_BUFFERED_BYTES {
+			state.write_event_pending = true;
+			return 0;
+		}
+		let to_send = data[write_offset..].to_vec();
+		let len = to_send.len();
+		state.outbound_data_len += len;
+		state.outbound_data.push_back(to_send);
+		self.conn.write_ready.notify_all();
+		len
+	}
+
+	fn disconnect_socket(&mut self) {
+		let mut state = self.conn.state.lock().unwrap();
+		self.conn.close(&mut state);
+	}
+}
+
+fn new_connection(stream: TcpStream) -> io::Result<TcpSocketDescriptor> {
+	stream.set_nonblocking(false)?;
+	stream.set_nodelay(true)?;
+	Ok(TcpSocketDescriptor {
+		conn: Arc::new(Connection {
+			id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::AcqRel),
+			stream,
+			state: Mutex::new(ConnectionState {
+				outbound_data: VecDeque::new(),
+				outbound_data_len: 0,
+				write_event_pending: false,
+				read_paused: false,
+				disconnected: false,
+				write_failed: false,
+			}),
+			read_ready: Condvar::new(),
+			write_ready: Condvar::new(),
+			event_lock: Mutex::new(()),
+		}),
+	})
+}
+
+fn refused_error() -> io::Error {
+	io::Error::new(io::ErrorKind::ConnectionRefused, "PeerManager refused the connection")
+}
+
+fn reader_thread(peer_manager: Arc<PeerManager<TcpSocketDescriptor>>, mut descriptor: TcpSocketDescriptor) {
+	let mut stream = match descriptor.conn.stream.try_clone() {
+		Ok(stream) => stream,
+		Err(_) => {
+			let mut state = descriptor.conn.state.lock().unwrap();
+			descriptor.conn.close(&mut state);
+			return;
+		},
+	};
+	let mut buf = [0; 8192];
+	loop {
+		{
+			let mut state = descriptor.conn.state.lock().unwrap();
+			while !state.disconnected && !state.write_failed && (state.read_paused || state.write_event_pending) {
+				state = descriptor.conn.read_ready.wait(state).unwrap();
+			}
+			if state.disconnected {
+				return;
+			}
+			if state.write_failed {
+				break;
+			}
+		}
+		let read_len = match stream.read(&mut buf) {
+			Ok(0) => break,
+			Ok(len) => len,
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(_) => break,
+		};
+
+		let conn = Arc::clone(&descriptor.conn);
+		let _event_lock = conn.event_lock.lock().unwrap();
+		if conn.state.lock().unwrap().disconnected {
+			return;
+		}
+		match peer_manager.read_event(&mut descriptor, buf[..read_len].to_vec()) {
+			Ok(pause_read) => {
+				if pause_read {
+					descriptor.conn.state.lock().unwrap().read_paused = true;
+				}
+			},
+			Err(_) => {
+				// read_event has already forgotten about this connection, no disconnect_event
+				// must be generated.
+				let mut state = descriptor.conn.state.lock().unwrap();
+				descriptor.conn.close(&mut state);
+				return;
+			},
+		}
+	}
+
+	// The remote end closed the connection, our socket failed or the writer thread failed.
+	let conn = Arc::clone(&descriptor.conn);
+	let _event_lock = conn.event_lock.lock().unwrap();
+	let generate_event = {
+		let mut state = descriptor.conn.state.lock().unwrap();
+		descriptor.conn.close(&mut state)
+	};
+	if generate_event {
+		peer_manager.disconnect_event(&descriptor);
+	}
+}
+
+fn writer_thread(peer_manager: Arc<PeerManager<TcpSocketDescriptor>>, mut descriptor: TcpSocketDescriptor) {
+	let mut stream = match descriptor.conn.stream.try_clone() {
+		Ok(stream) => stream,
+		Err(_) => {
+			descriptor.conn.fail_write();
+			return;
+		},
+	};
+	loop {
+		let next_data = {
+			let mut state = descriptor.conn.state.lock().unwrap();
+			while !state.disconnected && state.outbound_data.is_empty() && !state.write_event_pending {
+				state = descriptor.conn.write_ready.wait(state).unwrap();
+			}
+			if state.disconnected {
+				return;
+			}
+			match state.outbound_data.pop_front() {
+				Some(data) => Some(data),
+				None => {
+					state.write_event_pending = false;
+					descriptor.conn.read_ready.notify_all();
+					None
+				},
+			}
+		};
+
+		match next_data {
+			Some(data) => {
+				if stream.write_all(&data).is_err() {
+					descriptor.conn.fail_write();
+					return;
+				}
+				descriptor.conn.state.lock().unwrap().outbound_data_len -= data.len();
+			},
+			None => {
+				let conn = Arc::clone(&descriptor.conn);
+				let _event_lock = conn.event_lock.lock().unwrap();
+				if conn.state.lock().unwrap().disconnected {
+					return;
+				}
+				if peer_manager.write_event(&mut descriptor).is_err() {
+					descriptor.conn.fail_write();
+					return;
+				}
+			},
+		}
+	}
+}
+
+fn spawn_connection_threads(peer_manager: Arc<PeerManager<TcpSocketDescriptor>>, descriptor: TcpSocketDescriptor) {
+	let writer_peer_manager = Arc::clone(&peer_manager);
+	let writer_descriptor = descriptor.clone();
+	thread::spawn(move || writer_thread(writer_peer_manager, writer_descriptor));
+	thread::spawn(move || reader_thread(peer_manager, descriptor));
+}
+
+/// Registers an already-connected outbound TcpStream to the node with the given node_id with the
+/// PeerManager and starts servicing it.
+pub fn setup_outbound(peer_manager: Arc<PeerManager<TcpSocketDescriptor>>, their_node_id: PublicKey, stream: TcpStream) -> io::Result<()> {
+	let descriptor = new_connection(stream)?;
+	let initial_data = match peer_manager.new_outbound_connection(their_node_id, descriptor.clone()) {
+		Ok(data) => data,
+		Err(_) => {
+			let _ = descriptor.conn.stream.shutdown(Shutdown::Both);
+			return Err(refused_error());
+		},
+	};
+	{
+		let mut state = descriptor.conn.state.lock().unwrap();
+		state.outbound_data_len += initial_data.len();
+		state.outbound_data.push_back(initial_data);
+	}
+	spawn_connection_threads(peer_manager, descriptor);
+	Ok(())
+}
+
+/// Opens a TCP connection to the node with the given node_id at the given address, registers it
+/// with the PeerManager and starts servicing it.
+///
+/// Blocks until the TCP connection is established (or fails), but returns before the noise
+/// handshake completes. Once it does, their_node_id will appear in
+/// PeerManager::get_peer_node_ids.
+pub fn connect_outbound(peer_manager: Arc<PeerManager<TcpSocketDescriptor>>, their_node_id: PublicKey, addr: &SocketAddr) -> io::Result<()> {
+	let stream = TcpStream::connect_timeout(addr, Duration::from_secs(CONNECT_TIMEOUT_SECS))?;
+	setup_outbound(peer_manager, their_node_id, stream)
+}
+
+/// Whether to connect to peers over clearnet (IPv4/IPv6) addresses, and how to prioritize them
+/// relative to onion addresses.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ClearnetPolicy {
+	/// Use both clearnet and onion addresses, trying clearnet addresses first.
+	PreferClearnet,
+	/// Use both clearnet and onion addresses, trying onion addresses first.
+	PreferOnion,
+	/// Never connect to clearnet addresses.
+	ForbidClearnet,
+}
+
+/// Configuration for how we open outbound connections.
+#[derive(Clone, Debug)]
+pub struct NetConfig {
+	/// A SOCKS5 proxy (eg a local Tor daemon) through which all outbound connections are made. Onion
+	/// addresses can only be reached if this is set.
+	pub socks5_proxy: Option<SocketAddr>,
+	/// Which kinds of addresses we may connect to.
+	pub clearnet_policy: ClearnetPolicy,
+}
+
+impl Default for NetConfig {
+	fn default() -> Self {
+		NetConfig {
+			socks5_proxy: None,
+			clearnet_policy: ClearnetPolicy::PreferClearnet,
+		}
+	}
+}
+
+impl NetConfig {
+	/// Returns true if we can connect to the given address under this configuration.
+	pub fn is_address_usable(&self, addr: &NetAddress) -> bool {
+		if onion::is_onion_address(addr) {
+			self.socks5_proxy.is_some()
+		} else {
+			self.clearnet_policy != ClearnetPolicy::ForbidClearnet
+		}
+	}
+
+	/// Removes any addresses we cannot connect to from the given list and (stably) sorts the rest
+	/// so that the preferred kind of address comes first.
+	pub fn filter_addresses(&self, addresses: &mut Vec<NetAddress>) {
+		addresses.retain(|addr| self.is_address_usable(addr));
+		let prefer_onion = self.clearnet_policy != ClearnetPolicy::PreferClearnet;
+		addresses.sort_by_key(|addr| onion::is_onion_address(addr) != prefer_onion);
+	}
+}
+
+/// Opens a connection to the node with the given node_id at the given address, as permitted by
+/// config, registers it with the PeerManager and starts servicing it. If config specifies a SOCKS5
+/// proxy the connection is made through it.
+///
+/// Blocks until the connection is established (or fails), but returns before the noise handshake
+/// completes.
+pub fn connect_to_address(peer_manager: Arc<PeerManager<TcpSocketDescriptor>>, their_node_id: PublicKey, addr: &NetAddress, config: &NetConfig) -> io::Result<()> {
+	if !config.is_address_usable(addr) {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "Address type not permitted by our NetConfig"));
+	}
+	let timeout = Duration::from_secs(CONNECT_TIMEOUT_SECS);
+	let stream = match config.socks5_proxy {
+		Some(ref proxy_addr) => socks5::connect_via_proxy(proxy_addr, addr, timeout)?,
+		None => {
+			let socket_addr = match addr {
+				&NetAddress::IPv4 { addr, port } => SocketAddr::new(Ipv4Addr::from(addr).into(), port),
+				&NetAddress::IPv6 { addr, port } => SocketAddr::new(Ipv6Addr::from(addr).into(), port),
+				_ => unreachable!(),
+			};
+			TcpStream::connect_timeout(&socket_addr, timeout)?
+		},
+	};
+	setup_outbound(peer_manager, their_node_id, stream)
+}
+
+/// A Connector for PeerReconnector which opens connections with connect_to_address on a
+/// background thread.
+pub struct TcpConnector {
+	peer_manager: Arc<PeerManager<TcpSocketDescriptor>>,
+	config: NetConfig,
+}
+
+impl TcpConnector {
+	/// Constructs a new TcpConnector opening connections for the given PeerManager.
+	pub fn new(peer_manager: Arc<PeerManager<TcpSocketDescriptor>>, config: NetConfig) -> TcpConnector {
+		TcpConnector { peer_manager, config }
+	}
+}
+
+impl Connector for TcpConnector {
+	fn connect(&self, their_node_id: &PublicKey, addr: &NetAddress) -> bool {
+		if !self.config.is_address_usable(addr) {
+			return false;
+		}
+		let peer_manager = Arc::clone(&self.peer_manager);
+		let their_node_id = *their_node_id;
+		let addr = addr.clone();
+		let config = self.config.clone();
+		thread::spawn(move || { let _ = connect_to_address(peer_manager, their_node_id, &addr, &config); });
+		true
+	}
+
+	fn filter_addresses(&self, addresses: &mut Vec<NetAddress>) {
+		self.config.filter_addresses(addresses);
+	}
+}
+
+/// Registers a newly-accepted inbound TcpStream with the PeerManager and starts servicing it.
+///
+/// If the PeerManager refuses the connection (eg because the remote address is banned or we have
+/// too many peers) the socket is closed and an Err returned.
+pub fn setup_inbound(peer_manager: Arc<PeerManager<TcpSocketDescriptor>>, stream: TcpStream) -> io::Result<()> {
+	let their_address = stream.peer_addr().ok().map(|addr| addr.ip());
+	let descriptor = new_connection(stream)?;
+	if peer_manager.new_inbound_connection(descriptor.clone(), their_address).is_err() {
+		let _ = descriptor.conn.stream.shutdown(Shutdown::Both);
+		return Err(refused_error());
+	}
+	spawn_connection_threads(peer_manager, descriptor);
+	Ok(())
+}
+
+/// A background thread accepting inbound connections for a PeerManager. Stops accepting new
+/// connections when stopped or dropped (existing connections are unaffected).
+pub struct Listener {
+	local_addr: SocketAddr,
+	stop: Arc<AtomicBool>,
+	thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Listener {
+	/// Binds to the given address and starts accepting inbound connections, handing them to the
+	/// PeerManager via setup_inbound.
+	pub fn bind(peer_manager: Arc<PeerManager<TcpSocketDescriptor>>, addr: &SocketAddr) -> io::Result<Listener> {
+		let listener = TcpListener::bind(addr)?;
+		listener.set_nonblocking(true)?;
+		let local_addr = listener.local_addr()?;
+		let stop = Arc::new(AtomicBool::new(false));
+		let thread_stop = Arc::clone(&stop);
+		let thread = thread::spawn(move || {
+			while !thread_stop.load(Ordering::Acquire) {
+				match listener.accept() {
+					Ok((stream, _)) => {
+						let _ = setup_inbound(Arc::clone(&peer_manager), stream);
+					},
+					Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+						thread::sleep(Duration::from_millis(LISTENER_POLL_MILLIS));
+					},
+					Err(_) => {
+						thread::sleep(Duration::from_millis(ACCEPT_ERROR_BACKOFF_MILLIS));
+					},
+				}
+			}
+		});
+		Ok(Listener {
+			local_addr,
+			stop,
+			thread: Some(thread),
+		})
+	}
+
+	/// Gets the address we are listening on (useful if bound to port 0).
+	pub fn local_addr(&self) -> SocketAddr {
+		self.local_addr
+	}
+
+	/// Stops accepting new connections, blocking until the listening thread exits.
+	pub fn stop(&mut self) {
+		self.stop.store(true, Ordering::Release);
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+impl Drop for Listener {
+	fn drop(&mut self) {
+		self.stop();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ln::msgs;
+	use ln::peer_handler::{MessageHandler, PeerLimits, PeerManager};
+	use ln::peer_handler::SocketDescriptor;
+	use net::reconnect::Connector;
+	use net::onion;
+	use net::socks5::tests::TestProxy;
+	use net::tcp::{connect_outbound, connect_to_address, new_connection, reader_thread, ClearnetPolicy, Listener, NetConfig, TcpConnector, TcpSocketDescriptor, MAX_BUFFERED_BYTES};
+	use util::events;
+	use util::logger::Logger;
+	use util::test_utils;
+
+	use secp256k1::Secp256k1;
+	use secp256k1::key::{PublicKey, SecretKey};
+
+	use rand::{thread_rng, Rng};
+
+	use std::collections::HashMap;
+	use std::net::{SocketAddr, TcpListener, TcpStream};
+	use std::sync::Arc;
+	use std::sync::mpsc;
+	use std::thread;
+	use std::time::{Duration, Instant};
+
+	struct Node {
+		chan_handler: Arc<test_utils::TestChannelMessageHandler>,
+		peer_manager: Arc<PeerManager<TcpSocketDescriptor>>,
+		node_id: PublicKey,
+	}
+
+	fn create_node() -> Node {
+		let secp_ctx = Secp256k1::new();
+		let mut key_slice = [0; 32];
+		thread_rng().fill_bytes(&mut key_slice);
+		let node_secret = SecretKey::from_slice(&secp_ctx, &key_slice).unwrap();
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chan_handler = Arc::new(test_utils::TestChannelMessageHandler::new());
+		let msg_handler = MessageHandler { chan_handler: chan_handler.clone(), route_handler: Arc::new(test_utils::TestRoutingMessageHandler::new()) };
+		Node {
+			chan_handler,
+			peer_manager: Arc::new(PeerManager::new(msg_handler, node_secret, PeerLimits::default(), logger)),
+			node_id: PublicKey::from_secret_key(&secp_ctx, &node_secret),
+		}
+	}
+
+	fn wait_for<F: Fn() -> bool>(condition: F) {
+		let start = Instant::now();
+		while !condition() {
+			assert!(start.elapsed() < Duration::from_secs(10));
+			thread::sleep(Duration::from_millis(10));
+		}
+	}
+
+	#[test]
+	fn test_loopback_connection() {
+		// Connect two nodes over loopback and then disconnect them from one side, checking both
+		// PeerManagers see the connection come and go.
+		let node_a = create_node();
+		let node_b = create_node();
+
+		let listener = Listener::bind(node_b.peer_manager.clone(), &"127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+		connect_outbound(node_a.peer_manager.clone(), node_b.node_id, &listener.local_addr()).unwrap();
+
+		wait_for(|| node_a.peer_manager.get_peer_node_ids() == vec![node_b.node_id]);
+		wait_for(|| node_b.peer_manager.get_peer_node_ids() == vec![node_a.node_id]);
+
+		node_a.chan_handler.pending_events.lock().unwrap().push(events::Event::HandleError {
+			node_id: node_b.node_id,
+			action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }),
+		});
+		node_a.peer_manager.process_events();
+		assert!(node_a.peer_manager.get_peer_node_ids().is_empty());
+		wait_for(|| node_b.peer_manager.get_peer_node_ids().is_empty());
+	}
+
+	#[test]
+	fn test_send_buffer_limit() {
+		// Once MAX_BUFFERED_BYTES are waiting to be written, send_data should refuse data (making
+		// PeerManager pause reads) until the buffer drains.
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let mut descriptor = new_connection(stream).unwrap();
+
+		let data = vec![0; MAX_BUFFERED_BYTES];
+		assert_eq!(descriptor.send_data(&data, 0, false), MAX_BUFFERED_BYTES);
+		assert_eq!(descriptor.send_data(&data, 0, false), 0);
+		{
+			let mut state = descriptor.conn.state.lock().unwrap();
+			assert!(state.write_event_pending);
+			state.read_paused = true;
+			state.outbound_data.clear();
+			state.outbound_data_len = 0;
+		}
+		assert_eq!(descriptor.send_data(&data, 0, true), MAX_BUFFERED_BYTES);
+		assert!(!descriptor.conn.state.lock().unwrap().read_paused);
+
+		descriptor.disconnect_socket();
+		assert_eq!(descriptor.send_data(&data, 0, false), 0);
+	}
+
+	#[test]
+	fn test_write_failure_disconnects() {
+		// If the writer thread fails while the reader thread is paused, the reader must still wake
+		// up and generate the disconnect_event.
+		let node = create_node();
+		let their_node_id = create_node().node_id;
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let mut descriptor = new_connection(stream).unwrap();
+		node.peer_manager.new_outbound_connection(their_node_id, descriptor.clone()).unwrap();
+		descriptor.conn.state.lock().unwrap().read_paused = true;
+
+		let (sender, receiver) = mpsc::channel();
+		let thread_peer_manager = node.peer_manager.clone();
+		let thread_descriptor = descriptor.clone();
+		thread::spawn(move || {
+			reader_thread(thread_peer_manager, thread_descriptor);
+			sender.send(()).unwrap();
+		});
+
+		descriptor.conn.fail_write();
+		receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+		assert!(descriptor.conn.state.lock().unwrap().disconnected);
+		// The PeerManager no longer knows about the connection
+		assert!(node.peer_manager.write_event(&mut descriptor).is_err());
+	}
+
+	#[test]
+	fn test_address_filtering() {
+		let onion_addr = onion::parse_onion_address("expyuzz4wqqyqhjn.onion", 9735).unwrap();
+		let ipv4_addr = msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 };
+		let ipv6_addr = msgs::NetAddress::IPv6 { addr: [0; 16], port: 9735 };
+		let addresses = vec![onion_addr.clone(), ipv4_addr.clone(), ipv6_addr.clone()];
+		let proxy = Some("127.0.0.1:9050".parse::<SocketAddr>().unwrap());
+
+		// Without a proxy onion addresses are unusable
+		let mut filtered = addresses.clone();
+		NetConfig::default().filter_addresses(&mut filtered);
+		assert_eq!(filtered, vec![ipv4_addr.clone(), ipv6_addr.clone()]);
+
+		let mut filtered = addresses.clone();
+		NetConfig { socks5_proxy: proxy, clearnet_policy: ClearnetPolicy::PreferClearnet }.filter_addresses(&mut filtered);
+		assert_eq!(filtered, vec![ipv4_addr.clone(), ipv6_addr.clone(), onion_addr.clone()]);
+
+		let mut filtered = addresses.clone();
+		NetConfig { socks5_proxy: proxy, clearnet_policy: ClearnetPolicy::PreferOnion }.filter_addresses(&mut filtered);
+		assert_eq!(filtered, addresses);
+
+		let mut filtered = addresses.clone();
+		NetConfig { socks5_proxy: proxy, clearnet_policy: ClearnetPolicy::ForbidClearnet }.filter_addresses(&mut filtered);
+		assert_eq!(filtered, vec![onion_addr.clone()]);
+
+		let mut filtered = addresses.clone();
+		NetConfig { socks5_proxy: None, clearnet_policy: ClearnetPolicy::ForbidClearnet }.filter_addresses(&mut filtered);
+		assert!(filtered.is_empty());
+	}
+
+	#[test]
+	fn test_onion_connection() {
+		// Connect to a peer's onion address via a stand-in SOCKS5 proxy which forwards the
+		// connection to the peer's loopback listener.
+		let node_a = create_node();
+		let node_b = create_node();
+		let listener = Listener::bind(node_b.peer_manager.clone(), &"127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+
+		let onion_addr = onion::parse_onion_address("2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion", 9735).unwrap();
+		let mut hosts = HashMap::new();
+		hosts.insert(onion::onion_hostname(&onion_addr).unwrap(), listener.local_addr());
+		let proxy = TestProxy::start(hosts);
+		let config = NetConfig { socks5_proxy: Some(proxy.addr), clearnet_policy: ClearnetPolicy::ForbidClearnet };
+
+		// Clearnet addresses are refused without touching the proxy
+		let local_addr = match listener.local_addr() {
+			SocketAddr::V4(addr) => msgs::NetAddress::IPv4 { addr: addr.ip().octets(), port: addr.port() },
+			SocketAddr::V6(_) => panic!(),
+		};
+		assert!(connect_to_address(node_a.peer_manager.clone(), node_b.node_id, &local_addr, &config).is_err());
+		let connector = TcpConnector::new(node_a.peer_manager.clone(), config.clone());
+		assert!(!connector.connect(&node_b.node_id, &local_addr));
+		assert!(proxy.requested.lock().unwrap().is_empty());
+
+		assert!(connector.connect(&node_b.node_id, &onion_addr));
+		wait_for(|| node_a.peer_manager.get_peer_node_ids() == vec![node_b.node_id]);
+		wait_for(|| node_b.peer_manager.get_peer_node_ids() == vec![node_a.node_id]);
+		assert_eq!(*proxy.requested.lock().unwrap(), vec![onion::onion_hostname(&onion_addr).unwrap()]);
+	}
+}
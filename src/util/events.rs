@@ -16,6 +16,7 @@ use ln::msgs;
 use chain::transaction::OutPoint;
 
 use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::Transaction;
 
 use secp256k1::key::PublicKey;
 
@@ -52,11 +53,21 @@ pub enum Event {
 	/// ChannelManager::claim_funds to get it....
 	/// Note that if the preimage is not known, you must call ChannelManager::fail_htlc_backwards
 	/// to free up resources for this HTLC.
+	/// If you do neither before the HTLC gets close to expiring, it is failed backwards for you
+	/// in ChannelManager::block_connected.
 	PaymentReceived {
 		/// The hash for which the preimage should be handed to the ChannelManager.
 		payment_hash: [u8; 32],
 		/// The value, in thousandths of a satoshi, that this payment is for.
 		amt: u64,
+		/// The payment_secret the sender provided. If set, it was returned by
+		/// ChannelManager::create_inbound_payment(_for_hash) for this payment_hash and the payment
+		/// has been checked against it. If not, the payment hasn't been checked at all, so you
+		/// should only claim it if you were expecting it.
+		payment_secret: Option<[u8; 32]>,
+		/// The preimage to claim this payment with, if it was created with
+		/// ChannelManager::create_inbound_payment.
+		payment_preimage: Option<[u8; 32]>,
 	},
 	/// Indicates an outbound payment we made succeeded (ie it made it all the way to its target
 	/// and we got back the payment preimage for it).
@@ -68,6 +79,45 @@ pub enum Event {
 		/// store it somehow!
 		payment_preimage: [u8; 32],
 	},
+	/// Indicates an HTLC we forwarded was claimed by the next hop and we've claimed the
+	/// corresponding inbound HTLC from the previous hop, earning the difference as a routing fee.
+	/// Only generated once the previous hop has irrevocably committed to our claim, ie has revoked
+	/// every commitment transaction which included the HTLC.
+	PaymentForwarded {
+		/// The channel_id of the channel over which we received the HTLC.
+		prev_channel_id: [u8; 32],
+		/// The channel_id of the channel over which we forwarded the HTLC.
+		next_channel_id: [u8; 32],
+		/// The value, in thousandths of a satoshi, of the HTLC we received.
+		incoming_amt_msat: u64,
+		/// The value, in thousandths of a satoshi, of the HTLC we forwarded.
+		outgoing_amt_msat: u64,
+		/// The fee, in thousandths of a satoshi, we earned for forwarding the payment.
+		fee_earned_msat: u64,
+	},
+	/// Indicates we're holding on to an HTLC we were asked to forward, either because our
+	/// ForwardingPolicy asked us to or because it asked to be forwarded over a short_channel_id we
+	/// have no channel for but which our ForwardingPolicy intercepts (eg so that a channel to the
+	/// next hop can be opened just in time).
+	/// Call ChannelManager::forward_intercepted_htlc or ChannelManager::fail_intercepted_htlc to
+	/// release it.
+	HTLCIntercepted {
+		/// The id to pass to ChannelManager::forward_intercepted_htlc/fail_intercepted_htlc.
+		intercept_id: [u8; 32],
+		/// The short_channel_id the sender asked us to forward the HTLC over.
+		requested_next_short_channel_id: u64,
+		/// The payment_hash of the HTLC.
+		payment_hash: [u8; 32],
+		/// The value, in thousandths of a satoshi, of the HTLC we received.
+		inbound_amount_msat: u64,
+		/// The value, in thousandths of a satoshi, the sender expects us to forward.
+		expected_outbound_amount_msat: u64,
+		/// The cltv_expiry of the HTLC we received.
+		inbound_cltv_expiry: u32,
+		/// The cltv_expiry the HTLC must be forwarded with. The channel it is forwarded over must
+		/// have a cltv_expiry_delta no greater than the difference between the two.
+		outbound_cltv_expiry: u32,
+	},
 	/// Indicates an outbound payment we made failed. Probably some intermediary node dropped
 	/// something. You may wish to retry with a different route.
 	/// Note that duplicative PaymentFailed Events may be generated - it is your responsibility to
@@ -75,6 +125,61 @@ pub enum Event {
 	PaymentFailed {
 		/// The hash which was given to ChannelManager::send_payment.
 		payment_hash: [u8; 32],
+		/// Indicates the payment was permanently rejected by the recipient itself (eg because it
+		/// did not know the payment_hash). This implies that the payment has failed, not just the
+		/// route in question, and retrying it over a different route is pointless.
+		/// If this is not set, you may retry the payment via a different route.
+		rejected_by_dest: bool,
+		/// The node which generated the failure, if we could identify it from the onion error.
+		/// None if the HTLC was failed before reaching any other node or if the error was garbled.
+		failing_node: Option<PublicKey>,
+		/// The short_channel_id of the channel the failing_node was asked to forward the HTLC
+		/// over. None if the failing_node is the recipient or is unknown.
+		failing_short_channel_id: Option<u64>,
+		/// The BOLT 4 failure code describing why the HTLC failed, if known.
+		error_code: Option<u16>,
+		/// The channel_update included in the failure by the failing node, if any. This should
+		/// generally be applied to your routing graph before retrying the payment.
+		channel_update: Option<msgs::ChannelUpdate>,
+	},
+	/// Indicates both sides have exchanged signatures for the funding transaction of a channel,
+	/// which is now waiting for the funding transaction to confirm.
+	ChannelPending {
+		/// The channel_id of the channel.
+		channel_id: [u8; 32],
+		/// The value passed in to ChannelManager::create_channel, or 0 for inbound channels.
+		user_channel_id: u64,
+		/// The node_id of our counterparty.
+		counterparty_node_id: PublicKey,
+		/// The channel's funding transaction output.
+		funding_txo: OutPoint,
+	},
+	/// Indicates both sides have sent funding_locked for a channel, which may now be used to send
+	/// and receive payments.
+	ChannelReady {
+		/// The channel_id of the channel.
+		channel_id: [u8; 32],
+		/// The value passed in to ChannelManager::create_channel, or 0 for inbound channels.
+		user_channel_id: u64,
+		/// The node_id of our counterparty.
+		counterparty_node_id: PublicKey,
+	},
+	/// Indicates a channel has been closed and removed from the ChannelManager. Any funds still
+	/// locked in the channel are now handled on-chain by its ChannelMonitor.
+	ChannelClosed {
+		/// The channel_id of the channel which was closed.
+		channel_id: [u8; 32],
+		/// The value passed in to ChannelManager::create_channel, or 0 for inbound channels.
+		user_channel_id: u64,
+		/// Why the channel was closed.
+		reason: ClosureReason,
+		/// The transaction we broadcast to close the channel, if any. This is the final closing
+		/// transaction for cooperative closes, or our latest commitment transaction if we
+		/// force-closed the channel.
+		closing_transaction: Option<Transaction>,
+		/// The total fee, in satoshis, paid by the cooperative closing transaction. This may exceed
+		/// the negotiated closing fee if an output was too small to be included.
+		fee_satoshis: Option<u64>,
 	},
 	/// Used to indicate that ChannelManager::process_pending_htlc_forwards should be called at a
 	/// time in the future.
@@ -115,6 +220,16 @@ pub enum Event {
 		/// An optional additional announcement_signatures message which should be sent.
 		announcement_sigs: Option<msgs::AnnouncementSignatures>,
 	},
+	/// Used to indicate that an announcement_signatures message should be sent to the peer with the
+	/// given node_id, eg on reconnection if we never received theirs.
+	///
+	/// This event is handled by PeerManager::process_events if you are using a PeerManager.
+	SendAnnouncementSignatures {
+		/// The node_id of the node which should receive this message
+		node_id: PublicKey,
+		/// The message which should be sent.
+		msg: msgs::AnnouncementSignatures,
+	},
 	/// Used to indicate that a series of HTLC update messages, as well as a commitment_signed
 	/// message should be sent to the peer with the given node_id.
 	///
@@ -125,6 +240,16 @@ pub enum Event {
 		/// The update messages which should be sent. ALL messages in the struct should be sent!
 		updates: msgs::CommitmentUpdate,
 	},
+	/// Used to indicate that a revoke_and_ack message should be sent to the peer with the given
+	/// node_id, eg once a ChannelMonitor update which was holding it back has been stored.
+	///
+	/// This event is handled by PeerManager::process_events if you are using a PeerManager.
+	SendRevokeAndACK {
+		/// The node_id of the node which should receive this message
+		node_id: PublicKey,
+		/// The message which should be sent.
+		msg: msgs::RevokeAndACK,
+	},
 	/// Used to indicate that a shutdown message should be sent to the peer with the given node_id.
 	///
 	/// This event is handled by PeerManager::process_events if you are using a PeerManager.
@@ -151,6 +276,13 @@ pub enum Event {
 		/// The channel_update which should be sent.
 		msg: msgs::ChannelUpdate,
 	},
+	/// Used to indicate that our node_announcement should be broadcast to all peers.
+	///
+	/// This event is handled by PeerManager::process_events if you are using a PeerManager.
+	BroadcastNodeAnnouncement {
+		/// The node_announcement which should be sent.
+		msg: msgs::NodeAnnouncement,
+	},
 
 	//Error handling
 	/// Broadcast an error downstream to be handled
@@ -164,6 +296,40 @@ pub enum Event {
 	}
 }
 
+/// The reason a channel was closed, as provided in Event::ChannelClosed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClosureReason {
+	/// The channel was closed cooperatively, via a shutdown/closing_signed negotiation (or before
+	/// it was funded).
+	CooperativeClosure,
+	/// We force-closed the channel via ChannelManager::force_close_channel or
+	/// ChannelManager::force_close_all_channels.
+	HolderForceClosed,
+	/// Our counterparty force-closed the channel, either by sending us an error message or by
+	/// broadcasting a transaction spending the funding output.
+	CounterpartyForceClosed {
+		/// The error message our counterparty sent us, if any.
+		peer_msg: Option<String>,
+	},
+	/// The channel was closed due to a protocol violation, either by our counterparty or detected
+	/// by us.
+	ProcessingError {
+		/// A description of the error.
+		err: String,
+	},
+	/// An update to the channel's ChannelMonitor failed permanently, so we could no longer safely
+	/// operate the channel.
+	MonitorUpdateFailed,
+	/// HTLCs in the channel were about to expire, so we broadcast our commitment transaction to
+	/// claim or time them out on-chain.
+	HTLCsTimedOut,
+	/// The channel's funding transaction was un-confirmed by a chain reorganization.
+	FundingTxUnconfirmed,
+	/// Our counterparty disconnected and can no longer be reached, or it disconnected before the
+	/// channel was funded.
+	DisconnectedPeer,
+}
+
 /// A trait indicating an object may generate events
 pub trait EventsProvider {
 	/// Gets the list of pending events which were generated by previous actions, clearing the list
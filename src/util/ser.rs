@@ -138,6 +138,51 @@ impl<R: Read> Readable<R> for bool {
 	}
 }
 
+/// A BigSize integer, as used for the types and lengths of TLV records: a single byte for values
+/// below 0xfd, otherwise 0xfd, 0xfe or 0xff followed by the value as a big-endian u16, u32 or u64.
+pub(crate) struct BigSize(pub u64);
+
+impl Writeable for BigSize {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		if self.0 < 0xfd {
+			(self.0 as u8).write(writer)
+		} else if self.0 <= 0xffff {
+			0xfdu8.write(writer)?;
+			(self.0 as u16).write(writer)
+		} else if self.0 <= 0xffffffff {
+			0xfeu8.write(writer)?;
+			(self.0 as u32).write(writer)
+		} else {
+			0xffu8.write(writer)?;
+			self.0.write(writer)
+		}
+	}
+}
+
+impl BigSize {
+	/// Reads the rest of a BigSize whose first byte has already been read as prefix. Non-minimal
+	/// encodings are rejected.
+	pub(crate) fn read_after_prefix<R: Read>(prefix: u8, reader: &mut R) -> Result<BigSize, DecodeError> {
+		let (value, min) = match prefix {
+			0xff => (Readable::read(reader)?, 0x100000000),
+			0xfe => (<u32 as Readable<R>>::read(reader)? as u64, 0x10000),
+			0xfd => (<u16 as Readable<R>>::read(reader)? as u64, 0xfd),
+			_ => return Ok(BigSize(prefix as u64)),
+		};
+		if value < min {
+			return Err(DecodeError::InvalidValue);
+		}
+		Ok(BigSize(value))
+	}
+}
+
+impl<R: Read> Readable<R> for BigSize {
+	fn read(reader: &mut R) -> Result<BigSize, DecodeError> {
+		let prefix: u8 = Readable::read(reader)?;
+		BigSize::read_after_prefix(prefix, reader)
+	}
+}
+
 // u8 arrays
 macro_rules! impl_array {
 	( $size:expr ) => (
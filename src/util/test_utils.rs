@@ -9,9 +9,11 @@ use util::logger::{Logger, Level, Record};
 use util::ser::{Readable, Writer};
 
 use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::script::Script;
 
 use secp256k1::PublicKey;
 
+use std::collections::HashSet;
 use std::sync::{Arc,Mutex};
 use std::{mem};
 
@@ -27,23 +29,30 @@ impl Writer for VecWriter {
 }
 
 pub struct TestFeeEstimator {
-	pub sat_per_kw: u64,
+	pub sat_per_kw: Mutex<u64>,
+}
+impl TestFeeEstimator {
+	pub fn new(sat_per_kw: u64) -> Self {
+		TestFeeEstimator { sat_per_kw: Mutex::new(sat_per_kw) }
+	}
 }
 impl chaininterface::FeeEstimator for TestFeeEstimator {
 	fn get_est_sat_per_1000_weight(&self, _confirmation_target: ConfirmationTarget) -> u64 {
-		self.sat_per_kw
+		*self.sat_per_kw.lock().unwrap()
 	}
 }
 
 pub struct TestChannelMonitor {
 	pub added_monitors: Mutex<Vec<(OutPoint, channelmonitor::ChannelMonitor)>>,
 	pub simple_monitor: Arc<channelmonitor::SimpleManyChannelMonitor<OutPoint>>,
+	pub update_ret: Mutex<Result<(), channelmonitor::ChannelMonitorUpdateErr>>,
 }
 impl TestChannelMonitor {
-	pub fn new(chain_monitor: Arc<chaininterface::ChainWatchInterface>, broadcaster: Arc<chaininterface::BroadcasterInterface>) -> Self {
+	pub fn new(chain_monitor: Arc<chaininterface::ChainWatchInterface>, broadcaster: Arc<chaininterface::BroadcasterInterface>, fee_estimator: Arc<chaininterface::FeeEstimator>) -> Self {
 		Self {
 			added_monitors: Mutex::new(Vec::new()),
-			simple_monitor: channelmonitor::SimpleManyChannelMonitor::new(chain_monitor, broadcaster),
+			simple_monitor: channelmonitor::SimpleManyChannelMonitor::new(chain_monitor, broadcaster, fee_estimator),
+			update_ret: Mutex::new(Ok(())),
 		}
 	}
 }
@@ -57,6 +66,7 @@ impl channelmonitor::ManyChannelMonitor for TestChannelMonitor {
 		w.0.clear();
 		monitor.write_for_watchtower(&mut w).unwrap(); // This at least shouldn't crash...
 		self.added_monitors.lock().unwrap().push((funding_txo, monitor.clone()));
+		self.update_ret.lock().unwrap().clone()?;
 		self.simple_monitor.add_update_monitor(funding_txo, monitor)
 	}
 }
@@ -70,14 +80,30 @@ impl chaininterface::BroadcasterInterface for TestBroadcaster {
 	}
 }
 
+pub struct TestWallet {
+	pub shutdown_scriptpubkey: Script,
+}
+impl TestWallet {
+	pub fn new(shutdown_scriptpubkey: Script) -> TestWallet {
+		TestWallet { shutdown_scriptpubkey }
+	}
+}
+impl chaininterface::WalletInterface for TestWallet {
+	fn get_shutdown_scriptpubkey(&self) -> Script {
+		self.shutdown_scriptpubkey.clone()
+	}
+}
+
 pub struct TestChannelMessageHandler {
 	pub pending_events: Mutex<Vec<events::Event>>,
+	pub peers_with_channels: Mutex<HashSet<PublicKey>>,
 }
 
 impl TestChannelMessageHandler {
 	pub fn new() -> Self {
 		TestChannelMessageHandler {
 			pending_events: Mutex::new(Vec::new()),
+			peers_with_channels: Mutex::new(HashSet::new()),
 		}
 	}
 }
@@ -131,8 +157,11 @@ impl msgs::ChannelMessageHandler for TestChannelMessageHandler {
 	fn handle_channel_reestablish(&self, _their_node_id: &PublicKey, _msg: &msgs::ChannelReestablish) -> Result<(Option<msgs::FundingLocked>, Option<msgs::RevokeAndACK>, Option<msgs::CommitmentUpdate>), HandleError> {
 		Err(HandleError { err: "", action: None })
 	}
+	fn has_funded_channels(&self, their_node_id: &PublicKey) -> bool {
+		self.peers_with_channels.lock().unwrap().contains(their_node_id)
+	}
 	fn peer_disconnected(&self, _their_node_id: &PublicKey, _no_connection_possible: bool) {}
-	fn peer_connected(&self, _their_node_id: &PublicKey) -> Vec<msgs::ChannelReestablish> {
+	fn peer_connected(&self, _their_node_id: &PublicKey, _their_features: &msgs::LocalFeatures) -> Vec<msgs::ChannelReestablish> {
 		Vec::new()
 	}
 	fn handle_error(&self, _their_node_id: &PublicKey, _msg: &msgs::ErrorMessage) {}